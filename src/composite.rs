@@ -0,0 +1,64 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Composites `image` over an opaque `background` color, returning an RGB image with no
+/// alpha channel.
+pub fn flatten_onto(image: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let rgba = image.to_rgba8();
+
+    let flattened = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as u32;
+        let mut flattened = [0u8; 3];
+        for channel in 0..3 {
+            let foreground = pixel[channel] as u32 * alpha;
+            let behind = background[channel] as u32 * (255 - alpha);
+            flattened[channel] = ((foreground + behind + 127) / 255) as u8;
+        }
+        Rgb(flattened)
+    });
+
+    DynamicImage::ImageRgb8(flattened)
+}
+
+/// Parses a background color given as `#RRGGBB`, `RRGGBB`, `white` or `black`.
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    match value.to_ascii_lowercase().as_str() {
+        "white" => return Some([255, 255, 255]),
+        "black" => return Some([0, 0, 0]),
+        _ => {}
+    }
+
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_half_alpha_red_over_white_is_pink() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 128])));
+
+        let flattened = flatten_onto(&image, [255, 255, 255]).to_rgba8();
+
+        for pixel in flattened.pixels() {
+            assert_eq!(pixel, &Rgba([255, 127, 127, 255]));
+        }
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_color("00ff00"), Some([0, 255, 0]));
+        assert_eq!(parse_color("white"), Some([255, 255, 255]));
+        assert_eq!(parse_color("#FFF"), None);
+        assert_eq!(parse_color("zzzzzz"), None);
+    }
+}
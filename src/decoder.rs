@@ -0,0 +1,1494 @@
+use crate::encoder::{
+    ADAM7_PASSES, EncoderError, FilterType, PNG_SIGNATURE, RawChunk, decompress_custom,
+    supported_color_types,
+};
+use image::{DynamicImage, ImageBuffer, Pixel};
+use std::io::Read;
+
+/// A single chunk as laid out in a PNG byte stream.
+pub struct Chunk<'a> {
+    pub chunk_type: [u8; 4],
+    pub data: &'a [u8],
+    /// The CRC-32 stored after the data, and the one computed over the type and data.
+    pub stored_crc: u32,
+    pub computed_crc: u32,
+    /// The whole chunk as stored, including its length, type and CRC fields.
+    pub raw: &'a [u8],
+}
+
+impl Chunk<'_> {
+    pub fn crc_ok(&self) -> bool {
+        self.stored_crc == self.computed_crc
+    }
+}
+
+/// Upper bounds on chunk sizes accepted while decoding.
+///
+/// Limits are looked up by chunk type first and fall back to `default_limit`. A limit of
+/// `None` leaves that chunk type unbounded.
+#[derive(Clone, Debug)]
+pub struct DecodeLimits {
+    pub default_limit: Option<usize>,
+    chunk_limits: Vec<([u8; 4], Option<usize>)>,
+}
+
+impl DecodeLimits {
+    /// Sets the limit for one chunk type, replacing any previous limit for it.
+    pub fn with_chunk_limit(mut self, chunk_type: [u8; 4], limit: Option<usize>) -> Self {
+        self.chunk_limits
+            .retain(|(existing, _)| *existing != chunk_type);
+        self.chunk_limits.push((chunk_type, limit));
+        self
+    }
+
+    pub fn limit_for(&self, chunk_type: &[u8; 4]) -> Option<usize> {
+        self.chunk_limits
+            .iter()
+            .find(|(existing, _)| existing == chunk_type)
+            .map_or(self.default_limit, |(_, limit)| *limit)
+    }
+}
+
+impl Default for DecodeLimits {
+    /// Caps text chunks at 1 MiB and iCCP at 16 MiB, leaves IDAT unbounded, and holds
+    /// everything else to the spec's 2^31 - 1 maximum chunk length.
+    fn default() -> Self {
+        DecodeLimits {
+            default_limit: Some(0x7FFF_FFFF),
+            chunk_limits: vec![
+                (*b"tEXt", Some(1 << 20)),
+                (*b"zTXt", Some(1 << 20)),
+                (*b"iTXt", Some(1 << 20)),
+                (*b"iCCP", Some(16 << 20)),
+                (*b"IDAT", None),
+            ],
+        }
+    }
+}
+
+/// Reads the chunks of a PNG byte stream one at a time, without decoding any pixels.
+///
+/// Iteration starts after the signature and stops after IEND. Truncated or over-limit
+/// chunks, or a stream that ends before IEND, yield an error and end the walk.
+pub struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    done: bool,
+    limits: DecodeLimits,
+}
+
+impl<'a> ChunkReader<'a> {
+    /// Checks the PNG signature and returns a reader over the chunks that follow it.
+    pub fn new(bytes: &'a [u8]) -> std::io::Result<Self> {
+        Self::with_limits(bytes, &DecodeLimits::default())
+    }
+
+    /// Like [`ChunkReader::new`], but holds every chunk to `limits`.
+    pub fn with_limits(bytes: &'a [u8], limits: &DecodeLimits) -> std::io::Result<Self> {
+        if bytes.len() < PNG_SIGNATURE.len() || bytes[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
+            return Err(invalid_data("Invalid PNG signature"));
+        }
+
+        Ok(ChunkReader {
+            bytes,
+            pos: PNG_SIGNATURE.len(),
+            done: false,
+            limits: limits.clone(),
+        })
+    }
+}
+
+impl<'a> Iterator for ChunkReader<'a> {
+    type Item = std::io::Result<Chunk<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let remaining = &self.bytes[self.pos..];
+        if remaining.is_empty() {
+            self.done = true;
+            return Some(Err(invalid_data("Missing IEND chunk")));
+        }
+        if remaining.len() < 12 {
+            self.done = true;
+            return Some(Err(invalid_data("Truncated chunk header")));
+        }
+        let length = u32::from_be_bytes([remaining[0], remaining[1], remaining[2], remaining[3]]);
+        let length = length as usize;
+        let chunk_type = [remaining[4], remaining[5], remaining[6], remaining[7]];
+
+        if let Some(limit) = self.limits.limit_for(&chunk_type)
+            && length > limit
+        {
+            self.done = true;
+            return Some(Err(chunk_too_large(&chunk_type, limit)));
+        }
+
+        if remaining.len() - 12 < length {
+            self.done = true;
+            return Some(Err(invalid_data("Truncated chunk data")));
+        }
+
+        let data = &remaining[8..8 + length];
+        let stored_crc = u32::from_be_bytes([
+            remaining[8 + length],
+            remaining[9 + length],
+            remaining[10 + length],
+            remaining[11 + length],
+        ]);
+
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&chunk_type);
+        crc.update(data);
+
+        self.pos += 12 + length;
+        if &chunk_type == b"IEND" {
+            self.done = true;
+        }
+
+        Some(Ok(Chunk {
+            chunk_type,
+            data,
+            stored_crc,
+            computed_crc: crc.finalize(),
+            raw: &remaining[..12 + length],
+        }))
+    }
+}
+
+/// Returns the chunks following the PNG signature, or an error if the signature is wrong.
+pub(crate) fn chunks(bytes: &[u8]) -> std::io::Result<ChunkReader<'_>> {
+    ChunkReader::new(bytes)
+}
+
+/// Checks that `bytes` is a structurally well-formed PNG without decoding any pixels.
+///
+/// Verifies the signature, every chunk CRC, that IHDR comes first, that the IDAT chunks
+/// are present and consecutive, and that IEND is the last thing in the stream.
+pub fn is_valid_png(bytes: &[u8]) -> std::io::Result<()> {
+    let mut seen_ihdr = false;
+    let mut seen_iend = false;
+    let mut idat_state = IdatState::NotSeen;
+    let mut consumed = PNG_SIGNATURE.len();
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        consumed += 12 + chunk.data.len();
+
+        if !chunk.chunk_type.iter().all(u8::is_ascii_alphabetic) {
+            return Err(invalid_data("Invalid chunk type"));
+        }
+
+        if !chunk.crc_ok() {
+            return Err(invalid_data(&format!(
+                "CRC mismatch in {} chunk",
+                String::from_utf8_lossy(&chunk.chunk_type)
+            )));
+        }
+
+        match &chunk.chunk_type {
+            b"IHDR" => {
+                if seen_ihdr {
+                    return Err(invalid_data("Duplicate IHDR chunk"));
+                }
+                if chunk.data.len() != 13 {
+                    return Err(invalid_data("Invalid IHDR length"));
+                }
+                seen_ihdr = true;
+            }
+            _ if !seen_ihdr => return Err(invalid_data("First chunk is not IHDR")),
+            b"IDAT" => match idat_state {
+                IdatState::NotSeen | IdatState::InProgress => idat_state = IdatState::InProgress,
+                IdatState::Finished => return Err(non_contiguous_idat()),
+            },
+            b"IEND" => seen_iend = true,
+            _ => {
+                if idat_state == IdatState::InProgress {
+                    idat_state = IdatState::Finished;
+                }
+            }
+        }
+    }
+
+    if !seen_ihdr {
+        return Err(invalid_data("Missing IHDR chunk"));
+    }
+    if idat_state == IdatState::NotSeen {
+        return Err(invalid_data("Missing IDAT chunk"));
+    }
+    if !seen_iend {
+        return Err(invalid_data("Missing IEND chunk"));
+    }
+    if consumed != bytes.len() {
+        return Err(invalid_data("Trailing data after IEND"));
+    }
+
+    Ok(())
+}
+
+/// How strictly the decoder treats spec violations it can recover from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeMode {
+    /// Reject any deviation from the PNG specification.
+    Strict,
+    /// Warn about recoverable deviations and carry on.
+    Lenient,
+}
+
+/// A recoverable spec violation that lenient decoding worked around.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeWarning {
+    /// IDAT chunks were interrupted by another chunk; their payloads were concatenated.
+    NonContiguousIdat,
+    /// The IDAT stream has no zlib header; it was inflated as raw DEFLATE.
+    RawDeflateIdat,
+}
+
+impl std::fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeWarning::NonContiguousIdat => {
+                write!(f, "IDAT chunks are not contiguous, concatenating")
+            }
+            DecodeWarning::RawDeflateIdat => {
+                write!(
+                    f,
+                    "IDAT is missing its zlib header, reading it as raw DEFLATE"
+                )
+            }
+        }
+    }
+}
+
+/// The concatenated IDAT payloads, plus any warnings lenient mode raised on the way.
+#[derive(Debug)]
+pub struct IdatStream {
+    pub data: Vec<u8>,
+    pub warnings: Vec<DecodeWarning>,
+}
+
+/// Concatenates the payloads of all IDAT chunks into a single zlib stream.
+///
+/// The spec requires IDAT chunks to be consecutive. In strict mode an IDAT sequence
+/// interrupted by another chunk fails with [`EncoderError::NonContiguousIdat`]; in
+/// lenient mode the payloads are concatenated anyway and the returned warnings say so,
+/// for the caller to report. Every chunk is held to `limits`, failing with
+/// [`EncoderError::ChunkTooLarge`].
+pub fn collect_idat(
+    bytes: &[u8],
+    mode: DecodeMode,
+    limits: &DecodeLimits,
+) -> Result<IdatStream, EncoderError> {
+    walk_idat(bytes, mode, limits, |_| Ok(()))
+}
+
+/// [`collect_idat`], handing every chunk to `visit` as it is read so callers that need
+/// other chunks too can pick them up in the same pass.
+fn walk_idat<'a>(
+    bytes: &'a [u8],
+    mode: DecodeMode,
+    limits: &DecodeLimits,
+    mut visit: impl FnMut(&Chunk<'a>) -> Result<(), EncoderError>,
+) -> Result<IdatStream, EncoderError> {
+    let mut idat_data = Vec::new();
+    let mut warnings = Vec::new();
+    let mut idat_state = IdatState::NotSeen;
+
+    for chunk in ChunkReader::with_limits(bytes, limits)? {
+        let chunk = chunk?;
+        visit(&chunk)?;
+
+        match &chunk.chunk_type {
+            b"IDAT" => {
+                if idat_state == IdatState::Finished {
+                    match mode {
+                        DecodeMode::Strict => return Err(EncoderError::NonContiguousIdat),
+                        DecodeMode::Lenient => warnings.push(DecodeWarning::NonContiguousIdat),
+                    }
+                }
+                idat_state = IdatState::InProgress;
+                idat_data.extend_from_slice(chunk.data);
+            }
+            _ => {
+                if idat_state == IdatState::InProgress {
+                    idat_state = IdatState::Finished;
+                }
+            }
+        }
+    }
+
+    if idat_state == IdatState::NotSeen {
+        return Err(invalid_data("Missing IDAT chunk").into());
+    }
+
+    Ok(IdatStream {
+        data: idat_data,
+        warnings,
+    })
+}
+
+/// Image properties from the IHDR chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PngInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub interlaced: bool,
+}
+
+/// Reads just the signature and IHDR chunk (the first 33 bytes) from `reader`.
+///
+/// Nothing past IHDR is read, which makes this suitable for quickly querying the
+/// dimensions of many files.
+pub fn read_header_only<R: Read>(mut reader: R) -> std::io::Result<PngInfo> {
+    let mut header = [0u8; 33];
+    reader.read_exact(&mut header)?;
+
+    if header[..8] != PNG_SIGNATURE {
+        return Err(invalid_data("Invalid PNG signature"));
+    }
+    if header[8..12] != 13u32.to_be_bytes() || &header[12..16] != b"IHDR" {
+        return Err(invalid_data("First chunk is not IHDR"));
+    }
+
+    let stored_crc = u32::from_be_bytes([header[29], header[30], header[31], header[32]]);
+    if crc32fast::hash(&header[12..29]) != stored_crc {
+        return Err(invalid_data("CRC mismatch in IHDR chunk"));
+    }
+
+    Ok(PngInfo::from_ihdr(&header[16..29]))
+}
+
+impl PngInfo {
+    /// Reads the fields of a 13-byte IHDR payload.
+    fn from_ihdr(data: &[u8]) -> Self {
+        PngInfo {
+            width: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            height: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            bit_depth: data[8],
+            color_type: data[9],
+            interlaced: data[12] == 1,
+        }
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// Decodes a PNG into an image.
+///
+/// Every chunk's CRC is checked before it is used, failing with
+/// [`EncoderError::CrcMismatch`]. Handles every color type and bit depth the encoder can
+/// write, interlaced or not.
+/// Palette images and gray images with a tRNS chunk gain an alpha channel; samples below
+/// 8 bits are scaled up to 8. Decodes strictly under the default [`DecodeLimits`]; see
+/// [`decode_png_with`].
+pub fn decode_png(bytes: &[u8]) -> Result<DynamicImage, EncoderError> {
+    decode_png_with(bytes, DecodeMode::Strict, &DecodeLimits::default()).map(|(image, _)| image)
+}
+
+/// Like [`decode_png`], but reads the chunks as [`collect_idat`] does under `mode` and
+/// `limits`. A second IHDR or a stream without IEND is always an error. Lenient mode
+/// also reads IDAT data that lacks its zlib header, as some broken encoders write it,
+/// as raw DEFLATE. Returns the warnings lenient mode raised alongside the image, for
+/// the caller to report.
+pub fn decode_png_with(
+    bytes: &[u8],
+    mode: DecodeMode,
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Vec<DecodeWarning>), EncoderError> {
+    let mut info = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+
+    let idat = walk_idat(bytes, mode, limits, |chunk| {
+        if !chunk.crc_ok() {
+            return Err(EncoderError::CrcMismatch {
+                chunk: chunk.chunk_type,
+                expected: chunk.stored_crc,
+                actual: chunk.computed_crc,
+            });
+        }
+
+        match &chunk.chunk_type {
+            b"IHDR" if info.is_some() => {
+                return Err(invalid_data("Duplicate IHDR chunk").into());
+            }
+            b"IHDR" if chunk.data.len() == 13 => {
+                // Compression and filter method 0 are the only ones defined; interlace
+                // method 1 is Adam7
+                if chunk.data[10] != 0 || chunk.data[11] != 0 || chunk.data[12] > 1 {
+                    return Err(invalid_data("Unsupported IHDR method").into());
+                }
+                info = Some(PngInfo::from_ihdr(chunk.data));
+            }
+            _ if info.is_none() => {
+                return Err(invalid_data("First chunk is not a valid IHDR").into());
+            }
+            b"PLTE" => palette = chunk.data,
+            b"tRNS" => transparency = chunk.data,
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let info = info.ok_or_else(|| invalid_data("Missing IHDR chunk"))?;
+    if !supported_color_types().contains(&(info.color_type, info.bit_depth)) {
+        return Err(EncoderError::UnsupportedColorType(info.color_type));
+    }
+    if idat.data.is_empty() {
+        return Err(invalid_data("Missing IDAT chunk").into());
+    }
+
+    let limit = filtered_len(&info)?;
+    let mut warnings = idat.warnings;
+    let inflated = if mode == DecodeMode::Lenient && !has_zlib_header(&idat.data) {
+        warnings.push(DecodeWarning::RawDeflateIdat);
+        inflate_raw_idat(&idat.data, limit)?
+    } else {
+        inflate_idat(&idat.data, limit)?
+    };
+    let pixels = unfilter_pixels(&inflated, &info)?;
+    let image = assemble_image(&info, pixels, palette, transparency)?;
+    Ok((image, warnings))
+}
+
+/// Whether `data` starts with a valid zlib header: DEFLATE compression with the header
+/// check bits making the first two bytes a multiple of 31.
+fn has_zlib_header(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0F == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// Size of the filtered scanlines of every pass: each row's samples plus its filter
+/// type byte.
+fn filtered_len(info: &PngInfo) -> Result<usize, EncoderError> {
+    let bits_per_pixel = info.channels() * info.bit_depth as usize;
+    let passes: &[(usize, usize, usize, usize)] = if info.interlaced {
+        &ADAM7_PASSES
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    passes
+        .iter()
+        .try_fold(0usize, |total, &(x0, y0, dx, dy)| {
+            let width = (info.width as usize + dx - 1 - x0) / dx;
+            let height = (info.height as usize + dy - 1 - y0) / dy;
+            if width == 0 || height == 0 {
+                return Some(total);
+            }
+            let row = width.checked_mul(bits_per_pixel)?.div_ceil(8) + 1;
+            total.checked_add(row.checked_mul(height)?)
+        })
+        .ok_or(EncoderError::ImageTooLarge)
+}
+
+/// Inflates the concatenated IDAT payloads, failing with
+/// [`EncoderError::OutputTooLarge`] rather than producing more than `limit` bytes.
+///
+/// The custom decompressor reads the stored and fixed-Huffman blocks the custom method
+/// writes; streams from other zlib encoders, which use a different header or dynamic
+/// Huffman blocks, go to flate2 instead.
+fn inflate_idat(data: &[u8], limit: usize) -> Result<Vec<u8>, EncoderError> {
+    match decompress_custom(data, limit) {
+        Err(EncoderError::InvalidZlibHeader | EncoderError::UnsupportedBlockType(2)) => {
+            let mut inflated = Vec::new();
+            flate2::read::ZlibDecoder::new(data)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut inflated)?;
+            if inflated.len() > limit {
+                return Err(EncoderError::OutputTooLarge(limit));
+            }
+            Ok(inflated)
+        }
+        result => result,
+    }
+}
+
+/// Inflates IDAT data that is a bare DEFLATE stream, without zlib header or Adler-32,
+/// under the same `limit` as [`inflate_idat`].
+fn inflate_raw_idat(data: &[u8], limit: usize) -> Result<Vec<u8>, EncoderError> {
+    let mut inflated = Vec::new();
+    flate2::read::DeflateDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > limit {
+        return Err(EncoderError::OutputTooLarge(limit));
+    }
+    Ok(inflated)
+}
+
+/// Reverses the scanline filters of each pass and places its pixels in image order.
+///
+/// Every sample of the result takes a whole byte, or two big-endian bytes at 16 bits.
+fn unfilter_pixels(data: &[u8], info: &PngInfo) -> Result<Vec<u8>, EncoderError> {
+    let (width, height) = (info.width as usize, info.height as usize);
+    let bit_depth = info.bit_depth as usize;
+    let bits_per_pixel = info.channels() * bit_depth;
+    let bytes_per_pixel = bits_per_pixel.div_ceil(8);
+
+    let mut pixels = width
+        .checked_mul(height)
+        .and_then(|count| count.checked_mul(bytes_per_pixel))
+        .map(|len| vec![0u8; len])
+        .ok_or(EncoderError::ImageTooLarge)?;
+
+    let passes: &[(usize, usize, usize, usize)] = if info.interlaced {
+        &ADAM7_PASSES
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    let mut rows = data;
+    for &(x0, y0, dx, dy) in passes {
+        let pass_width = (width + dx - 1).saturating_sub(x0) / dx;
+        let pass_height = (height + dy - 1).saturating_sub(y0) / dy;
+        if pass_width == 0 {
+            continue;
+        }
+
+        let stride = (pass_width * bits_per_pixel).div_ceil(8);
+        let mut previous = vec![0u8; stride];
+        for y in 0..pass_height {
+            let Some((&filter, rest)) = rows.split_first() else {
+                return Err(EncoderError::Truncated);
+            };
+            if rest.len() < stride {
+                return Err(EncoderError::Truncated);
+            }
+            let (row, rest) = rest.split_at(stride);
+            rows = rest;
+
+            let mut row = row.to_vec();
+            FilterType::from_byte(filter)
+                .ok_or(EncoderError::InvalidFilterType(filter))?
+                .unfilter_row(&mut row, &previous, bytes_per_pixel);
+
+            let image_row = (y0 + y * dy) * width;
+            for x in 0..pass_width {
+                let target = (image_row + x0 + x * dx) * bytes_per_pixel;
+                if bit_depth < 8 {
+                    // Sub-byte samples only occur with a single channel
+                    let bit = x * bit_depth;
+                    pixels[target] = (row[bit / 8] << (bit % 8)) >> (8 - bit_depth);
+                } else {
+                    let source = x * bytes_per_pixel;
+                    pixels[target..target + bytes_per_pixel]
+                        .copy_from_slice(&row[source..source + bytes_per_pixel]);
+                }
+            }
+            previous = row;
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Builds the image for `pixels` as produced by [`unfilter_pixels`], resolving palette
+/// indices and tRNS transparency.
+fn assemble_image(
+    info: &PngInfo,
+    pixels: Vec<u8>,
+    palette: &[u8],
+    transparency: &[u8],
+) -> Result<DynamicImage, EncoderError> {
+    let wide = || -> Vec<u16> {
+        pixels
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect()
+    };
+
+    let image = match (info.color_type, info.bit_depth) {
+        (3, _) => {
+            let channels = if transparency.is_empty() { 3 } else { 4 };
+            let mut samples = Vec::with_capacity(pixels.len() * channels);
+            for &index in &pixels {
+                let index = index as usize;
+                let entry = palette
+                    .get(index * 3..index * 3 + 3)
+                    .ok_or_else(|| invalid_data("Palette index out of range"))?;
+                samples.extend_from_slice(entry);
+                if channels == 4 {
+                    samples.push(transparency.get(index).copied().unwrap_or(255));
+                }
+            }
+            if channels == 4 {
+                DynamicImage::ImageRgba8(image_buffer(info, samples)?)
+            } else {
+                DynamicImage::ImageRgb8(image_buffer(info, samples)?)
+            }
+        }
+        (0, 16) => {
+            let samples = wide();
+            match transparent_level(transparency) {
+                Some(key) => DynamicImage::ImageLumaA16(image_buffer(
+                    info,
+                    samples
+                        .iter()
+                        .flat_map(|&gray| [gray, if gray == key { 0 } else { u16::MAX }])
+                        .collect(),
+                )?),
+                None => DynamicImage::ImageLuma16(image_buffer(info, samples)?),
+            }
+        }
+        (0, bit_depth) => {
+            let max = (1u16 << bit_depth) - 1;
+            let scale = |level: u8| (level as u16 * 255 / max) as u8;
+            match transparent_level(transparency) {
+                Some(key) => DynamicImage::ImageLumaA8(image_buffer(
+                    info,
+                    pixels
+                        .iter()
+                        .flat_map(|&level| {
+                            [scale(level), if level as u16 == key { 0 } else { 255 }]
+                        })
+                        .collect(),
+                )?),
+                None => DynamicImage::ImageLuma8(image_buffer(
+                    info,
+                    pixels.iter().map(|&level| scale(level)).collect(),
+                )?),
+            }
+        }
+        (2, 16) => DynamicImage::ImageRgb16(image_buffer(info, wide())?),
+        (4, 16) => DynamicImage::ImageLumaA16(image_buffer(info, wide())?),
+        (6, 16) => DynamicImage::ImageRgba16(image_buffer(info, wide())?),
+        (2, _) => DynamicImage::ImageRgb8(image_buffer(info, pixels)?),
+        (4, _) => DynamicImage::ImageLumaA8(image_buffer(info, pixels)?),
+        (6, _) => DynamicImage::ImageRgba8(image_buffer(info, pixels)?),
+        (color_type, _) => return Err(EncoderError::UnsupportedColorType(color_type)),
+    };
+
+    Ok(image)
+}
+
+/// The gray level a gray image's tRNS chunk marks as transparent, if there is one.
+fn transparent_level(transparency: &[u8]) -> Option<u16> {
+    match transparency {
+        [high, low] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+fn image_buffer<P: Pixel>(
+    info: &PngInfo,
+    samples: Vec<P::Subpixel>,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, EncoderError> {
+    ImageBuffer::from_raw(info.width, info.height, samples).ok_or(EncoderError::ImageTooLarge)
+}
+
+/// Ancillary chunks that stay valid when the image data is re-encoded, whatever their
+/// safe-to-copy bit says.
+const REENCODE_SAFE_CHUNKS: [&[u8; 4]; 10] = [
+    b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"pHYs", b"tEXt", b"zTXt", b"iTXt", b"tIME", b"eXIf",
+];
+
+/// Ancillary chunks tied to the color type, bit depth or palette, or to APNG frames,
+/// which a re-encode may change.
+const REENCODE_UNSAFE_CHUNKS: [&[u8; 4]; 8] = [
+    b"tRNS", b"bKGD", b"sBIT", b"hIST", b"sPLT", b"acTL", b"fcTL", b"fdAT",
+];
+
+/// Reads the ancillary chunks of a PNG that can be carried over when its pixels are
+/// re-encoded, for [`EncodeOptions::copied_chunks`](crate::encoder::EncodeOptions).
+///
+/// Critical chunks are never returned. Of the ancillary ones, this keeps the
+/// color-space, physical size, text, time and EXIF chunks plus unknown chunks marked
+/// safe to copy, and drops chunks that describe the old color encoding or animation.
+/// Chunks with a bad CRC are skipped. eXIf is copied as stored; a caller that rotates the
+/// pixels upright should reset its orientation with [`crate::exif::reset_orientation`].
+pub fn read_ancillary_chunks(bytes: &[u8]) -> std::io::Result<Vec<RawChunk>> {
+    let mut copied = Vec::new();
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        // Bit 5 of a type byte is its lowercase bit: ancillary in the first byte,
+        // safe-to-copy in the last
+        let ancillary = chunk.chunk_type[0] & 0x20 != 0;
+        let safe_to_copy = chunk.chunk_type[3] & 0x20 != 0;
+        let known_safe = REENCODE_SAFE_CHUNKS.contains(&&chunk.chunk_type);
+        let known_unsafe = REENCODE_UNSAFE_CHUNKS.contains(&&chunk.chunk_type);
+
+        if ancillary && chunk.crc_ok() && (known_safe || (safe_to_copy && !known_unsafe)) {
+            copied.push(RawChunk {
+                chunk_type: chunk.chunk_type,
+                data: chunk.data.to_vec(),
+            });
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Reads the keyword/text pairs of every tEXt, zTXt and iTXt chunk, in file order.
+///
+/// Compressed text is inflated with the custom decompressor first and with standard
+/// zlib if that fails, so files from either compression method (or other encoders) can
+/// be read. Inflated text is held to the chunk type's [`DecodeLimits::default`] limit.
+pub fn read_text(bytes: &[u8]) -> std::io::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    let limits = DecodeLimits::default();
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        if !matches!(&chunk.chunk_type, b"tEXt" | b"zTXt" | b"iTXt") {
+            continue;
+        }
+
+        let separator = chunk
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid_data("Text chunk is missing its keyword separator"))?;
+        let keyword = decode_latin1(&chunk.data[..separator]);
+        let rest = &chunk.data[separator + 1..];
+        let limit = limits.limit_for(&chunk.chunk_type).unwrap_or(usize::MAX);
+
+        let text = match &chunk.chunk_type {
+            b"tEXt" => decode_latin1(rest),
+            b"zTXt" => match rest {
+                [0, compressed @ ..] => decode_latin1(&inflate_text(compressed, limit)?),
+                _ => return Err(invalid_data("Unsupported zTXt compression method")),
+            },
+            _ => {
+                // Compression flag and method, then null-terminated language tag and
+                // translated keyword
+                let [flag, _method, tail @ ..] = rest else {
+                    return Err(invalid_data("Truncated iTXt chunk"));
+                };
+                let text = tail
+                    .splitn(3, |&b| b == 0)
+                    .nth(2)
+                    .ok_or_else(|| invalid_data("Truncated iTXt chunk"))?;
+                let text = if *flag == 1 {
+                    inflate_text(text, limit)?
+                } else {
+                    text.to_vec()
+                };
+                String::from_utf8(text).map_err(|_| invalid_data("iTXt text is not UTF-8"))?
+            }
+        };
+        entries.push((keyword, text));
+    }
+
+    Ok(entries)
+}
+
+/// Inflates a zTXt or iTXt payload, failing rather than producing more than `limit`
+/// bytes.
+fn inflate_text(compressed: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+    let too_large = || invalid_data(&format!("Compressed text inflates past {} bytes", limit));
+    match crate::encoder::decompress_custom(compressed, limit) {
+        Ok(text) => return Ok(text),
+        Err(EncoderError::OutputTooLarge(_)) => return Err(too_large()),
+        Err(_) => {}
+    }
+
+    let mut text = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut text)
+        .map_err(|_| invalid_data("Invalid compressed text"))?;
+    if text.len() > limit {
+        return Err(too_large());
+    }
+    Ok(text)
+}
+
+fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Reads the bKGD background color from a PNG, scaled to 8-bit RGB.
+///
+/// Returns `Ok(None)` when the file has no bKGD chunk.
+pub fn read_background(bytes: &[u8]) -> std::io::Result<Option<[u8; 3]>> {
+    let mut bit_depth = 8;
+    let mut color_type = 6;
+    let mut palette: &[u8] = &[];
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+
+        match &chunk.chunk_type {
+            b"IHDR" if chunk.data.len() == 13 => {
+                bit_depth = chunk.data[8];
+                color_type = chunk.data[9];
+            }
+            b"PLTE" => palette = chunk.data,
+            b"bKGD" => {
+                let data = chunk.data;
+                let sample = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+
+                let color = match (color_type, data.len()) {
+                    (0 | 4, 2) => {
+                        let gray = scale_sample(sample(0), bit_depth);
+                        [gray, gray, gray]
+                    }
+                    (2 | 6, 6) => [
+                        scale_sample(sample(0), bit_depth),
+                        scale_sample(sample(2), bit_depth),
+                        scale_sample(sample(4), bit_depth),
+                    ],
+                    (3, 1) => {
+                        let entry = data[0] as usize * 3;
+                        if entry + 3 > palette.len() {
+                            return Err(invalid_data("bKGD palette index out of range"));
+                        }
+                        [palette[entry], palette[entry + 1], palette[entry + 2]]
+                    }
+                    _ => return Err(invalid_data("Invalid bKGD chunk")),
+                };
+
+                return Ok(Some(color));
+            }
+            b"IDAT" => break,
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// Scales a sample of the given bit depth to the 0-255 range.
+fn scale_sample(value: u16, bit_depth: u8) -> u8 {
+    match bit_depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        _ => {
+            let max = (1u32 << bit_depth) - 1;
+            ((value as u32).min(max) * 255 / max) as u8
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum IdatState {
+    NotSeen,
+    InProgress,
+    Finished,
+}
+
+/// Wraps `error` for the `io::Result` APIs; converting back to [`EncoderError`]
+/// recovers the typed variant.
+fn typed_error(error: EncoderError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+fn chunk_too_large(chunk_type: &[u8; 4], limit: usize) -> std::io::Error {
+    typed_error(EncoderError::ChunkTooLarge {
+        chunk: *chunk_type,
+        limit,
+    })
+}
+
+fn non_contiguous_idat() -> std::io::Error {
+    typed_error(EncoderError::NonContiguousIdat)
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample_png() -> Vec<u8> {
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        let mut output = Cursor::new(Vec::new());
+        image
+            .write_to(&mut output, image::ImageFormat::Png)
+            .unwrap();
+        output.into_inner()
+    }
+
+    /// Splits an encoder-produced PNG into (type, data) pairs after the signature.
+    fn split_chunks(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        chunks(bytes)
+            .unwrap()
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                (chunk.chunk_type, chunk.data.to_vec())
+            })
+            .collect()
+    }
+
+    fn assemble(chunks: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        for (chunk_type, data) in chunks {
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(chunk_type);
+            bytes.extend_from_slice(data);
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(chunk_type);
+            crc.update(data);
+            bytes.extend_from_slice(&crc.finalize().to_be_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_valid_png() {
+        assert!(is_valid_png(&sample_png()).is_ok());
+    }
+
+    #[test]
+    fn test_missing_iend() {
+        let mut chunks = split_chunks(&sample_png());
+        chunks.retain(|(chunk_type, _)| chunk_type != b"IEND");
+
+        let err = is_valid_png(&assemble(&chunks)).unwrap_err();
+        assert_eq!(err.to_string(), "Missing IEND chunk");
+    }
+
+    #[test]
+    fn test_idat_before_ihdr() {
+        let mut chunks = split_chunks(&sample_png());
+        let idat_index = chunks
+            .iter()
+            .position(|(chunk_type, _)| chunk_type == b"IDAT")
+            .unwrap();
+        let idat = chunks.remove(idat_index);
+        chunks.insert(0, idat);
+
+        let err = is_valid_png(&assemble(&chunks)).unwrap_err();
+        assert_eq!(err.to_string(), "First chunk is not IHDR");
+    }
+
+    #[test]
+    fn test_corrupted_crc() {
+        let mut bytes = sample_png();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+
+        assert!(is_valid_png(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_non_contiguous_idat() {
+        let chunks = split_chunks(&sample_png());
+        let idat = chunks
+            .iter()
+            .find(|(chunk_type, _)| chunk_type == b"IDAT")
+            .unwrap()
+            .1
+            .clone();
+        let (first_half, second_half) = idat.split_at(idat.len() / 2);
+
+        let interrupted = assemble(&[
+            chunks[0].clone(),
+            (*b"IDAT", first_half.to_vec()),
+            (*b"tEXt", b"Comment\0split".to_vec()),
+            (*b"IDAT", second_half.to_vec()),
+            (*b"IEND", Vec::new()),
+        ]);
+
+        let err =
+            collect_idat(&interrupted, DecodeMode::Strict, &DecodeLimits::default()).unwrap_err();
+        assert!(matches!(err, EncoderError::NonContiguousIdat));
+        assert!(matches!(
+            is_valid_png(&interrupted).map_err(EncoderError::from),
+            Err(EncoderError::NonContiguousIdat)
+        ));
+
+        let lenient =
+            collect_idat(&interrupted, DecodeMode::Lenient, &DecodeLimits::default()).unwrap();
+        assert_eq!(lenient.data, idat);
+        assert_eq!(lenient.warnings, [DecodeWarning::NonContiguousIdat]);
+
+        let contiguous =
+            collect_idat(&sample_png(), DecodeMode::Lenient, &DecodeLimits::default()).unwrap();
+        assert!(contiguous.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_read_background() {
+        let mut chunks = split_chunks(&sample_png());
+        assert_eq!(read_background(&assemble(&chunks)).unwrap(), None);
+
+        // Sample PNG is 8-bit RGBA, so bKGD holds three 16-bit samples
+        chunks.insert(1, (*b"bKGD", vec![0, 255, 0, 128, 0, 0]));
+        assert_eq!(
+            read_background(&assemble(&chunks)).unwrap(),
+            Some([255, 128, 0])
+        );
+    }
+
+    #[test]
+    fn test_per_chunk_limits() {
+        let mut chunks = split_chunks(&sample_png());
+        chunks.insert(
+            1,
+            (
+                *b"tEXt",
+                b"Comment\0".iter().copied().chain([b'x'; 100]).collect(),
+            ),
+        );
+        let bytes = assemble(&chunks);
+
+        assert!(collect_idat(&bytes, DecodeMode::Strict, &DecodeLimits::default()).is_ok());
+
+        let tight_text = DecodeLimits::default().with_chunk_limit(*b"tEXt", Some(64));
+        let err = collect_idat(&bytes, DecodeMode::Strict, &tight_text).unwrap_err();
+        assert!(matches!(
+            err,
+            EncoderError::ChunkTooLarge {
+                chunk: [b't', b'E', b'X', b't'],
+                limit: 64
+            }
+        ));
+        assert_eq!(err.to_string(), "tEXt chunk exceeds the 64 byte limit");
+
+        let tight_idat = DecodeLimits::default().with_chunk_limit(*b"IDAT", Some(1));
+        let err = collect_idat(&bytes, DecodeMode::Strict, &tight_idat).unwrap_err();
+        assert!(matches!(
+            err,
+            EncoderError::ChunkTooLarge {
+                chunk: [b'I', b'D', b'A', b'T'],
+                limit: 1
+            }
+        ));
+
+        let unbounded = DecodeLimits {
+            default_limit: None,
+            ..DecodeLimits::default()
+        }
+        .with_chunk_limit(*b"tEXt", None);
+        assert!(collect_idat(&bytes, DecodeMode::Strict, &unbounded).is_ok());
+    }
+
+    #[test]
+    fn test_decode_png_round_trip() {
+        use crate::encoder::{CompressionMethod, EncodeOptions, FilterStrategy, PngEncoderBuilder};
+
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(19, 13, |x, y| {
+            image::Rgba([(x * 13) as u8, (y * 19) as u8, (x ^ y) as u8, (x * y) as u8])
+        }));
+        let rgb = DynamicImage::ImageRgb8(rgba.to_rgb8());
+        let gray = DynamicImage::ImageLuma8(rgba.to_luma8());
+        let gray_alpha = DynamicImage::ImageLumaA8(rgba.to_luma_alpha8());
+        let deep = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(9, 7, |x, y| {
+            image::Rgba([x as u16 * 7000, y as u16 * 9000, 1234, 65535 - x as u16])
+        }));
+        let two_level = DynamicImage::ImageLuma8(image::GrayImage::from_fn(21, 5, |x, y| {
+            image::Luma([if (x + y) % 3 == 0 { 255 } else { 0 }])
+        }));
+        let few_colors = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(23, 9, |x, y| {
+            [
+                image::Rgba([255, 0, 0, 255]),
+                image::Rgba([0, 128, 0, 90]),
+                image::Rgba([0, 0, 0, 0]),
+                image::Rgba([10, 20, 30, 255]),
+                image::Rgba([200, 200, 0, 255]),
+            ][((x + 2 * y) % 5) as usize]
+        }));
+
+        let indexed = EncodeOptions {
+            indexed: true,
+            ..EncodeOptions::default()
+        };
+        let cases = [
+            (&rgba, EncodeOptions::default()),
+            (&rgb, EncodeOptions::default()),
+            (&gray, EncodeOptions::default()),
+            (&gray_alpha, EncodeOptions::default()),
+            (
+                &deep,
+                EncodeOptions {
+                    auto_precision: true,
+                    filter_strategy: FilterStrategy::Paeth,
+                    ..EncodeOptions::default()
+                },
+            ),
+            (&two_level, EncodeOptions::default()),
+            (
+                &two_level,
+                EncodeOptions {
+                    transparent_gray: Some(0),
+                    interlace: true,
+                    ..EncodeOptions::default()
+                },
+            ),
+            (&few_colors, indexed.clone()),
+            (
+                &few_colors,
+                EncodeOptions {
+                    interlace: true,
+                    filter_strategy: FilterStrategy::Adaptive,
+                    ..indexed
+                },
+            ),
+            (
+                &rgba,
+                EncodeOptions {
+                    interlace: true,
+                    filter_strategy: FilterStrategy::Average,
+                    ..EncodeOptions::default()
+                },
+            ),
+        ];
+
+        for (image, options) in cases {
+            for compression in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+                let transparent_gray = options.transparent_gray;
+                let mut png = Vec::new();
+                PngEncoderBuilder::new()
+                    .compression(compression)
+                    .options(options.clone())
+                    .encode(image, &mut png)
+                    .unwrap();
+
+                let decoded = decode_png(&png).unwrap();
+                assert_eq!(
+                    (decoded.width(), decoded.height()),
+                    (image.width(), image.height())
+                );
+                if image.color().bytes_per_pixel() > 4 {
+                    assert_eq!(decoded.to_rgba16(), image.to_rgba16());
+                } else if let Some(level) = transparent_gray {
+                    let luma = image.to_luma8();
+                    let expected = image::RgbaImage::from_fn(21, 5, |x, y| {
+                        let gray = luma.get_pixel(x, y)[0];
+                        let alpha = if gray == level { 0 } else { 255 };
+                        image::Rgba([gray, gray, gray, alpha])
+                    });
+                    assert_eq!(decoded.to_rgba8(), expected);
+                } else {
+                    assert_eq!(decoded.to_rgba8(), image.to_rgba8());
+                }
+            }
+        }
+
+        // PNGs from other encoders use dynamic Huffman blocks
+        let decoded = decode_png(&sample_png()).unwrap();
+        assert_eq!(
+            decoded,
+            image::DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4))
+        );
+
+        // A 1x1 gray image whose only scanline claims filter type 7
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut zlib, &[7, 0]).unwrap();
+        let bad_filter = assemble(&[
+            (*b"IHDR", vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]),
+            (*b"IDAT", zlib.finish().unwrap()),
+            (*b"IEND", Vec::new()),
+        ]);
+        assert!(matches!(
+            decode_png(&bad_filter),
+            Err(EncoderError::InvalidFilterType(7))
+        ));
+        assert!(decode_png(&bad_filter[..8]).is_err());
+        assert!(decode_png(b"not a png").is_err());
+    }
+
+    #[test]
+    fn test_decode_png_rejects_corrupted_idat() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([x as u8 * 16, y as u8 * 16, 77])
+        }));
+        let mut png = crate::encode_to_vec(&image, crate::CompressionMethod::Custom).unwrap();
+        assert!(decode_png(&png).is_ok());
+
+        // First byte of the first IDAT payload, after its length and type fields
+        let idat = png.windows(4).position(|window| window == b"IDAT").unwrap();
+        let length = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+        let stored = u32::from_be_bytes(
+            png[idat + 4 + length..idat + 8 + length]
+                .try_into()
+                .unwrap(),
+        );
+        png[idat + 4] ^= 0x10;
+
+        match decode_png(&png) {
+            Err(EncoderError::CrcMismatch {
+                chunk,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(&chunk, b"IDAT");
+                assert_eq!(expected, stored);
+                assert_ne!(actual, stored);
+            }
+            other => panic!("expected a CRC mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_decode_png_with_checks_chunk_structure() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([x as u8 * 16, y as u8 * 16, 77])
+        }));
+        let png = crate::encode_to_vec(&image, crate::CompressionMethod::Flate2).unwrap();
+        let chunks = split_chunks(&png);
+        let idat_at = chunks.iter().position(|(kind, _)| kind == b"IDAT").unwrap();
+        let strict =
+            |bytes: &[u8]| decode_png_with(bytes, DecodeMode::Strict, &DecodeLimits::default());
+
+        // IDAT split around a tEXt chunk
+        let (first, second) = chunks[idat_at].1.split_at(chunks[idat_at].1.len() / 2);
+        let mut interrupted = chunks.clone();
+        interrupted.splice(
+            idat_at..=idat_at,
+            [
+                (*b"IDAT", first.to_vec()),
+                (*b"tEXt", b"Comment\0split".to_vec()),
+                (*b"IDAT", second.to_vec()),
+            ],
+        );
+        let interrupted = assemble(&interrupted);
+        assert!(matches!(
+            decode_png(&interrupted),
+            Err(EncoderError::NonContiguousIdat)
+        ));
+        let (decoded, warnings) =
+            decode_png_with(&interrupted, DecodeMode::Lenient, &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+        assert_eq!(warnings, [DecodeWarning::NonContiguousIdat]);
+
+        let tight_idat = DecodeLimits::default().with_chunk_limit(*b"IDAT", Some(8));
+        assert!(matches!(
+            decode_png_with(&png, DecodeMode::Strict, &tight_idat),
+            Err(EncoderError::ChunkTooLarge { limit: 8, .. })
+        ));
+
+        let mut duplicate_ihdr = chunks.clone();
+        duplicate_ihdr.insert(1, chunks[0].clone());
+        let err = strict(&assemble(&duplicate_ihdr)).unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate IHDR chunk");
+
+        let without_iend = assemble(&chunks[..chunks.len() - 1]);
+        let err = strict(&without_iend).unwrap_err();
+        assert_eq!(err.to_string(), "Missing IEND chunk");
+    }
+
+    #[test]
+    fn test_lenient_decode_reads_raw_deflate_idat() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([x as u8 * 16, y as u8 * 16, 77])
+        }));
+        let png = crate::encode_to_vec(&image, crate::CompressionMethod::Flate2).unwrap();
+        let mut chunks = split_chunks(&png);
+        let idat = chunks.iter_mut().find(|(kind, _)| kind == b"IDAT").unwrap();
+        // Drop the zlib header and Adler-32 trailer, leaving the bare DEFLATE stream
+        idat.1 = idat.1[2..idat.1.len() - 4].to_vec();
+        let raw = assemble(&chunks);
+
+        assert!(decode_png(&raw).is_err());
+        let (decoded, warnings) =
+            decode_png_with(&raw, DecodeMode::Lenient, &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+        assert_eq!(warnings, [DecodeWarning::RawDeflateIdat]);
+
+        // A well-formed stream raises no warning in lenient mode
+        let (_, warnings) =
+            decode_png_with(&png, DecodeMode::Lenient, &DecodeLimits::default()).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_read_header_only() {
+        /// Counts how many bytes were pulled from the inner reader.
+        struct CountingReader<'a> {
+            inner: &'a [u8],
+            read: usize,
+        }
+
+        impl Read for CountingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.inner[self.read..].as_ref().read(buf)?;
+                self.read += n;
+                Ok(n)
+            }
+        }
+
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::new(37, 11));
+        let mut output = Cursor::new(Vec::new());
+        image
+            .write_to(&mut output, image::ImageFormat::Png)
+            .unwrap();
+        let bytes = output.into_inner();
+
+        let mut reader = CountingReader {
+            inner: &bytes,
+            read: 0,
+        };
+        let info = read_header_only(&mut reader).unwrap();
+
+        assert_eq!((info.width, info.height), (37, 11));
+        assert_eq!((info.bit_depth, info.color_type), (8, 6));
+        assert!(!info.interlaced);
+        assert!(reader.read <= 33);
+
+        // Only the header needs to be present
+        assert_eq!(read_header_only(&bytes[..33]).unwrap(), info);
+        assert!(read_header_only(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_reader_walks_encoder_output() {
+        use crate::encoder::{CompressionMethod, EncodeOptions, PngEncoderBuilder};
+
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, (x ^ y) as u8])
+        }));
+        let mut bytes = Vec::new();
+        PngEncoderBuilder::new()
+            .compression(CompressionMethod::Custom)
+            .options(EncodeOptions {
+                max_idat_chunk_size: Some(64),
+                ..Default::default()
+            })
+            .encode(&image, &mut bytes)
+            .unwrap();
+
+        let chunks: Vec<_> = ChunkReader::new(&bytes)
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert!(chunks.iter().all(Chunk::crc_ok));
+        assert_eq!(&chunks[0].chunk_type, b"IHDR");
+        assert_eq!(&chunks[chunks.len() - 1].chunk_type, b"IEND");
+        let middle = &chunks[1..chunks.len() - 1];
+        assert!(middle.len() > 1);
+        assert!(middle.iter().all(|chunk| &chunk.chunk_type == b"IDAT"));
+
+        // Anything after IEND is left alone, and cutting the stream short is an error
+        let mut trailing = bytes.clone();
+        trailing.extend_from_slice(b"junk");
+        assert_eq!(ChunkReader::new(&trailing).unwrap().count(), chunks.len());
+        for end in [bytes.len() - 12, bytes.len() - 5, 40] {
+            let last = ChunkReader::new(&bytes[..end]).unwrap().last().unwrap();
+            assert!(last.is_err());
+        }
+        assert!(ChunkReader::new(&bytes[..4]).is_err());
+    }
+
+    #[test]
+    fn test_ancillary_chunks_survive_reencoding() {
+        use crate::encoder::{
+            BackgroundColor, CompressionMethod, EncodeOptions, PngEncoderBuilder, TextChunk,
+        };
+
+        let chunk_types = |bytes: &[u8]| -> Vec<[u8; 4]> {
+            chunks(bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap().chunk_type)
+                .collect()
+        };
+        let image = image::DynamicImage::ImageLuma8(image::GrayImage::from_fn(16, 16, |x, y| {
+            image::Luma([(x * 16 + y) as u8])
+        }));
+
+        let mut options = EncodeOptions {
+            transparent_gray: Some(0),
+            text: vec![TextChunk::Latin1 {
+                keyword: "Title".to_string(),
+                text: "Gradient".to_string(),
+            }],
+            // A private chunk that is safe to copy and one that is not
+            copied_chunks: vec![
+                RawChunk {
+                    chunk_type: *b"prVt",
+                    data: b"keep".to_vec(),
+                },
+                RawChunk {
+                    chunk_type: *b"prVT",
+                    data: b"drop".to_vec(),
+                },
+            ],
+            ..EncodeOptions::default()
+        };
+        options.set_dpi(300.0);
+        options.set_gamma(0.5);
+        options.set_background(BackgroundColor::Gray(128));
+        let mut source = Vec::new();
+        PngEncoderBuilder::new()
+            .options(options)
+            .encode(&image, &mut source)
+            .unwrap();
+
+        let copied = read_ancillary_chunks(&source).unwrap();
+        let types: Vec<_> = copied.iter().map(|chunk| chunk.chunk_type).collect();
+        assert_eq!(types, [*b"gAMA", *b"pHYs", *b"tEXt", *b"prVt"]);
+
+        // Re-encode the decoded pixels as RGB, overriding the gamma
+        let mut options = EncodeOptions {
+            copied_chunks: copied,
+            ..EncodeOptions::default()
+        };
+        options.set_gamma(1.0);
+        let mut output = Vec::new();
+        PngEncoderBuilder::new()
+            .compression(CompressionMethod::Flate2)
+            .color_type(2)
+            .options(options)
+            .encode(&decode_png(&source).unwrap(), &mut output)
+            .unwrap();
+
+        assert!(is_valid_png(&output).is_ok());
+        assert_eq!(
+            chunk_types(&output),
+            [
+                *b"IHDR", *b"gAMA", *b"pHYs", *b"tEXt", *b"prVt", *b"IDAT", *b"IEND"
+            ]
+        );
+        let find = |bytes: &[u8], chunk_type: &[u8; 4]| {
+            chunks(bytes)
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|chunk| &chunk.chunk_type == chunk_type)
+                .map(|chunk| chunk.data.to_vec())
+        };
+        assert_eq!(find(&output, b"pHYs"), find(&source, b"pHYs"));
+        assert_eq!(find(&output, b"gAMA").unwrap(), 100_000u32.to_be_bytes());
+        assert_eq!(read_text(&output).unwrap(), read_text(&source).unwrap());
+    }
+
+    #[test]
+    fn test_read_text_caps_inflated_size() {
+        use std::io::Write;
+
+        let bomb = vec![b'a'; (1 << 20) + 1];
+        let mut flate2_stream =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        flate2_stream.write_all(&bomb).unwrap();
+        let flate2_stream = flate2_stream.finish().unwrap();
+        let custom_stream = crate::encoder::compress_zlib_with_dictionary(&bomb, &[]).unwrap();
+
+        for compressed in [flate2_stream, custom_stream] {
+            assert!(compressed.len() < 1 << 16);
+            let mut data = b"Comment\0\0".to_vec();
+            data.extend_from_slice(&compressed);
+            let mut chunks = split_chunks(&sample_png());
+            chunks.insert(1, (*b"zTXt", data));
+
+            let err = read_text(&assemble(&chunks)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+
+        // Text right at the limit still reads
+        let mut data = b"Comment\0\0".to_vec();
+        data.extend_from_slice(
+            &crate::encoder::compress_zlib_with_dictionary(&bomb[1..], &[]).unwrap(),
+        );
+        let mut chunks = split_chunks(&sample_png());
+        chunks.insert(1, (*b"zTXt", data));
+        assert_eq!(read_text(&assemble(&chunks)).unwrap()[0].1.len(), 1 << 20);
+    }
+}
@@ -0,0 +1,75 @@
+use image::{DynamicImage, GrayImage, Luma};
+
+/// Builds a grayscale visualization of the per-pixel difference between two images.
+///
+/// Each output pixel is the largest absolute channel difference (RGBA) at that position,
+/// multiplied by `amplify` and clamped to 255, so identical images produce all black.
+pub fn difference_image(
+    a: &DynamicImage,
+    b: &DynamicImage,
+    amplify: u32,
+) -> std::io::Result<DynamicImage> {
+    if a.width() != b.width() || a.height() != b.height() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Images differ in size: {}x{} vs {}x{}",
+                a.width(),
+                a.height(),
+                b.width(),
+                b.height()
+            ),
+        ));
+    }
+
+    let a = a.to_rgba8();
+    let b = b.to_rgba8();
+
+    let diff = GrayImage::from_fn(a.width(), a.height(), |x, y| {
+        let difference = a
+            .get_pixel(x, y)
+            .0
+            .iter()
+            .zip(b.get_pixel(x, y).0.iter())
+            .map(|(&pa, &pb)| pa.abs_diff(pb) as u32)
+            .max()
+            .unwrap_or(0);
+
+        Luma([(difference * amplify).min(255) as u8])
+    });
+
+    Ok(DynamicImage::ImageLuma8(diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_identical_images_produce_black() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| {
+            Rgba([x as u8 * 30, y as u8 * 30, 7, 255])
+        }));
+
+        let diff = difference_image(&image, &image, 8).unwrap().to_luma8();
+        assert!(diff.as_raw().iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn test_differences_are_amplified() {
+        let a = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255])));
+        let mut b = a.to_rgba8();
+        b.put_pixel(1, 2, Rgba([103, 100, 100, 255]));
+        b.put_pixel(3, 3, Rgba([0, 100, 100, 255]));
+        let b = DynamicImage::ImageRgba8(b);
+
+        let diff = difference_image(&a, &b, 10).unwrap().to_luma8();
+        assert_eq!(diff.get_pixel(1, 2), &Luma([30]));
+        assert_eq!(diff.get_pixel(3, 3), &Luma([255]));
+        assert_eq!(diff.get_pixel(0, 0), &Luma([0]));
+
+        let smaller = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        assert!(difference_image(&a, &smaller, 1).is_err());
+    }
+}
@@ -1,545 +1,7279 @@
+#[cfg(any(feature = "sha256", feature = "md5"))]
+use crate::hashing::HashingWriter;
+#[cfg(feature = "mmap")]
+use crate::mmap_output::MmapWriter;
 use flate2::{Compression, write::ZlibEncoder};
 use image::DynamicImage;
-use std::{fs::File, io::Write};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+pub const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
-#[derive(Clone, Copy, Debug)]
+/// Shortest and longest back-references DEFLATE can encode.
+const MIN_MATCH_LENGTH: usize = 3;
+const MAX_MATCH_LENGTH: usize = 258;
+/// Farthest back a match may reach, DEFLATE's 32K window.
+const WINDOW_SIZE: usize = 32768;
+
+/// Tokens buffered before they are written out as one fixed-Huffman block.
+const BLOCK_TOKENS: usize = 16384;
+/// Most bytes a stored block can hold, given its 16-bit length field.
+const MAX_STORED_BLOCK: usize = 65535;
+/// The end-of-block symbol in the literal/length alphabet.
+const END_OF_BLOCK: u16 = 256;
+
+/// First length of each length code 257..=285 and the extra bits that follow it.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// First distance of each distance code 0..=29 and the extra bits that follow it.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Bits in the 3-byte prefix hash used to index match candidates.
+const HASH_BITS: u32 = 15;
+/// Candidates followed per search before settling for the best match so far.
+const MAX_CHAIN_LENGTH: usize = 1024;
+/// Matches at least this long are taken without checking whether the next position has
+/// a longer one, like zlib's `max_lazy`.
+const MAX_LAZY_LENGTH: usize = 32;
+
+/// gAMA value (1/2.2 times 100000) written alongside an sRGB chunk, as the spec asks.
+const SRGB_GAMMA: u32 = 45455;
+
+/// IDAT payload size used when [`EncodeOptions::max_idat_chunk_size`] is unset.
+const DEFAULT_MAX_IDAT_CHUNK_SIZE: usize = 32768;
+/// Largest chunk length the PNG specification allows.
+const MAX_CHUNK_LENGTH: usize = 0x7FFF_FFFF;
+
+/// Adam7 passes as (x start, y start, x step, y step), in pass order.
+pub(crate) const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Rows converted from the source image at a time when streaming, unless
+/// [`EncodeOptions::max_memory`] calls for fewer.
+const STREAM_BAND_ROWS: u32 = 16;
+/// Filtered bytes collected before they are handed to the streaming compressor, unless
+/// [`EncodeOptions::max_memory`] calls for fewer.
+const STREAM_SEGMENT_SIZE: usize = 65536;
+
+/// Scanlines sampled by the adaptive-fast entropy estimate.
+const ENTROPY_SAMPLE_ROWS: usize = 8;
+/// Sampled entropy (bits per byte) above which data is treated as incompressible.
+const INCOMPRESSIBLE_ENTROPY_BITS: f64 = 7.5;
+
+/// Rows below which filtering stays on the calling thread; smaller images finish before
+/// the thread pool would pay off.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_ROWS: usize = 64;
+/// Rows filtered concurrently between progress reports.
+#[cfg(feature = "parallel")]
+const PARALLEL_BAND_ROWS: usize = 256;
+
+/// zlib CMF byte: deflate (CM = 8) with a 32 KiB window (CINFO = 7).
+const ZLIB_CMF: u8 = 0x78;
+/// zlib header of [`CompressionMethod::Custom`] output, marked as default compression.
+const CUSTOM_ZLIB_HEADER: [u8; 2] = zlib_header(ZLIB_CMF, 2, false);
+/// zlib header of custom output compressed against a preset dictionary.
+const DICTIONARY_ZLIB_HEADER: [u8; 2] = zlib_header(ZLIB_CMF, 2, true);
+/// zlib header of [`CompressionMethod::Stored`] output, marked as the fastest compression
+/// level.
+const STORED_ZLIB_HEADER: [u8; 2] = zlib_header(ZLIB_CMF, 0, false);
+
+/// Bytes compressed between checks of the cancellation flag.
+const CANCEL_CHECK_INTERVAL: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default)]
 pub enum CompressionMethod {
+    #[default]
     Custom,
     Flate2,
+    /// No compression: the data goes into stored DEFLATE blocks, adding 5 bytes per
+    /// 65535-byte block plus the 6-byte zlib header and checksum. Readable by any
+    /// decoder; useful for debugging and for data that doesn't compress.
+    Stored,
 }
 
-#[allow(dead_code)]
-enum FilterType {
-    None = 0,
-    Sub = 1,
-    Up = 2,
-    Average = 3,
-    Paeth = 4,
+/// Errors produced while encoding or decoding PNG data.
+#[derive(Debug)]
+pub enum EncoderError {
+    Io(std::io::Error),
+    /// The zlib stream does not start with a header this encoder writes.
+    InvalidZlibHeader,
+    /// The zlib stream was compressed against a preset dictionary with this Adler-32,
+    /// and no dictionary with that checksum is set.
+    MissingDictionary(u32),
+    /// The Adler-32 checksum stored in the zlib stream does not match the data.
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The PNG color type cannot be encoded.
+    UnsupportedColorType(u8),
+    /// Compressed or pixel data ended before it was complete.
+    Truncated,
+    /// A back-reference points before the start of the output or has zero length.
+    InvalidBackReference,
+    /// The DEFLATE stream uses a block type the custom decompressor does not read.
+    UnsupportedBlockType(u8),
+    /// A stored block's length does not match the one's complement stored after it.
+    StoredLengthMismatch,
+    /// The image is too large to hold in memory on this platform.
+    ImageTooLarge,
+    /// The image is 0 pixels wide or high, which PNG cannot represent.
+    InvalidDimensions {
+        width: u32,
+        height: u32,
+    },
+    /// An [`EncodeOptions`] value is out of range.
+    InvalidOptions(String),
+    /// The encode was cancelled through [`EncodeOptions::cancel`].
+    Cancelled,
+    /// The output file already exists and the save was asked not to replace it.
+    OutputExists(PathBuf),
+    /// The image has more distinct colors than fit in a 256-entry palette.
+    TooManyColors,
+    /// A decoded scanline starts with a filter type other than 0-4.
+    InvalidFilterType(u8),
+    /// A raw pixel buffer is not the size its dimensions and format call for.
+    PixelBufferSize {
+        expected: usize,
+        actual: usize,
+    },
+    /// Decompressed data would grow past this many bytes, more than its image can hold.
+    OutputTooLarge(usize),
+    /// A float source has this sample outside 0.0-1.0 under [`FloatMapping::Strict`].
+    FloatOutOfRange(f32),
+    /// The CRC-32 stored after a chunk does not match its type and data.
+    CrcMismatch {
+        chunk: [u8; 4],
+        expected: u32,
+        actual: u32,
+    },
+    /// IDAT chunks are interrupted by another chunk, which strict decoding rejects.
+    NonContiguousIdat,
+    /// A chunk is longer than the [`DecodeLimits`](crate::decoder::DecodeLimits) allow
+    /// for its type.
+    ChunkTooLarge {
+        chunk: [u8; 4],
+        limit: usize,
+    },
+    /// Trimming left no pixels, as happens to a fully transparent image.
+    EmptyImage,
 }
 
-struct PngEncoder {
-    width: u32,
-    height: u32,
-    bit_depth: u8,
-    color_type: u8,
-    compression_method: CompressionMethod,
+impl std::fmt::Display for EncoderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderError::Io(e) => write!(f, "{}", e),
+            EncoderError::InvalidZlibHeader => write!(f, "Invalid zlib header"),
+            EncoderError::MissingDictionary(id) => {
+                write!(
+                    f,
+                    "zlib stream needs a preset dictionary with Adler-32 {:08x}",
+                    id
+                )
+            }
+            EncoderError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Adler32 checksum mismatch (expected {:08x}, got {:08x})",
+                expected, actual
+            ),
+            EncoderError::UnsupportedColorType(color_type) => {
+                write!(f, "Unsupported color type {}", color_type)
+            }
+            EncoderError::Truncated => write!(f, "Data ended unexpectedly"),
+            EncoderError::InvalidBackReference => write!(f, "Invalid back-reference parameters"),
+            EncoderError::UnsupportedBlockType(block_type) => {
+                write!(f, "Unsupported DEFLATE block type: {}", block_type)
+            }
+            EncoderError::StoredLengthMismatch => {
+                write!(f, "Stored block length does not match its complement")
+            }
+            EncoderError::ImageTooLarge => write!(f, "Image dimensions are too large to encode"),
+            EncoderError::InvalidOptions(message) => write!(f, "{}", message),
+            EncoderError::Cancelled => write!(f, "Encoding cancelled"),
+            EncoderError::InvalidDimensions { width, height } => write!(
+                f,
+                "Invalid image dimensions {}x{}; PNG needs at least one pixel in each direction",
+                width, height
+            ),
+            EncoderError::OutputExists(path) => {
+                write!(f, "Output file already exists: {}", path.display())
+            }
+            EncoderError::TooManyColors => write!(f, "Image has more than 256 colors"),
+            EncoderError::InvalidFilterType(filter_type) => {
+                write!(f, "Invalid scanline filter type: {}", filter_type)
+            }
+            EncoderError::PixelBufferSize { expected, actual } => write!(
+                f,
+                "Pixel buffer holds {} bytes, but the image needs {}",
+                actual, expected
+            ),
+            EncoderError::OutputTooLarge(limit) => {
+                write!(f, "Decompressed data exceeds the expected {} bytes", limit)
+            }
+            EncoderError::FloatOutOfRange(sample) => write!(
+                f,
+                "Float image has a sample ({}) outside 0.0-1.0 that PNG cannot hold; \
+                 tone-map it first or clamp it with FloatMapping::Clamp",
+                sample
+            ),
+            EncoderError::CrcMismatch {
+                chunk,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "CRC mismatch in {} chunk (expected {:08x}, got {:08x})",
+                String::from_utf8_lossy(chunk),
+                expected,
+                actual
+            ),
+            EncoderError::NonContiguousIdat => write!(f, "IDAT chunks are not contiguous"),
+            EncoderError::ChunkTooLarge { chunk, limit } => write!(
+                f,
+                "{} chunk exceeds the {} byte limit",
+                String::from_utf8_lossy(chunk),
+                limit
+            ),
+            EncoderError::EmptyImage => write!(f, "Image has no pixels left after trimming"),
+        }
+    }
 }
 
-impl PngEncoder {
-    fn new(width: u32, height: u32, compression_method: CompressionMethod) -> Self {
-        PngEncoder {
-            width,
-            height,
-            bit_depth: 8,
-            color_type: 6,
-            compression_method,
+impl std::error::Error for EncoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncoderError::Io(e) => Some(e),
+            _ => None,
         }
     }
+}
 
-    fn encode<W: Write>(&self, image: &DynamicImage, writer: &mut W) -> std::io::Result<()> {
-        writer.write_all(&PNG_SIGNATURE)?;
+impl From<std::io::Error> for EncoderError {
+    /// Unwraps errors the chunk reader reports through `io::Error` back into their
+    /// typed variant.
+    fn from(e: std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<EncoderError>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast::<EncoderError>().expect("checked above");
+        }
+        EncoderError::Io(e)
+    }
+}
 
-        self.write_ihdr(writer)?;
+/// Optional encoder settings beyond the compression method.
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+    /// Significant bits per R, G, B, A channel. When set, the bits below each channel's
+    /// precision are zeroed before filtering and an sBIT chunk records the precision.
+    /// Grayscale output uses the red value for its gray channel.
+    pub significant_bits: Option<[u8; 4]>,
+    /// When set to `true` from another thread, the encode stops at the next scanline or
+    /// compression block and returns [`EncoderError::Cancelled`]. A buffered encode has
+    /// written nothing by then; a [`streaming`](Self::streaming) one may have written a
+    /// partial file.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Called with the number of scanlines filtered so far and the total, once per
+    /// scanline. See [`EncodeOptions::on_progress`].
+    pub progress: Option<ProgressCallback>,
+    /// Text metadata written as tEXt/iTXt chunks before the image data.
+    pub text: Vec<TextChunk>,
+    pub filter_strategy: FilterStrategy,
+    /// How 32-bit float sources are quantized. Ignored for integer sources.
+    pub float_mapping: FloatMapping,
+    /// Whether the source's color channels are premultiplied by alpha. Only affects
+    /// output with an alpha channel, and cannot be combined with `indexed`.
+    pub alpha_mode: AlphaMode,
+    /// Deliberately damages the IEND chunk, for producing malformed files to test
+    /// decoders against.
+    #[cfg(feature = "testing-malformed")]
+    pub malformed_iend: Option<MalformedIend>,
+    /// Hash the encoded bytes with SHA-256 as they are written and return the digest in
+    /// the [`EncodeReport`].
+    #[cfg(feature = "sha256")]
+    pub compute_sha256: bool,
+    /// Hash the encoded bytes with MD5 as they are written and return the digest in the
+    /// [`EncodeReport`].
+    #[cfg(feature = "md5")]
+    pub compute_md5: bool,
+    /// Write 16-bit samples when the source image has more than 8 bits per channel, and
+    /// 8-bit samples otherwise. Ignored when `significant_bits` is set, since that
+    /// already limits every channel to at most 8 bits.
+    pub auto_precision: bool,
+    /// Write color images with at most 256 distinct colors as indexed color with a PLTE
+    /// chunk, plus a tRNS chunk for translucent entries. Other images are written as
+    /// truecolor.
+    pub indexed: bool,
+    /// Drop the alpha channel of gray+alpha and RGBA images whose every pixel is fully
+    /// opaque, writing them as gray or RGB instead.
+    pub auto_strip_opaque_alpha: bool,
+    /// Gray level (0-255) that decoders should show as fully transparent, recorded in a
+    /// tRNS chunk. Only applies to gray output without an alpha channel.
+    pub transparent_gray: Option<u8>,
+    /// Largest filtered image (every scanline plus its filter type byte) the encoder will
+    /// hold in memory. Bigger images fail with [`EncoderError::ImageTooLarge`] before
+    /// any pixels are converted. `None` only rejects sizes that overflow `usize`.
+    /// Streaming encodes never hold the whole image and ignore it.
+    pub max_filtered_bytes: Option<usize>,
+    /// Largest payload of a single IDAT chunk; longer compressed data is split across
+    /// consecutive IDAT chunks. Defaults to 32 KiB.
+    pub max_idat_chunk_size: Option<usize>,
+    /// Write the image with Adam7 interlacing, so decoders can show a coarse preview
+    /// before the whole file has arrived. Interlaced files are usually a little larger.
+    pub interlace: bool,
+    /// Pixel density recorded in a pHYs chunk, so printed output comes out the
+    /// intended size.
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    /// Image gamma times 100000, written as gAMA. Ignored when `srgb` is set, which
+    /// implies the sRGB gamma.
+    pub gamma: Option<u32>,
+    /// Marks the image as sRGB with this rendering intent, written as sRGB plus a
+    /// matching gAMA.
+    pub srgb: Option<RenderingIntent>,
+    /// Last-modification time (UTC) recorded in a tIME chunk. This is the only
+    /// time-dependent part of the output, and it is never filled in automatically.
+    pub modification_time: Option<ModificationTime>,
+    /// Convert, filter and compress the image a few rows at a time, writing IDAT chunks
+    /// as compressed data becomes available, so memory use does not grow with the image.
+    /// Cannot be combined with `interlace`. `AdaptiveFast` filters like `Sub`, and a
+    /// cancelled streaming encode leaves partial output behind.
+    pub streaming: bool,
+    /// Rough cap, in bytes, on the buffers the encoder holds: converted and filtered
+    /// scanlines and compressed data waiting to be written. Setting it implies
+    /// `streaming` (which filters rows serially), and the band of rows converted at a
+    /// time, the data handed to the compressor at once and the IDAT chunk size all
+    /// shrink to fit. An encode whose scanlines are too wide to fit even one row at a
+    /// time fails with [`EncoderError::InvalidOptions`]. The compressor's own state,
+    /// such as its 32 KiB history window, is not counted. Cannot be combined with
+    /// `interlace`.
+    pub max_memory: Option<usize>,
+    /// flate2 compression level from 0 (store) to 9 (smallest output). Defaults to
+    /// flate2's default level, 6. The custom method ignores it.
+    pub compression_level: Option<u8>,
+    /// Back-references closer than this many bytes are never used by the custom method.
+    /// Defaults to 1, allowing any distance, and must be at most 32768. Other methods
+    /// ignore it.
+    pub min_match_distance: Option<usize>,
+    /// Have the custom method take every match of 4 bytes or more instead of weighing
+    /// each against the literals it replaces by encoded size. Other methods ignore it.
+    pub fixed_match_threshold: bool,
+    /// Background color suggested to viewers, written as bKGD.
+    pub background: Option<BackgroundColor>,
+    /// CIE x,y chromaticities of the white point and the red, green and blue primaries,
+    /// in that order, times 100000. Written as cHRM.
+    pub chromaticities: Option<[u32; 8]>,
+    /// Embedded ICC profile, written as iCCP. Cannot be combined with `srgb`.
+    pub icc_profile: Option<IccProfile>,
+    /// Ancillary chunks written unchanged, e.g. from
+    /// [`read_ancillary_chunks`](crate::decoder::read_ancillary_chunks). gAMA, cHRM,
+    /// iCCP and sRGB go before the palette, everything else just before the image data.
+    /// A copied chunk is dropped when the other options already write one of its type
+    /// (or, for sRGB and iCCP, the other of the two).
+    pub copied_chunks: Vec<RawChunk>,
+}
 
-        self.write_idat(image, writer)?;
+impl EncodeOptions {
+    /// Calls `callback(rows_done, total)` as scanlines are filtered, with `rows_done`
+    /// counting up from 1 to `total`. `total` is the image height, or the scanlines of
+    /// all seven passes for interlaced output.
+    pub fn on_progress(&mut self, callback: impl FnMut(u32, u32) + Send + 'static) {
+        self.progress = Some(ProgressCallback(Arc::new(Mutex::new(callback))));
+    }
 
-        self.write_iend(writer)?;
+    /// Records the original precision of each channel in an sBIT chunk, e.g. `5, 6, 5, 8`
+    /// for an upconverted RGB565 source. The chunk holds only the channels of the output
+    /// color type; each value must be between 1 and the output bit depth.
+    pub fn set_significant_bits(&mut self, red: u8, green: u8, blue: u8, alpha: u8) {
+        self.significant_bits = Some([red, green, blue, alpha]);
+    }
+
+    /// Suggests a background color to show behind transparent pixels, written as a bKGD
+    /// chunk in the form the output color type calls for.
+    pub fn set_background(&mut self, color: BackgroundColor) {
+        self.background = Some(color);
+    }
+
+    /// Records `ppu_x` by `ppu_y` pixels per `unit` in a pHYs chunk.
+    pub fn set_physical_dimensions(&mut self, ppu_x: u32, ppu_y: u32, unit: PhysicalUnit) {
+        self.physical_dimensions = Some(PhysicalDimensions { ppu_x, ppu_y, unit });
+    }
+
+    /// Records `dpi` dots per inch, converted to the pixels per meter pHYs stores.
+    pub fn set_dpi(&mut self, dpi: f32) {
+        let ppm = (dpi as f64 / 0.0254).round() as u32;
+        self.set_physical_dimensions(ppm, ppm, PhysicalUnit::Meter);
+    }
+
+    /// Records the image gamma in a gAMA chunk. This is the exponent the samples were
+    /// encoded with, e.g. `1.0 / 2.2` for an image meant for a 2.2 display.
+    pub fn set_gamma(&mut self, gamma: f32) {
+        self.gamma = Some((gamma as f64 * 100000.0).round() as u32);
+    }
+
+    /// Records the CIE x,y chromaticities of the white point and primaries in a cHRM
+    /// chunk, describing the color space together with gAMA.
+    pub fn set_chromaticities(
+        &mut self,
+        white: (f32, f32),
+        red: (f32, f32),
+        green: (f32, f32),
+        blue: (f32, f32),
+    ) {
+        let [white, red, green, blue] = [white, red, green, blue]
+            .map(|(x, y)| [x, y].map(|v| (v as f64 * 100000.0).round() as u32));
+        self.chromaticities = Some([
+            white[0], white[1], red[0], red[1], green[0], green[1], blue[0], blue[1],
+        ]);
+    }
 
+    /// Embeds an ICC profile in an iCCP chunk, compressed with the encoder's compression
+    /// method. Fails with [`EncoderError::InvalidOptions`] unless `name` is 1-79
+    /// printable Latin-1 characters.
+    pub fn set_icc_profile(&mut self, name: &str, profile: &[u8]) -> Result<(), EncoderError> {
+        encode_keyword(name).map_err(|e| EncoderError::InvalidOptions(e.to_string()))?;
+        self.icc_profile = Some(IccProfile {
+            name: name.to_string(),
+            profile: profile.to_vec(),
+        });
         Ok(())
     }
 
-    fn write_ihdr<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        let mut chunk_data = Vec::new();
+    /// Marks the image as sRGB, written as an sRGB chunk and a compatible gAMA chunk.
+    pub fn set_srgb(&mut self, intent: RenderingIntent) {
+        self.srgb = Some(intent);
+    }
 
-        chunk_data.extend_from_slice(&self.width.to_be_bytes());
-        chunk_data.extend_from_slice(&self.height.to_be_bytes());
-        chunk_data.push(self.bit_depth);
-        chunk_data.push(self.color_type);
-        chunk_data.push(0);
-        chunk_data.push(0);
-        chunk_data.push(0);
+    /// Records a UTC last-modification time in a tIME chunk. Fails with
+    /// [`EncoderError::InvalidOptions`] if a field is out of range.
+    pub fn set_modification_time(
+        &mut self,
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Result<(), EncoderError> {
+        let time = ModificationTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        };
+        time.validate()?;
+        self.modification_time = Some(time);
+        Ok(())
+    }
 
-        self.write_chunk(writer, b"IHDR", &chunk_data)
+    /// Records the current system time in a tIME chunk. Output written with it is no
+    /// longer reproducible from one run to the next.
+    pub fn set_modification_time_now(&mut self) {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.modification_time = Some(ModificationTime::from_unix_seconds(seconds));
     }
+}
+
+/// A chunk copied byte for byte from another PNG, see [`EncodeOptions::copied_chunks`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawChunk {
+    pub chunk_type: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// Copied chunks that must come before PLTE and IDAT.
+const COLOR_SPACE_CHUNKS: [&[u8; 4]; 4] = [b"gAMA", b"cHRM", b"iCCP", b"sRGB"];
+
+/// A UTC timestamp as stored in a tIME chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModificationTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
 
-    fn write_idat<W: Write>(&self, image: &DynamicImage, writer: &mut W) -> std::io::Result<()> {
-        let filtered_data = self.apply_filters(image);
+impl ModificationTime {
+    /// Converts seconds since the Unix epoch to calendar fields, using the days-to-civil
+    /// algorithm from Howard Hinnant's date library.
+    fn from_unix_seconds(seconds: u64) -> Self {
+        let days = seconds / 86400;
+        let time_of_day = seconds % 86400;
 
-        let compressed_data = self.compress_data(&filtered_data)?;
+        // Shift the epoch to 0000-03-01 so leap days fall at the end of each year
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
 
-        self.write_chunk(writer, b"IDAT", &compressed_data)
+        ModificationTime {
+            year: year.min(u16::MAX as u64) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time_of_day / 3600) as u8,
+            minute: (time_of_day / 60 % 60) as u8,
+            second: (time_of_day % 60) as u8,
+        }
     }
 
-    fn write_iend<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        // IEND chunk has no data
-        self.write_chunk(writer, b"IEND", &[])
+    fn validate(&self) -> Result<(), EncoderError> {
+        // tIME allows a second of 60 for leap seconds
+        let valid = (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour <= 23
+            && self.minute <= 59
+            && self.second <= 60;
+        if valid {
+            Ok(())
+        } else {
+            Err(EncoderError::InvalidOptions(format!(
+                "Invalid modification time {:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                self.year, self.month, self.day, self.hour, self.minute, self.second
+            )))
+        }
     }
 
-    fn write_chunk<W: Write>(
-        &self,
-        writer: &mut W,
-        chunk_type: &[u8],
-        data: &[u8],
-    ) -> std::io::Result<()> {
-        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    fn to_bytes(self) -> [u8; 7] {
+        let [year_hi, year_lo] = self.year.to_be_bytes();
+        [
+            year_hi,
+            year_lo,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+        ]
+    }
+}
 
-        writer.write_all(chunk_type)?;
+/// Rendering intent stored in an sRGB chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderingIntent {
+    Perceptual = 0,
+    RelativeColorimetric = 1,
+    Saturation = 2,
+    AbsoluteColorimetric = 3,
+}
 
-        writer.write_all(data)?;
+/// A bKGD background color. Samples are at the output bit depth: 0-255, or 0-65535
+/// for 16-bit output. Gray output below 8 bits takes 8-bit gray levels, like
+/// [`EncodeOptions::transparent_gray`].
+///
+/// The color is converted to the output color type where possible: gray becomes
+/// truecolor with equal channels, truecolor becomes gray by Rec. 709 luma, and palette
+/// output looks for an entry with the same color. When no conversion applies, such as a
+/// palette index for non-indexed output, no bKGD chunk is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackgroundColor {
+    Gray(u16),
+    Rgb(u16, u16, u16),
+    PaletteIndex(u8),
+}
 
-        let mut crc = crc32fast::Hasher::new();
-        crc.update(chunk_type);
-        crc.update(data);
-        let crc_value = crc.finalize();
+/// Progress hook set through [`EncodeOptions::on_progress`]. Clones share the same
+/// callback.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<Mutex<dyn FnMut(u32, u32) + Send>>);
 
-        writer.write_all(&crc_value.to_be_bytes())?;
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
 
-        Ok(())
+/// Counts filtered scanlines and reports each one to the progress callback, if any.
+struct ProgressTracker<'a> {
+    callback: Option<&'a ProgressCallback>,
+    done: u32,
+    total: u32,
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn new(callback: Option<&'a ProgressCallback>, total: u32) -> Self {
+        ProgressTracker {
+            callback,
+            done: 0,
+            total,
+        }
+    }
+
+    fn advance(&mut self, rows: usize) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        // A callback that panicked earlier is still called; its state is its own concern
+        let mut callback = callback.0.lock().unwrap_or_else(|e| e.into_inner());
+        for _ in 0..rows {
+            self.done += 1;
+            callback(self.done, self.total);
+        }
     }
+}
 
-    fn apply_filters(&self, image: &DynamicImage) -> Vec<u8> {
-        let bytes_per_pixel = 4;
-        let stride = self.width as usize * bytes_per_pixel;
-        let mut filtered_data = Vec::with_capacity(self.height as usize * (stride + 1));
+/// A named ICC profile embedded in an iCCP chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IccProfile {
+    pub name: String,
+    pub profile: Vec<u8>,
+}
 
-        let img = image.to_rgba8();
-        let img_data = img.as_raw();
+/// Pixels per unit along each axis, as stored in a pHYs chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalDimensions {
+    pub ppu_x: u32,
+    pub ppu_y: u32,
+    pub unit: PhysicalUnit,
+}
 
-        for y in 0..self.height {
-            filtered_data.push(FilterType::Sub as u8);
+/// Unit of a pHYs pixel density.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PhysicalUnit {
+    /// Only the pixel aspect ratio is known.
+    Unspecified,
+    Meter,
+}
 
-            let row_start = y as usize * stride;
-            let row_end = row_start + stride;
-            let row = &img_data[row_start..row_end];
+/// What happens to a frame's area of the canvas before the next APNG frame is drawn.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DisposeOp {
+    /// Leave the canvas as it is.
+    #[default]
+    None = 0,
+    /// Clear the area to fully transparent black.
+    Background = 1,
+    /// Restore the area to what it was before this frame.
+    Previous = 2,
+}
 
-            for x in 0..stride {
-                if x < bytes_per_pixel {
-                    filtered_data.push(row[x]);
-                } else {
-                    filtered_data.push(row[x].wrapping_sub(row[x - bytes_per_pixel]));
+/// How an APNG frame is combined with the canvas.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlendOp {
+    /// Replace the area, alpha included.
+    #[default]
+    Source = 0,
+    /// Composite the frame over the current canvas.
+    Over = 1,
+}
+
+/// One frame of an animated PNG. Every frame covers the whole canvas, so all frames
+/// must have the same dimensions.
+#[derive(Clone, Debug)]
+pub struct ApngFrame {
+    pub image: DynamicImage,
+    /// The frame is shown for `delay_num / delay_den` seconds; a denominator of 0 means
+    /// hundredths of a second.
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+/// Animation-wide settings for [`encode_apng_with`].
+#[derive(Clone, Debug, Default)]
+pub struct ApngOptions {
+    /// How many times the animation plays, as stored in acTL; 0 loops forever.
+    pub num_plays: u32,
+    /// A still image for decoders without APNG support, written as the IDAT default
+    /// image. It is not part of the animation: every frame then goes in fdAT chunks and
+    /// acTL counts only the frames. It must have the frames' dimensions.
+    pub fallback: Option<DynamicImage>,
+}
+
+/// Ways to break the IEND chunk when the `testing-malformed` feature is enabled.
+#[cfg(feature = "testing-malformed")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MalformedIend {
+    /// Leave the IEND chunk out entirely.
+    Omit,
+    /// Write IEND with an incorrect CRC.
+    CorruptCrc,
+}
+
+/// A keyword/value text entry stored alongside the image.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextChunk {
+    /// Written as tEXt; both keyword and text must be representable in Latin-1.
+    Latin1 { keyword: String, text: String },
+    /// Written as an uncompressed iTXt with no language tag, allowing any UTF-8 text.
+    Utf8 { keyword: String, text: String },
+    /// Written as zTXt, with the Latin-1 text compressed by the encoder's compression
+    /// method. Worthwhile for long values such as embedded JSON or XMP.
+    Compressed { keyword: String, text: String },
+    /// Written as an iTXt with every field filled in: a language tag such as `de-CH`
+    /// (letters, digits and hyphens; may be empty), the keyword translated into that
+    /// language, and UTF-8 text that is compressed by the encoder's compression method
+    /// when `compressed` is set.
+    International {
+        keyword: String,
+        language: String,
+        translated_keyword: String,
+        text: String,
+        compressed: bool,
+    },
+}
+
+impl TextChunk {
+    /// Serializes the chunk, returning its type and data.
+    ///
+    /// Line endings in the text are normalized to a bare LF as the PNG spec requires.
+    /// zTXt and compressed iTXt text is returned uncompressed; the encoder compresses it
+    /// when writing the chunk.
+    fn to_chunk(&self) -> std::io::Result<([u8; 4], Vec<u8>)> {
+        let (keyword, text) = match self {
+            TextChunk::Latin1 { keyword, text }
+            | TextChunk::Utf8 { keyword, text }
+            | TextChunk::Compressed { keyword, text }
+            | TextChunk::International { keyword, text, .. } => {
+                (keyword, text.replace("\r\n", "\n").replace('\r', "\n"))
+            }
+        };
+
+        let mut data = encode_keyword(keyword)?;
+        match self {
+            TextChunk::Latin1 { .. } | TextChunk::Compressed { .. } => {
+                let latin1 = encode_latin1(&text).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "Text for '{}' is not Latin-1; use UTF-8 text (iTXt) instead",
+                            keyword
+                        ),
+                    )
+                })?;
+                if let TextChunk::Compressed { .. } = self {
+                    // Compression method 0 (zlib)
+                    data.push(0);
+                    data.extend_from_slice(&latin1);
+                    return Ok((*b"zTXt", data));
+                }
+                data.extend_from_slice(&latin1);
+                Ok((*b"tEXt", data))
+            }
+            TextChunk::Utf8 { .. } => {
+                // Compression flag, compression method, then empty language tag and
+                // translated keyword, each null-terminated
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(text.as_bytes());
+                Ok((*b"iTXt", data))
+            }
+            TextChunk::International {
+                language,
+                translated_keyword,
+                compressed,
+                ..
+            } => {
+                let invalid = |message: String| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        message,
+                    ))
+                };
+                if !language
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                {
+                    return invalid(format!(
+                        "Invalid language tag '{}' for '{}': use letters, digits and hyphens",
+                        language, keyword
+                    ));
+                }
+                if translated_keyword.contains('\0') || text.contains('\0') {
+                    return invalid(format!("iTXt fields for '{}' contain a NUL", keyword));
                 }
+
+                // Compression flag and method (0, zlib), then the null-terminated
+                // language tag and translated keyword
+                data.extend_from_slice(&[u8::from(*compressed), 0]);
+                data.extend_from_slice(language.as_bytes());
+                data.push(0);
+                data.extend_from_slice(translated_keyword.as_bytes());
+                data.push(0);
+                data.extend_from_slice(text.as_bytes());
+                Ok((*b"iTXt", data))
             }
         }
+    }
 
-        filtered_data
+    /// Number of null bytes in the serialized chunk before text that the encoder
+    /// compresses, or `None` for chunks stored uncompressed.
+    fn compressed_text_separators(&self) -> Option<usize> {
+        match self {
+            // Keyword terminator and compression method
+            TextChunk::Compressed { .. } => Some(2),
+            // Keyword terminator, compression method, language tag and translated keyword
+            TextChunk::International {
+                compressed: true, ..
+            } => Some(4),
+            _ => None,
+        }
     }
+}
 
-    fn compress_data(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
-        match self.compression_method {
-            CompressionMethod::Custom => {
-                let mut compressed = Vec::new();
+/// Validates a chunk keyword (1-79 printable Latin-1 characters) and returns it
+/// null-terminated.
+pub(crate) fn encode_keyword(keyword: &str) -> std::io::Result<Vec<u8>> {
+    let mut bytes = encode_latin1(keyword)
+        .filter(|bytes| {
+            !bytes.is_empty()
+                && bytes.len() <= 79
+                && bytes.iter().all(|&b| (32..=126).contains(&b) || b >= 161)
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid text keyword '{}': must be 1-79 printable Latin-1 characters",
+                    keyword
+                ),
+            )
+        })?;
+    bytes.push(0);
+    Ok(bytes)
+}
 
-                // Zlib header (2 bytes)
-                // CMF (Compression Method and Flags): 0x78 (deflate, 32k window)
-                // FLG (Flags): 0x9C (check bits, no preset dict, default compression)
-                compressed.push(0x78);
-                compressed.push(0x9C);
+/// Converts `text` to Latin-1, or `None` if it contains a NUL or a character above U+00FF.
+fn encode_latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars()
+        .map(|c| match c as u32 {
+            1..=0xFF => Some(c as u8),
+            _ => None,
+        })
+        .collect()
+}
 
-                let deflate_data = self.simple_deflate(data);
-                compressed.extend_from_slice(&deflate_data);
+/// Size statistics gathered while encoding a single image.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodeReport {
+    /// Unfiltered pixel bytes fed to the encoder.
+    pub raw_bytes: usize,
+    /// Compressed bytes written across the IDAT chunk(s).
+    pub idat_bytes: usize,
+    /// `raw_bytes / idat_bytes`; higher means better compression.
+    pub compression_ratio: f64,
+    /// SHA-256 of the complete PNG output, when requested via `compute_sha256`.
+    #[cfg(feature = "sha256")]
+    pub sha256: Option<[u8; 32]>,
+    /// MD5 of the complete PNG output, when requested via `compute_md5`.
+    #[cfg(feature = "md5")]
+    pub md5: Option<[u8; 16]>,
+}
 
-                // Adler32 checksum (4 bytes, big-endian)
-                let checksum = self.adler32(data);
-                compressed.extend_from_slice(&checksum.to_be_bytes());
+impl EncodeReport {
+    fn new(raw_bytes: usize, idat_bytes: usize) -> Self {
+        EncodeReport {
+            raw_bytes,
+            idat_bytes,
+            compression_ratio: if idat_bytes == 0 {
+                0.0
+            } else {
+                raw_bytes as f64 / idat_bytes as f64
+            },
+            #[cfg(feature = "sha256")]
+            sha256: None,
+            #[cfg(feature = "md5")]
+            md5: None,
+        }
+    }
+}
 
-                Ok(compressed)
-            }
-            CompressionMethod::Flate2 => {
-                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-                encoder.write_all(data)?;
-                encoder.finish()
-            }
+/// How scanline filters are chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FilterStrategy {
+    /// Apply the Sub filter to every row.
+    #[default]
+    Sub,
+    /// Sample a few scanlines first; if they look incompressible (e.g. photographic
+    /// noise), skip filtering and use minimal-effort compression to save encode time.
+    AdaptiveFast,
+    /// Store every row unfiltered.
+    None,
+    /// Apply the Up filter to every row.
+    Up,
+    /// Apply the Average filter to every row.
+    Average,
+    /// Apply the Paeth filter to every row.
+    Paeth,
+    /// Try all five filters on each row and keep the one with the smallest sum of
+    /// absolute (signed) filtered bytes, as libpng does.
+    Adaptive,
+}
+
+impl FilterStrategy {
+    /// The filter applied to every row, or `None` when the filter is chosen per row.
+    fn fixed_filter(self) -> Option<FilterType> {
+        match self {
+            FilterStrategy::Sub | FilterStrategy::AdaptiveFast => Some(FilterType::Sub),
+            FilterStrategy::None => Some(FilterType::None),
+            FilterStrategy::Up => Some(FilterType::Up),
+            FilterStrategy::Average => Some(FilterType::Average),
+            FilterStrategy::Paeth => Some(FilterType::Paeth),
+            FilterStrategy::Adaptive => None,
         }
     }
+}
 
-    fn simple_deflate(&self, data: &[u8]) -> Vec<u8> {
-        let mut result = Vec::new();
-        let mut i = 0;
+/// How the color channels of the source image relate to its alpha channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AlphaMode {
+    /// Color channels are independent of alpha, as PNG stores them.
+    #[default]
+    Straight,
+    /// Color channels have already been multiplied by alpha, as GPU framebuffers often
+    /// deliver them. They are divided by alpha again before filtering; fully transparent
+    /// pixels are left as they are.
+    Premultiplied,
+}
 
-        while i < data.len() {
-            let (match_distance, match_length) = self.find_longest_match(data, i);
-
-            if match_length >= 4 && match_distance > 0 && match_distance <= 65535 {
-                // Encoding: 255, distance_low, distance_high, length
-                let distance_low = (match_distance & 0xFF) as u8;
-                let distance_high = ((match_distance >> 8) & 0xFF) as u8;
-
-                if distance_low != 255 {
-                    result.push(255); // Escape byte
-                    result.push(distance_low);
-                    result.push(distance_high);
-                    result.push(std::cmp::min(match_length, 255) as u8);
-                    i += std::cmp::min(match_length, 255);
-                } else {
-                    if data[i] == 255 {
-                        result.push(255);
-                        result.push(255);
-                    } else {
-                        result.push(data[i]);
-                    }
-                    i += 1;
-                }
+/// How samples of 32-bit float sources are mapped onto PNG's integer samples. 0.0 maps to
+/// black (or transparent) and 1.0 to the largest sample value.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FloatMapping {
+    /// Scale linearly, and fail with [`EncoderError::FloatOutOfRange`] if any sample
+    /// lies outside 0.0-1.0 rather than silently losing high-dynamic-range content.
+    /// Tone-map such images first, or pick another mapping.
+    #[default]
+    Strict,
+    /// Clamp samples to 0.0-1.0, then scale linearly.
+    Clamp,
+    /// Clamp color samples to 0.0-1.0 and gamma-encode them as `value^(1/gamma)`, e.g.
+    /// 2.2 for linear-light sources; alpha is only clamped. Pair with
+    /// [`EncodeOptions::set_gamma`] to record the encoding in the file.
+    Gamma(f32),
+}
+
+/// Channel order of the color samples in a raw pixel buffer passed to [`encode_raw`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PixelOrder {
+    /// Red, green, blue (then alpha), as PNG stores them.
+    #[default]
+    Rgba,
+    /// Blue, green, red (then alpha), as Windows GDI and some GPU readbacks deliver
+    /// them. Red and blue are swapped back before filtering.
+    Bgra,
+}
+
+/// Byte order of the 16-bit samples passed to [`encode_raw_u16`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleEndian {
+    /// Most significant byte first, as PNG stores samples.
+    Big,
+    /// Least significant byte first, as little-endian GPU buffers hold them.
+    Little,
+}
+
+/// (color type, bit depth) pairs the encoder can write.
+const SUPPORTED_COLOR_TYPES: &[(u8, u8)] = &[
+    (0, 1),
+    (0, 2),
+    (0, 4),
+    (0, 8),
+    (0, 16),
+    (2, 8),
+    (2, 16),
+    (3, 1),
+    (3, 2),
+    (3, 4),
+    (3, 8),
+    (4, 8),
+    (4, 16),
+    (6, 8),
+    (6, 16),
+];
+
+/// Returns the (color type, bit depth) pairs this build can encode.
+pub fn supported_color_types() -> &'static [(u8, u8)] {
+    SUPPORTED_COLOR_TYPES
+}
+
+/// Returns whether this build can write Adam7-interlaced images.
+pub fn supports_interlace() -> bool {
+    true
+}
+
+/// A PNG scanline filter, as recorded in the first byte of each filtered row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterType {
+    None = 0,
+    Sub = 1,
+    Up = 2,
+    Average = 3,
+    Paeth = 4,
+}
+
+impl FilterType {
+    const ALL: [FilterType; 5] = [
+        FilterType::None,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Average,
+        FilterType::Paeth,
+    ];
+
+    /// The filter type stored as the first byte of a filtered scanline.
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    fn predict(self, left: u8, up: u8, up_left: u8) -> u8 {
+        match self {
+            FilterType::None => 0,
+            FilterType::Sub => left,
+            FilterType::Up => up,
+            FilterType::Average => ((left as u16 + up as u16) / 2) as u8,
+            FilterType::Paeth => paeth_predictor(left, up, up_left),
+        }
+    }
+
+    /// Appends `row` filtered against `previous` (all zeros for the first row).
+    fn filter_row(self, row: &[u8], previous: &[u8], bytes_per_pixel: usize, out: &mut Vec<u8>) {
+        for x in 0..row.len() {
+            let left = if x >= bytes_per_pixel {
+                row[x - bytes_per_pixel]
             } else {
-                if data[i] == 255 {
-                    result.push(255);
-                    result.push(255);
-                } else {
-                    result.push(data[i]);
+                0
+            };
+            let up = previous[x];
+            let up_left = if x >= bytes_per_pixel {
+                previous[x - bytes_per_pixel]
+            } else {
+                0
+            };
+
+            out.push(row[x].wrapping_sub(self.predict(left, up, up_left)));
+        }
+    }
+
+    /// Reverses [`FilterType::filter_row`] in place, given the unfiltered `previous` row.
+    pub(crate) fn unfilter_row(self, row: &mut [u8], previous: &[u8], bytes_per_pixel: usize) {
+        for x in 0..row.len() {
+            let (left, up_left) = if x >= bytes_per_pixel {
+                (row[x - bytes_per_pixel], previous[x - bytes_per_pixel])
+            } else {
+                (0, 0)
+            };
+
+            row[x] = row[x].wrapping_add(self.predict(left, previous[x], up_left));
+        }
+    }
+}
+
+/// Appends `row`'s filter type byte and filtered bytes to `filtered_data`, using
+/// `filter_type` or the filter with the smallest residuals when it is `None`.
+fn filter_scanline(
+    row: &[u8],
+    previous: &[u8],
+    bytes_per_pixel: usize,
+    filter_type: Option<FilterType>,
+    filtered_data: &mut Vec<u8>,
+) {
+    if let Some(filter_type) = filter_type {
+        filtered_data.push(filter_type as u8);
+        filter_type.filter_row(row, previous, bytes_per_pixel, filtered_data);
+        return;
+    }
+
+    let mut candidate = Vec::with_capacity(row.len());
+    let mut best = Vec::with_capacity(row.len());
+    let mut best_type = FilterType::None;
+    let mut best_score = u64::MAX;
+    for filter_type in FilterType::ALL {
+        candidate.clear();
+        filter_type.filter_row(row, previous, bytes_per_pixel, &mut candidate);
+        // Score bytes as signed so small negative residuals count as small
+        let score = candidate
+            .iter()
+            .map(|&b| (b as i8).unsigned_abs() as u64)
+            .sum();
+        if score < best_score {
+            best_score = score;
+            best_type = filter_type;
+            std::mem::swap(&mut best, &mut candidate);
+        }
+    }
+    filtered_data.push(best_type as u8);
+    filtered_data.extend_from_slice(&best);
+}
+
+/// Picks whichever of left, up and upper-left is closest to `left + up - up_left`.
+fn paeth_predictor(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance_left = (estimate - left as i16).abs();
+    let distance_up = (estimate - up as i16).abs();
+    let distance_up_left = (estimate - up_left as i16).abs();
+
+    if distance_left <= distance_up && distance_left <= distance_up_left {
+        left
+    } else if distance_up <= distance_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Chains of earlier positions sharing the same 3-byte prefix hash, as in zlib, so a
+/// match search only visits positions that can actually match.
+struct HashChain {
+    /// Most recent position (plus one) for each hash; zero means none.
+    head: Vec<usize>,
+    /// For each position, the previous position (plus one) with the same hash.
+    prev: Vec<usize>,
+}
+
+impl HashChain {
+    fn new(len: usize) -> Self {
+        HashChain {
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; len],
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let prefix =
+            ((data[pos] as usize) << 10) ^ ((data[pos + 1] as usize) << 5) ^ data[pos + 2] as usize;
+        prefix & ((1 << HASH_BITS) - 1)
+    }
+
+    /// Records `pos` as the newest candidate for its prefix.
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + 3 > data.len() {
+            return;
+        }
+        let hash = Self::hash(data, pos);
+        self.prev[pos] = self.head[hash];
+        self.head[hash] = pos + 1;
+    }
+
+    /// The newest recorded position sharing `pos`'s prefix hash.
+    fn first_candidate(&self, data: &[u8], pos: usize) -> Option<usize> {
+        if pos + 3 > data.len() {
+            return None;
+        }
+        self.head[Self::hash(data, pos)].checked_sub(1)
+    }
+
+    fn next_candidate(&self, candidate: usize) -> Option<usize> {
+        self.prev[candidate].checked_sub(1)
+    }
+}
+
+struct PngEncoder {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    compression_method: CompressionMethod,
+    /// Back-references closer than this are never considered by the custom matcher.
+    min_match_distance: usize,
+    /// Choose between a match and literals by estimated encoded size rather than a fixed
+    /// minimum match length.
+    use_cost_model: bool,
+    /// Before taking a match, check whether one starting a byte later is longer, and emit
+    /// a literal first if so.
+    lazy_matching: bool,
+    /// RGBA entries of the PLTE chunk when `color_type` is 3.
+    palette: Vec<[u8; 4]>,
+    /// Preset dictionary for the custom compressor; see [`Self::set_dictionary`].
+    dictionary: Vec<u8>,
+    options: EncodeOptions,
+}
+
+impl PngEncoder {
+    fn new(width: u32, height: u32, compression_method: CompressionMethod) -> Self {
+        PngEncoder {
+            width,
+            height,
+            bit_depth: 8,
+            color_type: 6,
+            compression_method,
+            min_match_distance: 1,
+            use_cost_model: true,
+            lazy_matching: true,
+            palette: Vec::new(),
+            dictionary: Vec::new(),
+            options: EncodeOptions::default(),
+        }
+    }
+
+    fn with_options(
+        width: u32,
+        height: u32,
+        compression_method: CompressionMethod,
+        options: EncodeOptions,
+    ) -> Result<Self, EncoderError> {
+        if width == 0 || height == 0 {
+            return Err(EncoderError::InvalidDimensions { width, height });
+        }
+
+        if let Some(bits) = options.significant_bits
+            && bits.iter().any(|&b| b == 0 || b > 8)
+        {
+            return Err(EncoderError::InvalidOptions(
+                "Significant bits must be between 1 and 8".to_string(),
+            ));
+        }
+
+        if options.max_memory.is_some() && options.interlace {
+            return Err(EncoderError::InvalidOptions(
+                "A memory budget cannot be combined with interlacing".to_string(),
+            ));
+        }
+
+        if options.streaming && options.interlace {
+            return Err(EncoderError::InvalidOptions(
+                "Streaming encodes cannot be interlaced".to_string(),
+            ));
+        }
+
+        if let FloatMapping::Gamma(gamma) = options.float_mapping
+            && !(gamma.is_finite() && gamma > 0.0)
+        {
+            return Err(EncoderError::InvalidOptions(
+                "Float gamma must be a positive number".to_string(),
+            ));
+        }
+
+        if options.indexed && options.alpha_mode == AlphaMode::Premultiplied {
+            return Err(EncoderError::InvalidOptions(
+                "Premultiplied alpha cannot be combined with indexed output".to_string(),
+            ));
+        }
+
+        if let Some(time) = &options.modification_time {
+            time.validate()?;
+        }
+
+        if let Some(icc) = &options.icc_profile {
+            if options.srgb.is_some() {
+                return Err(EncoderError::InvalidOptions(
+                    "An ICC profile cannot be combined with sRGB".to_string(),
+                ));
+            }
+            encode_keyword(&icc.name).map_err(|e| EncoderError::InvalidOptions(e.to_string()))?;
+        }
+
+        if let Some(level) = options.compression_level
+            && level > 9
+        {
+            return Err(EncoderError::InvalidOptions(
+                "Compression level must be between 0 and 9".to_string(),
+            ));
+        }
+
+        if let Some(distance) = options.min_match_distance
+            && !(1..=WINDOW_SIZE).contains(&distance)
+        {
+            return Err(EncoderError::InvalidOptions(format!(
+                "Minimum match distance must be between 1 and {}",
+                WINDOW_SIZE
+            )));
+        }
+
+        if let Some(size) = options.max_idat_chunk_size
+            && !(1..=MAX_CHUNK_LENGTH).contains(&size)
+        {
+            return Err(EncoderError::InvalidOptions(format!(
+                "IDAT chunk size must be between 1 and {}",
+                MAX_CHUNK_LENGTH
+            )));
+        }
+
+        for text in &options.text {
+            text.to_chunk()
+                .map_err(|e| EncoderError::InvalidOptions(e.to_string()))?;
+        }
+
+        let mut encoder = PngEncoder::new(width, height, compression_method);
+        encoder.min_match_distance = options.min_match_distance.unwrap_or(1);
+        encoder.use_cost_model = !options.fixed_match_threshold;
+        encoder.options = options;
+        encoder.options.streaming |= encoder.options.max_memory.is_some();
+        Ok(encoder)
+    }
+
+    fn encode<W: Write>(
+        &self,
+        image: &DynamicImage,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        #[cfg(feature = "md5")]
+        if self.options.compute_md5 {
+            let mut hashing = HashingWriter::<_, md5::Md5>::new(writer);
+            let mut report = self.encode_sha256(image, &mut hashing)?;
+            report.md5 = hashing.finalize().try_into().ok();
+            return Ok(report);
+        }
+
+        self.encode_sha256(image, writer)
+    }
+
+    /// Encodes `image`, hashing the output with SHA-256 on the way if requested.
+    fn encode_sha256<W: Write>(
+        &self,
+        image: &DynamicImage,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        #[cfg(feature = "sha256")]
+        if self.options.compute_sha256 {
+            let mut hashing = HashingWriter::<_, sha2::Sha256>::new(writer);
+            let mut report = self.encode_unhashed(image, &mut hashing)?;
+            report.sha256 = hashing.finalize().try_into().ok();
+            return Ok(report);
+        }
+
+        self.encode_unhashed(image, writer)
+    }
+
+    fn encode_unhashed<W: Write>(
+        &self,
+        image: &DynamicImage,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_float_range(image)?;
+        if self.options.streaming {
+            return self.encode_streaming(image, writer);
+        }
+
+        self.checked_filtered_len()?;
+        let pixels = self.prepare_pixels(image);
+        self.encode_pixels(&pixels, writer)
+    }
+
+    /// Filters, compresses and writes `pixels`, which are already laid out as the
+    /// unfiltered scanlines of the encoder's color type and bit depth.
+    fn encode_pixels<W: Write>(
+        &self,
+        pixels: &[u8],
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        // Filter and compress before writing anything so a cancelled encode leaves the
+        // writer untouched
+        let (filter_type, low_effort) = self.filter_choice(pixels);
+
+        let filtered_data = self.apply_filters(pixels, filter_type)?;
+        let compressed_data = self.compress_data_with_effort(&filtered_data, low_effort)?;
+
+        self.write_header_chunks(writer)?;
+        self.write_idat(&compressed_data, writer)?;
+        self.write_iend(writer)?;
+
+        Ok(EncodeReport::new(pixels.len(), compressed_data.len()))
+    }
+
+    /// The filter to apply to every row of `pixels`, or `None` to pick one per row, and
+    /// whether the data looks incompressible enough to store with minimal effort.
+    fn filter_choice(&self, pixels: &[u8]) -> (Option<FilterType>, bool) {
+        let low_effort = self.options.filter_strategy == FilterStrategy::AdaptiveFast
+            && !self.options.streaming
+            && self.looks_incompressible(pixels);
+        if low_effort {
+            (Some(FilterType::None), true)
+        } else {
+            (self.options.filter_strategy.fixed_filter(), false)
+        }
+    }
+
+    /// Filters `image` as [`Self::encode`] would and returns each scanline's filter.
+    fn row_filters(&self, image: &DynamicImage) -> Result<Vec<FilterType>, EncoderError> {
+        self.check_float_range(image)?;
+        self.checked_filtered_len()?;
+        let pixels = self.prepare_pixels(image);
+        let (filter_type, _) = self.filter_choice(&pixels);
+        let filtered = self.apply_filters(&pixels, filter_type)?;
+
+        let passes: &[(usize, usize, usize, usize)] = if self.options.interlace {
+            &ADAM7_PASSES
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        let mut filters = Vec::new();
+        let mut offset = 0;
+        for &pass in passes {
+            let (width, height) = self.pass_dimensions(pass);
+            if width == 0 || height == 0 {
+                continue;
+            }
+            let row_len = self.stride_for(width).ok_or(EncoderError::ImageTooLarge)? + 1;
+            for _ in 0..height {
+                let byte = filtered[offset];
+                filters.push(
+                    FilterType::from_byte(byte).ok_or(EncoderError::InvalidFilterType(byte))?,
+                );
+                offset += row_len;
+            }
+        }
+        Ok(filters)
+    }
+
+    /// Writes `frames` as an APNG: acTL after the header chunks, then an fcTL per frame
+    /// followed by the first frame's IDAT or a later frame's fdAT chunks. fcTL and fdAT
+    /// chunks share one sequence counter. With a fallback image, its IDAT comes right
+    /// after acTL without an fcTL, and every frame is written as fdAT.
+    fn encode_animation<W: Write>(
+        &self,
+        frames: &[ApngFrame],
+        options: &ApngOptions,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.checked_filtered_len()?;
+        let filter_type = self.options.filter_strategy.fixed_filter();
+        let mut compressed_frames = Vec::with_capacity(frames.len());
+        let mut raw_bytes = 0;
+        for image in options
+            .fallback
+            .iter()
+            .chain(frames.iter().map(|frame| &frame.image))
+        {
+            self.check_float_range(image)?;
+            let pixels = self.prepare_pixels(image);
+            raw_bytes += pixels.len();
+            let filtered = self.apply_filters(&pixels, filter_type)?;
+            compressed_frames.push(self.compress_data(&filtered)?);
+        }
+
+        self.write_header_chunks(writer)?;
+
+        let mut actl = Vec::with_capacity(8);
+        actl.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+        actl.extend_from_slice(&options.num_plays.to_be_bytes());
+        self.write_chunk(writer, b"acTL", &actl)?;
+
+        let mut frame_data = compressed_frames.iter();
+        if options.fallback.is_some()
+            && let Some(compressed) = frame_data.next()
+        {
+            self.write_idat(compressed, writer)?;
+        }
+
+        let mut sequence = 0u32;
+        for (index, (frame, compressed)) in frames.iter().zip(frame_data).enumerate() {
+            let mut fctl = Vec::with_capacity(26);
+            fctl.extend_from_slice(&sequence.to_be_bytes());
+            fctl.extend_from_slice(&self.width.to_be_bytes());
+            fctl.extend_from_slice(&self.height.to_be_bytes());
+            // x and y offset
+            fctl.extend_from_slice(&[0; 8]);
+            fctl.extend_from_slice(&frame.delay_num.to_be_bytes());
+            fctl.extend_from_slice(&frame.delay_den.to_be_bytes());
+            fctl.push(frame.dispose_op as u8);
+            fctl.push(frame.blend_op as u8);
+            self.write_chunk(writer, b"fcTL", &fctl)?;
+            sequence += 1;
+
+            if index == 0 && options.fallback.is_none() {
+                self.write_idat(compressed, writer)?;
+                continue;
+            }
+
+            // fdAT is IDAT prefixed with the sequence number
+            for data in compressed.chunks(self.max_idat_chunk_size().saturating_sub(4).max(1)) {
+                let mut fdat = Vec::with_capacity(data.len() + 4);
+                fdat.extend_from_slice(&sequence.to_be_bytes());
+                fdat.extend_from_slice(data);
+                self.write_chunk(writer, b"fdAT", &fdat)?;
+                sequence += 1;
+            }
+        }
+
+        self.write_iend(writer)?;
+
+        let idat_bytes = compressed_frames.iter().map(Vec::len).sum();
+        Ok(EncodeReport::new(raw_bytes, idat_bytes))
+    }
+
+    /// Writes the signature and every chunk that precedes the image data.
+    fn write_header_chunks<W: Write>(&self, writer: &mut W) -> Result<(), EncoderError> {
+        writer.write_all(&PNG_SIGNATURE)?;
+
+        self.write_ihdr(writer)?;
+
+        if let Some(bits) = self.channel_significant_bits() {
+            self.write_chunk(writer, b"sBIT", &bits)?;
+        }
+
+        let gamma = match self.options.srgb {
+            Some(_) => Some(SRGB_GAMMA),
+            None => self.options.gamma,
+        };
+        if let Some(gamma) = gamma {
+            self.write_chunk(writer, b"gAMA", &gamma.to_be_bytes())?;
+        }
+        if let Some(chromaticities) = self.options.chromaticities {
+            let data: Vec<u8> = chromaticities
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect();
+            self.write_chunk(writer, b"cHRM", &data)?;
+        }
+        if let Some(icc) = &self.options.icc_profile {
+            // Profile name, then compression method 0 (zlib) and the compressed profile
+            let mut data = encode_keyword(&icc.name)?;
+            data.push(0);
+            data.extend_from_slice(&self.compress_data(&icc.profile)?);
+            self.write_chunk(writer, b"iCCP", &data)?;
+        }
+        if let Some(intent) = self.options.srgb {
+            self.write_chunk(writer, b"sRGB", &[intent as u8])?;
+        }
+        self.write_copied_chunks(writer, true)?;
+
+        if self.color_type == 3 {
+            let entries: Vec<u8> = self
+                .palette
+                .iter()
+                .flat_map(|&[red, green, blue, _]| [red, green, blue])
+                .collect();
+            self.write_chunk(writer, b"PLTE", &entries)?;
+
+            // Entries after the last translucent one default to opaque
+            let alphas: Vec<u8> = self.palette.iter().map(|entry| entry[3]).collect();
+            let translucent = alphas.iter().rposition(|&alpha| alpha != 255);
+            if let Some(last) = translucent {
+                self.write_chunk(writer, b"tRNS", &alphas[..=last])?;
+            }
+        }
+
+        if let Some(background) = self.background_chunk()? {
+            self.write_chunk(writer, b"bKGD", &background)?;
+        }
+
+        if self.color_type == 0
+            && let Some(level) = self.options.transparent_gray
+        {
+            let sample = match self.bit_depth {
+                16 => level as u16 * 257,
+                8 => level as u16,
+                bit_depth => (level / gray_level_step(bit_depth)) as u16,
+            };
+            self.write_chunk(writer, b"tRNS", &sample.to_be_bytes())?;
+        }
+
+        if let Some(dimensions) = self.options.physical_dimensions {
+            let mut data = Vec::with_capacity(9);
+            data.extend_from_slice(&dimensions.ppu_x.to_be_bytes());
+            data.extend_from_slice(&dimensions.ppu_y.to_be_bytes());
+            data.push(match dimensions.unit {
+                PhysicalUnit::Unspecified => 0,
+                PhysicalUnit::Meter => 1,
+            });
+            self.write_chunk(writer, b"pHYs", &data)?;
+        }
+
+        for text in &self.options.text {
+            let (chunk_type, data) = self.text_chunk(text)?;
+            self.write_chunk(writer, &chunk_type, &data)?;
+        }
+
+        if let Some(time) = self.options.modification_time {
+            self.write_chunk(writer, b"tIME", &time.to_bytes())?;
+        }
+        self.write_copied_chunks(writer, false)?;
+
+        Ok(())
+    }
+
+    /// Writes the copied color-space chunks, or all the other copied chunks, skipping
+    /// those the options replace.
+    fn write_copied_chunks<W: Write>(
+        &self,
+        writer: &mut W,
+        color_space: bool,
+    ) -> Result<(), EncoderError> {
+        for chunk in &self.options.copied_chunks {
+            if COLOR_SPACE_CHUNKS.contains(&&chunk.chunk_type) == color_space
+                && !self.replaces_copied_chunk(&chunk.chunk_type)
+            {
+                self.write_chunk(writer, &chunk.chunk_type, &chunk.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the options write their own chunk in place of a copied `chunk_type`.
+    fn replaces_copied_chunk(&self, chunk_type: &[u8; 4]) -> bool {
+        let options = &self.options;
+        match chunk_type {
+            b"gAMA" => options.gamma.is_some() || options.srgb.is_some(),
+            b"cHRM" => options.chromaticities.is_some(),
+            b"iCCP" | b"sRGB" => options.icc_profile.is_some() || options.srgb.is_some(),
+            b"pHYs" => options.physical_dimensions.is_some(),
+            b"tIME" => options.modification_time.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Encodes `image` a band of rows at a time: each band is converted, filtered and fed
+    /// to the compressor, and IDAT chunks are written as compressed output accumulates,
+    /// so memory use stays bounded by the band size rather than the image size.
+    fn encode_streaming<W: Write>(
+        &self,
+        image: &DynamicImage,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        let stride = self.stride().ok_or(EncoderError::ImageTooLarge)?;
+        let filter_type = self.options.filter_strategy.fixed_filter();
+        if let Some(budget) = self.options.max_memory
+            && budget < Self::min_streaming_memory(stride)
+        {
+            return Err(EncoderError::InvalidOptions(format!(
+                "{}-byte scanlines need at least {} bytes, more than the {}-byte memory budget",
+                stride,
+                Self::min_streaming_memory(stride),
+                budget
+            )));
+        }
+        let band_rows = self.stream_band_rows();
+        let segment_size = self.stream_segment_size();
+
+        self.write_header_chunks(writer)?;
+
+        let idat = IdatWriter::new(self, &mut *writer);
+        let mut compressor = match self.compression_method {
+            CompressionMethod::Custom | CompressionMethod::Stored => {
+                StreamCompressor::Custom(CustomStream::new(self, idat)?)
+            }
+            CompressionMethod::Flate2 => {
+                StreamCompressor::Flate2(ZlibEncoder::new(idat, self.flate2_level()))
+            }
+        };
+
+        let mut previous = vec![0u8; stride];
+        // Rows are handed to the compressor in batches; per-row writes are slow for
+        // narrow images
+        let mut filtered = Vec::with_capacity(segment_size + stride + 1);
+        let mut raw_bytes = 0;
+        let mut progress = ProgressTracker::new(self.options.progress.as_ref(), self.height);
+        for band_start in (0..self.height).step_by(band_rows as usize) {
+            let band_rows = band_rows.min(self.height - band_start);
+            let mut band =
+                self.prepare_pixels(&image.crop_imm(0, band_start, self.width, band_rows));
+            self.unpremultiply(&mut band);
+            raw_bytes += band.len();
+
+            for y in 0..band_rows as usize {
+                self.check_cancelled()?;
+                let row = &band[y * stride..(y + 1) * stride];
+                filter_scanline(
+                    row,
+                    &previous,
+                    self.bytes_per_pixel(),
+                    filter_type,
+                    &mut filtered,
+                );
+                previous.copy_from_slice(row);
+                progress.advance(1);
+                if filtered.len() >= segment_size {
+                    compressor.write(&filtered)?;
+                    filtered.clear();
+                }
+            }
+        }
+
+        compressor.write(&filtered)?;
+        let idat_bytes = compressor.finish()?;
+        self.write_iend(writer)?;
+
+        Ok(EncodeReport::new(raw_bytes, idat_bytes))
+    }
+
+    /// Serializes `text`, compressing the text of a zTXt or compressed iTXt chunk with
+    /// `compress_data`.
+    fn text_chunk(&self, text: &TextChunk) -> Result<([u8; 4], Vec<u8>), EncoderError> {
+        let (chunk_type, mut data) = text.to_chunk()?;
+        if let Some(separators) = text.compressed_text_separators() {
+            // None of the header fields may contain a NUL, so the text starts right
+            // after the last separator
+            let header_len = data
+                .iter()
+                .enumerate()
+                .filter(|&(_, &b)| b == 0)
+                .nth(separators - 1)
+                .map_or(data.len(), |(index, _)| index + 1);
+            let compressed = self.compress_data(&data[header_len..])?;
+            data.truncate(header_len);
+            data.extend_from_slice(&compressed);
+        }
+        Ok((chunk_type, data))
+    }
+
+    fn write_ihdr<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut chunk_data = Vec::new();
+
+        chunk_data.extend_from_slice(&self.width.to_be_bytes());
+        chunk_data.extend_from_slice(&self.height.to_be_bytes());
+        chunk_data.push(self.bit_depth);
+        chunk_data.push(self.color_type);
+        chunk_data.push(0);
+        chunk_data.push(0);
+        chunk_data.push(u8::from(self.options.interlace));
+
+        self.write_chunk(writer, b"IHDR", &chunk_data)
+    }
+
+    fn write_idat<W: Write>(&self, compressed_data: &[u8], writer: &mut W) -> std::io::Result<()> {
+        if compressed_data.is_empty() {
+            return self.write_chunk(writer, b"IDAT", &[]);
+        }
+
+        for data in compressed_data.chunks(self.max_idat_chunk_size()) {
+            self.write_chunk(writer, b"IDAT", data)?;
+        }
+        Ok(())
+    }
+
+    fn max_idat_chunk_size(&self) -> usize {
+        let size = self
+            .options
+            .max_idat_chunk_size
+            .unwrap_or(DEFAULT_MAX_IDAT_CHUNK_SIZE);
+        match self.spare_memory() {
+            Some(spare) => size.min(spare / 4).max(1),
+            None => size,
+        }
+    }
+
+    /// Memory a streaming encode needs at the least: a band of one converted row, the
+    /// row above it and one filtered row.
+    fn min_streaming_memory(stride: usize) -> usize {
+        stride.saturating_mul(3).saturating_add(1)
+    }
+
+    /// What is left of `options.max_memory` after the least a streaming encode needs,
+    /// shared out as a quarter for the IDAT chunk, a quarter each for filtered data and
+    /// compressor input, and the rest for extra rows per band.
+    fn spare_memory(&self) -> Option<usize> {
+        let stride = self.stride().unwrap_or(usize::MAX);
+        self.options
+            .max_memory
+            .map(|budget| budget.saturating_sub(Self::min_streaming_memory(stride)))
+    }
+
+    /// Rows converted from the source image at a time when streaming.
+    fn stream_band_rows(&self) -> u32 {
+        match (self.spare_memory(), self.stride()) {
+            (Some(spare), Some(stride)) if stride > 0 => {
+                (1 + spare / 2 / stride).min(STREAM_BAND_ROWS as usize) as u32
+            }
+            _ => STREAM_BAND_ROWS,
+        }
+    }
+
+    /// Filtered bytes collected before they are handed to the streaming compressor.
+    fn stream_segment_size(&self) -> usize {
+        match self.spare_memory() {
+            Some(spare) => (spare / 4).clamp(1, STREAM_SEGMENT_SIZE),
+            None => STREAM_SEGMENT_SIZE,
+        }
+    }
+
+    fn write_iend<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        #[cfg(feature = "testing-malformed")]
+        match self.options.malformed_iend {
+            Some(MalformedIend::Omit) => return Ok(()),
+            Some(MalformedIend::CorruptCrc) => {
+                writer.write_all(&0u32.to_be_bytes())?;
+                writer.write_all(b"IEND")?;
+                let crc_value = !crc32fast::hash(b"IEND");
+                return writer.write_all(&crc_value.to_be_bytes());
+            }
+            None => {}
+        }
+
+        // IEND chunk has no data
+        self.write_chunk(writer, b"IEND", &[])
+    }
+
+    fn write_chunk<W: Write>(
+        &self,
+        writer: &mut W,
+        chunk_type: &[u8],
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+
+        writer.write_all(chunk_type)?;
+
+        writer.write_all(data)?;
+
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(chunk_type);
+        crc.update(data);
+        let crc_value = crc.finalize();
+
+        writer.write_all(&crc_value.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns [`EncoderError::Cancelled`] if the caller has requested cancellation.
+    fn check_cancelled(&self) -> Result<(), EncoderError> {
+        match &self.options.cancel {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(EncoderError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Samples per pixel for the encoder's color type.
+    fn channels(&self) -> usize {
+        match self.color_type {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Bytes per pixel at the encoder's color type and bit depth, rounded up to one for
+    /// bit depths below 8 as the filters require.
+    fn bytes_per_pixel(&self) -> usize {
+        (self.channels() * self.bit_depth as usize / 8).max(1)
+    }
+
+    /// Bytes in one scanline of the full image, excluding the filter byte. `None` if the
+    /// row would not fit in memory addressable by this platform.
+    fn stride(&self) -> Option<usize> {
+        self.stride_for(self.width as usize)
+    }
+
+    /// Bytes in a scanline `width` pixels wide. Rows of sub-byte pixels end in a
+    /// partially filled byte when they do not divide evenly.
+    fn stride_for(&self, width: usize) -> Option<usize> {
+        width
+            .checked_mul(self.channels() * self.bit_depth as usize)
+            .map(|bits| bits.div_ceil(8))
+    }
+
+    /// Size of the filtered image data (a filter byte plus one scanline per row, summed
+    /// over the Adam7 passes when interlacing), or `None` if it would not fit in memory
+    /// addressable by this platform.
+    fn filtered_len(&self) -> Option<usize> {
+        let passes = if self.options.interlace {
+            &ADAM7_PASSES[..]
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        passes.iter().try_fold(0usize, |total, &pass| {
+            let (width, height) = self.pass_dimensions(pass);
+            if width == 0 || height == 0 {
+                return Some(total);
+            }
+            self.stride_for(width)
+                .and_then(|stride| stride.checked_add(1))
+                .and_then(|stride| stride.checked_mul(height))
+                .and_then(|len| total.checked_add(len))
+        })
+    }
+
+    /// [`Self::filtered_len`], failing with [`EncoderError::ImageTooLarge`] if it
+    /// overflows or exceeds [`EncodeOptions::max_filtered_bytes`].
+    fn checked_filtered_len(&self) -> Result<usize, EncoderError> {
+        self.filtered_len()
+            .filter(|&len| self.options.max_filtered_bytes.is_none_or(|max| len <= max))
+            .ok_or(EncoderError::ImageTooLarge)
+    }
+
+    /// Width and height of the sub-image holding every pixel at `(x start + n * x step,
+    /// y start + m * y step)`.
+    fn pass_dimensions(&self, (x0, y0, dx, dy): (usize, usize, usize, usize)) -> (usize, usize) {
+        (
+            (self.width as usize + dx - 1 - x0) / dx,
+            (self.height as usize + dy - 1 - y0) / dy,
+        )
+    }
+
+    /// Rough upper bound on the encoded file size: the filtered data stored uncompressed
+    /// plus chunk overhead. Used to pre-size memory-mapped output.
+    #[cfg(feature = "mmap")]
+    fn size_estimate(&self) -> usize {
+        let text_bytes: usize = self
+            .options
+            .text
+            .iter()
+            .filter_map(|text| text.to_chunk().ok())
+            .map(|(_, data)| data.len() + 12)
+            .sum();
+        let filtered_len = self.filtered_len().unwrap_or(0);
+        let idat_overhead = (filtered_len / self.max_idat_chunk_size() + 1).saturating_mul(12);
+        filtered_len
+            .saturating_add(idat_overhead)
+            .saturating_add(text_bytes)
+            .saturating_add(1024)
+    }
+
+    /// Chooses the output color type from `image`'s channels, so grayscale sources and
+    /// sources without alpha are not widened to RGBA. Also picks the bit depth when
+    /// `auto_precision` is enabled, and builds the palette when `indexed` is enabled and
+    /// the image's colors fit in one. Gray and indexed images drop to 1, 2 or 4 bits per
+    /// pixel when that loses nothing, and `auto_strip_opaque_alpha` drops an alpha
+    /// channel that is opaque everywhere. Every check reads `image` in place or a band of
+    /// rows at a time, keeping within a streaming encode's memory bound.
+    fn configure_for_image(&mut self, image: &DynamicImage) {
+        let color = image.color();
+        self.color_type = match (color.has_color(), color.has_alpha()) {
+            (false, false) => 0,
+            (false, true) => 4,
+            (true, false) => 2,
+            (true, true) => 6,
+        };
+        if self.options.auto_strip_opaque_alpha && is_fully_opaque(image) {
+            self.color_type &= !4;
+        }
+
+        if self.options.auto_precision && self.options.significant_bits.is_none() {
+            let bits_per_channel = color.bits_per_pixel() / color.channel_count() as u16;
+            self.bit_depth = if bits_per_channel > 8 { 16 } else { 8 };
+        }
+
+        // Gray sources already use one byte per pixel
+        if self.options.indexed
+            && color.has_color()
+            && self.bit_depth == 8
+            && let Ok(palette) = self.image_palette(image)
+        {
+            self.use_palette(palette);
+        }
+
+        // sBIT may not exceed the bit depth, so a gray precision below 8 keeps 8-bit output
+        if self.color_type == 0 && self.bit_depth == 8 && self.options.significant_bits.is_none() {
+            // The transparent level has to stay distinguishable at the chosen depth too
+            let mut levels = gray_levels(image);
+            levels.extend(self.options.transparent_gray);
+            if let Some(BackgroundColor::Gray(level)) = self.options.background {
+                levels.extend(u8::try_from(level).ok());
+            }
+            self.bit_depth = lowest_gray_bit_depth(&levels);
+        }
+    }
+
+    /// Switches to indexed color with `palette`, at the smallest bit depth that can
+    /// address every entry.
+    fn use_palette(&mut self, mut palette: Vec<[u8; 4]>) {
+        // Translucent entries first keeps the tRNS chunk as short as possible. Ordering by
+        // value within each group makes the palette depend only on the set of colors,
+        // not on where in the image they first appear.
+        palette.sort_by_key(|entry| (entry[3] == 255, *entry));
+        self.color_type = 3;
+        self.bit_depth = match palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+        self.palette = palette;
+    }
+
+    /// Overrides the color type chosen by [`Self::configure_for_image`]. Indexed color
+    /// fails with [`EncoderError::TooManyColors`] if the image does not fit a palette.
+    fn force_color_type(
+        &mut self,
+        image: &DynamicImage,
+        color_type: u8,
+    ) -> Result<(), EncoderError> {
+        if color_type == 3 && self.options.alpha_mode == AlphaMode::Premultiplied {
+            return Err(EncoderError::InvalidOptions(
+                "Premultiplied alpha cannot be combined with indexed output".to_string(),
+            ));
+        }
+        if color_type == self.color_type {
+            return Ok(());
+        }
+
+        match color_type {
+            3 => {
+                self.bit_depth = 8;
+                let palette = self.image_palette(image)?;
+                self.use_palette(palette);
+            }
+            0 | 2 | 4 | 6 => {
+                self.color_type = color_type;
+                self.bit_depth = self.bit_depth.max(8);
+                self.palette.clear();
+            }
+            _ => return Err(EncoderError::UnsupportedColorType(color_type)),
+        }
+        Ok(())
+    }
+
+    /// Significant bits for each channel of the output color type, in sBIT order. Gray
+    /// channels take the red value of `significant_bits`.
+    fn channel_significant_bits(&self) -> Option<Vec<u8>> {
+        let [red, green, blue, alpha] = self.options.significant_bits?;
+        Some(match self.color_type {
+            0 => vec![red],
+            2 | 3 => vec![red, green, blue],
+            4 => vec![red, alpha],
+            _ => vec![red, green, blue, alpha],
+        })
+    }
+
+    /// The bKGD chunk data for `options.background` in the output color type, or `None`
+    /// if it has no equivalent there.
+    fn background_chunk(&self) -> Result<Option<Vec<u8>>, EncoderError> {
+        let Some(background) = self.options.background else {
+            return Ok(None);
+        };
+
+        let max = if self.bit_depth == 16 { u16::MAX } else { 255 };
+        let sample = |value: u16| {
+            if value > max {
+                return Err(EncoderError::InvalidOptions(format!(
+                    "Background sample {} does not fit {}-bit output",
+                    value,
+                    self.bit_depth.max(8)
+                )));
+            }
+            Ok(match self.bit_depth {
+                8 | 16 => value,
+                bit_depth => value / gray_level_step(bit_depth) as u16,
+            })
+        };
+
+        let samples = match (self.color_type, background) {
+            (3, BackgroundColor::PaletteIndex(index)) => {
+                if index as usize >= self.palette.len() {
+                    return Err(EncoderError::InvalidOptions(format!(
+                        "Background palette index {} is outside the {}-entry palette",
+                        index,
+                        self.palette.len()
+                    )));
+                }
+                return Ok(Some(vec![index]));
+            }
+            (3, BackgroundColor::Gray(level)) => {
+                return Ok(self.palette_index_of(level, level, level).map(|i| vec![i]));
+            }
+            (3, BackgroundColor::Rgb(red, green, blue)) => {
+                return Ok(self.palette_index_of(red, green, blue).map(|i| vec![i]));
+            }
+            (_, BackgroundColor::PaletteIndex(_)) => return Ok(None),
+            (0 | 4, BackgroundColor::Gray(level)) => vec![sample(level)?],
+            (0 | 4, BackgroundColor::Rgb(red, green, blue)) => {
+                let luma = (2126 * red as u32 + 7152 * green as u32 + 722 * blue as u32) / 10000;
+                vec![sample(luma as u16)?]
+            }
+            (_, BackgroundColor::Gray(level)) => vec![sample(level)?; 3],
+            (_, BackgroundColor::Rgb(red, green, blue)) => {
+                vec![sample(red)?, sample(green)?, sample(blue)?]
+            }
+        };
+
+        Ok(Some(
+            samples
+                .iter()
+                .flat_map(|sample| sample.to_be_bytes())
+                .collect(),
+        ))
+    }
+
+    /// Index of the palette entry with this color, ignoring its alpha.
+    fn palette_index_of(&self, red: u16, green: u16, blue: u16) -> Option<u8> {
+        self.palette
+            .iter()
+            .position(|entry| [entry[0], entry[1], entry[2]].map(u16::from) == [red, green, blue])
+            .map(|index| index as u8)
+    }
+
+    /// Converts the image to samples for the encoder's color type and bit depth, applying
+    /// any significant-bits masking. 16-bit samples are stored big-endian as PNG requires.
+    fn prepare_pixels(&self, image: &DynamicImage) -> Vec<u8> {
+        let image = &*self.map_float_samples(image);
+        if self.bit_depth == 16 {
+            let samples = match self.color_type {
+                0 => image.to_luma16().into_raw(),
+                2 => image.to_rgb16().into_raw(),
+                4 => image.to_luma_alpha16().into_raw(),
+                _ => image.to_rgba16().into_raw(),
+            };
+            return samples
+                .iter()
+                .flat_map(|sample| sample.to_be_bytes())
+                .collect();
+        }
+
+        if self.color_type == 3 {
+            let indices: BTreeMap<[u8; 4], u8> = self
+                .palette
+                .iter()
+                .enumerate()
+                .map(|(index, &entry)| (entry, index as u8))
+                .collect();
+            // The palette was built from this image, so every pixel has an entry
+            let samples = self
+                .masked_rgba(image)
+                .chunks_exact(4)
+                .map(|pixel| indices[&palette_entry(pixel)])
+                .collect();
+            return pack_samples(samples, self.width as usize, self.bit_depth);
+        }
+
+        let mut samples = match self.color_type {
+            0 => image.to_luma8().into_raw(),
+            2 => image.to_rgb8().into_raw(),
+            4 => image.to_luma_alpha8().into_raw(),
+            _ => image.to_rgba8().into_raw(),
+        };
+        if let Some(bits) = self.channel_significant_bits() {
+            mask_samples(&mut samples, &bits);
+        }
+        if self.bit_depth < 8 {
+            // configure_for_image only lowers the depth when every gray level is a
+            // multiple of the step, so this division is exact
+            let step = gray_level_step(self.bit_depth);
+            samples.iter_mut().for_each(|sample| *sample /= step);
+            return pack_samples(samples, self.width as usize, self.bit_depth);
+        }
+        samples
+    }
+
+    /// Fails if `image` is a float image with samples that [`FloatMapping::Strict`] would
+    /// have to clamp. NaN counts as out of range.
+    fn check_float_range(&self, image: &DynamicImage) -> Result<(), EncoderError> {
+        if self.options.float_mapping != FloatMapping::Strict {
+            return Ok(());
+        }
+        let samples = match image {
+            DynamicImage::ImageRgb32F(buffer) => buffer.as_raw(),
+            DynamicImage::ImageRgba32F(buffer) => buffer.as_raw(),
+            _ => return Ok(()),
+        };
+        match samples.iter().find(|sample| !(0.0..=1.0).contains(*sample)) {
+            Some(&sample) => Err(EncoderError::FloatOutOfRange(sample)),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies [`FloatMapping::Gamma`] to float sources. Clamping and scaling are left to
+    /// the `image` crate's conversions, which clamp float samples to 0.0-1.0.
+    fn map_float_samples<'a>(&self, image: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        let FloatMapping::Gamma(gamma) = self.options.float_mapping else {
+            return Cow::Borrowed(image);
+        };
+        let encode = |sample: &mut f32| *sample = sample.clamp(0.0, 1.0).powf(1.0 / gamma);
+        match image {
+            DynamicImage::ImageRgb32F(buffer) => {
+                let mut buffer = buffer.clone();
+                buffer.iter_mut().for_each(encode);
+                Cow::Owned(DynamicImage::ImageRgb32F(buffer))
+            }
+            DynamicImage::ImageRgba32F(buffer) => {
+                let mut buffer = buffer.clone();
+                for pixel in buffer.pixels_mut() {
+                    pixel.0[..3].iter_mut().for_each(encode);
+                }
+                Cow::Owned(DynamicImage::ImageRgba32F(buffer))
+            }
+            _ => Cow::Borrowed(image),
+        }
+    }
+
+    /// The palette entries of `image`, converted a band of rows at a time so a streaming
+    /// encode never holds an RGBA copy of the whole image.
+    fn image_palette(&self, image: &DynamicImage) -> Result<Vec<[u8; 4]>, EncoderError> {
+        let mut palette = Vec::new();
+        let mut seen = BTreeSet::new();
+        for band in image_bands(image) {
+            extend_palette(&mut palette, &mut seen, &self.masked_rgba(&band))?;
+        }
+        Ok(palette)
+    }
+
+    /// RGBA8 samples of `image` with `significant_bits` masking applied, which is what
+    /// palette entries are built from and matched against.
+    fn masked_rgba(&self, image: &DynamicImage) -> Vec<u8> {
+        let mut samples = self.map_float_samples(image).to_rgba8().into_raw();
+        if let Some(bits) = self.options.significant_bits {
+            mask_samples(&mut samples, &bits);
+        }
+        samples
+    }
+
+    /// Estimates whether filtered data would be incompressible by measuring the byte
+    /// entropy of a few Sub-filtered sample scanlines.
+    fn looks_incompressible(&self, img_data: &[u8]) -> bool {
+        let bytes_per_pixel = self.bytes_per_pixel();
+        let stride = self.stride().unwrap_or(0);
+        let sample_rows = std::cmp::min(self.height as usize, ENTROPY_SAMPLE_ROWS);
+        if sample_rows == 0 || stride == 0 {
+            return false;
+        }
+
+        let mut histogram = [0usize; 256];
+        for i in 0..sample_rows {
+            let y = i * self.height as usize / sample_rows;
+            let row = &img_data[y * stride..(y + 1) * stride];
+            for x in 0..stride {
+                let filtered = if x < bytes_per_pixel {
+                    row[x]
+                } else {
+                    row[x].wrapping_sub(row[x - bytes_per_pixel])
+                };
+                histogram[filtered as usize] += 1;
+            }
+        }
+
+        let total = (sample_rows * stride) as f64;
+        let entropy: f64 = histogram
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                -p * p.log2()
+            })
+            .sum();
+
+        entropy > INCOMPRESSIBLE_ENTROPY_BITS
+    }
+
+    /// Divides the color samples of whole pixels in `samples` by their alpha when the
+    /// source is premultiplied, rounding to nearest. Does nothing for straight alpha or
+    /// output without an alpha channel.
+    fn unpremultiply(&self, samples: &mut [u8]) {
+        let channels = match self.color_type {
+            4 => 2,
+            6 => 4,
+            _ => return,
+        };
+        if self.options.alpha_mode != AlphaMode::Premultiplied {
+            return;
+        }
+
+        if self.bit_depth == 16 {
+            for pixel in samples.chunks_exact_mut(channels * 2) {
+                let (color, alpha) = pixel.split_at_mut((channels - 1) * 2);
+                let alpha = u32::from(u16::from_be_bytes([alpha[0], alpha[1]]));
+                if alpha == 0 {
+                    continue;
+                }
+                for sample in color.chunks_exact_mut(2) {
+                    let value = u32::from(u16::from_be_bytes([sample[0], sample[1]]));
+                    let straight = ((value * 0xFFFF + alpha / 2) / alpha).min(0xFFFF);
+                    sample.copy_from_slice(&(straight as u16).to_be_bytes());
+                }
+            }
+            return;
+        }
+
+        for pixel in samples.chunks_exact_mut(channels) {
+            let (color, alpha) = pixel.split_at_mut(channels - 1);
+            let alpha = u32::from(alpha[0]);
+            if alpha == 0 {
+                continue;
+            }
+            for sample in color {
+                *sample = ((u32::from(*sample) * 255 + alpha / 2) / alpha).min(255) as u8;
+            }
+        }
+    }
+
+    /// Filters every scanline with `filter_type`, or with the best filter for each row
+    /// when it is `None`. When interlacing, the scanlines of each Adam7 pass are
+    /// filtered in turn. Premultiplied sources are converted to straight alpha first.
+    fn apply_filters(
+        &self,
+        img_data: &[u8],
+        filter_type: Option<FilterType>,
+    ) -> Result<Vec<u8>, EncoderError> {
+        let stride = self.stride().ok_or(EncoderError::ImageTooLarge)?;
+        let filtered_len = self.checked_filtered_len()?;
+        let image_len = stride
+            .checked_mul(self.height as usize)
+            .ok_or(EncoderError::ImageTooLarge)?;
+        if img_data.len() < image_len {
+            return Err(EncoderError::Truncated);
+        }
+        let straight;
+        let img_data = if self.options.alpha_mode == AlphaMode::Premultiplied {
+            straight = {
+                let mut samples = img_data[..image_len].to_vec();
+                self.unpremultiply(&mut samples);
+                samples
+            };
+            &straight
+        } else {
+            img_data
+        };
+        let mut filtered_data = Vec::with_capacity(filtered_len);
+
+        if !self.options.interlace {
+            let mut progress = ProgressTracker::new(self.options.progress.as_ref(), self.height);
+            self.filter_rows(
+                img_data,
+                stride,
+                self.height as usize,
+                filter_type,
+                &mut filtered_data,
+                &mut progress,
+            )?;
+            return Ok(filtered_data);
+        }
+
+        let total_rows = ADAM7_PASSES
+            .iter()
+            .map(|&pass| match self.pass_dimensions(pass) {
+                (0, _) => 0,
+                (_, height) => height as u32,
+            })
+            .sum();
+        let mut progress = ProgressTracker::new(self.options.progress.as_ref(), total_rows);
+        // Each pass is filtered as an image of its own; empty passes are skipped
+        for pass in ADAM7_PASSES {
+            let (width, height) = self.pass_dimensions(pass);
+            if width == 0 || height == 0 {
+                continue;
+            }
+            let pass_data = self.extract_pass(img_data, stride, pass);
+            let pass_stride = pass_data.len() / height;
+            self.filter_rows(
+                &pass_data,
+                pass_stride,
+                height,
+                filter_type,
+                &mut filtered_data,
+                &mut progress,
+            )?;
+        }
+
+        Ok(filtered_data)
+    }
+
+    /// Filters the first `rows` `stride`-byte scanlines of `img_data` into
+    /// `filtered_data`, each preceded by its filter type byte, and advances `progress`
+    /// past them. With the `parallel` feature, images of [`PARALLEL_MIN_ROWS`] rows or
+    /// more are spread across rayon's thread pool.
+    fn filter_rows(
+        &self,
+        img_data: &[u8],
+        stride: usize,
+        rows: usize,
+        filter_type: Option<FilterType>,
+        filtered_data: &mut Vec<u8>,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), EncoderError> {
+        #[cfg(feature = "parallel")]
+        if rows >= PARALLEL_MIN_ROWS {
+            return self.filter_rows_parallel(
+                img_data,
+                stride,
+                rows,
+                filter_type,
+                filtered_data,
+                progress,
+            );
+        }
+        self.filter_rows_sequential(img_data, stride, rows, filter_type, filtered_data, progress)
+    }
+
+    fn filter_rows_sequential(
+        &self,
+        img_data: &[u8],
+        stride: usize,
+        rows: usize,
+        filter_type: Option<FilterType>,
+        filtered_data: &mut Vec<u8>,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), EncoderError> {
+        let zero_row = vec![0u8; stride];
+
+        for y in 0..rows {
+            self.check_cancelled()?;
+
+            let row = &img_data[y * stride..(y + 1) * stride];
+            let previous = if y == 0 {
+                &zero_row[..]
+            } else {
+                &img_data[(y - 1) * stride..y * stride]
+            };
+            filter_scanline(
+                row,
+                previous,
+                self.bytes_per_pixel(),
+                filter_type,
+                filtered_data,
+            );
+            progress.advance(1);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Self::filter_rows_sequential`], but filters rows concurrently. Each row
+    /// only reads the raw bytes of the row above, so rows are independent and every
+    /// filtered row (filter byte plus `stride` bytes) lands in its own slot of the output.
+    /// Progress is reported in order after each band of [`PARALLEL_BAND_ROWS`] rows.
+    #[cfg(feature = "parallel")]
+    fn filter_rows_parallel(
+        &self,
+        img_data: &[u8],
+        stride: usize,
+        rows: usize,
+        filter_type: Option<FilterType>,
+        filtered_data: &mut Vec<u8>,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), EncoderError> {
+        use rayon::prelude::*;
+
+        let bytes_per_pixel = self.bytes_per_pixel();
+        let cancel = self.options.cancel.as_deref();
+        let zero_row = vec![0u8; stride];
+        let start = filtered_data.len();
+        filtered_data.resize(start + rows * (stride + 1), 0);
+
+        let band_size = PARALLEL_BAND_ROWS * (stride + 1);
+        for (band, band_output) in filtered_data[start..].chunks_mut(band_size).enumerate() {
+            let first_row = band * PARALLEL_BAND_ROWS;
+            band_output
+                .par_chunks_mut(stride + 1)
+                .enumerate()
+                .try_for_each_init(
+                    || Vec::with_capacity(stride + 1),
+                    |scratch, (offset, output)| {
+                        if cancel.is_some_and(|flag| flag.load(Ordering::Relaxed)) {
+                            return Err(EncoderError::Cancelled);
+                        }
+                        let y = first_row + offset;
+                        let row = &img_data[y * stride..(y + 1) * stride];
+                        let previous = if y == 0 {
+                            &zero_row[..]
+                        } else {
+                            &img_data[(y - 1) * stride..y * stride]
+                        };
+                        scratch.clear();
+                        filter_scanline(row, previous, bytes_per_pixel, filter_type, scratch);
+                        output.copy_from_slice(scratch);
+                        Ok(())
+                    },
+                )?;
+            progress.advance(band_output.len() / (stride + 1));
+        }
+
+        Ok(())
+    }
+
+    /// Copies the pixels of one Adam7 pass out of the full-image scanlines in `img_data`
+    /// into packed scanlines of the pass's own width.
+    fn extract_pass(
+        &self,
+        img_data: &[u8],
+        stride: usize,
+        pass: (usize, usize, usize, usize),
+    ) -> Vec<u8> {
+        let (x0, y0, dx, dy) = pass;
+        let (width, height) = self.pass_dimensions(pass);
+        let bits_per_pixel = self.channels() * self.bit_depth as usize;
+        // The full image fits in memory, so a narrower pass row does too
+        let pass_stride = self.stride_for(width).unwrap_or(0);
+        let mut pass_data = vec![0u8; pass_stride * height];
+
+        for (pass_row, y) in pass_data
+            .chunks_exact_mut(pass_stride)
+            .zip((y0..).step_by(dy))
+        {
+            let row = &img_data[y * stride..(y + 1) * stride];
+            for (pass_x, x) in (0..width).zip((x0..).step_by(dx)) {
+                if bits_per_pixel >= 8 {
+                    let size = bits_per_pixel / 8;
+                    pass_row[pass_x * size..(pass_x + 1) * size]
+                        .copy_from_slice(&row[x * size..(x + 1) * size]);
+                } else {
+                    let bit = x * bits_per_pixel;
+                    let sample = (row[bit / 8] << (bit % 8)) >> (8 - bits_per_pixel);
+                    let pass_bit = pass_x * bits_per_pixel;
+                    pass_row[pass_bit / 8] |= sample << (8 - bits_per_pixel - pass_bit % 8);
+                }
+            }
+        }
+        pass_data
+    }
+
+    fn compress_data(&self, data: &[u8]) -> Result<Vec<u8>, EncoderError> {
+        self.compress_data_with_effort(data, false)
+    }
+
+    /// Seeds the custom compressor's history with `dict`, so matches early in the data
+    /// can refer back into it, and records its Adler-32 (DICTID) in the zlib header.
+    /// Only the last 32 KiB of `dict` are reachable. Decompressing needs the same
+    /// dictionary set. PNG forbids preset dictionaries, so this is for standalone zlib
+    /// streams only (see [`compress_zlib_with_dictionary`]) and is never set while
+    /// writing a PNG.
+    fn set_dictionary(&mut self, dict: &[u8]) {
+        self.dictionary = dict[dict.len().saturating_sub(WINDOW_SIZE)..].to_vec();
+    }
+
+    /// Compresses `data`; with `low_effort` set, stores the data uncompressed instead for
+    /// content that won't compress anyway.
+    fn compress_data_with_effort(
+        &self,
+        data: &[u8],
+        low_effort: bool,
+    ) -> Result<Vec<u8>, EncoderError> {
+        match self.compression_method {
+            CompressionMethod::Custom => {
+                let mut compressed = Vec::new();
+
+                // Zlib header (2 bytes): deflate with a 32k window, followed by the
+                // dictionary's checksum when there is one
+                if self.dictionary.is_empty() {
+                    compressed.extend_from_slice(&CUSTOM_ZLIB_HEADER);
+                } else {
+                    compressed.extend_from_slice(&DICTIONARY_ZLIB_HEADER);
+                    compressed.extend_from_slice(&self.adler32(&self.dictionary).to_be_bytes());
+                }
+
+                let deflate_data = if low_effort {
+                    self.literal_deflate(data)
+                } else {
+                    self.simple_deflate(data)?
+                };
+                compressed.extend_from_slice(&deflate_data);
+
+                // Adler32 checksum (4 bytes, big-endian)
+                let checksum = self.adler32(data);
+                compressed.extend_from_slice(&checksum.to_be_bytes());
+
+                Ok(compressed)
+            }
+            CompressionMethod::Stored => {
+                let mut compressed = Vec::with_capacity(stored_zlib_len(data.len()));
+                // FLEVEL 0 marks the fastest (here: no) compression
+                compressed.extend_from_slice(&STORED_ZLIB_HEADER);
+                compressed.extend_from_slice(&self.literal_deflate(data));
+                compressed.extend_from_slice(&self.adler32(data).to_be_bytes());
+                Ok(compressed)
+            }
+            CompressionMethod::Flate2 => {
+                let level = if low_effort {
+                    Compression::none()
+                } else {
+                    self.flate2_level()
+                };
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                for block in data.chunks(CANCEL_CHECK_INTERVAL) {
+                    self.check_cancelled()?;
+                    encoder.write_all(block)?;
+                }
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    fn flate2_level(&self) -> Compression {
+        self.options
+            .compression_level
+            .map_or(Compression::default(), |level| {
+                Compression::new(level as u32)
+            })
+    }
+
+    /// Stores `data` in uncompressed blocks without searching for matches.
+    fn literal_deflate(&self, data: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        write_stored_blocks(&mut writer, data, true);
+        writer.finish()
+    }
+
+    fn simple_deflate(&self, data: &[u8]) -> Result<Vec<u8>, EncoderError> {
+        let mut writer = BitWriter::new();
+        if self.dictionary.is_empty() {
+            self.deflate_from(data, 0, &mut writer, true)?;
+        } else {
+            let history = [self.dictionary.as_slice(), data].concat();
+            self.deflate_from(&history, self.dictionary.len(), &mut writer, true)?;
+        }
+        Ok(writer.finish())
+    }
+
+    /// Writes `data[start..]` to `writer` as DEFLATE blocks, marking the last one final if
+    /// `last` is set. Bytes before `start` were already encoded and only serve as history
+    /// that matches may refer back to.
+    fn deflate_from(
+        &self,
+        data: &[u8],
+        start: usize,
+        writer: &mut BitWriter,
+        last: bool,
+    ) -> Result<(), EncoderError> {
+        let mut block = Vec::with_capacity(BLOCK_TOKENS);
+        let mut block_start = start;
+        let mut block_end = start;
+        self.tokenize(data, start, |token| {
+            block.push(token);
+            block_end += token.len();
+            if block.len() == BLOCK_TOKENS {
+                write_block(writer, &block, &data[block_start..block_end], false);
+                block.clear();
+                block_start = block_end;
+            }
+        })?;
+        write_block(writer, &block, &data[block_start..block_end], last);
+        Ok(())
+    }
+
+    /// Runs LZ77 over `data[start..]`, passing each literal or back-reference to `emit`.
+    fn tokenize(
+        &self,
+        data: &[u8],
+        start: usize,
+        mut emit: impl FnMut(Token),
+    ) -> Result<(), EncoderError> {
+        let mut chain = HashChain::new(data.len());
+        let mut indexed = 0;
+        let mut i = start;
+        let mut next_cancel_check = start;
+        // Match already found for `i` while looking ahead from the previous position
+        let mut pending = None;
+
+        while i < data.len() {
+            if i >= next_cancel_check {
+                self.check_cancelled()?;
+                next_cancel_check = i + CANCEL_CHECK_INTERVAL;
+            }
+
+            // Index every position passed so far, including those inside emitted matches
+            while indexed < i {
+                chain.insert(data, indexed);
+                indexed += 1;
+            }
+
+            let (match_distance, match_length) = pending
+                .take()
+                .unwrap_or_else(|| self.find_longest_match(data, i, &chain));
+
+            let match_length = std::cmp::min(match_length, MAX_MATCH_LENGTH);
+
+            if match_distance > 0
+                && match_distance <= WINDOW_SIZE
+                && match_length >= MIN_MATCH_LENGTH
+                && self.is_match_worthwhile(&data[i..i + match_length], match_distance)
+            {
+                if self.lazy_matching && match_length < MAX_LAZY_LENGTH && i + 1 < data.len() {
+                    chain.insert(data, i);
+                    indexed = i + 1;
+                    let next = self.find_longest_match(data, i + 1, &chain);
+                    if next.1 > match_length {
+                        emit(Token::Literal(data[i]));
+                        i += 1;
+                        pending = Some(next);
+                        continue;
+                    }
+                }
+
+                emit(Token::Match {
+                    length: match_length as u16,
+                    distance: match_distance as u16,
+                });
+                i += match_length;
+            } else {
+                emit(Token::Literal(data[i]));
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decides whether a back-reference covering `covered` beats emitting it as literals,
+    /// comparing their sizes in fixed-Huffman bits.
+    fn is_match_worthwhile(&self, covered: &[u8], distance: usize) -> bool {
+        if !self.use_cost_model {
+            return covered.len() >= 4;
+        }
+
+        let literal_cost: usize = covered
+            .iter()
+            .map(|&byte| fixed_code(byte as u16).1 as usize)
+            .sum();
+
+        let (length_symbol, length_extra, _) = length_code(covered.len());
+        let (_, distance_extra, _) = distance_code(distance);
+        let match_cost = (fixed_code(length_symbol).1 + length_extra + 5 + distance_extra) as usize;
+
+        match_cost < literal_cost
+    }
+
+    /// Returns the (distance, length) of the longest match for `pos` among the earlier
+    /// positions in `chain`, or a length of zero if there is none.
+    fn find_longest_match(&self, data: &[u8], pos: usize, chain: &HashChain) -> (usize, usize) {
+        let mut best_distance = 0;
+        let mut best_length = 0;
+        let max_length = std::cmp::min(MAX_MATCH_LENGTH, data.len() - pos);
+
+        let mut candidate = chain.first_candidate(data, pos);
+        let mut visited = 0;
+        while let Some(start) = candidate
+            && visited < MAX_CHAIN_LENGTH
+        {
+            let distance = pos - start;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+
+            if distance >= self.min_match_distance {
+                // Overlapping matches compare against bytes inside the match itself,
+                // which is exactly what the decoder's copy reproduces
+                let mut length = 0;
+                while length < max_length && data[start + length] == data[pos + length] {
+                    length += 1;
+                }
+
+                if length > best_length {
+                    best_length = length;
+                    best_distance = distance;
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            candidate = chain.next_candidate(start);
+            visited += 1;
+        }
+
+        (best_distance, best_length)
+    }
+
+    fn adler32(&self, data: &[u8]) -> u32 {
+        adler32_update(1, data)
+    }
+
+    #[cfg(test)]
+    fn decompress_data(&self, compressed: &[u8]) -> Result<Vec<u8>, EncoderError> {
+        self.decompress_limited(compressed, usize::MAX)
+    }
+
+    /// Decompresses a zlib stream written by the custom method, failing with
+    /// [`EncoderError::OutputTooLarge`] instead of producing more than `limit` bytes.
+    fn decompress_limited(&self, compressed: &[u8], limit: usize) -> Result<Vec<u8>, EncoderError> {
+        if compressed.len() < 6 {
+            return Err(EncoderError::Truncated);
+        }
+
+        let (header_len, history) = if compressed[..2] == CUSTOM_ZLIB_HEADER {
+            (2, &[][..])
+        } else if compressed[..2] == DICTIONARY_ZLIB_HEADER {
+            if compressed.len() < 10 {
+                return Err(EncoderError::Truncated);
+            }
+            let id =
+                u32::from_be_bytes([compressed[2], compressed[3], compressed[4], compressed[5]]);
+            if self.dictionary.is_empty() || self.adler32(&self.dictionary) != id {
+                return Err(EncoderError::MissingDictionary(id));
+            }
+            (6, self.dictionary.as_slice())
+        } else {
+            return Err(EncoderError::InvalidZlibHeader);
+        };
+
+        // Extract deflate data (skip the header, 4-byte adler32 checksum)
+        let deflate_data = &compressed[header_len..compressed.len() - 4];
+
+        let decompressed = self.inflate_limited(deflate_data, history, limit)?;
+
+        let expected_checksum = u32::from_be_bytes([
+            compressed[compressed.len() - 4],
+            compressed[compressed.len() - 3],
+            compressed[compressed.len() - 2],
+            compressed[compressed.len() - 1],
+        ]);
+
+        let actual_checksum = self.adler32(&decompressed);
+        if actual_checksum != expected_checksum {
+            return Err(EncoderError::ChecksumMismatch {
+                expected: expected_checksum,
+                actual: actual_checksum,
+            });
+        }
+
+        Ok(decompressed)
+    }
+
+    #[cfg(test)]
+    fn simple_inflate(&self, data: &[u8]) -> Result<Vec<u8>, EncoderError> {
+        self.inflate_limited(data, &[], usize::MAX)
+    }
+
+    /// Inflates stored and fixed-Huffman blocks, failing as soon as the output would
+    /// exceed `limit` bytes so corrupt or hostile data can't force a huge allocation.
+    /// Back-references may reach into `history`, which is not part of the output.
+    fn inflate_limited(
+        &self,
+        data: &[u8],
+        history: &[u8],
+        limit: usize,
+    ) -> Result<Vec<u8>, EncoderError> {
+        // The shortest fixed-Huffman back-reference takes 13 bits for MAX_MATCH_LENGTH
+        // bytes, which bounds the output even when the image dimensions suggest more.
+        let filtered_len = self.filtered_len();
+        let max_expansion = data.len().saturating_mul(MAX_MATCH_LENGTH * 8 / 13 + 1);
+        let mut result = Vec::with_capacity(
+            filtered_len
+                .map_or(max_expansion, |len| len.min(max_expansion))
+                .min(limit)
+                .saturating_add(history.len()),
+        );
+        result.extend_from_slice(history);
+        let output_limit = limit.saturating_add(history.len());
+        let mut reader = BitReader::new(data);
+
+        loop {
+            let is_final = reader.read_bits(1)? == 1;
+            match reader.read_bits(2)? {
+                0 => inflate_stored_block(&mut reader, &mut result, output_limit)?,
+                1 => inflate_fixed_block(&mut reader, &mut result, output_limit)?,
+                block_type => return Err(EncoderError::UnsupportedBlockType(block_type as u8)),
+            }
+            if is_final {
+                break;
+            }
+        }
+
+        result.drain(..history.len());
+        Ok(result)
+    }
+
+    #[cfg(test)]
+    fn test_compression(&self, data: &[u8]) -> bool {
+        match self.compress_data(data) {
+            Ok(compressed) => match self.decompress_data(&compressed) {
+                Ok(decompressed) => decompressed == data,
+                Err(_) => false,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Continues an Adler-32 checksum over `data`; start from 1 for a fresh checksum.
+fn adler32_update(checksum: u32, data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    // Largest n such that 255 * n * (n + 1) / 2 + (n + 1) * (MOD_ADLER - 1) fits in a u32,
+    // so the sums can go that many bytes without being reduced (zlib's NMAX)
+    const NMAX: usize = 5552;
+    let mut a = checksum & 0xFFFF;
+    let mut b = checksum >> 16;
+
+    for block in data.chunks(NMAX) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+
+    (b << 16) | a
+}
+
+/// One LZ77 step: a byte to copy through or a run to repeat from earlier output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+impl Token {
+    /// Number of input bytes the token stands for.
+    fn len(self) -> usize {
+        match self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => length as usize,
+        }
+    }
+}
+
+/// Packs DEFLATE's variable-length fields into bytes, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which unlike the other fields is packed most significant
+    /// bit first.
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Pads with zero bits up to the next byte boundary, as stored blocks require.
+    fn align_to_byte(&mut self) {
+        if self.count > 0 {
+            self.write_bits(0, 8 - self.count);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.count, 0);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Removes and returns the completed bytes, keeping any partial byte for later.
+    fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    /// Pads the last partial byte with zero bits and returns the remaining bytes.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Reads DEFLATE's fields back out of a byte slice, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position of the next bit to read.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, EncoderError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.position / 8)
+                .ok_or(EncoderError::Truncated)?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary and returns the `len` bytes starting there.
+    fn read_aligned_bytes(&mut self, len: usize) -> Result<&'a [u8], EncoderError> {
+        let start = self.position.div_ceil(8);
+        let bytes = self
+            .data
+            .get(start..start + len)
+            .ok_or(EncoderError::Truncated)?;
+        self.position = (start + len) * 8;
+        Ok(bytes)
+    }
+
+    /// Extends a Huffman code read so far by its next bit.
+    fn read_code_bit(&mut self, code: u32) -> Result<u32, EncoderError> {
+        Ok((code << 1) | self.read_bits(1)?)
+    }
+}
+
+/// The fixed-Huffman code (RFC 1951, section 3.2.6) and its length in bits for a symbol
+/// of the literal/length alphabet.
+fn fixed_code(symbol: u16) -> (u32, u32) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    }
+}
+
+/// The length code for a match length of 3..=258, with its extra bit count and value.
+fn length_code(length: usize) -> (u16, u32, u32) {
+    let index = LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1;
+    (
+        257 + index as u16,
+        LENGTH_EXTRA_BITS[index] as u32,
+        (length - LENGTH_BASE[index] as usize) as u32,
+    )
+}
+
+/// The distance code for a distance of 1..=32768, with its extra bit count and value.
+fn distance_code(distance: usize) -> (u32, u32, u32) {
+    let index = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+    (
+        index as u32,
+        DISTANCE_EXTRA_BITS[index] as u32,
+        (distance - DISTANCE_BASE[index] as usize) as u32,
+    )
+}
+
+/// Writes the tokens covering `raw` as one fixed-Huffman block, or stores `raw` instead
+/// when that is smaller, as it is for data the matcher found nothing in.
+fn write_block(writer: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let fixed_bits: usize = tokens
+        .iter()
+        .map(|&token| match token {
+            Token::Literal(byte) => fixed_code(byte as u16).1 as usize,
+            Token::Match { length, distance } => {
+                let (symbol, length_extra, _) = length_code(length as usize);
+                let (_, distance_extra, _) = distance_code(distance as usize);
+                (fixed_code(symbol).1 + length_extra + 5 + distance_extra) as usize
+            }
+        })
+        .sum();
+    // Each stored block adds a 32-bit length header after padding to a byte boundary
+    let stored_bits = raw.len() * 8 + raw.len().div_ceil(MAX_STORED_BLOCK).max(1) * (32 + 7);
+
+    if stored_bits < fixed_bits {
+        write_stored_blocks(writer, raw, last);
+    } else {
+        write_fixed_block(writer, tokens, last);
+    }
+}
+
+/// Builds a zlib header from `cmf`, the 2-bit compression level `flevel` and whether a
+/// preset dictionary follows (FDICT), with FCHECK set so the header is a multiple of 31.
+const fn zlib_header(cmf: u8, flevel: u8, dictionary: bool) -> [u8; 2] {
+    let flg = (flevel & 0b11) << 6 | (dictionary as u8) << 5;
+    let remainder = ((cmf as u16) << 8 | flg as u16) % 31;
+    let fcheck = ((31 - remainder) % 31) as u8;
+    [cmf, flg | fcheck]
+}
+
+/// Size of `len` bytes wrapped by [`CompressionMethod::Stored`]: zlib header, a 5-byte
+/// header per stored block, the data and the Adler-32 checksum.
+fn stored_zlib_len(len: usize) -> usize {
+    2 + len.div_ceil(MAX_STORED_BLOCK).max(1) * 5 + len + 4
+}
+
+/// Writes `data` uncompressed (BTYPE=00), split into as many blocks as it needs.
+fn write_stored_blocks(writer: &mut BitWriter, data: &[u8], last: bool) {
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // An empty final block still has to be written
+        write_stored_block(writer, &[], last);
+    }
+    while let Some(block) = blocks.next() {
+        write_stored_block(writer, block, last && blocks.peek().is_none());
+    }
+}
+
+fn write_stored_block(writer: &mut BitWriter, block: &[u8], last: bool) {
+    writer.write_bits(u32::from(last), 1);
+    writer.write_bits(0, 2);
+    writer.align_to_byte();
+    let len = block.len() as u16;
+    writer.write_bits(len as u32, 16);
+    writer.write_bits(!len as u32, 16);
+    writer.write_bytes(block);
+}
+
+/// Writes `tokens` as one block compressed with the fixed Huffman codes (BTYPE=01).
+fn write_fixed_block(writer: &mut BitWriter, tokens: &[Token], last: bool) {
+    writer.write_bits(u32::from(last), 1);
+    writer.write_bits(1, 2);
+
+    for &token in tokens {
+        match token {
+            Token::Literal(byte) => {
+                let (code, length) = fixed_code(byte as u16);
+                writer.write_code(code, length);
+            }
+            Token::Match { length, distance } => {
+                let (symbol, extra_bits, extra) = length_code(length as usize);
+                let (code, code_length) = fixed_code(symbol);
+                writer.write_code(code, code_length);
+                writer.write_bits(extra, extra_bits);
+
+                // Fixed distance codes are plain 5-bit numbers
+                let (code, extra_bits, extra) = distance_code(distance as usize);
+                writer.write_code(code, 5);
+                writer.write_bits(extra, extra_bits);
+            }
+        }
+    }
+
+    let (code, length) = fixed_code(END_OF_BLOCK);
+    writer.write_code(code, length);
+}
+
+/// Reads one literal/length symbol coded with the fixed Huffman codes.
+fn read_fixed_symbol(reader: &mut BitReader) -> Result<u16, EncoderError> {
+    let mut code = 0;
+    for _ in 0..7 {
+        code = reader.read_code_bit(code)?;
+    }
+    if code <= 0x17 {
+        return Ok(256 + code as u16);
+    }
+
+    code = reader.read_code_bit(code)?;
+    match code {
+        0x30..=0xBF => Ok((code - 0x30) as u16),
+        0xC0..=0xC7 => Ok((280 + code - 0xC0) as u16),
+        _ => Ok((144 + reader.read_code_bit(code)? - 0x190) as u16),
+    }
+}
+
+/// Fails unless `output` has room for `additional` more bytes under `limit`.
+fn check_output_room(output: &[u8], additional: usize, limit: usize) -> Result<(), EncoderError> {
+    if additional > limit.saturating_sub(output.len()) {
+        return Err(EncoderError::OutputTooLarge(limit));
+    }
+    Ok(())
+}
+
+/// Copies one stored block's bytes to `output`.
+fn inflate_stored_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), EncoderError> {
+    let header = reader.read_aligned_bytes(4)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if len != !complement {
+        return Err(EncoderError::StoredLengthMismatch);
+    }
+    let bytes = reader.read_aligned_bytes(len as usize)?;
+    check_output_room(output, bytes.len(), limit)?;
+    output.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Decodes one fixed-Huffman block, appending its bytes to `output`.
+fn inflate_fixed_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), EncoderError> {
+    loop {
+        let symbol = read_fixed_symbol(reader)?;
+        if symbol < END_OF_BLOCK {
+            check_output_room(output, 1, limit)?;
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        // Symbols 286 and 287 take part in the code but never appear in valid data
+        let index = (symbol - 257) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(EncoderError::InvalidBackReference);
+        }
+        let length = LENGTH_BASE[index] as usize
+            + reader.read_bits(LENGTH_EXTRA_BITS[index] as u32)? as usize;
+
+        let mut code = 0;
+        for _ in 0..5 {
+            code = reader.read_code_bit(code)?;
+        }
+        let index = code as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(EncoderError::InvalidBackReference);
+        }
+        let distance = DISTANCE_BASE[index] as usize
+            + reader.read_bits(DISTANCE_EXTRA_BITS[index] as u32)? as usize;
+
+        let start_pos = output
+            .len()
+            .checked_sub(distance)
+            .ok_or(EncoderError::InvalidBackReference)?;
+        check_output_room(output, length, limit)?;
+
+        // Copy from back-reference. When the match overlaps its own output the source
+        // window repeats every `distance` bytes, so copy a period at a time.
+        let mut remaining = length;
+        while remaining > 0 {
+            let run = remaining.min(distance);
+            output.extend_from_within(start_pos..start_pos + run);
+            remaining -= run;
+        }
+    }
+}
+
+/// Splits everything written to it into IDAT chunks of the encoder's maximum size.
+struct IdatWriter<'a, W: Write> {
+    encoder: &'a PngEncoder,
+    writer: W,
+    buffer: Vec<u8>,
+    /// Payload bytes written so far, across all chunks.
+    written: usize,
+}
+
+impl<'a, W: Write> IdatWriter<'a, W> {
+    fn new(encoder: &'a PngEncoder, writer: W) -> Self {
+        IdatWriter {
+            encoder,
+            writer,
+            buffer: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Writes the last, possibly short, chunk and returns the total payload size.
+    fn finish(mut self) -> std::io::Result<usize> {
+        if !self.buffer.is_empty() || self.written == 0 {
+            self.encoder
+                .write_chunk(&mut self.writer, b"IDAT", &self.buffer)?;
+            self.written += self.buffer.len();
+        }
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> Write for IdatWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let max = self.encoder.max_idat_chunk_size();
+        let mut full = 0;
+        while self.buffer.len() - full >= max {
+            self.encoder
+                .write_chunk(&mut self.writer, b"IDAT", &self.buffer[full..full + max])?;
+            full += max;
+        }
+        self.buffer.drain(..full);
+        self.written += full;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Incremental form of the custom compressor. Input is compressed a segment at a time,
+/// keeping the previous `WINDOW_SIZE` bytes so matches can still reach back into them.
+struct CustomStream<'a, W: Write> {
+    encoder: &'a PngEncoder,
+    output: IdatWriter<'a, W>,
+    bits: BitWriter,
+    /// Already-compressed history followed by input still waiting to be compressed.
+    window: Vec<u8>,
+    history_len: usize,
+    checksum: u32,
+}
+
+impl<'a, W: Write> CustomStream<'a, W> {
+    fn new(encoder: &'a PngEncoder, mut output: IdatWriter<'a, W>) -> std::io::Result<Self> {
+        // Same zlib header as compress_data
+        output.write_all(match encoder.compression_method {
+            CompressionMethod::Stored => &STORED_ZLIB_HEADER,
+            _ => &CUSTOM_ZLIB_HEADER,
+        })?;
+        Ok(CustomStream {
+            encoder,
+            output,
+            bits: BitWriter::new(),
+            window: Vec::new(),
+            history_len: 0,
+            checksum: 1,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EncoderError> {
+        self.checksum = adler32_update(self.checksum, data);
+        self.window.extend_from_slice(data);
+        if self.window.len() - self.history_len >= self.encoder.stream_segment_size() {
+            self.compress_pending(false)?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the pending input into blocks, the last of them final if `last` is set.
+    fn compress_pending(&mut self, last: bool) -> Result<(), EncoderError> {
+        if let CompressionMethod::Stored = self.encoder.compression_method {
+            write_stored_blocks(&mut self.bits, &self.window[self.history_len..], last);
+        } else {
+            self.encoder
+                .deflate_from(&self.window, self.history_len, &mut self.bits, last)?;
+        }
+        self.output.write_all(&self.bits.take_bytes())?;
+
+        let keep = self.window.len().min(WINDOW_SIZE);
+        self.window.drain(..self.window.len() - keep);
+        self.history_len = keep;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<usize, EncoderError> {
+        self.compress_pending(true)?;
+        self.output.write_all(&self.bits.finish())?;
+        self.output.write_all(&self.checksum.to_be_bytes())?;
+        Ok(self.output.finish()?)
+    }
+}
+
+/// The compressor a streaming encode feeds filtered rows into.
+enum StreamCompressor<'a, W: Write> {
+    Custom(CustomStream<'a, W>),
+    Flate2(ZlibEncoder<IdatWriter<'a, W>>),
+}
+
+impl<W: Write> StreamCompressor<'_, W> {
+    fn write(&mut self, data: &[u8]) -> Result<(), EncoderError> {
+        match self {
+            StreamCompressor::Custom(stream) => stream.write(data),
+            StreamCompressor::Flate2(encoder) => Ok(encoder.write_all(data)?),
+        }
+    }
+
+    /// Flushes the remaining compressed data and returns the total IDAT payload size.
+    fn finish(self) -> Result<usize, EncoderError> {
+        match self {
+            StreamCompressor::Custom(stream) => stream.finish(),
+            StreamCompressor::Flate2(encoder) => Ok(encoder.finish()?.finish()?),
+        }
+    }
+}
+
+/// Zeroes the bits below each channel's significant-bit count in interleaved 8-bit
+/// `samples`, where `bits` holds one count per channel.
+fn mask_samples(samples: &mut [u8], bits: &[u8]) {
+    for pixel in samples.chunks_exact_mut(bits.len()) {
+        for (sample, &channel_bits) in pixel.iter_mut().zip(bits.iter()) {
+            *sample &= 0xFFu8 << (8 - channel_bits);
+        }
+    }
+}
+
+/// Difference between adjacent 8-bit gray levels that a `bit_depth`-bit sample can
+/// represent, e.g. 85 for 2 bits (0, 85, 170, 255).
+fn gray_level_step(bit_depth: u8) -> u8 {
+    255 / ((1u8 << bit_depth) - 1)
+}
+
+/// Whether every pixel of `image` has the maximum alpha value of its sample type. Images
+/// without an alpha channel are not considered.
+fn is_fully_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageLumaA8(buffer) => buffer.pixels().all(|pixel| pixel[1] == u8::MAX),
+        DynamicImage::ImageRgba8(buffer) => buffer.pixels().all(|pixel| pixel[3] == u8::MAX),
+        DynamicImage::ImageLumaA16(buffer) => buffer.pixels().all(|pixel| pixel[1] == u16::MAX),
+        DynamicImage::ImageRgba16(buffer) => buffer.pixels().all(|pixel| pixel[3] == u16::MAX),
+        DynamicImage::ImageRgba32F(buffer) => buffer.pixels().all(|pixel| pixel[3] >= 1.0),
+        _ => false,
+    }
+}
+
+/// Splits `image` into bands of [`STREAM_BAND_ROWS`] rows, for scanning it without a
+/// full-size conversion.
+fn image_bands(image: &DynamicImage) -> impl Iterator<Item = DynamicImage> + '_ {
+    (0..image.height())
+        .step_by(STREAM_BAND_ROWS as usize)
+        .map(|y| {
+            image.crop_imm(
+                0,
+                y,
+                image.width(),
+                STREAM_BAND_ROWS.min(image.height() - y),
+            )
+        })
+}
+
+/// The distinct 8-bit gray levels of `image`, in ascending order.
+fn gray_levels(image: &DynamicImage) -> Vec<u8> {
+    let mut seen = [false; 256];
+    for band in image_bands(image) {
+        for &level in band.to_luma8().as_raw() {
+            seen[level as usize] = true;
+        }
+    }
+    (0..=u8::MAX)
+        .filter(|&level| seen[level as usize])
+        .collect()
+}
+
+/// The smallest bit depth that represents every 8-bit gray level in `samples` exactly.
+fn lowest_gray_bit_depth(samples: &[u8]) -> u8 {
+    [1, 2, 4]
+        .into_iter()
+        .find(|&bit_depth| {
+            let step = gray_level_step(bit_depth);
+            samples.iter().all(|&sample| sample % step == 0)
+        })
+        .unwrap_or(8)
+}
+
+/// Packs one sample per byte into `bit_depth`-bit samples, most significant bits first.
+/// Each row of `width` samples starts on a fresh byte, with any unused low bits of its
+/// last byte left zero.
+fn pack_samples(samples: Vec<u8>, width: usize, bit_depth: u8) -> Vec<u8> {
+    if bit_depth == 8 || width == 0 {
+        return samples;
+    }
+
+    let per_byte = 8 / bit_depth as usize;
+    let mut packed = Vec::with_capacity(samples.len() / width * width.div_ceil(per_byte));
+    for row in samples.chunks_exact(width) {
+        for group in row.chunks(per_byte) {
+            let byte = group.iter().enumerate().fold(0u8, |byte, (i, &sample)| {
+                byte | sample << (8 - bit_depth as usize * (i + 1))
+            });
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+/// Collects the distinct colors of `image` in first-seen order, as RGBA entries for an
+/// indexed-color PNG.
+pub fn build_palette(image: &DynamicImage) -> Result<Vec<[u8; 4]>, EncoderError> {
+    palette_from_rgba(image.to_rgba8().as_raw())
+}
+
+/// The palette entry for an RGBA pixel. Fully transparent pixels all share one entry,
+/// since their color is never seen.
+fn palette_entry(pixel: &[u8]) -> [u8; 4] {
+    match pixel {
+        [_, _, _, 0] => [0, 0, 0, 0],
+        _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+    }
+}
+
+fn palette_from_rgba(samples: &[u8]) -> Result<Vec<[u8; 4]>, EncoderError> {
+    let mut palette = Vec::new();
+    extend_palette(&mut palette, &mut BTreeSet::new(), samples)?;
+    Ok(palette)
+}
+
+/// Appends the entries of the RGBA `samples` not yet in `seen` to `palette`.
+fn extend_palette(
+    palette: &mut Vec<[u8; 4]>,
+    seen: &mut BTreeSet<[u8; 4]>,
+    samples: &[u8],
+) -> Result<(), EncoderError> {
+    for pixel in samples.chunks_exact(4) {
+        let entry = palette_entry(pixel);
+        if seen.insert(entry) {
+            if palette.len() == 256 {
+                return Err(EncoderError::TooManyColors);
+            }
+            palette.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses a zlib stream written by the custom compression method, such as a zTXt
+/// payload from an encoder using [`CompressionMethod::Custom`].
+pub(crate) fn decompress_custom(compressed: &[u8], limit: usize) -> Result<Vec<u8>, EncoderError> {
+    PngEncoder::new(0, 0, CompressionMethod::Custom).decompress_limited(compressed, limit)
+}
+
+/// Compresses `data` into a standalone zlib stream with the custom method, using
+/// `dictionary` as a preset dictionary: matches may refer back into its last 32 KiB,
+/// which shrinks short inputs that resemble it. An empty dictionary gives a plain stream.
+///
+/// PNG does not allow preset dictionaries, so this is for zlib payloads outside PNG
+/// files. Read the stream back with [`decompress_zlib_with_dictionary`].
+pub fn compress_zlib_with_dictionary(
+    data: &[u8],
+    dictionary: &[u8],
+) -> Result<Vec<u8>, EncoderError> {
+    let mut encoder = PngEncoder::new(0, 0, CompressionMethod::Custom);
+    encoder.set_dictionary(dictionary);
+    encoder.compress_data(data)
+}
+
+/// Decompresses a zlib stream written by [`compress_zlib_with_dictionary`], or any other
+/// custom-method stream. A stream compressed against a different dictionary fails with
+/// [`EncoderError::MissingDictionary`].
+pub fn decompress_zlib_with_dictionary(
+    compressed: &[u8],
+    dictionary: &[u8],
+) -> Result<Vec<u8>, EncoderError> {
+    let mut encoder = PngEncoder::new(0, 0, CompressionMethod::Custom);
+    encoder.set_dictionary(dictionary);
+    encoder.decompress_limited(compressed, usize::MAX)
+}
+
+/// Configures and runs a PNG encode.
+///
+/// Unset choices fall back to the same defaults as the free functions: custom
+/// compression, a color type picked from the image's channels, and Sub filtering.
+///
+/// Encoding is deterministic: the same image and settings give byte-identical output on
+/// every run, with or without the `parallel` feature, so PNGs can be cached by content.
+/// Only [`EncodeOptions::modification_time`] records anything time-dependent.
+///
+/// ```
+/// use rpng::{CompressionMethod, PngEncoderBuilder};
+/// use rpng::encoder::{FilterStrategy, TextChunk};
+///
+/// let image = image::DynamicImage::new_rgb8(32, 16);
+/// let mut png = Vec::new();
+/// PngEncoderBuilder::new()
+///     .compression(CompressionMethod::Flate2)
+///     .color_type(2)
+///     .filter_strategy(FilterStrategy::Adaptive)
+///     .interlace(true)
+///     .add_text(TextChunk::Latin1 {
+///         keyword: "Software".to_string(),
+///         text: "rpng".to_string(),
+///     })
+///     .encode(&image, &mut png)?;
+///
+/// assert_eq!(image::load_from_memory(&png)?.to_rgb8(), image.to_rgb8());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PngEncoderBuilder {
+    compression: CompressionMethod,
+    color_type: Option<u8>,
+    options: EncodeOptions,
+}
+
+impl PngEncoderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compression(mut self, compression: CompressionMethod) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Writes this PNG color type (0, 2, 3, 4 or 6) instead of choosing one from the
+    /// image. Type 3 (indexed) fails to encode if the image has more than 256 colors.
+    pub fn color_type(mut self, color_type: u8) -> Self {
+        self.color_type = Some(color_type);
+        self
+    }
+
+    pub fn filter_strategy(mut self, filter_strategy: FilterStrategy) -> Self {
+        self.options.filter_strategy = filter_strategy;
+        self
+    }
+
+    pub fn alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.options.alpha_mode = alpha_mode;
+        self
+    }
+
+    pub fn float_mapping(mut self, float_mapping: FloatMapping) -> Self {
+        self.options.float_mapping = float_mapping;
+        self
+    }
+
+    pub fn interlace(mut self, interlace: bool) -> Self {
+        self.options.interlace = interlace;
+        self
+    }
+
+    /// Caps the encoder's buffers at `bytes`; see [`EncodeOptions::max_memory`].
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.options.max_memory = Some(bytes);
+        self
+    }
+
+    /// Sets the flate2 compression level (0-9); see [`EncodeOptions::compression_level`].
+    pub fn compression_level(mut self, level: u8) -> Self {
+        self.options.compression_level = Some(level);
+        self
+    }
+
+    /// See [`EncodeOptions::min_match_distance`].
+    pub fn min_match_distance(mut self, distance: usize) -> Self {
+        self.options.min_match_distance = Some(distance);
+        self
+    }
+
+    /// See [`EncodeOptions::fixed_match_threshold`].
+    pub fn fixed_match_threshold(mut self, fixed: bool) -> Self {
+        self.options.fixed_match_threshold = fixed;
+        self
+    }
+
+    /// Reports filtering progress; see [`EncodeOptions::on_progress`].
+    pub fn on_progress(mut self, callback: impl FnMut(u32, u32) + Send + 'static) -> Self {
+        self.options.on_progress(callback);
+        self
+    }
+
+    pub fn add_text(mut self, text: TextChunk) -> Self {
+        self.options.text.push(text);
+        self
+    }
+
+    /// Replaces every setting other than compression and color type, for options the
+    /// builder has no method for.
+    pub fn options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Encodes `image` as a complete PNG into `writer`.
+    pub fn encode<W: Write>(
+        &self,
+        image: &DynamicImage,
+        writer: &mut W,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.configure(image)?.encode(image, writer)
+    }
+
+    /// Runs the filtering step of [`Self::encode`] without compressing anything, and
+    /// returns the filter used for each scanline in file order (pass by pass when
+    /// interlacing). Shows what an adaptive [`FilterStrategy`] picks for an image.
+    pub fn row_filters(&self, image: &DynamicImage) -> Result<Vec<FilterType>, EncoderError> {
+        self.configure(image)?.row_filters(image)
+    }
+
+    /// Encodes `image` to a PNG file at `path`, removing the file if encoding fails.
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        image: &DynamicImage,
+        path: P,
+    ) -> Result<EncodeReport, EncoderError> {
+        let path = path.as_ref();
+        let encoder = self.configure(image)?;
+        let file = File::create(path)?;
+        Self::write_file(&encoder, image, path, file)
+    }
+
+    /// Like [`Self::save`], but fails with [`EncoderError::OutputExists`] instead of
+    /// replacing a file already at `path`. The check and the file's creation are one
+    /// step, so a file appearing concurrently is never clobbered either.
+    pub fn save_new<P: AsRef<Path>>(
+        &self,
+        image: &DynamicImage,
+        path: P,
+    ) -> Result<EncodeReport, EncoderError> {
+        let path = path.as_ref();
+        let encoder = self.configure(image)?;
+        let file = match File::create_new(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(EncoderError::OutputExists(path.to_path_buf()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::write_file(&encoder, image, path, file)
+    }
+
+    /// Like [`Self::save`], but writes through a memory-mapped file pre-sized from the
+    /// encoder's size estimate and truncated to the exact length afterwards.
+    #[cfg(feature = "mmap")]
+    pub fn save_mmap<P: AsRef<Path>>(
+        &self,
+        image: &DynamicImage,
+        path: P,
+    ) -> Result<EncodeReport, EncoderError> {
+        let path = path.as_ref();
+        let encoder = self.configure(image)?;
+        let writer = MmapWriter::create(path, encoder.size_estimate())?;
+        Self::write_mmap(&encoder, image, path, writer)
+    }
+
+    /// Like [`Self::save_mmap`], but fails with [`EncoderError::OutputExists`] instead of
+    /// replacing a file already at `path`, checking and creating in one step as
+    /// [`Self::save_new`] does.
+    #[cfg(feature = "mmap")]
+    pub fn save_mmap_new<P: AsRef<Path>>(
+        &self,
+        image: &DynamicImage,
+        path: P,
+    ) -> Result<EncodeReport, EncoderError> {
+        let path = path.as_ref();
+        let encoder = self.configure(image)?;
+        let writer = match MmapWriter::create_new(path, encoder.size_estimate()) {
+            Ok(writer) => writer,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(EncoderError::OutputExists(path.to_path_buf()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::write_mmap(&encoder, image, path, writer)
+    }
+
+    /// Encodes into the freshly mapped `writer`, removing the file if encoding fails.
+    #[cfg(feature = "mmap")]
+    fn write_mmap(
+        encoder: &PngEncoder,
+        image: &DynamicImage,
+        path: &Path,
+        mut writer: MmapWriter,
+    ) -> Result<EncodeReport, EncoderError> {
+        let result = encoder.encode(image, &mut writer).and_then(|report| {
+            writer.finish()?;
+            Ok(report)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    /// Encodes into the freshly created `file`, removing it if encoding fails.
+    fn write_file(
+        encoder: &PngEncoder,
+        image: &DynamicImage,
+        path: &Path,
+        mut file: File,
+    ) -> Result<EncodeReport, EncoderError> {
+        let result = encoder.encode(image, &mut file);
+        if result.is_err() {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    fn configure(&self, image: &DynamicImage) -> Result<PngEncoder, EncoderError> {
+        let mut encoder = PngEncoder::with_options(
+            image.width(),
+            image.height(),
+            self.compression,
+            self.options.clone(),
+        )?;
+        encoder.configure_for_image(image);
+        if let Some(color_type) = self.color_type {
+            encoder.force_color_type(image, color_type)?;
+        }
+        Ok(encoder)
+    }
+}
+
+/// Encodes `image` to an in-memory PNG.
+pub fn encode_to_vec(
+    image: &DynamicImage,
+    compression: CompressionMethod,
+) -> Result<Vec<u8>, EncoderError> {
+    let mut output = Vec::new();
+    encode_to_writer(image, &mut output, compression)?;
+    Ok(output)
+}
+
+/// Encodes `image` as a PNG into `writer`, such as a socket, a hasher or a compressing
+/// wrapper, without going through the filesystem.
+pub fn encode_to_writer<W: Write>(
+    image: &DynamicImage,
+    writer: &mut W,
+    compression: CompressionMethod,
+) -> Result<EncodeReport, EncoderError> {
+    PngEncoderBuilder::new()
+        .compression(compression)
+        .encode(image, writer)
+}
+
+/// Encodes raw pixel data, e.g. from a GPU readback, as a PNG into `writer` without
+/// wrapping it in a [`DynamicImage`].
+///
+/// `pixels` must hold `height` rows in PNG sample layout: interleaved channels of
+/// `color_type`, 16-bit samples big-endian, and samples below 8 bits packed from the
+/// most significant bit with each row starting on a new byte. Indexed color (type 3)
+/// needs a palette and is not accepted. `order` gives the channel order of color
+/// samples; it has no effect on gray images.
+#[allow(clippy::too_many_arguments)]
+pub fn encode_raw<W: Write>(
+    width: u32,
+    height: u32,
+    color_type: u8,
+    bit_depth: u8,
+    pixels: &[u8],
+    order: PixelOrder,
+    compression: CompressionMethod,
+    writer: &mut W,
+) -> Result<EncodeReport, EncoderError> {
+    if color_type == 3 || !SUPPORTED_COLOR_TYPES.contains(&(color_type, bit_depth)) {
+        return Err(EncoderError::InvalidOptions(format!(
+            "Unsupported color type {} at bit depth {} for raw pixels",
+            color_type, bit_depth
+        )));
+    }
+
+    let mut encoder =
+        PngEncoder::with_options(width, height, compression, EncodeOptions::default())?;
+    encoder.color_type = color_type;
+    encoder.bit_depth = bit_depth;
+    let expected = encoder
+        .stride()
+        .and_then(|stride| stride.checked_mul(height as usize))
+        .ok_or(EncoderError::ImageTooLarge)?;
+    if pixels.len() != expected {
+        return Err(EncoderError::PixelBufferSize {
+            expected,
+            actual: pixels.len(),
+        });
+    }
+
+    encoder.checked_filtered_len()?;
+    match (order, color_type) {
+        (PixelOrder::Bgra, 2 | 6) => {
+            let mut swapped = pixels.to_vec();
+            let sample_len = usize::from(bit_depth / 8);
+            let pixel_len = if color_type == 6 { 4 } else { 3 } * sample_len;
+            for pixel in swapped.chunks_exact_mut(pixel_len) {
+                let (blue, rest) = pixel.split_at_mut(sample_len);
+                blue.swap_with_slice(&mut rest[sample_len..2 * sample_len]);
+            }
+            encoder.encode_pixels(&swapped, writer)
+        }
+        _ => encoder.encode_pixels(pixels, writer),
+    }
+}
+
+/// Encodes 16-bit samples as a PNG into `writer`, like [`encode_raw`] at bit depth 16.
+///
+/// `samples` holds `height` rows of interleaved RGBA-order channels of `color_type`,
+/// viewed straight from a buffer whose samples are stored in `endian` byte order, e.g.
+/// [`SampleEndian::Little`] for a little-endian GPU readback. They are written to the
+/// file big-endian, as PNG requires.
+pub fn encode_raw_u16<W: Write>(
+    width: u32,
+    height: u32,
+    color_type: u8,
+    samples: &[u16],
+    endian: SampleEndian,
+    compression: CompressionMethod,
+    writer: &mut W,
+) -> Result<EncodeReport, EncoderError> {
+    let pixels: Vec<u8> = samples
+        .iter()
+        .flat_map(|&sample| match endian {
+            SampleEndian::Big => sample.to_ne_bytes(),
+            SampleEndian::Little => u16::from_le_bytes(sample.to_ne_bytes()).to_be_bytes(),
+        })
+        .collect();
+    encode_raw(
+        width,
+        height,
+        color_type,
+        16,
+        &pixels,
+        PixelOrder::Rgba,
+        compression,
+        writer,
+    )
+}
+
+/// Encodes `frames` as an animated PNG that loops forever. The first frame is also the
+/// image shown by decoders without APNG support.
+///
+/// All frames must share the first frame's dimensions. They are written in the
+/// smallest 8-bit color type that holds every frame.
+pub fn encode_apng<W: Write>(
+    frames: &[ApngFrame],
+    writer: &mut W,
+    compression: CompressionMethod,
+) -> Result<EncodeReport, EncoderError> {
+    encode_apng_with(frames, &ApngOptions::default(), writer, compression)
+}
+
+/// Like [`encode_apng`], with the animation-wide settings in `options`, including an
+/// optional fallback image that replaces the first frame as the default image.
+pub fn encode_apng_with<W: Write>(
+    frames: &[ApngFrame],
+    options: &ApngOptions,
+    writer: &mut W,
+    compression: CompressionMethod,
+) -> Result<EncodeReport, EncoderError> {
+    let Some(first) = frames.first() else {
+        return Err(EncoderError::InvalidOptions(
+            "An animation needs at least one frame".to_string(),
+        ));
+    };
+    let (width, height) = (first.image.width(), first.image.height());
+    let images = || {
+        frames
+            .iter()
+            .map(|frame| &frame.image)
+            .chain(&options.fallback)
+    };
+    if let Some(image) = images().find(|image| (image.width(), image.height()) != (width, height)) {
+        return Err(EncoderError::InvalidOptions(format!(
+            "Frame size {}x{} differs from the first frame's {}x{}",
+            image.width(),
+            image.height(),
+            width,
+            height
+        )));
+    }
+
+    let mut encoder =
+        PngEncoder::with_options(width, height, compression, EncodeOptions::default())?;
+    let has_color = images().any(|image| image.color().has_color());
+    let has_alpha = images().any(|image| image.color().has_alpha());
+    encoder.color_type = match (has_color, has_alpha) {
+        (false, false) => 0,
+        (false, true) => 4,
+        (true, false) => 2,
+        (true, true) => 6,
+    };
+    encoder.encode_animation(frames, options, writer)
+}
+
+pub fn save_to_png_with_compression(
+    image: &DynamicImage,
+    path: &str,
+    compression: CompressionMethod,
+) -> Result<EncodeReport, EncoderError> {
+    PngEncoderBuilder::new()
+        .compression(compression)
+        .save(image, path)
+}
+
+pub fn save_to_png_with_options(
+    image: &DynamicImage,
+    path: &str,
+    compression: CompressionMethod,
+    options: EncodeOptions,
+) -> Result<EncodeReport, EncoderError> {
+    PngEncoderBuilder::new()
+        .compression(compression)
+        .options(options)
+        .save(image, path)
+}
+
+/// Like [`save_to_png_with_options`], but fails with [`EncoderError::OutputExists`]
+/// rather than overwrite an existing file.
+pub fn save_to_png_no_clobber(
+    image: &DynamicImage,
+    path: &str,
+    compression: CompressionMethod,
+    options: EncodeOptions,
+) -> Result<EncodeReport, EncoderError> {
+    PngEncoderBuilder::new()
+        .compression(compression)
+        .options(options)
+        .save_new(image, path)
+}
+
+/// Like [`save_to_png_with_options`], but writes through a memory-mapped file; see
+/// [`PngEncoderBuilder::save_mmap`].
+#[cfg(feature = "mmap")]
+pub fn save_to_png_mmap(
+    image: &DynamicImage,
+    path: &str,
+    compression: CompressionMethod,
+    options: EncodeOptions,
+) -> Result<EncodeReport, EncoderError> {
+    PngEncoderBuilder::new()
+        .compression(compression)
+        .options(options)
+        .save_mmap(image, path)
+}
+
+/// Encodes a few sample images with every compression method and checks that the
+/// `image` crate, an independent decoder, reads each one back with the original pixels.
+/// The crate's own round-trip tests can't catch output only this crate understands.
+pub fn self_check() -> Result<(), EncoderError> {
+    let gradient = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(67, 45, |x, y| {
+        image::Rgba([
+            (x * 3) as u8,
+            (y * 5) as u8,
+            (x * y) as u8,
+            255 - (x + y) as u8,
+        ])
+    }));
+    let stripes = DynamicImage::ImageLuma8(image::GrayImage::from_fn(300, 20, |x, y| {
+        image::Luma([if (x / 7 + y) % 3 == 0 { 250 } else { 10 }])
+    }));
+    let samples = [
+        (&gradient, EncodeOptions::default()),
+        (
+            &gradient,
+            EncodeOptions {
+                interlace: true,
+                filter_strategy: FilterStrategy::Adaptive,
+                ..Default::default()
+            },
+        ),
+        (&stripes, EncodeOptions::default()),
+        (
+            &stripes,
+            EncodeOptions {
+                streaming: true,
+                ..Default::default()
+            },
+        ),
+    ];
+
+    for compression in [
+        CompressionMethod::Custom,
+        CompressionMethod::Flate2,
+        CompressionMethod::Stored,
+    ] {
+        for (image, options) in &samples {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(compression)
+                .options(options.clone())
+                .encode(image, &mut bytes)?;
+            let decoded = image::load_from_memory(&bytes).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} output rejected by the image crate: {}",
+                        compression, e
+                    ),
+                )
+            })?;
+            if decoded.to_rgba8() != image.to_rgba8() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} output decodes to different pixels", compression),
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_compression() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let test_data = b"Hello, World! This is a test string for compression.";
+
+        assert!(encoder.test_compression(test_data));
+    }
+
+    #[test]
+    fn test_repetitive_data_compression() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let mut test_data = Vec::new();
+
+        // Create repetitive data that should compress well
+        for _ in 0..100 {
+            test_data.extend_from_slice(b"ABCDEFGH");
+        }
+
+        assert!(encoder.test_compression(&test_data));
+    }
+
+    #[test]
+    fn test_empty_data() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let test_data = b"";
+
+        assert!(encoder.test_compression(test_data));
+    }
+
+    #[test]
+    fn test_single_byte() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let test_data = b"A";
+
+        assert!(encoder.test_compression(test_data));
+    }
+
+    #[test]
+    fn test_escape_byte_handling() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let test_data = b"\xFF\xFF\xFF\x00\x01\x02";
+
+        assert!(encoder.test_compression(test_data));
+    }
+
+    #[test]
+    fn test_long_matches() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let mut test_data = Vec::new();
+
+        // Create a pattern with long repeating sequences
+        let pattern = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        for _ in 0..20 {
+            test_data.extend_from_slice(pattern);
+        }
+
+        assert!(encoder.test_compression(&test_data));
+    }
+
+    #[test]
+    fn test_adler32_checksum() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+
+        // Test known Adler32 values
+        assert_eq!(encoder.adler32(b""), 1);
+        assert_eq!(encoder.adler32(b"a"), 0x00620062);
+        assert_eq!(encoder.adler32(b"abc"), 0x024d0127);
+        assert_eq!(encoder.adler32(b"message digest"), 0x29750586);
+    }
+
+    #[test]
+    fn test_adler32_large_input_matches_reference() {
+        fn reference(data: &[u8]) -> u32 {
+            let (mut a, mut b) = (1u32, 0u32);
+            for &byte in data {
+                a = (a + byte as u32) % 65521;
+                b = (b + a) % 65521;
+            }
+            (b << 16) | a
+        }
+
+        // All 0xFF bytes drive the sums closest to overflow between reductions
+        let ones = vec![0xFF; 3 * 5552 + 17];
+        assert_eq!(adler32_update(1, &ones), reference(&ones));
+
+        let data: Vec<u8> = (0..1_000_003u32).map(noise).collect();
+        assert_eq!(adler32_update(1, &data), reference(&data));
+
+        // Continuing across an odd split gives the same result as one pass
+        let (head, tail) = data.split_at(12_345);
+        assert_eq!(
+            adler32_update(adler32_update(1, head), tail),
+            reference(&data)
+        );
+    }
+
+    #[test]
+    #[allow(clippy::same_item_push)]
+    fn test_compression_reduces_size() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+
+        // Highly repetitive data
+        let mut test_data = Vec::new();
+        for _ in 0..1000 {
+            test_data.push(0x42); // Repeat the same byte
+        }
+
+        let compressed = encoder.compress_data(&test_data).unwrap();
+
+        // Compressed size should be much smaller than original (accounting for headers and overhead)
+        assert!(compressed.len() < test_data.len() / 2);
+    }
+
+    #[test]
+    #[allow(clippy::len_zero)]
+    fn test_flate2_compression() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
+        let test_data = b"Hello, World! This is a test string for compression.";
+
+        let compressed = encoder.compress_data(test_data).unwrap();
+        assert!(compressed.len() > 0);
+        assert!(compressed.len() < test_data.len() + 100); // Should be reasonable size
+    }
+
+    #[test]
+    fn test_flate2_compression_repetitive_data() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
+        let mut test_data = Vec::new();
+
+        // Create repetitive data that should compress well
+        for _ in 0..100 {
+            test_data.extend_from_slice(b"ABCDEFGH");
+        }
+
+        let compressed = encoder.compress_data(&test_data).unwrap();
+
+        // Flate2 should compress repetitive data very well
+        assert!(compressed.len() < test_data.len() / 4);
+    }
+
+    #[test]
+    fn test_compression_methods_comparison() {
+        let custom_encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let flate2_encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
+
+        // Test data with longer repetitive patterns that our custom algorithm can compress
+        let mut test_data = Vec::new();
+        let pattern = b"ABCDEFGH";
+        for _ in 0..50 {
+            test_data.extend_from_slice(pattern);
+        }
+
+        let custom_compressed = custom_encoder.compress_data(&test_data).unwrap();
+        let flate2_compressed = flate2_encoder.compress_data(&test_data).unwrap();
+
+        // Both should compress the data significantly
+        assert!(custom_compressed.len() < test_data.len() / 2);
+        assert!(flate2_compressed.len() < test_data.len() / 2);
+
+        // Flate2 should generally compress better due to Huffman coding
+        println!("Original size: {}", test_data.len());
+        println!("Custom compressed: {}", custom_compressed.len());
+        println!("Flate2 compressed: {}", flate2_compressed.len());
+    }
+
+    #[test]
+    fn test_both_methods_create_valid_pngs() {
+        use std::io::Cursor;
+
+        // Create a simple test image
+        let width = 10;
+        let height = 10;
+        let mut image_data = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                // Simple gradient pattern
+                let r = (x * 255 / width) as u8;
+                let g = (y * 255 / height) as u8;
+                let b = 128;
+                let a = 255;
+                image_data.extend_from_slice(&[r, g, b, a]);
+            }
+        }
+
+        let image_buffer = image::RgbaImage::from_raw(width, height, image_data).unwrap();
+        let dynamic_image = DynamicImage::ImageRgba8(image_buffer);
+
+        // Test both compression methods create valid PNG structure
+        let custom_encoder = PngEncoder::new(width, height, CompressionMethod::Custom);
+        let flate2_encoder = PngEncoder::new(width, height, CompressionMethod::Flate2);
+
+        let mut custom_output = Cursor::new(Vec::new());
+        let mut flate2_output = Cursor::new(Vec::new());
+
+        assert!(
+            custom_encoder
+                .encode(&dynamic_image, &mut custom_output)
+                .is_ok()
+        );
+        assert!(
+            flate2_encoder
+                .encode(&dynamic_image, &mut flate2_output)
+                .is_ok()
+        );
+
+        // Both outputs should start with PNG signature
+        let custom_data = custom_output.into_inner();
+        let flate2_data = flate2_output.into_inner();
+
+        assert_eq!(&custom_data[0..8], &PNG_SIGNATURE);
+        assert_eq!(&flate2_data[0..8], &PNG_SIGNATURE);
+
+        // Both should have reasonable file sizes
+        assert!(custom_data.len() > 100);
+        assert!(flate2_data.len() > 100);
+    }
+
+    #[test]
+    fn test_self_check_decodes_with_image_crate() {
+        self_check().unwrap();
+    }
+
+    #[test]
+    fn test_compression_ratio_report() {
+        let width = 64;
+        let height = 64;
+        let encoder = PngEncoder::new(width, height, CompressionMethod::Flate2);
+
+        let flat = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            width,
+            height,
+            image::Rgba([30, 60, 90, 255]),
+        ));
+        let flat_report = encoder.encode(&flat, &mut Vec::new()).unwrap();
+        assert_eq!(flat_report.raw_bytes, (width * height * 4) as usize);
+        assert!(flat_report.compression_ratio > 10.0);
+
+        // Xorshift noise is effectively incompressible
+        let mut state: u32 = 0x12345678;
+        let noise_data: Vec<u8> = (0..width * height * 4)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+        let noise = DynamicImage::ImageRgba8(
+            image::RgbaImage::from_raw(width, height, noise_data).unwrap(),
+        );
+        let noise_report = encoder.encode(&noise, &mut Vec::new()).unwrap();
+        assert!(noise_report.compression_ratio > 0.9);
+        assert!(noise_report.compression_ratio < 1.1);
+    }
+
+    #[test]
+    fn test_cost_model_on_distance_one_runs() {
+        // Short runs of 0xFF: each run is one nine-bit literal followed by a
+        // distance-1 match that is too short for the fixed length threshold
+        let mut test_data = Vec::new();
+        for i in 0..50u8 {
+            test_data.extend_from_slice(&[255, 255, 255, 255, i]);
+        }
+
+        let mut encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        encoder.use_cost_model = false;
+        let without_cost_model = encoder.compress_data(&test_data).unwrap();
+
+        encoder.use_cost_model = true;
+        let with_cost_model = encoder.compress_data(&test_data).unwrap();
+
+        assert!(with_cost_model.len() < without_cost_model.len());
+        assert!(encoder.test_compression(&test_data));
+    }
+
+    #[test]
+    fn test_lazy_matching_compresses_better_than_greedy() {
+        let mut encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let tokens = |encoder: &PngEncoder, data: &[u8]| {
+            let mut tokens = Vec::new();
+            encoder
+                .tokenize(data, 0, |token| tokens.push(token))
+                .unwrap();
+            tokens
+        };
+
+        // A short match at "zabc" hides the longer "abcdefghijkl" one byte later
+        let crafted = b"abcdefghijkl-1zabc-2zabcdefghijkl";
+        encoder.lazy_matching = false;
+        assert_eq!(
+            tokens(&encoder, crafted)[18..],
+            [
+                Token::Match {
+                    length: 4,
+                    distance: 6
+                },
+                Token::Match {
+                    length: 9,
+                    distance: 21
+                }
+            ]
+        );
+        encoder.lazy_matching = true;
+        assert_eq!(
+            tokens(&encoder, crafted)[18..],
+            [
+                Token::Literal(b'z'),
+                Token::Match {
+                    length: 12,
+                    distance: 21
+                }
+            ]
+        );
+
+        // Filtered scanlines of a dithered gradient, and repetitive text
+        let mut scanlines = Vec::new();
+        for y in 0..64u32 {
+            scanlines.push(1);
+            scanlines.extend((0..96u32).map(|x| ((x * 7 + y * 3) % 11 + (x ^ y) % 3) as u8));
+        }
+        let text = "name=tile_%d x=%d y=%d layer=ground; "
+            .repeat(40)
+            .into_bytes();
+        for (data, must_shrink) in [(&scanlines, true), (&text, false)] {
+            encoder.lazy_matching = false;
+            let greedy = encoder.compress_data(data).unwrap();
+            encoder.lazy_matching = true;
+            let lazy = encoder.compress_data(data).unwrap();
+
+            assert!(lazy.len() <= greedy.len());
+            if must_shrink {
+                assert!(lazy.len() < greedy.len());
+            }
+            assert_eq!(encoder.decompress_data(&lazy).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn test_min_match_distance() {
+        let test_data = vec![0x42; 64];
+
+        let mut encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let unrestricted = encoder.compress_data(&test_data).unwrap();
+
+        encoder.min_match_distance = 3;
+        let restricted = encoder.compress_data(&test_data).unwrap();
+
+        // Runs can still be matched from three bytes back, just not from one
+        assert!(encoder.test_compression(&test_data));
+        assert!(restricted.len() < test_data.len());
+        assert!(restricted.len() >= unrestricted.len());
+    }
+
+    #[test]
+    fn test_significant_bits_masking_and_sbit() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([0xFF, 0xB7, 0x0F, 0xFF]),
+        ));
+        let options = EncodeOptions {
+            significant_bits: Some([5, 6, 5, 8]),
+            ..Default::default()
+        };
+        let encoder = PngEncoder::with_options(4, 4, CompressionMethod::Flate2, options).unwrap();
+
+        let mut output = Vec::new();
+        encoder.encode(&image, &mut output).unwrap();
+
+        // sBIT follows the 25-byte IHDR chunk
+        let sbit_start = PNG_SIGNATURE.len() + 25;
+        assert_eq!(&output[sbit_start..sbit_start + 4], &4u32.to_be_bytes());
+        assert_eq!(&output[sbit_start + 4..sbit_start + 8], b"sBIT");
+        assert_eq!(&output[sbit_start + 8..sbit_start + 12], &[5, 6, 5, 8]);
+
+        let decoded = image::load_from_memory(&output).unwrap().to_rgba8();
+        assert_eq!(
+            decoded.get_pixel(0, 0),
+            &image::Rgba([0xF8, 0xB4, 0x08, 0xFF])
+        );
+
+        assert!(
+            PngEncoder::with_options(
+                4,
+                4,
+                CompressionMethod::Flate2,
+                EncodeOptions {
+                    significant_bits: Some([9, 8, 8, 8]),
+                    ..Default::default()
+                },
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_sbit_length_matches_color_type() {
+        let cases = [
+            (
+                DynamicImage::ImageLuma8(image::GrayImage::new(2, 2)),
+                vec![5],
+            ),
+            (
+                DynamicImage::ImageLumaA8(image::GrayAlphaImage::new(2, 2)),
+                vec![5, 7],
+            ),
+            (
+                DynamicImage::ImageRgb8(image::RgbImage::new(2, 2)),
+                vec![5, 6, 5],
+            ),
+            (
+                DynamicImage::ImageRgba8(image::RgbaImage::new(2, 2)),
+                vec![5, 6, 5, 7],
+            ),
+        ];
+
+        for (image, expected) in cases {
+            let mut options = EncodeOptions::default();
+            options.set_significant_bits(5, 6, 5, 7);
+            let mut encoder =
+                PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).unwrap();
+            encoder.configure_for_image(&image);
+            let mut output = Vec::new();
+            encoder.encode(&image, &mut output).unwrap();
+
+            let sbit = crate::decoder::chunks(&output)
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|chunk| &chunk.chunk_type == b"sBIT")
+                .unwrap();
+            assert_eq!(sbit.data, expected, "{:?}", image.color());
+        }
+
+        let mut too_deep = EncodeOptions::default();
+        too_deep.set_significant_bits(8, 8, 8, 9);
+        assert!(PngEncoder::with_options(2, 2, CompressionMethod::Flate2, too_deep).is_err());
+    }
+
+    #[test]
+    fn test_cancel_before_filtering() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let encoder = PngEncoder::with_options(16, 16, CompressionMethod::Flate2, options).unwrap();
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(16, 16));
+
+        let mut output = Vec::new();
+        let err = encoder.encode(&image, &mut output).unwrap_err();
+
+        assert!(matches!(err, EncoderError::Cancelled));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_mid_encode() {
+        use std::sync::atomic::AtomicU32;
+
+        const CANCEL_AT: u32 = 100;
+        let (width, height) = (64, 2048);
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([noise(x), noise(y), noise(x ^ y), 255])
+        }));
+
+        // Request cancellation from the progress callback once CANCEL_AT rows are done,
+        // so the cancel point is fixed instead of depending on timing
+        let cancel = Arc::new(AtomicBool::new(false));
+        let rows_done = Arc::new(AtomicU32::new(0));
+        let mut options = EncodeOptions {
+            cancel: Some(Arc::clone(&cancel)),
+            ..Default::default()
+        };
+        let reported = Arc::clone(&rows_done);
+        options.on_progress(move |done, _| {
+            reported.store(done, Ordering::Relaxed);
+            if done == CANCEL_AT {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        let encoder =
+            PngEncoder::with_options(width, height, CompressionMethod::Custom, options).unwrap();
+
+        let mut output = Vec::new();
+        let err = encoder.encode(&image, &mut output).unwrap_err();
+
+        assert!(matches!(err, EncoderError::Cancelled));
+        assert!(output.is_empty());
+        // Rows are checked one at a time, or a band at a time when filtering in parallel
+        #[cfg(feature = "parallel")]
+        let slack = PARALLEL_BAND_ROWS as u32;
+        #[cfg(not(feature = "parallel"))]
+        let slack = 0;
+        let rows_done = rows_done.load(Ordering::Relaxed);
+        assert!((CANCEL_AT..=CANCEL_AT + slack).contains(&rows_done));
+    }
+
+    #[test]
+    fn test_progress_callback_counts_scanlines() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 300, |x, y| {
+            image::Rgb([x as u8, y as u8, (x * y) as u8])
+        }));
+        // Interlaced output reports the rows of every non-empty pass
+        let pass_rows = 38 + 38 + 37 + 75 + 75 + 150 + 150;
+        for (interlace, streaming, total) in [
+            (false, false, 300),
+            (true, false, pass_rows),
+            (false, true, 300),
+        ] {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&calls);
+            let encoder = PngEncoderBuilder::new()
+                .options(EncodeOptions {
+                    interlace,
+                    streaming,
+                    ..EncodeOptions::default()
+                })
+                .on_progress(move |done, total| recorded.lock().unwrap().push((done, total)));
+            encoder.encode(&image, &mut Vec::new()).unwrap();
+
+            let calls = calls.lock().unwrap();
+            let expected: Vec<_> = (1..=total).map(|done| (done, total)).collect();
+            assert_eq!(*calls, expected);
+        }
+    }
+
+    #[cfg(feature = "testing-malformed")]
+    #[test]
+    fn test_malformed_iend_rejected_by_validator() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        let encode = |malformed_iend| {
+            let options = EncodeOptions {
+                malformed_iend,
+                ..Default::default()
+            };
+            let encoder =
+                PngEncoder::with_options(4, 4, CompressionMethod::Flate2, options).unwrap();
+            let mut output = Vec::new();
+            encoder.encode(&image, &mut output).unwrap();
+            output
+        };
+
+        assert!(crate::decoder::is_valid_png(&encode(None)).is_ok());
+
+        let omitted = crate::decoder::is_valid_png(&encode(Some(MalformedIend::Omit)));
+        assert_eq!(omitted.unwrap_err().to_string(), "Missing IEND chunk");
+
+        let corrupted = crate::decoder::is_valid_png(&encode(Some(MalformedIend::CorruptCrc)));
+        assert_eq!(
+            corrupted.unwrap_err().to_string(),
+            "CRC mismatch in IEND chunk"
+        );
+    }
+
+    #[test]
+    fn test_text_chunks() {
+        let latin1 = TextChunk::Latin1 {
+            keyword: "Author".to_string(),
+            text: "Jos\u{e9}\r\nLine two".to_string(),
+        };
+        let (chunk_type, data) = latin1.to_chunk().unwrap();
+        assert_eq!(&chunk_type, b"tEXt");
+        assert_eq!(data, b"Author\0Jos\xE9\nLine two");
+
+        let non_latin1 = "\u{65E5}\u{672C}".to_string();
+        let options = EncodeOptions {
+            text: vec![TextChunk::Latin1 {
+                keyword: "Title".to_string(),
+                text: non_latin1.clone(),
+            }],
+            ..Default::default()
+        };
+        assert!(PngEncoder::with_options(4, 4, CompressionMethod::Flate2, options).is_err());
+
+        let options = EncodeOptions {
+            text: vec![TextChunk::Utf8 {
+                keyword: "Title".to_string(),
+                text: non_latin1.clone(),
+            }],
+            ..Default::default()
+        };
+        let encoder = PngEncoder::with_options(4, 4, CompressionMethod::Flate2, options).unwrap();
+        let mut output = Vec::new();
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        encoder.encode(&image, &mut output).unwrap();
+
+        let mut expected = b"iTXtTitle\0\0\0\0\0".to_vec();
+        expected.extend_from_slice(non_latin1.as_bytes());
+        assert!(output.windows(expected.len()).any(|w| w == expected));
+
+        let bad_keyword = TextChunk::Latin1 {
+            keyword: String::new(),
+            text: "value".to_string(),
+        };
+        assert!(bad_keyword.to_chunk().is_err());
+    }
+
+    #[test]
+    fn test_international_text_round_trip() {
+        let description = "Sch\u{f6}ne Gr\u{fc}\u{df}e \u{2014} \u{65E5}\u{672C}\u{8A9E}".repeat(4);
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            for compressed in [false, true] {
+                let options = EncodeOptions {
+                    text: vec![TextChunk::International {
+                        keyword: "Description".to_string(),
+                        language: "de-CH".to_string(),
+                        translated_keyword: "Beschreibung".to_string(),
+                        text: description.clone(),
+                        compressed,
+                    }],
+                    ..Default::default()
+                };
+                let encoder = PngEncoder::with_options(4, 4, method, options).unwrap();
+                let mut output = Vec::new();
+                encoder.encode(&image, &mut output).unwrap();
+
+                let mut header = b"iTXtDescription\0".to_vec();
+                header.extend_from_slice(&[u8::from(compressed), 0]);
+                header.extend_from_slice(b"de-CH\0Beschreibung\0");
+                assert!(output.windows(header.len()).any(|w| w == header));
+                let raw_text_present = output
+                    .windows(description.len())
+                    .any(|w| w == description.as_bytes());
+                assert_eq!(raw_text_present, !compressed);
+
+                assert_eq!(
+                    crate::decoder::read_text(&output).unwrap(),
+                    [("Description".to_string(), description.clone())]
+                );
+            }
+        }
+
+        let bad_language = TextChunk::International {
+            keyword: "Title".to_string(),
+            language: "de CH".to_string(),
+            translated_keyword: String::new(),
+            text: "Titel".to_string(),
+            compressed: false,
+        };
+        assert!(bad_language.to_chunk().is_err());
+
+        let embedded_nul = TextChunk::International {
+            keyword: "Title".to_string(),
+            language: String::new(),
+            translated_keyword: "Ti\0tel".to_string(),
+            text: "Titel".to_string(),
+            compressed: false,
+        };
+        assert!(embedded_nul.to_chunk().is_err());
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn test_streamed_sha256_matches_file() {
+        use sha2::Digest;
+
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(8, 8, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 30, 60, 255])
+        }));
+        let path = std::env::temp_dir().join("rpng_test_streamed_sha256.png");
+        let options = EncodeOptions {
+            compute_sha256: true,
+            ..Default::default()
+        };
+
+        let report = save_to_png_with_options(
+            &image,
+            &path.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+
+        let file_bytes = std::fs::read(&path).unwrap();
+        let expected: [u8; 32] = sha2::Sha256::digest(&file_bytes).into();
+        assert_eq!(report.sha256, Some(expected));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_adaptive_fast_on_noise() {
+        use std::time::{Duration, Instant};
+
+        let width = 256;
+        let height = 256;
+        let mut state: u32 = 0xC0FFEE;
+        let noise_data: Vec<u8> = (0..width * height * 4)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+        let noise = DynamicImage::ImageRgba8(
+            image::RgbaImage::from_raw(width, height, noise_data).unwrap(),
+        );
+
+        let options = EncodeOptions {
+            filter_strategy: FilterStrategy::AdaptiveFast,
+            ..Default::default()
+        };
+        let encoder =
+            PngEncoder::with_options(width, height, CompressionMethod::Custom, options).unwrap();
+        assert!(encoder.looks_incompressible(noise.to_rgba8().as_raw()));
+
+        // The full custom matcher would take far longer than this on noise
+        let start = Instant::now();
+        let mut output = Vec::new();
+        encoder.encode(&noise, &mut output).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let idat = crate::decoder::collect_idat(
+            &output,
+            crate::decoder::DecodeMode::Strict,
+            &crate::decoder::DecodeLimits::default(),
+        )
+        .unwrap()
+        .data;
+        let inflated = encoder.decompress_data(&idat).unwrap();
+        let stride = width as usize * 4 + 1;
+        assert!(
+            inflated
+                .chunks(stride)
+                .all(|row| row[0] == FilterType::None as u8)
+        );
+        assert_eq!(
+            &inflated[1..stride],
+            &noise.to_rgba8().as_raw()[..stride - 1]
+        );
+    }
+
+    #[test]
+    fn test_row_filters_report_adaptive_choices() {
+        // Every row is the same horizontal ramp: the first row is best predicted from
+        // its left neighbor, the rest from the row above
+        let ramp = DynamicImage::ImageLuma8(image::GrayImage::from_fn(16, 12, |x, _| {
+            image::Luma([x as u8 * 10])
+        }));
+        let adaptive = PngEncoderBuilder::new().filter_strategy(FilterStrategy::Adaptive);
+        let filters = adaptive.row_filters(&ramp).unwrap();
+        assert_eq!(filters.len(), 12);
+        assert_eq!(filters[0], FilterType::Sub);
+        assert!(filters[1..].iter().all(|&filter| filter == FilterType::Up));
+
+        let fixed = PngEncoderBuilder::new().filter_strategy(FilterStrategy::Paeth);
+        assert_eq!(fixed.row_filters(&ramp).unwrap(), [FilterType::Paeth; 12]);
+
+        // Interlaced images report the rows of each pass: 2 + 2 + 1 + 3 + 3 + 6 + 6
+        let interlaced = adaptive.interlace(true).row_filters(&ramp).unwrap();
+        assert_eq!(interlaced.len(), 23);
+    }
+
+    #[test]
+    fn test_adaptive_fast_keeps_filtering_smooth_images() {
+        let gradient = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([x as u8 * 4, y as u8 * 4, 128, 255])
+        }));
+        let options = EncodeOptions {
+            filter_strategy: FilterStrategy::AdaptiveFast,
+            ..Default::default()
+        };
+        let encoder = PngEncoder::with_options(64, 64, CompressionMethod::Flate2, options).unwrap();
+
+        assert!(!encoder.looks_incompressible(gradient.to_rgba8().as_raw()));
+    }
+
+    /// Writes `tokens` as a single final fixed-Huffman block.
+    fn fixed_block(tokens: &[Token]) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        write_fixed_block(&mut writer, tokens, true);
+        writer.finish()
+    }
+
+    #[test]
+    fn test_inflate_overlapping_max_length_copies() {
+        let encoder = PngEncoder::new(1024, 1024, CompressionMethod::Custom);
+        let run = Token::Match {
+            length: MAX_MATCH_LENGTH as u16,
+            distance: 1,
+        };
+
+        // One literal followed by many maximal-length copies at distance 1
+        let mut tokens = vec![Token::Literal(7)];
+        tokens.extend(std::iter::repeat_n(run, 4096));
+        let inflated = encoder.simple_inflate(&fixed_block(&tokens)).unwrap();
+        assert_eq!(inflated.len(), 1 + 4096 * MAX_MATCH_LENGTH);
+        assert!(inflated.iter().all(|&b| b == 7));
+
+        // A three-byte period repeated past its own end
+        let period = Token::Match {
+            length: 200,
+            distance: 3,
+        };
+        let tokens = [1, 2, 3].map(Token::Literal);
+        let inflated = encoder
+            .simple_inflate(&fixed_block(&[&tokens[..], &[period]].concat()))
+            .unwrap();
+        let expected: Vec<u8> = (0..203).map(|i| [1, 2, 3][i % 3]).collect();
+        assert_eq!(inflated, expected);
+
+        let too_far = Token::Match {
+            length: 4,
+            distance: 3,
+        };
+        assert!(matches!(
+            encoder.simple_inflate(&fixed_block(&[Token::Literal(1), too_far])),
+            Err(EncoderError::InvalidBackReference)
+        ));
+        let block = fixed_block(&[Token::Literal(1), run]);
+        assert!(matches!(
+            encoder.simple_inflate(&block[..block.len() - 1]),
+            Err(EncoderError::Truncated)
+        ));
+        // A dynamic-Huffman block header
+        assert!(matches!(
+            encoder.simple_inflate(&[0b101]),
+            Err(EncoderError::UnsupportedBlockType(2))
+        ));
+
+        // Length symbols 286-287 and distance codes 30-31 have fixed codes but no meaning
+        for (symbol, distance_code) in [(286, 0), (287, 0), (257, 30), (257, 31)] {
+            let mut writer = BitWriter::new();
+            writer.write_bits(0b011, 3);
+            let (code, length) = fixed_code(0);
+            writer.write_code(code, length);
+            let (code, length) = fixed_code(symbol);
+            writer.write_code(code, length);
+            writer.write_code(distance_code, 5);
+            assert!(matches!(
+                encoder.simple_inflate(&writer.finish()),
+                Err(EncoderError::InvalidBackReference)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_inflate_limit_and_corrupt_streams() {
+        let encoder = PngEncoder::new(64, 64, CompressionMethod::Custom);
+
+        // A few kilobytes that expand to a megabyte stop at the limit
+        let bomb = encoder.compress_data(&vec![0u8; 1 << 20]).unwrap();
+        assert!(bomb.len() < 8192);
+        assert!(matches!(
+            encoder.decompress_limited(&bomb, 1000),
+            Err(EncoderError::OutputTooLarge(1000))
+        ));
+        let stored = encoder.compress_data_with_effort(&[5; 2000], true).unwrap();
+        assert!(matches!(
+            encoder.decompress_limited(&stored, 1999),
+            Err(EncoderError::OutputTooLarge(1999))
+        ));
+        assert_eq!(
+            encoder.decompress_limited(&stored, 2000).unwrap(),
+            [5; 2000]
+        );
+
+        // Every truncation and single-bit flip of a valid stream fails cleanly or
+        // decodes within the limit
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * i % 251) as u8 / 4).collect();
+        let compressed = encoder.compress_data(&data).unwrap();
+        for len in 0..compressed.len() {
+            assert!(
+                encoder
+                    .decompress_limited(&compressed[..len], 4096)
+                    .is_err()
+            );
+        }
+        for bit in 0..compressed.len() * 8 {
+            let mut corrupt = compressed.clone();
+            corrupt[bit / 8] ^= 1 << (bit % 8);
+            if let Ok(output) = encoder.decompress_limited(&corrupt, 4096) {
+                assert!(output.len() <= 4096);
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_precision_matches_source_depth() {
+        let path = std::env::temp_dir().join("rpng_test_auto_precision.png");
+        let options = EncodeOptions {
+            auto_precision: true,
+            ..Default::default()
+        };
+
+        let eight_bit = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(5, 3, |x, y| {
+            image::Rgba([x as u8 * 50, y as u8 * 80, 9, 255])
+        }));
+        save_to_png_with_options(
+            &eight_bit,
+            &path.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options.clone(),
+        )
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        // IHDR bit depth sits right after the signature, chunk header, width and height
+        assert_eq!(bytes[24], 8);
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().to_rgba8(),
+            eight_bit.to_rgba8()
+        );
+
+        let sixteen_bit = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+            image::Rgba([x as u16 * 12345, y as u16 * 321 + 1, 0xABCD, 0xFFFF])
+        }));
+        save_to_png_with_options(
+            &sixteen_bit,
+            &path.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[24], 16);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageRgba16(_)));
+        assert_eq!(decoded.to_rgba16(), sixteen_bit.to_rgba16());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_tall_thin_round_trip() {
+        let path = std::env::temp_dir().join("rpng_test_tall_thin.png");
+        let tall = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(1, 100_000, |_, y| {
+            image::Rgba([y as u8, (y >> 8) as u8, (y >> 16) as u8, 255])
+        }));
+
+        let report = save_to_png_with_options(
+            &tall,
+            &path.to_string_lossy(),
+            CompressionMethod::Flate2,
+            EncodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.raw_bytes, 400_000);
+
+        let decoded = image::open(&path).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1, 100_000));
+        assert_eq!(decoded.to_rgba8(), tall.to_rgba8());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_zero_dimensions_rejected() {
+        let path = std::env::temp_dir().join("rpng_test_zero_dimensions.png");
+        std::fs::remove_file(&path).ok();
+        for (width, height) in [(0, 10), (10, 0)] {
+            let image = DynamicImage::new_rgb8(width, height);
+            assert!(matches!(
+                encode_to_vec(&image, CompressionMethod::Custom),
+                Err(EncoderError::InvalidDimensions { width: w, height: h })
+                    if (w, h) == (width, height)
+            ));
+            assert!(matches!(
+                save_to_png_with_compression(
+                    &image,
+                    &path.to_string_lossy(),
+                    CompressionMethod::Flate2
+                ),
+                Err(EncoderError::InvalidDimensions { .. })
+            ));
+            // Rejected before the output file is created
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn test_oversized_dimensions_error() {
+        let encoder = PngEncoder::new(u32::MAX, u32::MAX, CompressionMethod::Flate2);
+        let err = encoder
+            .apply_filters(&[], Some(FilterType::Sub))
+            .unwrap_err();
+        assert!(matches!(err, EncoderError::ImageTooLarge));
+
+        let encoder = PngEncoder::new(2, 500_000, CompressionMethod::Flate2);
+        assert!(
+            encoder
+                .apply_filters(&[0; 16], Some(FilterType::Sub))
+                .is_err()
+        );
+
+        // 16-bit RGBA rows of u32::MAX pixels fit in a 64-bit usize, but not the
+        // whole image; the Adam7 pass sums must overflow just the same
+        for interlace in [false, true] {
+            let options = EncodeOptions {
+                interlace,
+                ..EncodeOptions::default()
+            };
+            let mut encoder =
+                PngEncoder::with_options(u32::MAX, u32::MAX, CompressionMethod::Custom, options)
+                    .unwrap();
+            encoder.color_type = 6;
+            encoder.bit_depth = 16;
+            assert!(encoder.stride().is_some());
+            assert!(matches!(
+                encoder.checked_filtered_len(),
+                Err(EncoderError::ImageTooLarge)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_max_filtered_bytes_limit() {
+        // A 100x100 8-bit gray image filters to 100 rows of 1 + 100 bytes
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(100, 100, |x, y| {
+            image::Luma([(x + y) as u8])
+        }));
+        let encode = |max_filtered_bytes| {
+            PngEncoderBuilder::new()
+                .options(EncodeOptions {
+                    max_filtered_bytes: Some(max_filtered_bytes),
+                    ..EncodeOptions::default()
+                })
+                .encode(&image, &mut Vec::new())
+        };
+        assert!(matches!(encode(10_099), Err(EncoderError::ImageTooLarge)));
+        assert!(encode(10_100).is_ok());
+
+        // Streaming never holds the whole image
+        let streamed = PngEncoderBuilder::new()
+            .options(EncodeOptions {
+                max_filtered_bytes: Some(1000),
+                streaming: true,
+                ..EncodeOptions::default()
+            })
+            .encode(&image, &mut Vec::new());
+        assert!(streamed.is_ok());
+    }
+
+    /// Noisy RGB scanlines with some structure, so every filter type wins somewhere.
+    #[cfg(feature = "parallel")]
+    fn noisy_rgb(width: u32, height: u32) -> DynamicImage {
+        let mut state = 0x2545_f491u32;
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = (state % 16) as u8;
+            image::Rgb([
+                x as u8 ^ noise,
+                (y as u8).wrapping_add(noise),
+                (x + y) as u8,
+            ])
+        }))
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_filtering_matches_sequential() {
+        let image = noisy_rgb(97, 150);
+        for filter_type in [None, Some(FilterType::Paeth), Some(FilterType::Up)] {
+            let encoder = PngEncoder::new(97, 150, CompressionMethod::Flate2);
+            let pixels = encoder.prepare_pixels(&image);
+            let stride = encoder.stride().unwrap();
+
+            let mut sequential = vec![0xAA];
+            encoder
+                .filter_rows_sequential(
+                    &pixels,
+                    stride,
+                    150,
+                    filter_type,
+                    &mut sequential,
+                    &mut ProgressTracker::new(None, 150),
+                )
+                .unwrap();
+            let mut parallel = vec![0xAA];
+            encoder
+                .filter_rows_parallel(
+                    &pixels,
+                    stride,
+                    150,
+                    filter_type,
+                    &mut parallel,
+                    &mut ProgressTracker::new(None, 150),
+                )
+                .unwrap();
+            assert_eq!(parallel, sequential);
+        }
+
+        // Whole encodes, including interlaced ones whose passes straddle the threshold
+        for interlace in [false, true] {
+            let options = EncodeOptions {
+                interlace,
+                ..EncodeOptions::default()
+            };
+            let mut encoder =
+                PngEncoder::with_options(97, 150, CompressionMethod::Custom, options).unwrap();
+            encoder.configure_for_image(&image);
+            let pixels = encoder.prepare_pixels(&image);
+            let filtered = encoder.apply_filters(&pixels, None).unwrap();
+            assert_eq!(filtered.len(), encoder.filtered_len().unwrap());
+
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+        }
+    }
+
+    /// Run with `cargo test --release --features parallel -- --ignored --nocapture`.
+    #[cfg(feature = "parallel")]
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_parallel_filtering() {
+        use std::time::Instant;
+
+        let image = noisy_rgb(4096, 4096);
+        let encoder = PngEncoder::new(4096, 4096, CompressionMethod::Flate2);
+        let pixels = encoder.prepare_pixels(&image);
+        let stride = encoder.stride().unwrap();
+
+        let start = Instant::now();
+        let mut sequential = Vec::new();
+        encoder
+            .filter_rows_sequential(
+                &pixels,
+                stride,
+                4096,
+                None,
+                &mut sequential,
+                &mut ProgressTracker::new(None, 4096),
+            )
+            .unwrap();
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut parallel = Vec::new();
+        encoder
+            .filter_rows_parallel(
+                &pixels,
+                stride,
+                4096,
+                None,
+                &mut parallel,
+                &mut ProgressTracker::new(None, 4096),
+            )
+            .unwrap();
+        let parallel_time = start.elapsed();
+
+        assert_eq!(parallel, sequential);
+        eprintln!(
+            "adaptive filtering of 4096x4096 RGB: sequential {:?}, parallel {:?} ({:.1}x on {} threads)",
+            sequential_time,
+            parallel_time,
+            sequential_time.as_secs_f64() / parallel_time.as_secs_f64(),
+            rayon::current_num_threads()
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_output_matches_file_output() {
+        let dir = std::env::temp_dir();
+        let file_path = dir.join("rpng_test_mmap_file.png");
+        let mmap_path = dir.join("rpng_test_mmap_mapped.png");
+
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(40, 30, |x, y| {
+            image::Rgba([(x * 6) as u8, (y * 8) as u8, (x ^ y) as u8, 255])
+        }));
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            save_to_png_with_options(
+                &image,
+                &file_path.to_string_lossy(),
+                method,
+                EncodeOptions::default(),
+            )
+            .unwrap();
+            save_to_png_mmap(
+                &image,
+                &mmap_path.to_string_lossy(),
+                method,
+                EncodeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                std::fs::read(&mmap_path).unwrap(),
+                std::fs::read(&file_path).unwrap()
+            );
+        }
+
+        std::fs::remove_file(&file_path).ok();
+        std::fs::remove_file(&mmap_path).ok();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_save_mmap_new_keeps_existing_file() {
+        let path = std::env::temp_dir().join("rpng_test_mmap_new.png");
+        std::fs::write(&path, b"keep me").unwrap();
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(8, 8));
+        let builder = PngEncoderBuilder::new();
+        match builder.save_mmap_new(&image, &path) {
+            Err(EncoderError::OutputExists(existing)) => assert_eq!(existing, path),
+            other => panic!("expected OutputExists, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep me");
+
+        std::fs::remove_file(&path).unwrap();
+        builder.save_mmap_new(&image, &path).unwrap();
+        assert_eq!(&std::fs::read(&path).unwrap()[..8], &PNG_SIGNATURE);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_capabilities_match_encoder_output() {
+        // Gray levels and palette sizes that need exactly 1, 2, 4 and 8 bits
+        let gray = |level: u8| {
+            DynamicImage::ImageLuma8(image::GrayImage::from_pixel(5, 4, image::Luma([level])))
+        };
+        let colors = |count: u32| {
+            DynamicImage::ImageRgb8(image::RgbImage::from_fn(5, 4, |x, y| {
+                image::Rgb([((y * 5 + x) % count) as u8, 0, 0])
+            }))
+        };
+
+        for &(color_type, bit_depth) in supported_color_types() {
+            let options = EncodeOptions {
+                auto_precision: true,
+                indexed: color_type == 3,
+                interlace: supports_interlace(),
+                ..Default::default()
+            };
+            let image = match (color_type, bit_depth) {
+                (0, 1) => gray(0),
+                (0, 2) => gray(85),
+                (0, 4) => gray(17),
+                (0, 8) => gray(1),
+                (0, 16) => DynamicImage::new_luma16(5, 4),
+                (2, 8) => DynamicImage::new_rgb8(5, 4),
+                (2, 16) => DynamicImage::new_rgb16(5, 4),
+                (3, 1) => colors(2),
+                (3, 2) => colors(3),
+                (3, 4) => colors(5),
+                (3, 8) => colors(17),
+                (4, 8) => DynamicImage::new_luma_a8(5, 4),
+                (4, 16) => DynamicImage::new_luma_a16(5, 4),
+                (6, 8) => DynamicImage::new_rgba8(5, 4),
+                (6, 16) => DynamicImage::new_rgba16(5, 4),
+                _ => panic!("unexpected format {:?}", (color_type, bit_depth)),
+            };
+            let mut encoder =
+                PngEncoder::with_options(5, 4, CompressionMethod::Flate2, options).unwrap();
+            encoder.configure_for_image(&image);
+
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+
+            // IHDR data: width, height, bit depth, color type, compression, filter, interlace
+            assert_eq!((bytes[25], bytes[24]), (color_type, bit_depth));
+            assert_eq!(bytes[28] != 0, supports_interlace());
+            assert!(image::load_from_memory(&bytes).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_apng_chunk_order_and_sequence_numbers() {
+        use image::AnimationDecoder;
+
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(6, 4, image::Rgb([255, 0, 0])));
+        let translucent = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(6, 4, |x, y| {
+            image::Rgba([0, x as u8 * 40, 255, y as u8 * 60])
+        }));
+        let frames = [
+            ApngFrame {
+                image: red,
+                delay_num: 1,
+                delay_den: 2,
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Source,
+            },
+            ApngFrame {
+                image: translucent.clone(),
+                delay_num: 3,
+                delay_den: 4,
+                dispose_op: DisposeOp::Background,
+                blend_op: BlendOp::Source,
+            },
+        ];
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let mut bytes = Vec::new();
+            encode_apng(&frames, &mut bytes, method).unwrap();
+
+            let chunks: Vec<_> = crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .map(|chunk| (chunk.chunk_type, chunk.data.to_vec()))
+                .collect();
+            let types: Vec<&[u8; 4]> = chunks.iter().map(|(chunk_type, _)| chunk_type).collect();
+            assert_eq!(
+                types,
+                [
+                    b"IHDR", b"acTL", b"fcTL", b"IDAT", b"fcTL", b"fdAT", b"IEND"
+                ]
+            );
+            // Two frames, looping forever
+            assert_eq!(chunks[1].1, [0, 0, 0, 2, 0, 0, 0, 0]);
+
+            let sequence = |data: &[u8]| u32::from_be_bytes(data[..4].try_into().unwrap());
+            assert_eq!(sequence(&chunks[2].1), 0);
+            assert_eq!(sequence(&chunks[4].1), 1);
+            assert_eq!(sequence(&chunks[5].1), 2);
+            // Delay and dispose/blend ops of the second frame
+            assert_eq!(&chunks[4].1[20..], &[0, 3, 0, 4, 1, 0]);
+
+            let decoder = image::codecs::png::PngDecoder::new(std::io::Cursor::new(&bytes))
+                .unwrap()
+                .apng()
+                .unwrap();
+            let decoded = decoder.into_frames().collect_frames().unwrap();
+            assert_eq!(decoded.len(), 2);
+            assert!(
+                decoded[0]
+                    .buffer()
+                    .pixels()
+                    .all(|pixel| pixel.0 == [255, 0, 0, 255])
+            );
+            assert_eq!(decoded[1].buffer(), &translucent.to_rgba8());
+        }
+
+        let mismatched = [
+            frames[0].clone(),
+            ApngFrame {
+                image: DynamicImage::new_rgb8(2, 2),
+                ..frames[1].clone()
+            },
+        ];
+        assert!(encode_apng(&mismatched, &mut Vec::new(), CompressionMethod::Flate2).is_err());
+        assert!(encode_apng(&[], &mut Vec::new(), CompressionMethod::Flate2).is_err());
+    }
+
+    #[test]
+    fn test_apng_static_fallback_layout() {
+        use image::AnimationDecoder;
+
+        let solid =
+            |rgb| DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 3, image::Rgb(rgb)));
+        let frame = |rgb| ApngFrame {
+            image: solid(rgb),
+            delay_num: 1,
+            delay_den: 10,
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        };
+        let frames = [frame([255, 0, 0]), frame([0, 255, 0])];
+        let options = ApngOptions {
+            fallback: Some(solid([9, 9, 9])),
+            ..Default::default()
+        };
+
+        let mut bytes = Vec::new();
+        encode_apng_with(&frames, &options, &mut bytes, CompressionMethod::Custom).unwrap();
+
+        let chunks: Vec<_> = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .map(|chunk| (chunk.chunk_type, chunk.data.to_vec()))
+            .collect();
+        let types: Vec<&[u8; 4]> = chunks.iter().map(|(chunk_type, _)| chunk_type).collect();
+        assert_eq!(
+            types,
+            [
+                b"IHDR", b"acTL", b"IDAT", b"fcTL", b"fdAT", b"fcTL", b"fdAT", b"IEND"
+            ]
+        );
+        // acTL counts only the animation frames
+        assert_eq!(chunks[1].1[..4], 2u32.to_be_bytes());
+        // Sequence numbers start at the first fcTL and run without gaps
+        let sequence: Vec<u32> = chunks[3..7]
+            .iter()
+            .map(|(_, data)| u32::from_be_bytes(data[..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(sequence, [0, 1, 2, 3]);
+
+        // Plain PNG decoders show the fallback, APNG decoders only the frames
+        assert_eq!(
+            crate::decoder::decode_png(&bytes).unwrap().to_rgb8(),
+            solid([9, 9, 9]).to_rgb8()
+        );
+        let decoded = image::codecs::png::PngDecoder::new(std::io::Cursor::new(&bytes))
+            .unwrap()
+            .apng()
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].buffer().get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(decoded[1].buffer().get_pixel(0, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_encode_raw_pixels() {
+        let rgba = image::RgbaImage::from_fn(7, 5, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 40, 200, 100 + x as u8])
+        });
+        let mut bytes = Vec::new();
+        let report = encode_raw(
+            7,
+            5,
+            6,
+            8,
+            rgba.as_raw(),
+            PixelOrder::Rgba,
+            CompressionMethod::Custom,
+            &mut bytes,
+        )
+        .unwrap();
+        assert_eq!(report.raw_bytes, 7 * 5 * 4);
+        assert_eq!(image::load_from_memory(&bytes).unwrap().to_rgba8(), rgba);
+
+        // Two-bit gray rows of 7 samples take 2 bytes each
+        let packed = [0b0001_1011, 0b1110_0100, 0b1111_1100, 0];
+        let mut bytes = Vec::new();
+        encode_raw(
+            7,
+            2,
+            0,
+            2,
+            &packed,
+            PixelOrder::Rgba,
+            CompressionMethod::Flate2,
+            &mut bytes,
+        )
+        .unwrap();
+        let gray = image::load_from_memory(&bytes).unwrap().to_luma8();
+        assert_eq!(gray.as_raw()[..7], [0, 85, 170, 255, 255, 170, 85]);
+        assert_eq!(gray.as_raw()[7..], [255, 255, 255, 0, 0, 0, 0]);
+
+        let mut bytes = Vec::new();
+        assert!(matches!(
+            encode_raw(
+                7,
+                5,
+                6,
+                8,
+                &rgba.as_raw()[1..],
+                PixelOrder::Rgba,
+                CompressionMethod::Custom,
+                &mut bytes
+            ),
+            Err(EncoderError::PixelBufferSize {
+                expected: 140,
+                actual: 139
+            })
+        ));
+        assert!(matches!(
+            encode_raw(
+                1,
+                1,
+                3,
+                8,
+                &[0],
+                PixelOrder::Rgba,
+                CompressionMethod::Custom,
+                &mut bytes
+            ),
+            Err(EncoderError::InvalidOptions(_))
+        ));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_encode_raw_bgra_pixels() {
+        let rgba = image::RgbaImage::from_fn(6, 4, |x, y| {
+            image::Rgba([x as u8 * 40, y as u8 * 60, 10, 128 + x as u8])
+        });
+        let bgra: Vec<u8> = rgba
+            .pixels()
+            .flat_map(|&image::Rgba([r, g, b, a])| [b, g, r, a])
+            .collect();
+        let mut bytes = Vec::new();
+        encode_raw(
+            6,
+            4,
+            6,
+            8,
+            &bgra,
+            PixelOrder::Bgra,
+            CompressionMethod::Custom,
+            &mut bytes,
+        )
+        .unwrap();
+        assert_eq!(image::load_from_memory(&bytes).unwrap().to_rgba8(), rgba);
+
+        // 16-bit BGR swaps whole two-byte samples
+        let bgr16 = [0x00, 0x03, 0x00, 0x02, 0x00, 0x01];
+        let mut bytes = Vec::new();
+        encode_raw(
+            1,
+            1,
+            2,
+            16,
+            &bgr16,
+            PixelOrder::Bgra,
+            CompressionMethod::Flate2,
+            &mut bytes,
+        )
+        .unwrap();
+        let rgb16 = image::load_from_memory(&bytes).unwrap().to_rgb16();
+        assert_eq!(rgb16.get_pixel(0, 0).0, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_encode_raw_u16_converts_endianness() {
+        // 0x1234 and 0xABCD as a little-endian buffer stores them
+        let little = [0x34, 0x12, 0xCD, 0xAB];
+        let big = [0x12, 0x34, 0xAB, 0xCD];
+        let view = |bytes: &[u8]| -> Vec<u16> {
+            bytes
+                .chunks_exact(2)
+                .map(|pair| u16::from_ne_bytes([pair[0], pair[1]]))
+                .collect()
+        };
+
+        for (buffer, endian) in [(little, SampleEndian::Little), (big, SampleEndian::Big)] {
+            let mut bytes = Vec::new();
+            encode_raw_u16(
+                2,
+                1,
+                0,
+                &view(&buffer),
+                endian,
+                CompressionMethod::Custom,
+                &mut bytes,
+            )
+            .unwrap();
+            let gray = image::load_from_memory(&bytes).unwrap().to_luma16();
+            assert_eq!(gray.as_raw(), &[0x1234, 0xABCD]);
+        }
+    }
+
+    #[test]
+    fn test_encode_to_writer() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(5, 3, |x, y| {
+            image::Rgb([x as u8 * 50, y as u8 * 80, 3])
+        }));
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let mut writer = std::io::BufWriter::new(Vec::new());
+            let report = encode_to_writer(&image, &mut writer, method).unwrap();
+            let bytes = writer.into_inner().unwrap();
+
+            assert_eq!(&bytes[..8], &PNG_SIGNATURE);
+            assert_eq!(bytes, encode_to_vec(&image, method).unwrap());
+            assert!(report.idat_bytes > 0 && report.idat_bytes < bytes.len());
+            assert_eq!(
+                image::load_from_memory(&bytes).unwrap().to_rgb8(),
+                image.to_rgb8()
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_to_vec_round_trip() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(7, 5, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 40, 200, 128])
+        }));
+
+        let bytes = encode_to_vec(&image, CompressionMethod::Flate2).unwrap();
+        assert!(bytes.starts_with(&PNG_SIGNATURE));
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().to_rgba8(),
+            image.to_rgba8()
+        );
+    }
+
+    #[test]
+    fn test_grayscale_round_trip() {
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::from_fn(9, 4, |x, y| {
+            image::Luma([(x * 25 + y) as u8])
+        }));
+        let bytes = encode_to_vec(&gray, CompressionMethod::Flate2).unwrap();
+        assert_eq!(bytes[25], 0);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageLuma8(_)));
+        assert_eq!(decoded.as_bytes(), gray.as_bytes());
+
+        let gray_alpha = DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_fn(9, 4, |x, y| {
+            image::LumaA([(x * 25) as u8, (y * 60) as u8])
+        }));
+        let bytes = encode_to_vec(&gray_alpha, CompressionMethod::Flate2).unwrap();
+        assert_eq!(bytes[25], 4);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageLumaA8(_)));
+        assert_eq!(decoded.as_bytes(), gray_alpha.as_bytes());
+    }
+
+    #[test]
+    fn test_rgb_round_trip_smaller_than_rgba() {
+        let rgb = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, ((x * y) % 251) as u8])
+        }));
+        let rgba = DynamicImage::ImageRgba8(rgb.to_rgba8());
+
+        let rgb_bytes = encode_to_vec(&rgb, CompressionMethod::Flate2).unwrap();
+        let rgba_bytes = encode_to_vec(&rgba, CompressionMethod::Flate2).unwrap();
+        assert_eq!((rgb_bytes[25], rgba_bytes[25]), (2, 6));
+        assert!(rgb_bytes.len() < rgba_bytes.len());
+
+        let decoded = image::load_from_memory(&rgb_bytes).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageRgb8(_)));
+        assert_eq!(decoded.as_bytes(), rgb.as_bytes());
+    }
+
+    /// Encodes `image` with `strategy` into memory using flate2.
+    fn encode_with_strategy(image: &DynamicImage, strategy: FilterStrategy) -> Vec<u8> {
+        let options = EncodeOptions {
+            filter_strategy: strategy,
+            ..Default::default()
+        };
+        let mut encoder = PngEncoder::with_options(
+            image.width(),
+            image.height(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+        encoder.configure_for_image(image);
+        let mut bytes = Vec::new();
+        encoder.encode(image, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// Cheap deterministic per-index noise for test images.
+    fn noise(i: u32) -> u8 {
+        (i.wrapping_mul(2_654_435_761) >> 24) as u8
+    }
+
+    #[test]
+    fn test_all_filters_round_trip() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(17, 11, |x, y| {
+            image::Rgba([noise(x * 31 + y), (x * 9 + y * 5) as u8, noise(y), 200])
+        }));
+
+        for strategy in [
+            FilterStrategy::None,
+            FilterStrategy::Sub,
+            FilterStrategy::Up,
+            FilterStrategy::Average,
+            FilterStrategy::Paeth,
+            FilterStrategy::Adaptive,
+        ] {
+            let bytes = encode_with_strategy(&image, strategy);
+            assert_eq!(
+                image::load_from_memory(&bytes).unwrap().to_rgba8(),
+                image.to_rgba8(),
+                "{:?} did not round-trip",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn test_up_beats_sub_on_vertical_gradient() {
+        // Every row is the same noisy pattern brightened by one step per row
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let value = noise(x).wrapping_add(y as u8);
+            image::Rgb([value, value.wrapping_mul(3), noise(x + 1000)])
+        }));
+
+        let up = encode_with_strategy(&image, FilterStrategy::Up);
+        let sub = encode_with_strategy(&image, FilterStrategy::Sub);
+        assert!(up.len() < sub.len(), "Up {} vs Sub {}", up.len(), sub.len());
+
+        let adaptive = encode_with_strategy(&image, FilterStrategy::Adaptive);
+        assert!(adaptive.len() < sub.len());
+    }
+
+    #[test]
+    fn test_sub_beats_up_on_horizontal_gradient() {
+        // Each row ramps left to right with its own random slope
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let value = (x as u8).wrapping_mul(noise(y) | 1);
+            image::Rgb([value, value, value.wrapping_add(17)])
+        }));
+
+        let up = encode_with_strategy(&image, FilterStrategy::Up);
+        let sub = encode_with_strategy(&image, FilterStrategy::Sub);
+        assert!(sub.len() < up.len(), "Sub {} vs Up {}", sub.len(), up.len());
+    }
+
+    #[test]
+    fn test_decompress_error_variants() {
+        let encoder = PngEncoder::new(4, 4, CompressionMethod::Custom);
+
+        assert!(matches!(
+            encoder.decompress_data(&[0x78]),
+            Err(EncoderError::Truncated)
+        ));
+        assert!(matches!(
+            encoder.decompress_data(&[0x78, 0x01, 1, 0, 0, 0, 0]),
+            Err(EncoderError::InvalidZlibHeader)
+        ));
+
+        let mut compressed = encoder.compress_data(b"checksum").unwrap();
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        let err = encoder.decompress_data(&compressed).unwrap_err();
+        assert!(matches!(
+            err,
+            EncoderError::ChecksumMismatch { expected, actual } if expected == actual ^ 1
+        ));
+
+        let io_error: EncoderError = std::io::Error::other("disk full").into();
+        assert!(std::error::Error::source(&io_error).is_some());
+    }
+
+    fn tokens(encoder: &PngEncoder, data: &[u8]) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        encoder
+            .tokenize(data, 0, |token| tokens.push(token))
+            .unwrap();
+        tokens
+    }
+
+    #[test]
+    fn test_long_run_uses_maximum_length() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let run = vec![9u8; 300];
+
+        // One literal, a maximal 258-byte back-reference and one for the remaining 41
+        assert_eq!(
+            tokens(&encoder, &run),
+            vec![
+                Token::Literal(9),
+                Token::Match {
+                    length: 258,
+                    distance: 1
+                },
+                Token::Match {
+                    length: 41,
+                    distance: 1
+                },
+            ]
+        );
+        let deflated = encoder.simple_deflate(&run).unwrap();
+        assert_eq!(encoder.simple_inflate(&deflated).unwrap(), run);
+        assert!(encoder.test_compression(&run));
+    }
+
+    #[test]
+    fn test_distance_with_extra_bits() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+
+        // 255 distinct bytes repeated once, so the only useful match is at distance 255,
+        // which needs distance code 15 and six extra bits
+        let block: Vec<u8> = (0..255).map(|i| (i * 7 % 255) as u8).collect();
+        let data = [block.clone(), block].concat();
+
+        let tokens = tokens(&encoder, &data);
+        assert_eq!(
+            tokens[255..],
+            [Token::Match {
+                length: 255,
+                distance: 255
+            }]
+        );
+        assert_eq!(distance_code(255), (15, 6, 62));
+        let deflated = encoder.simple_deflate(&data).unwrap();
+        assert_eq!(encoder.simple_inflate(&deflated).unwrap(), data);
+        assert!(encoder.test_compression(&data));
+    }
+
+    /// Size of `data` in the previous byte-oriented format: literals as bytes with 255
+    /// escaped as `255, 255`, and back-references as `255, distance_high, distance_low,
+    /// length`, taken only where they beat the literals they replace.
+    fn escape_scheme_len(encoder: &PngEncoder, data: &[u8]) -> usize {
+        let literal_len = |byte: &u8| if *byte == 255 { 2 } else { 1 };
+        let mut len = 6;
+        let mut pos = 0;
+        for token in tokens(encoder, data) {
+            match token {
+                Token::Literal(byte) => {
+                    len += literal_len(&byte);
+                    pos += 1;
+                }
+                Token::Match { length, .. } => {
+                    let covered = &data[pos..pos + length as usize];
+                    len += covered.iter().map(literal_len).sum::<usize>().min(4);
+                    pos += length as usize;
+                }
+            }
+        }
+        len
+    }
+
+    #[test]
+    fn test_fixed_huffman_beats_escape_scheme() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+
+        let text = "It was the best of times, it was the worst of times, it was the age of \
+                    wisdom, it was the age of foolishness, it was the epoch of belief, it was \
+                    the epoch of incredulity, it was the season of Light, it was the season of \
+                    Darkness, it was the spring of hope, it was the winter of despair, we had \
+                    everything before us, we had nothing before us, we were all going direct \
+                    to Heaven, we were all going direct the other way.";
+        let compressed = encoder.compress_data(text.as_bytes()).unwrap();
+        let escaped = escape_scheme_len(&encoder, text.as_bytes());
+        assert!(
+            compressed.len() < escaped,
+            "text: Huffman {} vs escape {}",
+            compressed.len(),
+            escaped
+        );
+        assert!(compressed.len() < text.len());
+
+        // A photo-like image: smooth shading with sensor noise, Sub-filtered as by default
+        let mut state: u32 = 0x2468ACE1;
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 128, |x, y| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = state >> 29;
+            let shade = |base: u32| (base + noise).min(255) as u8;
+            image::Rgb([shade(x + y / 2), shade(64 + y), shade(200 - x / 2)])
+        }));
+        let mut encoder = PngEncoder::new(128, 128, CompressionMethod::Custom);
+        encoder.configure_for_image(&image);
+        let filtered = encoder
+            .apply_filters(&encoder.prepare_pixels(&image), Some(FilterType::Sub))
+            .unwrap();
+        let compressed = encoder.compress_data(&filtered).unwrap();
+        let escaped = escape_scheme_len(&encoder, &filtered);
+        assert!(
+            compressed.len() < escaped,
+            "image: Huffman {} vs escape {}",
+            compressed.len(),
+            escaped
+        );
+        assert_eq!(encoder.decompress_data(&compressed).unwrap(), filtered);
+    }
+
+    #[test]
+    fn test_custom_stream_inflates_with_flate2() {
+        use flate2::read::ZlibDecoder;
+        use std::io::Read;
+
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let mut data = Vec::new();
+        for i in 0..40000u32 {
+            data.push((i % 251) as u8 ^ (i / 997) as u8);
+        }
+        data.extend_from_slice(&[255; 600]);
+
+        for compressed in [
+            encoder.compress_data(&data).unwrap(),
+            encoder.compress_data_with_effort(&data, true).unwrap(),
+        ] {
+            let mut inflated = Vec::new();
+            ZlibDecoder::new(&compressed[..])
+                .read_to_end(&mut inflated)
+                .unwrap();
+            assert_eq!(inflated, data);
+        }
+    }
+
+    #[test]
+    fn test_stored_blocks_for_incompressible_data() {
+        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+        let mut state: u32 = 0x9E3779B9;
+        let data: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+
+        // Fixed Huffman would spend nine bits on half of these bytes; stored blocks
+        // only add five bytes for each block of up to BLOCK_TOKENS tokens
+        let compressed = encoder.compress_data(&data).unwrap();
+        let blocks = data.len().div_ceil(BLOCK_TOKENS);
+        assert!(compressed.len() <= data.len() + 6 + blocks * 5);
+        assert_eq!(encoder.decompress_data(&compressed).unwrap(), data);
+
+        let mut stored = encoder.literal_deflate(b"stored");
+        assert_eq!(stored[..5], [0b001, 6, 0, !6, 0xFF]);
+        assert_eq!(encoder.simple_inflate(&stored).unwrap(), b"stored");
+        stored[3] ^= 1;
+        assert!(matches!(
+            encoder.simple_inflate(&stored),
+            Err(EncoderError::StoredLengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_zlib_header_check_bits() {
+        assert_eq!(CUSTOM_ZLIB_HEADER, [0x78, 0x9C]);
+        assert_eq!(STORED_ZLIB_HEADER, [0x78, 0x01]);
+
+        for cinfo in 0..=7u8 {
+            let cmf = cinfo << 4 | 8;
+            for flevel in 0..=3u8 {
+                for dictionary in [false, true] {
+                    let [header_cmf, flg] = zlib_header(cmf, flevel, dictionary);
+                    assert_eq!(header_cmf, cmf);
+                    assert_eq!(flg >> 6, flevel);
+                    assert_eq!(flg & 0x20 != 0, dictionary);
+                    assert_eq!(u16::from_be_bytes([cmf, flg]) % 31, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_preset_dictionary_warm_start() {
+        let dictionary: &[u8] = b"tile row: sky sky sky cloud cloud grass grass water edge";
+        let data = b"tile row: grass grass water edge sky cloud";
+
+        let plain = PngEncoder::new(16, 16, CompressionMethod::Custom);
+        let without = plain.compress_data(data).unwrap();
+        let mut seeded = PngEncoder::new(16, 16, CompressionMethod::Custom);
+        seeded.set_dictionary(dictionary);
+        let with = seeded.compress_data(data).unwrap();
+
+        assert!(
+            with.len() < without.len(),
+            "{} vs {}",
+            with.len(),
+            without.len()
+        );
+        assert_eq!(with[..2], DICTIONARY_ZLIB_HEADER);
+        assert_eq!(with[2..6], adler32_update(1, dictionary).to_be_bytes());
+        assert_eq!(seeded.decompress_data(&with).unwrap(), data);
+        // Streams without a dictionary still decode with one set
+        assert_eq!(seeded.decompress_data(&without).unwrap(), data);
+
+        let id = adler32_update(1, dictionary);
+        assert!(matches!(
+            plain.decompress_data(&with),
+            Err(EncoderError::MissingDictionary(found)) if found == id
+        ));
+        let mut other = PngEncoder::new(16, 16, CompressionMethod::Custom);
+        other.set_dictionary(b"something else entirely");
+        assert!(matches!(
+            other.decompress_data(&with),
+            Err(EncoderError::MissingDictionary(_))
+        ));
+    }
+
+    #[test]
+    fn test_zlib_with_dictionary_round_trips() {
+        let dictionary = b"GET /api/v1/images HTTP/1.1\r\nAccept: image/png\r\n".repeat(4);
+        let request = b"GET /api/v1/images HTTP/1.1\r\nAccept: image/png\r\nHost: a\r\n";
+
+        let with = compress_zlib_with_dictionary(request, &dictionary).unwrap();
+        let without = compress_zlib_with_dictionary(request, &[]).unwrap();
+        assert!(with.len() < without.len());
+        assert_eq!(with[..2], DICTIONARY_ZLIB_HEADER);
+        assert_eq!(without[..2], CUSTOM_ZLIB_HEADER);
+
+        assert_eq!(
+            decompress_zlib_with_dictionary(&with, &dictionary).unwrap(),
+            request
+        );
+        assert_eq!(
+            decompress_zlib_with_dictionary(&without, &[]).unwrap(),
+            request
+        );
+        assert!(matches!(
+            decompress_zlib_with_dictionary(&with, b"wrong"),
+            Err(EncoderError::MissingDictionary(_))
+        ));
+    }
+
+    #[test]
+    fn test_stored_method_round_trips() {
+        // Larger than one stored block, and noisy enough that the filter choice matters
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 150, |x, y| {
+            image::Rgb([(x * 7 + y) as u8, (x ^ y) as u8, (x * y) as u8])
+        }));
+        let filtered_len = 150 * (1 + 200 * 3);
+
+        for streaming in [false, true] {
+            let mut bytes = Vec::new();
+            let report = PngEncoderBuilder::new()
+                .compression(CompressionMethod::Stored)
+                .options(EncodeOptions {
+                    streaming,
+                    ..EncodeOptions::default()
+                })
+                .encode(&image, &mut bytes)
+                .unwrap();
+
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+            assert_eq!(
+                crate::decode_png(&bytes).unwrap().to_rgb8(),
+                image.to_rgb8()
+            );
+
+            let idat = crate::decoder::collect_idat(
+                &bytes,
+                crate::decoder::DecodeMode::Strict,
+                &crate::decoder::DecodeLimits::default(),
+            )
+            .unwrap()
+            .data;
+            assert_eq!(idat[..2], STORED_ZLIB_HEADER);
+            assert_eq!(report.idat_bytes, idat.len());
+            if !streaming {
+                assert_eq!(idat.len(), stored_zlib_len(filtered_len));
+            }
+        }
+
+        let encoder = PngEncoder::new(1, 1, CompressionMethod::Stored);
+        assert_eq!(
+            encoder.compress_data(&[]).unwrap(),
+            [0x78, 0x01, 0b001, 0, 0, 0xFF, 0xFF, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_custom_png_decodes_with_image_crate() {
+        let smooth = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(96, 64, |x, y| {
+            image::Rgba([(x * 2) as u8, (y * 3) as u8, 128, (255 - x) as u8])
+        }));
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([noise(x * 64 + y), noise(y * 64 + x), noise(x ^ y)])
+        }));
+        let indexed = DynamicImage::ImageRgb8(image::RgbImage::from_fn(37, 23, |x, y| {
+            [
+                image::Rgb([255, 0, 0]),
+                image::Rgb([0, 0, 255]),
+                image::Rgb([9, 9, 9]),
+            ][((x / 3 + y) % 3) as usize]
+        }));
+
+        let builders = [
+            (smooth.clone(), PngEncoderBuilder::new()),
+            (
+                noisy.clone(),
+                PngEncoderBuilder::new().filter_strategy(FilterStrategy::AdaptiveFast),
+            ),
+            (
+                indexed.clone(),
+                PngEncoderBuilder::new()
+                    .interlace(true)
+                    .options(EncodeOptions {
+                        indexed: true,
+                        ..EncodeOptions::default()
+                    }),
+            ),
+            (
+                smooth,
+                PngEncoderBuilder::new().options(EncodeOptions {
+                    streaming: true,
+                    ..EncodeOptions::default()
+                }),
+            ),
+        ];
+
+        for (image, builder) in builders {
+            let mut png = Vec::new();
+            builder
+                .compression(CompressionMethod::Custom)
+                .encode(&image, &mut png)
+                .unwrap();
+            let decoded = image::load_from_memory(&png).unwrap();
+            assert_eq!(decoded.to_rgba8(), image.to_rgba8());
+        }
+    }
+
+    #[test]
+    fn test_hash_chain_speed_and_round_trip() {
+        use std::time::{Duration, Instant};
+
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(256, 256, |x, y| {
+            image::Rgba([(x ^ y) as u8, (x * 3) as u8, (y / 4) as u8, 255])
+        }));
+        let encoder = PngEncoder::new(256, 256, CompressionMethod::Custom);
+
+        let start = Instant::now();
+        let mut output = Vec::new();
+        encoder.encode(&image, &mut output).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(20));
+
+        let filtered = encoder
+            .apply_filters(image.as_bytes(), Some(FilterType::Sub))
+            .unwrap();
+        let compressed = encoder.compress_data(&filtered).unwrap();
+        assert!(compressed.len() < filtered.len());
+        assert_eq!(encoder.decompress_data(&compressed).unwrap(), filtered);
+    }
+
+    #[test]
+    fn test_sixteen_bit_gradients_round_trip() {
+        let options = EncodeOptions {
+            auto_precision: true,
+            filter_strategy: FilterStrategy::Paeth,
+            ..Default::default()
+        };
+        let rgb = DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(20, 12, |x, y| {
+            image::Rgb([
+                x as u16 * 3000,
+                y as u16 * 5000 + 7,
+                0xFFFF - x as u16 * 257,
+            ])
+        }));
+        let gray = DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(20, 12, |x, y| {
+            image::Luma([(x * 12 + y) as u16 * 271])
+        }));
+
+        for (image, color_type) in [(rgb, 2), (gray, 0)] {
+            let mut encoder = PngEncoder::with_options(
+                image.width(),
+                image.height(),
+                CompressionMethod::Flate2,
+                options.clone(),
+            )
+            .unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+
+            assert_eq!((bytes[24], bytes[25]), (16, color_type));
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            assert_eq!(decoded.color(), image.color());
+            assert_eq!(decoded.as_bytes(), image.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_indexed_four_colors_round_trip() {
+        let colors = [[200, 30, 30], [30, 200, 30], [30, 30, 200], [240, 240, 240]];
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb(colors[((x / 8 + y / 4) % 4) as usize])
+        }));
+        let options = EncodeOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(64, 64, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut indexed = Vec::new();
+        encoder.encode(&image, &mut indexed).unwrap();
+        let truecolor = encode_to_vec(&image, CompressionMethod::Flate2).unwrap();
+
+        assert_eq!((indexed[24], indexed[25]), (2, 3));
+        assert_eq!(encoder.palette.len(), 4);
+        assert!(indexed.len() < truecolor.len());
+        let decoded = image::load_from_memory(&indexed).unwrap();
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn test_auto_strip_opaque_alpha() {
+        let mut rgba = image::RgbaImage::from_fn(24, 24, |x, y| {
+            image::Rgba([(x * 10) as u8, (y * 10) as u8, 128, 255])
+        });
+        let options = EncodeOptions {
+            auto_strip_opaque_alpha: true,
+            ..Default::default()
+        };
+        let encode = |image: &DynamicImage| {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(CompressionMethod::Flate2)
+                .options(options.clone())
+                .encode(image, &mut bytes)
+                .unwrap();
+            bytes
+        };
+
+        let opaque = DynamicImage::ImageRgba8(rgba.clone());
+        let bytes = encode(&opaque);
+        assert_eq!((bytes[24], bytes[25]), (8, 2));
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().to_rgba8(),
+            opaque.to_rgba8()
+        );
+        // Off by default
+        let bytes = encode_to_vec(&opaque, CompressionMethod::Flate2).unwrap();
+        assert_eq!(bytes[25], 6);
+
+        rgba.put_pixel(5, 7, image::Rgba([0, 0, 0, 254]));
+        let translucent = DynamicImage::ImageRgba8(rgba);
+        let bytes = encode(&translucent);
+        assert_eq!((bytes[24], bytes[25]), (8, 6));
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().to_rgba8(),
+            translucent.to_rgba8()
+        );
+
+        let gray = DynamicImage::ImageLumaA16(image::ImageBuffer::from_pixel(
+            4,
+            4,
+            image::LumaA([1000u16, u16::MAX]),
+        ));
+        assert_eq!(encode(&gray)[25], 0);
+    }
+
+    #[test]
+    fn test_premultiplied_alpha_is_unpremultiplied() {
+        // (premultiplied source, expected straight output)
+        let cases = [
+            ([100, 50, 25, 128], [199, 100, 50, 128]),
+            ([32, 16, 8, 64], [128, 64, 32, 64]),
+            ([10, 20, 30, 255], [10, 20, 30, 255]),
+            ([0, 0, 0, 0], [0, 0, 0, 0]),
+            ([1, 2, 3, 3], [85, 170, 255, 3]),
+            // Colors above alpha are invalid input; they clamp instead of wrapping
+            ([100, 0, 0, 50], [255, 0, 0, 50]),
+        ];
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(6, 3, |x, _| {
+            image::Rgba(cases[x as usize].0)
+        }));
+
+        for streaming in [false, true] {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .options(EncodeOptions {
+                    streaming,
+                    ..Default::default()
+                })
+                .alpha_mode(AlphaMode::Premultiplied)
+                .encode(&image, &mut bytes)
+                .unwrap();
+            let decoded = image::load_from_memory(&bytes).unwrap().to_rgba8();
+            for (x, (_, expected)) in cases.iter().enumerate() {
+                let pixel = decoded.get_pixel(x as u32, 2).0;
+                for (actual, expected) in pixel.iter().zip(expected) {
+                    assert!(actual.abs_diff(*expected) <= 1, "{pixel:?} vs {expected:?}");
                 }
-                i += 1;
             }
         }
 
-        result
+        // 16-bit gray+alpha
+        let image = DynamicImage::ImageLumaA16(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::LumaA([0x4000u16, 0x8000]),
+        ));
+        let mut bytes = Vec::new();
+        PngEncoderBuilder::new()
+            .options(EncodeOptions {
+                alpha_mode: AlphaMode::Premultiplied,
+                auto_precision: true,
+                ..Default::default()
+            })
+            .encode(&image, &mut bytes)
+            .unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap().to_luma_alpha16();
+        assert_eq!(decoded.get_pixel(1, 1).0, [0x8000, 0x8000]);
+
+        let options = EncodeOptions {
+            alpha_mode: AlphaMode::Premultiplied,
+            indexed: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            PngEncoder::with_options(1, 1, CompressionMethod::Custom, options),
+            Err(EncoderError::InvalidOptions(_))
+        ));
+        assert!(matches!(
+            PngEncoderBuilder::new()
+                .color_type(3)
+                .alpha_mode(AlphaMode::Premultiplied)
+                .encode(&image, &mut Vec::new()),
+            Err(EncoderError::InvalidOptions(_))
+        ));
     }
 
-    fn find_longest_match(&self, data: &[u8], pos: usize) -> (usize, usize) {
-        let mut best_distance = 0;
-        let mut best_length = 0;
-        let max_distance = std::cmp::min(pos, 32768);
-        let max_length = std::cmp::min(258, data.len() - pos);
+    #[test]
+    fn test_float_sources_map_explicitly() {
+        let hdr = image::Rgb32FImage::from_fn(3, 2, |x, y| {
+            let value = [0.0, 0.5, 1.0][x as usize];
+            image::Rgb([value, value, if y == 1 { 1.5 } else { value }])
+        });
+        let encode = |image: &DynamicImage, float_mapping, streaming| {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .options(EncodeOptions {
+                    streaming,
+                    ..Default::default()
+                })
+                .float_mapping(float_mapping)
+                .encode(image, &mut bytes)
+                .map(|_| image::load_from_memory(&bytes).unwrap().to_rgb8())
+        };
 
-        for distance in 1..=max_distance {
-            let start = pos - distance;
-            let mut length = 0;
+        // In-range samples scale linearly; HDR values are refused instead of clamped
+        let in_range = DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(3, 1, |x, _| {
+            *hdr.get_pixel(x, 0)
+        }));
+        let decoded = encode(&in_range, FloatMapping::Strict, false).unwrap();
+        assert_eq!(decoded.as_raw(), &[0, 0, 0, 128, 128, 128, 255, 255, 255]);
+        let hdr = DynamicImage::ImageRgb32F(hdr);
+        for streaming in [false, true] {
+            assert!(matches!(
+                encode(&hdr, FloatMapping::Strict, streaming),
+                Err(EncoderError::FloatOutOfRange(1.5))
+            ));
+        }
 
-            while length < max_length
-                && pos + length < data.len()
-                && data[start + (length % distance)] == data[pos + length]
-            {
-                length += 1;
-            }
+        let clamped = encode(&hdr, FloatMapping::Clamp, false).unwrap();
+        assert_eq!(clamped.get_pixel(0, 1).0, [0, 0, 255]);
+        assert_eq!(clamped.get_pixel(1, 1).0, [128, 128, 255]);
 
-            if length > best_length {
-                best_length = length;
-                best_distance = distance;
-            }
-        }
+        // 0.5 ^ (1 / 2.2) = 0.7297
+        let gamma = encode(&hdr, FloatMapping::Gamma(2.2), false).unwrap();
+        assert_eq!(gamma.get_pixel(1, 0).0, [186, 186, 186]);
+        assert_eq!(gamma.get_pixel(2, 1).0, [255, 255, 255]);
 
-        (best_distance, best_length)
+        assert!(matches!(
+            encode(&hdr, FloatMapping::Gamma(0.0), false),
+            Err(EncoderError::InvalidOptions(_))
+        ));
     }
 
-    fn adler32(&self, data: &[u8]) -> u32 {
-        let mut a: u32 = 1;
-        let mut b: u32 = 0;
-        const MOD_ADLER: u32 = 65521;
+    #[test]
+    fn test_encoding_is_reproducible() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(96, 80, |x, y| {
+            image::Rgba([
+                (x * y) as u8,
+                (x ^ y) as u8,
+                noise(x + y * 96),
+                200 + (x % 5) as u8,
+            ])
+        }));
+        let few_colors = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(40, 40, |x, y| {
+            let level = ((x / 5 + y / 8) % 6) as u8 * 40;
+            image::Rgba([level, 255 - level, 90, if x < 3 { 128 } else { 255 }])
+        }));
+        let encode = |image: &DynamicImage, compression, options: EncodeOptions| {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(compression)
+                .options(options)
+                .encode(image, &mut bytes)
+                .unwrap();
+            bytes
+        };
 
-        for &byte in data {
-            a = (a + byte as u32) % MOD_ADLER;
-            b = (b + a) % MOD_ADLER;
+        let configurations = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                filter_strategy: FilterStrategy::Adaptive,
+                interlace: true,
+                text: vec![TextChunk::Latin1 {
+                    keyword: "Title".to_string(),
+                    text: "tiles".to_string(),
+                }],
+                ..Default::default()
+            },
+            EncodeOptions {
+                indexed: true,
+                streaming: true,
+                ..Default::default()
+            },
+        ];
+        for compression in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            for options in &configurations {
+                for image in [&image, &few_colors] {
+                    assert_eq!(
+                        encode(image, compression, options.clone()),
+                        encode(image, compression, options.clone())
+                    );
+                }
+            }
         }
 
-        (b << 16) | a
+        // The palette depends on the colors used, not on where they first appear
+        let indexed = EncodeOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        let plte = |image: &DynamicImage| {
+            let bytes = encode(image, CompressionMethod::Flate2, indexed.clone());
+            crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .find(|chunk| &chunk.chunk_type == b"PLTE")
+                .unwrap()
+                .data
+                .to_vec()
+        };
+        assert_eq!(plte(&few_colors), plte(&few_colors.fliph().flipv()));
     }
 
-    fn decompress_data(&self, compressed: &[u8]) -> std::io::Result<Vec<u8>> {
-        if compressed.len() < 6 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Compressed data too short",
-            ));
-        }
+    #[test]
+    fn test_palette_rejects_too_many_colors() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(257, 1, |x, _| {
+            image::Rgb([x as u8, (x >> 8) as u8, 0])
+        }));
+        assert!(matches!(
+            build_palette(&image),
+            Err(EncoderError::TooManyColors)
+        ));
+        assert_eq!(
+            build_palette(&image.crop_imm(0, 0, 256, 1)).unwrap().len(),
+            256
+        );
 
-        if compressed[0] != 0x78 || compressed[1] != 0x9C {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid zlib header",
-            ));
-        }
+        // Too many colors falls back to truecolor
+        let options = EncodeOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(257, 1, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        assert_eq!(encoder.color_type, 2);
+    }
 
-        // Extract deflate data (skip 2-byte header, 4-byte adler32 checksum)
-        let deflate_data = &compressed[2..compressed.len() - 4];
+    /// Inflates the IDAT data of a flate2-compressed PNG.
+    fn inflate_idat(bytes: &[u8]) -> Vec<u8> {
+        use crate::decoder::{DecodeLimits, DecodeMode, collect_idat};
+        use std::io::Read;
 
-        let decompressed = self.simple_inflate(deflate_data)?;
+        let idat = collect_idat(bytes, DecodeMode::Strict, &DecodeLimits::default())
+            .unwrap()
+            .data;
+        let mut filtered = Vec::new();
+        flate2::read::ZlibDecoder::new(&idat[..])
+            .read_to_end(&mut filtered)
+            .unwrap();
+        filtered
+    }
 
-        let expected_checksum = u32::from_be_bytes([
-            compressed[compressed.len() - 4],
-            compressed[compressed.len() - 3],
-            compressed[compressed.len() - 2],
-            compressed[compressed.len() - 1],
-        ]);
+    #[test]
+    fn test_one_bit_checkerboard_round_trip() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(13, 10, |x, y| {
+            image::Luma([if (x + y) % 2 == 0 { 0 } else { 255 }])
+        }));
+        let bytes = encode_with_strategy(&image, FilterStrategy::None);
 
-        let actual_checksum = self.adler32(&decompressed);
-        if actual_checksum != expected_checksum {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Adler32 checksum mismatch",
-            ));
-        }
+        assert_eq!((bytes[24], bytes[25]), (1, 0));
+        // 13 pixels pack into two bytes per row, plus the filter byte
+        let filtered = inflate_idat(&bytes);
+        assert_eq!(filtered.len(), 10 * (1 + 2));
+        assert_eq!(&filtered[..3], &[0, 0b0101_0101, 0b0101_0000]);
 
-        Ok(decompressed)
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(decoded.to_luma8(), image.to_luma8());
     }
 
-    fn simple_inflate(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
-        let mut result = Vec::new();
-        let mut i = 0;
-
-        while i < data.len() {
-            if data[i] == 255 {
-                if i + 1 >= data.len() {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Unexpected end of data",
-                    ));
-                }
+    #[test]
+    fn test_two_bit_gray_round_trip() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(7, 5, |x, y| {
+            image::Luma([((x + y) % 4) as u8 * 85])
+        }));
+        let bytes = encode_with_strategy(&image, FilterStrategy::Adaptive);
 
-                if data[i + 1] == 255 {
-                    // Escaped literal 255
-                    result.push(255);
-                    i += 2;
-                } else {
-                    // Back-reference: 255, distance_low, distance_high, length
-                    if i + 3 >= data.len() {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Incomplete back-reference",
-                        ));
-                    }
+        assert_eq!((bytes[24], bytes[25]), (2, 0));
+        // 7 pixels at 2 bits need two bytes per row, plus the filter byte
+        assert_eq!(inflate_idat(&bytes).len(), 5 * (1 + 2));
 
-                    let distance = (data[i + 1] as usize) | ((data[i + 2] as usize) << 8);
-                    let length = data[i + 3] as usize;
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(decoded.to_luma8(), image.to_luma8());
+    }
 
-                    if distance == 0 || distance > result.len() || length == 0 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Invalid back-reference parameters",
-                        ));
-                    }
+    #[test]
+    fn test_idat_split_into_bounded_chunks() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([noise(y * 64 + x), noise(x * 7 + y), 90])
+        }));
+        let options = EncodeOptions {
+            max_idat_chunk_size: Some(256),
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(64, 64, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
 
-                    // Copy from back-reference
-                    let start_pos = result.len() - distance;
-                    for j in 0..length {
-                        let src_idx = start_pos + (j % distance);
-                        let byte = result[src_idx];
-                        result.push(byte);
-                    }
+        let idat_sizes: Vec<usize> = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .filter(|chunk| &chunk.chunk_type == b"IDAT")
+            .map(|chunk| chunk.data.len())
+            .collect();
+        assert!(idat_sizes.len() > 3);
+        assert!(idat_sizes.iter().all(|&size| size <= 256));
+        assert!(
+            idat_sizes[..idat_sizes.len() - 1]
+                .iter()
+                .all(|&size| size == 256)
+        );
+        assert!(crate::decoder::is_valid_png(&bytes).is_ok());
 
-                    i += 4;
-                }
-            } else {
-                // Literal byte
-                result.push(data[i]);
-                i += 1;
-            }
-        }
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
 
-        Ok(result)
+        let zero = EncodeOptions {
+            max_idat_chunk_size: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            PngEncoder::with_options(1, 1, CompressionMethod::Flate2, zero),
+            Err(EncoderError::InvalidOptions(_))
+        ));
     }
 
-    #[allow(dead_code)]
-    fn test_compression(&self, data: &[u8]) -> bool {
-        match self.compress_data(data) {
-            Ok(compressed) => match self.decompress_data(&compressed) {
-                Ok(decompressed) => decompressed == data,
-                Err(_) => false,
-            },
-            Err(_) => false,
+    #[test]
+    fn test_interlaced_round_trip() {
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(13, 11, |x, y| {
+            image::Rgba([noise(y * 13 + x), (x * 19) as u8, (y * 23) as u8, 200])
+        }));
+        let mask = DynamicImage::ImageLuma8(image::GrayImage::from_fn(11, 9, |x, y| {
+            image::Luma([if (x * y) % 3 == 0 { 255 } else { 0 }])
+        }));
+        // Narrower than the first pass's 8-pixel step, so some passes are empty
+        let tiny = DynamicImage::ImageRgb8(image::RgbImage::from_fn(3, 2, |x, y| {
+            image::Rgb([x as u8 * 80, y as u8 * 120, 7])
+        }));
+
+        for image in [rgba, mask, tiny] {
+            let options = EncodeOptions {
+                interlace: true,
+                filter_strategy: FilterStrategy::Adaptive,
+                ..Default::default()
+            };
+            let mut encoder = PngEncoder::with_options(
+                image.width(),
+                image.height(),
+                CompressionMethod::Flate2,
+                options,
+            )
+            .unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+
+            assert_eq!(bytes[28], 1);
+            assert_eq!(inflate_idat(&bytes).len(), encoder.filtered_len().unwrap());
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            assert_eq!(decoded.to_rgba8(), image.to_rgba8());
         }
     }
-}
 
-pub fn save_to_png_with_compression(
-    image: &DynamicImage,
-    path: &str,
-    compression: CompressionMethod,
-) -> std::io::Result<()> {
-    let mut file = File::create(path)?;
-    let encoder = PngEncoder::new(image.width(), image.height(), compression);
-    encoder.encode(image, &mut file)
-}
+    #[test]
+    fn test_ztxt_compresses_repetitive_text() {
+        let json = "{\"layer\": \"background\", \"visible\": true}, ".repeat(200);
+        for compression in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let options = EncodeOptions {
+                text: vec![TextChunk::Compressed {
+                    keyword: "Layers".to_string(),
+                    text: json.clone(),
+                }],
+                ..Default::default()
+            };
+            let image = DynamicImage::new_rgb8(2, 2);
+            let mut encoder = PngEncoder::with_options(2, 2, compression, options).unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            let ztxt = crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .find(|chunk| &chunk.chunk_type == b"zTXt")
+                .unwrap();
+            assert!(ztxt.data.starts_with(b"Layers\0\0"));
+            assert!(ztxt.data.len() < json.len());
+
+            let text = crate::decoder::read_text(&bytes).unwrap();
+            assert_eq!(text, vec![("Layers".to_string(), json.clone())]);
+        }
+    }
 
     #[test]
-    fn test_basic_compression() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let test_data = b"Hello, World! This is a test string for compression.";
+    fn test_dpi_written_as_pixels_per_meter() {
+        let mut options = EncodeOptions::default();
+        options.set_dpi(300.0);
+        let image = DynamicImage::new_rgb8(2, 2);
+        let mut encoder =
+            PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
 
-        assert!(encoder.test_compression(test_data));
+        let phys = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .find(|chunk| &chunk.chunk_type == b"pHYs")
+            .unwrap();
+        let mut expected = 11811u32.to_be_bytes().repeat(2);
+        expected.push(1);
+        assert_eq!(phys.data, &expected[..]);
+        assert!(image::load_from_memory(&bytes).is_ok());
     }
 
     #[test]
-    fn test_repetitive_data_compression() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let mut test_data = Vec::new();
+    fn test_gamma_and_srgb_chunks() {
+        let encode_chunks = |options: EncodeOptions| {
+            let image = DynamicImage::new_rgb8(2, 2);
+            let mut encoder =
+                PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+            assert!(image::load_from_memory(&bytes).is_ok());
+            crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .map(|chunk| (chunk.chunk_type, chunk.data.to_vec()))
+                .collect::<Vec<_>>()
+        };
 
-        // Create repetitive data that should compress well
-        for _ in 0..100 {
-            test_data.extend_from_slice(b"ABCDEFGH");
-        }
+        let mut options = EncodeOptions::default();
+        options.set_gamma(1.0 / 2.2);
+        let chunks = encode_chunks(options);
+        assert!(chunks.contains(&(*b"gAMA", 45455u32.to_be_bytes().to_vec())));
+        assert!(!chunks.iter().any(|(chunk_type, _)| chunk_type == b"sRGB"));
 
-        assert!(encoder.test_compression(&test_data));
+        // sRGB brings its own gAMA, overriding an explicit gamma
+        let mut options = EncodeOptions::default();
+        options.set_gamma(1.0);
+        options.set_srgb(RenderingIntent::Perceptual);
+        let chunks = encode_chunks(options);
+        let types: Vec<&[u8; 4]> = chunks.iter().map(|(chunk_type, _)| chunk_type).collect();
+        assert_eq!(&types[..3], &[b"IHDR", b"gAMA", b"sRGB"]);
+        assert_eq!(chunks[1].1, 45455u32.to_be_bytes());
+        assert_eq!(chunks[2].1, [0]);
     }
 
     #[test]
-    fn test_empty_data() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let test_data = b"";
+    fn test_srgb_chromaticities_chunk() {
+        let mut options = EncodeOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        options.set_gamma(1.0 / 2.2);
+        options.set_chromaticities((0.3127, 0.3290), (0.64, 0.33), (0.30, 0.60), (0.15, 0.06));
+        let image = DynamicImage::new_rgb8(2, 2);
+        let mut encoder =
+            PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
+        assert!(image::load_from_memory(&bytes).is_ok());
 
-        assert!(encoder.test_compression(test_data));
+        let chunks: Vec<_> = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect();
+        let types: Vec<&[u8; 4]> = chunks.iter().map(|chunk| &chunk.chunk_type).collect();
+        assert_eq!(&types[..4], &[b"IHDR", b"gAMA", b"cHRM", b"PLTE"]);
+
+        // The values the PNG specification gives for sRGB
+        let expected: Vec<u8> = [31270u32, 32900, 64000, 33000, 30000, 60000, 15000, 6000]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        assert_eq!(chunks[2].data, &expected[..]);
     }
 
     #[test]
-    fn test_single_byte() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let test_data = b"A";
+    fn test_icc_profile_round_trip() {
+        use std::io::Read;
 
-        assert!(encoder.test_compression(test_data));
+        // Not a real profile, but long and repetitive enough to exercise compression
+        let profile: Vec<u8> = (0..600u32).map(|i| (i % 7 * 31 + i / 50) as u8).collect();
+        let image = DynamicImage::new_rgb8(2, 2);
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let mut options = EncodeOptions::default();
+            options.set_icc_profile("Display P3", &profile).unwrap();
+            let mut encoder = PngEncoder::with_options(2, 2, method, options).unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+            assert!(image::load_from_memory(&bytes).is_ok());
+
+            let iccp = crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .find(|chunk| &chunk.chunk_type == b"iCCP")
+                .unwrap();
+            let compressed = iccp.data.strip_prefix(b"Display P3\0\0").unwrap();
+            assert!(compressed.len() < profile.len());
+
+            let mut inflated = Vec::new();
+            flate2::read::ZlibDecoder::new(compressed)
+                .read_to_end(&mut inflated)
+                .unwrap();
+            assert_eq!(inflated, profile);
+        }
+
+        let mut options = EncodeOptions::default();
+        assert!(options.set_icc_profile("", &profile).is_err());
+        assert!(options.set_icc_profile(&"x".repeat(80), &profile).is_err());
+        options.set_icc_profile(&"x".repeat(79), &profile).unwrap();
+        options.set_srgb(RenderingIntent::Perceptual);
+        assert!(PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).is_err());
     }
 
     #[test]
-    fn test_escape_byte_handling() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let test_data = b"\xFF\xFF\xFF\x00\x01\x02";
+    fn test_background_chunk_per_color_type() {
+        let chunk_data = |image: DynamicImage, options: EncodeOptions| {
+            let (width, height) = (image.width(), image.height());
+            let mut encoder =
+                PngEncoder::with_options(width, height, CompressionMethod::Flate2, options)
+                    .unwrap();
+            encoder.configure_for_image(&image);
+            let mut bytes = Vec::new();
+            encoder.encode(&image, &mut bytes).unwrap();
+            assert!(image::load_from_memory(&bytes).is_ok());
 
-        assert!(encoder.test_compression(test_data));
+            let chunks: Vec<_> = crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .map(|chunk| (chunk.chunk_type, chunk.data.to_vec()))
+                .collect();
+            let position = |chunk_type: &[u8; 4]| chunks.iter().position(|(t, _)| t == chunk_type);
+            let bkgd = position(b"bKGD")?;
+            assert!(bkgd < position(b"IDAT").unwrap());
+            if let Some(plte) = position(b"PLTE") {
+                assert!(plte < bkgd);
+            }
+            Some(chunks[bkgd].1.clone())
+        };
+
+        let mut options = EncodeOptions::default();
+        options.set_background(BackgroundColor::Rgb(255, 128, 0));
+        let rgb = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(8, 8, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 30, 90, 128])
+        }));
+        assert_eq!(
+            chunk_data(rgb.clone(), options.clone()),
+            Some(vec![0, 255, 0, 128, 0, 0])
+        );
+
+        // Gray output takes a single sample, here the luma of the requested color
+        let gray = DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_fn(8, 8, |x, _| {
+            image::LumaA([x as u8 * 30, 200])
+        }));
+        assert_eq!(chunk_data(gray, options.clone()), Some(vec![0, 145]));
+
+        // Palette output refers to the matching entry
+        let two_colors = DynamicImage::ImageRgb8(image::RgbImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                image::Rgb([255, 128, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        }));
+        options.indexed = true;
+        let index = chunk_data(two_colors.clone(), options.clone()).unwrap();
+        assert_eq!(index.len(), 1);
+        let mut bytes = Vec::new();
+        let mut encoder =
+            PngEncoder::with_options(8, 8, CompressionMethod::Flate2, options.clone()).unwrap();
+        encoder.configure_for_image(&two_colors);
+        encoder.encode(&two_colors, &mut bytes).unwrap();
+        assert_eq!(
+            crate::decoder::read_background(&bytes).unwrap(),
+            Some([255, 128, 0])
+        );
+
+        // A palette index means nothing without a palette
+        options.set_background(BackgroundColor::PaletteIndex(1));
+        assert_eq!(chunk_data(two_colors, options.clone()), Some(vec![1]));
+        options.indexed = false;
+        assert_eq!(chunk_data(rgb.clone(), options), None);
+
+        let mut too_bright = EncodeOptions::default();
+        too_bright.set_background(BackgroundColor::Gray(300));
+        let mut encoder =
+            PngEncoder::with_options(8, 8, CompressionMethod::Flate2, too_bright).unwrap();
+        encoder.configure_for_image(&rgb);
+        assert!(encoder.encode(&rgb, &mut Vec::new()).is_err());
     }
 
     #[test]
-    fn test_long_matches() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let mut test_data = Vec::new();
+    fn test_indexed_transparency_round_trip() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(16, 8, |x, y| {
+            match (x + y) % 4 {
+                // Hidden colors differ but all collapse to one transparent entry
+                0 => image::Rgba([x as u8, y as u8, 9, 0]),
+                1 => image::Rgba([255, 0, 0, 255]),
+                2 => image::Rgba([0, 255, 0, 255]),
+                _ => image::Rgba([0, 0, 255, 255]),
+            }
+        }));
+        let options = EncodeOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(16, 8, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
 
-        // Create a pattern with long repeating sequences
-        let pattern = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-        for _ in 0..20 {
-            test_data.extend_from_slice(pattern);
-        }
+        assert_eq!((bytes[24], bytes[25]), (2, 3));
+        let trns = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .find(|chunk| &chunk.chunk_type == b"tRNS")
+            .unwrap();
+        assert_eq!(trns.data, &[0]);
 
-        assert!(encoder.test_compression(&test_data));
+        let decoded = image::load_from_memory(&bytes).unwrap().to_rgba8();
+        for (decoded, original) in decoded.pixels().zip(image.to_rgba8().pixels()) {
+            assert_eq!(decoded[3], original[3]);
+            if original[3] == 255 {
+                assert_eq!(decoded, original);
+            }
+        }
     }
 
     #[test]
-    fn test_adler32_checksum() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+    fn test_transparent_gray_level() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(8, 4, |x, _| {
+            image::Luma([if x % 2 == 0 { 0 } else { 255 }])
+        }));
+        let options = EncodeOptions {
+            transparent_gray: Some(255),
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(8, 4, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
 
-        // Test known Adler32 values
-        assert_eq!(encoder.adler32(b""), 1);
-        assert_eq!(encoder.adler32(b"a"), 0x00620062);
-        assert_eq!(encoder.adler32(b"abc"), 0x024d0127);
-        assert_eq!(encoder.adler32(b"message digest"), 0x29750586);
+        // 1-bit output stores white as sample 1
+        assert_eq!((bytes[24], bytes[25]), (1, 0));
+        let decoded = image::load_from_memory(&bytes).unwrap().to_luma_alpha8();
+        for (x, _, pixel) in decoded.enumerate_pixels() {
+            let expected = if x % 2 == 0 { [0, 255] } else { [255, 0] };
+            assert_eq!(pixel.0, expected);
+        }
     }
 
     #[test]
-    fn test_compression_reduces_size() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
+    fn test_modification_time_chunk() {
+        let mut options = EncodeOptions::default();
+        assert!(options.set_modification_time(2024, 13, 1, 0, 0, 0).is_err());
+        assert!(options.set_modification_time(2024, 2, 0, 0, 0, 0).is_err());
+        options
+            .set_modification_time(2024, 2, 29, 23, 5, 59)
+            .unwrap();
 
-        // Highly repetitive data
-        let mut test_data = Vec::new();
-        for _ in 0..1000 {
-            test_data.push(0x42); // Repeat the same byte
-        }
+        let image = DynamicImage::new_rgb8(2, 2);
+        let mut encoder =
+            PngEncoder::with_options(2, 2, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
+        let mut bytes = Vec::new();
+        encoder.encode(&image, &mut bytes).unwrap();
 
-        let compressed = encoder.compress_data(&test_data).unwrap();
+        let time = crate::decoder::chunks(&bytes)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .find(|chunk| &chunk.chunk_type == b"tIME")
+            .unwrap();
+        assert_eq!(time.data, &[0x07, 0xE8, 2, 29, 23, 5, 59]);
+        assert!(image::load_from_memory(&bytes).is_ok());
+    }
 
-        // Compressed size should be much smaller than original (accounting for headers and overhead)
-        assert!(compressed.len() < test_data.len() / 2);
+    #[test]
+    fn test_modification_time_from_unix_seconds() {
+        let at = |seconds| {
+            let time = ModificationTime::from_unix_seconds(seconds);
+            (
+                time.year,
+                time.month,
+                time.day,
+                time.hour,
+                time.minute,
+                time.second,
+            )
+        };
+        assert_eq!(at(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(at(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(at(1_709_251_199), (2024, 2, 29, 23, 59, 59));
+        assert_eq!(at(1_735_689_600), (2025, 1, 1, 0, 0, 0));
     }
 
     #[test]
-    fn test_flate2_compression() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
-        let test_data = b"Hello, World! This is a test string for compression.";
+    fn test_builder_color_type_override() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(20, 20, |x, y| {
+            image::Rgb([(x * 12) as u8, (y * 12) as u8, 0])
+        }));
+        let encode = |color_type: u8| {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(CompressionMethod::Flate2)
+                .color_type(color_type)
+                .encode(&image, &mut bytes)
+                .map(|_| bytes)
+        };
 
-        let compressed = encoder.compress_data(test_data).unwrap();
-        assert!(compressed.len() > 0);
-        assert!(compressed.len() < test_data.len() + 100); // Should be reasonable size
+        for color_type in [0, 2, 4, 6] {
+            let bytes = encode(color_type).unwrap();
+            assert_eq!((bytes[24], bytes[25]), (8, color_type));
+            assert!(image::load_from_memory(&bytes).is_ok());
+        }
+        assert!(matches!(encode(3), Err(EncoderError::TooManyColors)));
+        assert!(matches!(
+            encode(5),
+            Err(EncoderError::UnsupportedColorType(5))
+        ));
+
+        let few_colors = DynamicImage::ImageRgb8(image::RgbImage::from_fn(4, 4, |x, _| {
+            image::Rgb([x as u8 * 60, 0, 0])
+        }));
+        let mut bytes = Vec::new();
+        PngEncoderBuilder::new()
+            .compression(CompressionMethod::Flate2)
+            .color_type(3)
+            .encode(&few_colors, &mut bytes)
+            .unwrap();
+        assert_eq!((bytes[24], bytes[25]), (2, 3));
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().to_rgb8(),
+            few_colors.to_rgb8()
+        );
     }
 
     #[test]
-    fn test_flate2_compression_repetitive_data() {
-        let encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
-        let mut test_data = Vec::new();
+    fn test_streaming_tall_thin_round_trip() {
+        let tall = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(1, 100_000, |_, y| {
+            image::Rgba([y as u8, (y >> 8) as u8, (y >> 16) as u8, 255])
+        }));
+        let options = EncodeOptions {
+            streaming: true,
+            ..Default::default()
+        };
 
-        // Create repetitive data that should compress well
-        for _ in 0..100 {
-            test_data.extend_from_slice(b"ABCDEFGH");
-        }
+        for compression in [
+            CompressionMethod::Custom,
+            CompressionMethod::Flate2,
+            CompressionMethod::Stored,
+        ] {
+            let mut encoder =
+                PngEncoder::with_options(1, 100_000, compression, options.clone()).unwrap();
+            encoder.configure_for_image(&tall);
+            let mut bytes = Vec::new();
+            let report = encoder.encode(&tall, &mut bytes).unwrap();
+            assert_eq!(report.raw_bytes, 400_000);
+            assert!(crate::decoder::is_valid_png(&bytes).is_ok());
 
-        let compressed = encoder.compress_data(&test_data).unwrap();
+            let idat = crate::decoder::collect_idat(
+                &bytes,
+                crate::decoder::DecodeMode::Strict,
+                &crate::decoder::DecodeLimits::default(),
+            )
+            .unwrap()
+            .data;
+            assert_eq!(idat.len(), report.idat_bytes);
 
-        // Flate2 should compress repetitive data very well
-        assert!(compressed.len() < test_data.len() / 4);
+            // The streamed stream must inflate to exactly what the buffered path filters
+            let expected = encoder
+                .apply_filters(&encoder.prepare_pixels(&tall), Some(FilterType::Sub))
+                .unwrap();
+            match compression {
+                CompressionMethod::Custom => {
+                    assert_eq!(encoder.decompress_data(&idat).unwrap(), expected);
+                }
+                CompressionMethod::Flate2 | CompressionMethod::Stored => {
+                    assert_eq!(inflate_idat(&bytes), expected);
+                    let decoded = image::load_from_memory(&bytes).unwrap();
+                    assert_eq!(decoded.to_rgba8(), tall.to_rgba8());
+                }
+            }
+        }
     }
 
     #[test]
-    fn test_compression_methods_comparison() {
-        let custom_encoder = PngEncoder::new(100, 100, CompressionMethod::Custom);
-        let flate2_encoder = PngEncoder::new(100, 100, CompressionMethod::Flate2);
+    fn test_max_memory_forces_frugal_streaming() {
+        // 256-byte scanlines: a band of one row, the row above and a filtered row need 769.
+        // Noise keeps the compressed data longer than one small IDAT chunk.
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 40, |x, y| {
+            let noise = (x * 40 + y).wrapping_mul(2_654_435_761).to_be_bytes();
+            image::Rgba(noise)
+        }));
+        let budget = 2000;
 
-        // Test data with longer repetitive patterns that our custom algorithm can compress
-        let mut test_data = Vec::new();
-        let pattern = b"ABCDEFGH";
-        for _ in 0..50 {
-            test_data.extend_from_slice(pattern);
+        for compression in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(compression)
+                .max_memory(budget)
+                .encode(&image, &mut bytes)
+                .unwrap();
+            assert_eq!(
+                image::load_from_memory(&bytes).unwrap().to_rgba8(),
+                image.to_rgba8()
+            );
+
+            let idat_sizes: Vec<usize> = crate::decoder::chunks(&bytes)
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|chunk| &chunk.chunk_type == b"IDAT")
+                .map(|chunk| chunk.data.len())
+                .collect();
+            assert!(idat_sizes.len() > 1);
+            assert!(idat_sizes.iter().all(|&size| size <= budget / 4));
         }
 
-        let custom_compressed = custom_encoder.compress_data(&test_data).unwrap();
-        let flate2_compressed = flate2_encoder.compress_data(&test_data).unwrap();
+        let encoder = PngEncoder::with_options(
+            64,
+            40,
+            CompressionMethod::Custom,
+            EncodeOptions {
+                max_memory: Some(budget),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(encoder.options.streaming);
 
-        // Both should compress the data significantly
-        assert!(custom_compressed.len() < test_data.len() / 2);
-        assert!(flate2_compressed.len() < test_data.len() / 2);
+        let mut bytes = Vec::new();
+        let err = PngEncoderBuilder::new()
+            .max_memory(700)
+            .encode(&image, &mut bytes)
+            .unwrap_err();
+        assert!(matches!(err, EncoderError::InvalidOptions(_)));
+        assert!(bytes.is_empty());
 
-        // Flate2 should generally compress better due to Huffman coding
-        println!("Original size: {}", test_data.len());
-        println!("Custom compressed: {}", custom_compressed.len());
-        println!("Flate2 compressed: {}", flate2_compressed.len());
+        assert!(matches!(
+            PngEncoderBuilder::new()
+                .max_memory(budget)
+                .interlace(true)
+                .encode(&image, &mut Vec::new()),
+            Err(EncoderError::InvalidOptions(_))
+        ));
     }
 
     #[test]
-    fn test_both_methods_create_valid_pngs() {
-        use std::io::Cursor;
+    fn test_streaming_writes_idat_before_filtering_finishes() {
+        /// Cancels the encode as soon as the first IDAT chunk reaches the output.
+        struct CancelOnIdat {
+            bytes: Vec<u8>,
+            cancel: Arc<AtomicBool>,
+        }
 
-        // Create a simple test image
-        let width = 10;
-        let height = 10;
-        let mut image_data = Vec::new();
-        for y in 0..height {
-            for x in 0..width {
-                // Simple gradient pattern
-                let r = (x * 255 / width) as u8;
-                let g = (y * 255 / height) as u8;
-                let b = 128;
-                let a = 255;
-                image_data.extend_from_slice(&[r, g, b, a]);
+        impl Write for CancelOnIdat {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if buf == b"IDAT" {
+                    self.cancel.store(true, Ordering::Relaxed);
+                }
+                self.bytes.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
         }
 
-        let image_buffer = image::RgbaImage::from_raw(width, height, image_data).unwrap();
-        let dynamic_image = DynamicImage::ImageRgba8(image_buffer);
+        let mut state = 0x2545_F491u32;
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 4096, |_, _| {
+            // xorshift, so filtering cannot make the noise compressible
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [red, green, blue, _] = state.to_le_bytes();
+            image::Rgb([red, green, blue])
+        }));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = EncodeOptions {
+            streaming: true,
+            cancel: Some(cancel.clone()),
+            max_idat_chunk_size: Some(1024),
+            ..Default::default()
+        };
+        let mut encoder =
+            PngEncoder::with_options(64, 4096, CompressionMethod::Flate2, options).unwrap();
+        encoder.configure_for_image(&image);
 
-        // Test both compression methods create valid PNG structure
-        let custom_encoder = PngEncoder::new(width, height, CompressionMethod::Custom);
-        let flate2_encoder = PngEncoder::new(width, height, CompressionMethod::Flate2);
+        let mut output = CancelOnIdat {
+            bytes: Vec::new(),
+            cancel,
+        };
+        let result = encoder.encode(&image, &mut output);
+        assert!(matches!(result, Err(EncoderError::Cancelled)));
+        // Far less than the whole image had been compressed when the first chunk went out
+        assert!(output.bytes.len() < 64 * 4096);
+    }
 
-        let mut custom_output = Cursor::new(Vec::new());
-        let mut flate2_output = Cursor::new(Vec::new());
+    #[test]
+    fn test_flate2_compression_levels() {
+        use crate::decoder::{DecodeLimits, DecodeMode, collect_idat};
 
-        assert!(
-            custom_encoder
-                .encode(&dynamic_image, &mut custom_output)
-                .is_ok()
-        );
-        assert!(
-            flate2_encoder
-                .encode(&dynamic_image, &mut flate2_output)
-                .is_ok()
-        );
+        // Repeating tiles with some variation, so the level's match search effort matters
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 96, |x, y| {
+            let tile = noise((x % 24) * 31 + (y % 17));
+            image::Rgb([tile, tile.wrapping_add((x / 24) as u8), (y / 8) as u8])
+        }));
+        let idat_len = |level: u8| {
+            let mut bytes = Vec::new();
+            PngEncoderBuilder::new()
+                .compression(CompressionMethod::Flate2)
+                .compression_level(level)
+                .encode(&image, &mut bytes)
+                .unwrap();
+            assert_eq!(
+                crate::decode_png(&bytes).unwrap().to_rgb8(),
+                image.to_rgb8()
+            );
+            assert_eq!(
+                image::load_from_memory(&bytes).unwrap().to_rgb8(),
+                image.to_rgb8()
+            );
+            collect_idat(&bytes, DecodeMode::Strict, &DecodeLimits::default())
+                .unwrap()
+                .data
+                .len()
+        };
 
-        // Both outputs should start with PNG signature
-        let custom_data = custom_output.into_inner();
-        let flate2_data = flate2_output.into_inner();
+        let fast = idat_len(1);
+        let best = idat_len(9);
+        assert!(best < fast, "level 9 {} vs level 1 {}", best, fast);
 
-        assert_eq!(&custom_data[0..8], &PNG_SIGNATURE);
-        assert_eq!(&flate2_data[0..8], &PNG_SIGNATURE);
+        let result = PngEncoderBuilder::new()
+            .compression_level(10)
+            .encode(&image, &mut Vec::new());
+        assert!(matches!(result, Err(EncoderError::InvalidOptions(_))));
+    }
 
-        // Both should have reasonable file sizes
-        assert!(custom_data.len() > 100);
-        assert!(flate2_data.len() > 100);
+    #[test]
+    fn test_banded_image_checks_see_every_row() {
+        // Colors and levels that only appear in the last, partial band
+        let height = STREAM_BAND_ROWS * 3 + 5;
+        let rgb = DynamicImage::ImageRgb8(image::RgbImage::from_fn(6, height, |x, y| {
+            if y == height - 1 && x == 5 {
+                image::Rgb([9, 8, 7])
+            } else {
+                image::Rgb([200, 100, 0])
+            }
+        }));
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::from_fn(6, height, |_, y| {
+            image::Luma(if y == height - 1 { [1] } else { [255] })
+        }));
+
+        for streaming in [false, true] {
+            let configured = |image: &DynamicImage| {
+                let options = EncodeOptions {
+                    indexed: true,
+                    streaming,
+                    ..Default::default()
+                };
+                let mut encoder =
+                    PngEncoder::with_options(6, height, CompressionMethod::Flate2, options)
+                        .unwrap();
+                encoder.configure_for_image(image);
+                encoder
+            };
+            let indexed = configured(&rgb);
+            assert_eq!(indexed.palette, [[9, 8, 7, 255], [200, 100, 0, 255]]);
+            assert_eq!(indexed.bit_depth, 1);
+
+            let gray_encoder = configured(&gray);
+            assert_eq!((gray_encoder.color_type, gray_encoder.bit_depth), (0, 8));
+        }
+        assert_eq!(gray_levels(&gray), [1, 255]);
+    }
+
+    #[test]
+    fn test_match_tuning_options_change_custom_output() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 96, |x, y| {
+            let tile = noise((x % 24) * 31 + (y % 17));
+            image::Rgb([tile, tile.wrapping_add((x / 24) as u8), (y / 8) as u8])
+        }));
+        let encode = |builder: PngEncoderBuilder| {
+            let mut bytes = Vec::new();
+            builder
+                .compression(CompressionMethod::Custom)
+                .encode(&image, &mut bytes)
+                .unwrap();
+            assert_eq!(
+                image::load_from_memory(&bytes).unwrap().to_rgb8(),
+                image.to_rgb8()
+            );
+            bytes.len()
+        };
+
+        let default = encode(PngEncoderBuilder::new());
+        // Only matches at least a row back are allowed, losing the ones within a tile
+        let distant = encode(PngEncoderBuilder::new().min_match_distance(128 * 3));
+        assert!(distant > default, "{} vs {}", distant, default);
+        let fixed = encode(PngEncoderBuilder::new().fixed_match_threshold(true));
+        assert!(fixed > default, "{} vs {}", fixed, default);
+
+        let result = PngEncoderBuilder::new()
+            .min_match_distance(0)
+            .encode(&image, &mut Vec::new());
+        assert!(matches!(result, Err(EncoderError::InvalidOptions(_))));
     }
 }
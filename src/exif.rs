@@ -0,0 +1,330 @@
+use crate::encoder::TextChunk;
+use image::{ImageDecoder, ImageReader};
+use std::path::Path;
+
+/// ASCII tags of the first IFD that are carried over as text, with the keyword each one
+/// is written under.
+pub const TEXT_TAGS: &[(u16, &str)] = &[
+    (0x010E, "ImageDescription"),
+    (0x010F, "Make"),
+    (0x0110, "Model"),
+    (0x0131, "Software"),
+    (0x0132, "DateTime"),
+    (0x013B, "Artist"),
+    (0x8298, "Copyright"),
+];
+
+/// TIFF field type of NUL-terminated 7-bit text.
+const ASCII: u16 = 2;
+/// TIFF field type of an unsigned 16-bit integer.
+const SHORT: u16 = 3;
+/// Tag of the orientation, from 1 (stored upright) to 8.
+const ORIENTATION: u16 = 0x0112;
+
+/// Reads the raw EXIF block (a TIFF structure) of the image at `path`. Returns `None` if
+/// the format carries no EXIF or the file can't be read.
+pub fn read_source_exif(path: &Path) -> Option<Vec<u8>> {
+    let mut decoder = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    decoder.exif_metadata().ok().flatten()
+}
+
+/// Returns the [`TEXT_TAGS`] present in `exif` as keyword/value pairs. Malformed EXIF
+/// yields no fields rather than an error.
+pub fn text_fields(exif: &[u8]) -> Vec<(&'static str, String)> {
+    let Some(ifd) = Ifd0::parse(exif) else {
+        return Vec::new();
+    };
+
+    TEXT_TAGS
+        .iter()
+        .filter_map(|&(tag, keyword)| Some((keyword, ifd.ascii(tag)?)))
+        .collect()
+}
+
+/// Converts the [`text_fields`] of `exif` into text chunks: tEXt for plain ASCII values,
+/// iTXt for anything else (many cameras store UTF-8 despite the ASCII field type).
+pub fn text_chunks(exif: &[u8]) -> Vec<TextChunk> {
+    text_fields(exif)
+        .into_iter()
+        .map(|(keyword, text)| {
+            let keyword = keyword.to_string();
+            if text.is_ascii() {
+                TextChunk::Latin1 { keyword, text }
+            } else {
+                TextChunk::Utf8 { keyword, text }
+            }
+        })
+        .collect()
+}
+
+/// Sets the orientation tag of `exif` to 1, for pixels that have already been rotated
+/// upright, so viewers don't rotate them a second time. Returns whether there was a tag
+/// to reset; malformed EXIF is left alone.
+pub fn reset_orientation(exif: &mut [u8]) -> bool {
+    let Some((value_at, big_endian)) = Ifd0::parse(exif).and_then(|ifd| {
+        let (count, value_at) = ifd.entry(ORIENTATION, SHORT)?;
+        (count == 1).then_some((value_at, ifd.big_endian))
+    }) else {
+        return false;
+    };
+    let Some(value) = exif.get_mut(value_at..value_at + 2) else {
+        return false;
+    };
+
+    let upright = if big_endian {
+        1u16.to_be_bytes()
+    } else {
+        1u16.to_le_bytes()
+    };
+    value.copy_from_slice(&upright);
+    true
+}
+
+/// The first image file directory of an EXIF block.
+struct Ifd0<'a> {
+    exif: &'a [u8],
+    big_endian: bool,
+    entries_offset: usize,
+    entry_count: usize,
+}
+
+impl<'a> Ifd0<'a> {
+    fn parse(exif: &'a [u8]) -> Option<Self> {
+        let big_endian = match exif.get(..4)? {
+            [b'I', b'I', 42, 0] => false,
+            [b'M', b'M', 0, 42] => true,
+            _ => return None,
+        };
+
+        let mut ifd = Ifd0 {
+            exif,
+            big_endian,
+            entries_offset: 0,
+            entry_count: 0,
+        };
+        let offset = ifd.u32_at(4)? as usize;
+        ifd.entry_count = ifd.u16_at(offset)? as usize;
+        ifd.entries_offset = offset + 2;
+        Some(ifd)
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .exif
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .exif
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Finds the entry for `tag` with the given field type, returning its value count and
+    /// the offset of its 4-byte value field.
+    fn entry(&self, tag: u16, field_type: u16) -> Option<(usize, usize)> {
+        (0..self.entry_count)
+            .map(|index| self.entries_offset + index * 12)
+            .find(|&at| self.u16_at(at) == Some(tag) && self.u16_at(at + 2) == Some(field_type))
+            .and_then(|at| Some((self.u32_at(at + 4)? as usize, at + 8)))
+    }
+
+    /// The trimmed text of an ASCII entry, or `None` if it is missing or empty.
+    fn ascii(&self, tag: u16) -> Option<String> {
+        let (count, value_at) = self.entry(tag, ASCII)?;
+        // Values that fit in the 4-byte field are stored inline
+        let start = if count <= 4 {
+            value_at
+        } else {
+            self.u32_at(value_at)? as usize
+        };
+        let bytes = self.exif.get(start..start.checked_add(count)?)?;
+
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::read_text;
+    use crate::encoder::{CompressionMethod, EncodeOptions, save_to_png_with_options};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{DynamicImage, RgbImage};
+
+    /// Builds a little- or big-endian EXIF block whose IFD0 holds the given ASCII entries.
+    fn exif_block(big_endian: bool, fields: &[(u16, &str)]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+
+        let mut block = if big_endian {
+            b"MM\0\x2a".to_vec()
+        } else {
+            b"II\x2a\0".to_vec()
+        };
+        block.extend_from_slice(&u32_bytes(8));
+        block.extend_from_slice(&u16_bytes(fields.len() as u16));
+
+        let mut data_offset = 8 + 2 + fields.len() * 12 + 4;
+        let mut data = Vec::new();
+        for &(tag, text) in fields {
+            let mut value = text.as_bytes().to_vec();
+            value.push(0);
+            block.extend_from_slice(&u16_bytes(tag));
+            block.extend_from_slice(&u16_bytes(ASCII));
+            block.extend_from_slice(&u32_bytes(value.len() as u32));
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                block.extend_from_slice(&value);
+            } else {
+                block.extend_from_slice(&u32_bytes(data_offset as u32));
+                data_offset += value.len();
+                data.extend_from_slice(&value);
+            }
+        }
+        block.extend_from_slice(&[0; 4]);
+        block.extend_from_slice(&data);
+        block
+    }
+
+    #[test]
+    fn test_reset_orientation() {
+        // IFD0 with Make (ASCII, inline) and Orientation (SHORT) = 6
+        let mut little = b"II\x2a\0\x08\0\0\0\x02\0".to_vec();
+        little.extend_from_slice(&[0x0F, 0x01, 2, 0, 3, 0, 0, 0, b'X', b'Y', 0, 0]);
+        little.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        little.extend_from_slice(&[0; 4]);
+        let mut big = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        big.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        big.extend_from_slice(&[0; 4]);
+
+        for (exif, big_endian) in [(&mut little, false), (&mut big, true)] {
+            assert!(reset_orientation(exif));
+            let ifd = Ifd0::parse(exif).unwrap();
+            let (_, value_at) = ifd.entry(ORIENTATION, SHORT).unwrap();
+            assert_eq!(ifd.u16_at(value_at), Some(1));
+            assert_eq!(ifd.big_endian, big_endian);
+        }
+        assert_eq!(text_fields(&little), [("Make", "XY".to_string())]);
+
+        let mut no_orientation = exif_block(false, &[(0x0110, "EOS 5D")]);
+        let before = no_orientation.clone();
+        assert!(!reset_orientation(&mut no_orientation));
+        assert_eq!(no_orientation, before);
+    }
+
+    #[test]
+    fn test_text_fields_in_both_byte_orders() {
+        let fields = [
+            (0x0110, "EOS 5D"),
+            (0x0132, "2024:05:01 12:00:00"),
+            (0x013B, "Jo"),
+        ];
+        for big_endian in [false, true] {
+            let exif = exif_block(big_endian, &fields);
+            assert_eq!(
+                text_fields(&exif),
+                [
+                    ("Model", "EOS 5D".to_string()),
+                    ("DateTime", "2024:05:01 12:00:00".to_string()),
+                    ("Artist", "Jo".to_string()),
+                ]
+            );
+        }
+
+        let utf8 = exif_block(false, &[(0x8298, "© Zoë")]);
+        assert!(matches!(&text_chunks(&utf8)[..], [TextChunk::Utf8 { .. }]));
+
+        let mut truncated = exif_block(false, &fields);
+        truncated.truncate(40);
+        assert_eq!(text_fields(&truncated), []);
+        assert_eq!(text_fields(b"not exif"), []);
+    }
+
+    #[test]
+    fn test_jpeg_exif_fields_become_text_chunks() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, image::Rgb([90, 120, 30])));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg).encode_image(&image).unwrap();
+
+        // Splice an APP1 segment holding the EXIF block in right after the SOI marker
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(exif_block(
+            false,
+            &[
+                (0x010F, "Canon"),
+                (0x0110, "EOS 5D"),
+                (0x0132, "2024:05:01 12:00:00"),
+            ],
+        ));
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        segment.extend(app1);
+        jpeg.splice(2..2, segment);
+
+        let dir = std::env::temp_dir();
+        let source = dir.join("rpng_test_exif.jpg");
+        let output = dir.join("rpng_test_exif.png");
+        std::fs::write(&source, &jpeg).unwrap();
+
+        let exif = read_source_exif(&source).unwrap();
+        let options = EncodeOptions {
+            text: text_chunks(&exif),
+            ..EncodeOptions::default()
+        };
+        let decoded = image::open(&source).unwrap();
+        save_to_png_with_options(
+            &decoded,
+            &output.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+
+        let text = read_text(&std::fs::read(&output).unwrap()).unwrap();
+        assert_eq!(
+            text,
+            [
+                ("Make".to_string(), "Canon".to_string()),
+                ("Model".to_string(), "EOS 5D".to_string()),
+                ("DateTime".to_string(), "2024:05:01 12:00:00".to_string()),
+            ]
+        );
+
+        std::fs::remove_file(&source).ok();
+        std::fs::remove_file(&output).ok();
+    }
+}
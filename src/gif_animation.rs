@@ -0,0 +1,148 @@
+use crate::encoder::{
+    ApngFrame, ApngOptions, BlendOp, CompressionMethod, DisposeOp, EncodeReport, EncoderError,
+    encode_apng_with,
+};
+use image::{AnimationDecoder, DynamicImage, codecs::gif::GifDecoder};
+use std::io::{Cursor, Write};
+
+/// Application extension header of the NETSCAPE2.0 block that holds a GIF's loop
+/// count, followed by the looping sub-block's length and ID.
+const NETSCAPE_LOOP: &[u8] = b"\x21\xFF\x0BNETSCAPE2.0\x03\x01";
+
+/// Reads an animated GIF as APNG frames, with each frame's delay and the GIF's loop
+/// count carried over.
+///
+/// Frames come back composited onto the full canvas, so each one replaces the last
+/// outright. GIF delays are hundredths of a second, which fcTL stores exactly.
+pub fn read_gif_animation(bytes: &[u8]) -> Result<(Vec<ApngFrame>, ApngOptions), EncoderError> {
+    let decoder = GifDecoder::new(Cursor::new(bytes)).map_err(to_encoder_error)?;
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(to_encoder_error)?;
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let hundredths = (u64::from(numer) + u64::from(denom) * 5) / (u64::from(denom) * 10);
+            ApngFrame {
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+                delay_num: hundredths.min(u64::from(u16::MAX)) as u16,
+                delay_den: 100,
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Source,
+            }
+        })
+        .collect();
+
+    let options = ApngOptions {
+        num_plays: loop_count(bytes),
+        ..Default::default()
+    };
+    Ok((frames, options))
+}
+
+/// Converts an animated GIF to an APNG in `writer`, keeping its frame delays and loop
+/// count.
+pub fn gif_to_apng<W: Write>(
+    bytes: &[u8],
+    writer: &mut W,
+    compression: CompressionMethod,
+) -> Result<EncodeReport, EncoderError> {
+    let (frames, options) = read_gif_animation(bytes)?;
+    encode_apng_with(&frames, &options, writer, compression)
+}
+
+/// The loop count from the GIF's NETSCAPE2.0 extension, with 0 looping forever as in
+/// acTL. A GIF without the extension plays once.
+fn loop_count(bytes: &[u8]) -> u32 {
+    bytes
+        .windows(NETSCAPE_LOOP.len() + 2)
+        .find(|window| window.starts_with(NETSCAPE_LOOP))
+        .map_or(1, |window| {
+            let count = &window[NETSCAPE_LOOP.len()..];
+            u32::from(u16::from_le_bytes([count[0], count[1]]))
+        })
+}
+
+fn to_encoder_error(e: image::ImageError) -> EncoderError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{
+        Delay, Frame, Rgba, RgbaImage,
+        codecs::gif::{GifEncoder, Repeat},
+    };
+
+    #[test]
+    fn test_gif_delays_and_loop_count_survive() {
+        let delays_ms = [100, 250, 40];
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            encoder.set_repeat(Repeat::Finite(3)).unwrap();
+            for (i, &delay) in delays_ms.iter().enumerate() {
+                let pixels = RgbaImage::from_pixel(5, 4, Rgba([i as u8 * 100, 20, 200, 255]));
+                let delay = Delay::from_numer_denom_ms(delay, 1);
+                encoder
+                    .encode_frame(Frame::from_parts(pixels, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+
+        let mut apng = Vec::new();
+        gif_to_apng(&gif, &mut apng, CompressionMethod::Custom).unwrap();
+
+        let chunks: Vec<_> = crate::decoder::chunks(&apng)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|chunk| (chunk.chunk_type, chunk.data.to_vec()))
+            .collect();
+        let actl = &chunks.iter().find(|(kind, _)| kind == b"acTL").unwrap().1;
+        assert_eq!(actl, &[0, 0, 0, 3, 0, 0, 0, 3]);
+        let fctl_delays: Vec<_> = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"fcTL")
+            .map(|(_, data)| {
+                (
+                    u16::from_be_bytes([data[20], data[21]]),
+                    u16::from_be_bytes([data[22], data[23]]),
+                )
+            })
+            .collect();
+        assert_eq!(fctl_delays, [(10, 100), (25, 100), (4, 100)]);
+
+        // An independent APNG decoder sees the same delays
+        let decoded = image::codecs::png::PngDecoder::new(Cursor::new(&apng))
+            .unwrap()
+            .apng()
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        let decoded_ms: Vec<_> = decoded
+            .iter()
+            .map(|frame| {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                numer / denom
+            })
+            .collect();
+        assert_eq!(decoded_ms, delays_ms);
+    }
+
+    #[test]
+    fn test_gif_without_loop_extension_plays_once() {
+        let mut gif = Vec::new();
+        GifEncoder::new(&mut gif)
+            .encode_frame(Frame::new(RgbaImage::new(2, 2)))
+            .unwrap();
+
+        let (frames, options) = read_gif_animation(&gif).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(options.num_plays, 1);
+    }
+}
@@ -0,0 +1,162 @@
+use digest::Digest;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Forwards writes to an inner writer while hashing every byte that was written.
+pub struct HashingWriter<W, D> {
+    inner: W,
+    hasher: D,
+}
+
+impl<W: Write, D: Digest> HashingWriter<W, D> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: D::new(),
+        }
+    }
+
+    /// Consumes the writer, returning the digest of everything written through it.
+    pub fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize().to_vec()
+    }
+}
+
+/// Formats a digest as lowercase hex.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes `<output>.<extension>` next to `output` in the `<hex>  <file name>` format
+/// read by `sha256sum -c` and `md5sum -c`, returning the sidecar's path.
+pub fn write_sidecar(output: &Path, extension: &str, digest: &[u8]) -> std::io::Result<PathBuf> {
+    let mut sidecar = output.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    let sidecar = PathBuf::from(sidecar);
+
+    let file_name = output
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    std::fs::write(&sidecar, format!("{}  {}\n", to_hex(digest), file_name))?;
+    Ok(sidecar)
+}
+
+/// Hashes the file at `path` with `algorithm` (`sha256` or `md5`), for files that were
+/// not written through a [`HashingWriter`]. Returns `None` for an algorithm this build
+/// does not support.
+pub fn digest_file(path: &Path, algorithm: &str) -> std::io::Result<Option<Vec<u8>>> {
+    let bytes = std::fs::read(path)?;
+    Ok(match algorithm {
+        #[cfg(feature = "sha256")]
+        "sha256" => Some(sha2::Sha256::digest(&bytes).to_vec()),
+        #[cfg(feature = "md5")]
+        "md5" => Some(md5::Md5::digest(&bytes).to_vec()),
+        _ => None,
+    })
+}
+
+impl<W: Write, D: Digest> Write for HashingWriter<W, D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Only hash what the inner writer actually accepted
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{CompressionMethod, EncodeOptions, save_to_png_with_options};
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn test_sha256_sidecar_matches_output() {
+        let output = std::env::temp_dir().join("rpng_test_sidecar.png");
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(6, 4, |x, y| {
+            image::Rgba([x as u8 * 40, y as u8 * 60, 3, 255])
+        }));
+        let options = EncodeOptions {
+            compute_sha256: true,
+            ..Default::default()
+        };
+        let report = save_to_png_with_options(
+            &image,
+            &output.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+
+        let sidecar = write_sidecar(&output, "sha256", &report.sha256.unwrap()).unwrap();
+        assert_eq!(sidecar, output.with_extension("png.sha256"));
+
+        let expected = sha2::Sha256::digest(std::fs::read(&output).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&sidecar).unwrap(),
+            format!("{}  rpng_test_sidecar.png\n", to_hex(&expected))
+        );
+
+        std::fs::remove_file(&output).ok();
+        std::fs::remove_file(&sidecar).ok();
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn test_digest_file_matches_streamed_digest() {
+        let output = std::env::temp_dir().join("rpng_test_digest_file.png");
+        let options = EncodeOptions {
+            compute_sha256: true,
+            ..Default::default()
+        };
+        let report = save_to_png_with_options(
+            &image::DynamicImage::new_rgb8(7, 3),
+            &output.to_string_lossy(),
+            CompressionMethod::Custom,
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            digest_file(&output, "sha256").unwrap(),
+            report.sha256.map(|digest| digest.to_vec())
+        );
+        assert_eq!(digest_file(&output, "crc32").unwrap(), None);
+
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn test_streamed_md5_matches_output() {
+        let output = std::env::temp_dir().join("rpng_test_streamed_md5.png");
+        let image = image::DynamicImage::new_rgba8(5, 5);
+        let options = EncodeOptions {
+            compute_md5: true,
+            ..Default::default()
+        };
+        let report = save_to_png_with_options(
+            &image,
+            &output.to_string_lossy(),
+            CompressionMethod::Custom,
+            options,
+        )
+        .unwrap();
+
+        let expected = md5::Md5::digest(std::fs::read(&output).unwrap());
+        assert_eq!(
+            report.md5.map(|digest| digest.to_vec()),
+            Some(expected.to_vec())
+        );
+
+        std::fs::remove_file(&output).ok();
+    }
+}
@@ -0,0 +1,38 @@
+//! Image-to-PNG encoding with a hand-written LZ77 compressor alongside standard flate2
+//! DEFLATE.
+//!
+//! [`PngEncoderBuilder`] is the entry point for configuring an encode:
+//!
+//! ```
+//! use rpng::{CompressionMethod, PngEncoderBuilder};
+//!
+//! let image = image::DynamicImage::new_rgba8(16, 16);
+//! let path = std::env::temp_dir().join("rpng_doc_example.png");
+//! PngEncoderBuilder::new()
+//!     .compression(CompressionMethod::Flate2)
+//!     .save(&image, &path)?;
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod composite;
+pub mod decoder;
+pub mod diff;
+pub mod encoder;
+pub mod exif;
+pub mod gif_animation;
+#[cfg(any(feature = "sha256", feature = "md5"))]
+pub mod hashing;
+#[cfg(feature = "mmap")]
+pub mod mmap_output;
+pub mod remux;
+#[cfg(feature = "tiff-pages")]
+pub mod tiff_pages;
+pub mod trim;
+
+pub use decoder::{ChunkReader, decode_png, decode_png_with};
+pub use encoder::{
+    ApngFrame, ApngOptions, CompressionMethod, EncoderError, PixelOrder, PngEncoderBuilder,
+    SampleEndian, encode_apng, encode_apng_with, encode_raw, encode_raw_u16, encode_to_vec,
+    encode_to_writer, save_to_png_with_compression,
+};
@@ -1,55 +1,383 @@
-use encoder::{CompressionMethod, save_to_png_with_compression};
-use image::ImageReader;
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, GenericImageView, ImageBuffer, ImageDecoder, ImageFormat, ImageReader, Pixel,
+};
+use rpng::encoder::{
+    AlphaMode, CompressionMethod, EncodeOptions, EncodeReport, EncoderError, FloatMapping,
+    RenderingIntent, TextChunk, save_to_png_with_options,
+};
+#[cfg(any(feature = "sha256", feature = "md5"))]
+use rpng::hashing;
+#[cfg(feature = "tiff-pages")]
+use rpng::tiff_pages;
+use rpng::{PngEncoderBuilder, composite, decode_png, decoder, diff, encoder, exif, trim};
 use std::env;
-use std::path::{Path, PathBuf};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
 
-mod encoder;
+/// Scale applied to channel differences so small deviations are visible in `--diff` output.
+const DIFF_AMPLIFICATION: u32 = 8;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args();
+    let program_name = args.next().unwrap_or_else(|| "rpng".to_string());
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            print_usage(&program_name);
+            std::process::exit(1);
+        }
+    };
+    if args.flag("--help") {
+        print_usage(&program_name);
+        return;
+    }
 
-    if args.len() < 2 {
-        print_usage(&args[0]);
+    let verbose = args.flag("--verbose");
+    let progress = args.flag("--progress");
+    let auto_precision = args.flag("--auto-precision");
+    let indexed = args.flag("--indexed");
+    let auto_strip_opaque_alpha = args.flag("--strip-opaque-alpha");
+    let alpha_mode = if args.flag("--premultiplied") {
+        AlphaMode::Premultiplied
+    } else {
+        AlphaMode::Straight
+    };
+    let transparent_gray =
+        args.value("--transparent-gray")
+            .map(|value| match value.parse::<u8>() {
+                Ok(level) => level,
+                Err(_) => {
+                    eprintln!("Invalid gray level (expected 0-255): {}", value);
+                    std::process::exit(1);
+                }
+            });
+    let interlace = args.flag("--interlace");
+    let streaming = args.flag("--streaming");
+    if streaming && interlace {
+        eprintln!("--streaming and --interlace cannot be used together");
         std::process::exit(1);
     }
-
-    let mut compression_method = CompressionMethod::Custom;
-    let mut image_path = &args[1];
-    let mut output_path_arg = args.get(2);
-
-    if args.len() >= 2 && (args[1] == "--custom" || args[1] == "--flate2") {
-        if args.len() < 3 {
-            print_usage(&args[0]);
+    let max_memory = args
+        .value("--max-memory")
+        .map(|value| match value.parse::<usize>() {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                eprintln!(
+                    "Invalid memory budget (expected a number of bytes): {}",
+                    value
+                );
+                std::process::exit(1);
+            }
+        });
+    if max_memory.is_some() && interlace {
+        eprintln!("--max-memory and --interlace cannot be used together");
+        std::process::exit(1);
+    }
+    let mmap = args.flag("--mmap");
+    let checksum = args.value("--checksum");
+    match checksum.as_deref() {
+        None => {}
+        #[cfg(feature = "sha256")]
+        Some("sha256") => {}
+        #[cfg(feature = "md5")]
+        Some("md5") => {}
+        Some(other) => {
+            eprintln!(
+                "Unsupported checksum algorithm '{}' (sha256 and md5 need their cargo features)",
+                other
+            );
             std::process::exit(1);
         }
-
-        compression_method = match args[1].as_str() {
-            "--custom" => CompressionMethod::Custom,
-            "--flate2" => CompressionMethod::Flate2,
-            _ => CompressionMethod::Custom,
-        };
-
-        image_path = &args[2];
-        output_path_arg = args.get(3);
     }
+    if mmap && !cfg!(feature = "mmap") {
+        eprintln!("--mmap requires rpng to be built with the `mmap` feature");
+        std::process::exit(1);
+    }
+    let flatten_onto = args.value("--flatten-onto");
+    let trim = args.flag("--trim");
+    let record_offset = args.flag("--record-offset");
+    if record_offset && !trim {
+        eprintln!("--record-offset requires --trim");
+        std::process::exit(1);
+    }
+    let empty_trim = if args.flag("--empty-pixel") {
+        trim::EmptyTrim::TransparentPixel
+    } else {
+        trim::EmptyTrim::Error
+    };
+    if empty_trim == trim::EmptyTrim::TransparentPixel && !trim {
+        eprintln!("--empty-pixel requires --trim");
+        std::process::exit(1);
+    }
+    let keep_metadata = args.flag("--keep-metadata");
+    let keep_chunks = args.flag("--keep-chunks");
+    if args.flag("--auto-orient") && args.flag("--no-auto-orient") {
+        eprintln!("--auto-orient and --no-auto-orient cannot be used together");
+        std::process::exit(1);
+    }
+    let auto_orient = !args.flag("--no-auto-orient");
+    let verify = args.flag("--verify");
+    if verify
+        && (args.value("--significant-bits").is_some()
+            || args.value("--transparent-gray").is_some()
+            || args.value("--color-type").is_some()
+            || args.flag("--premultiplied")
+            || args.value("--hdr").is_some())
+    {
+        eprintln!("--verify cannot be combined with options that change pixel values");
+        std::process::exit(1);
+    }
+    let passthrough = args.flag("--passthrough");
+    let reencode = args.flag("--reencode");
+    if passthrough && reencode {
+        eprintln!("--passthrough and --reencode cannot be used together");
+        std::process::exit(1);
+    }
+    let file_mode = match args.value("--mode") {
+        Some(value) => match parse_file_mode(&value) {
+            Some(mode) => Some(mode),
+            None => {
+                eprintln!("Invalid file mode (expected octal, e.g. 644): {}", value);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut text = Vec::new();
+    for value in args.values("--text") {
+        let (keyword, text_value) = parse_text_option(&value);
+        text.push(TextChunk::Latin1 {
+            keyword,
+            text: text_value,
+        });
+    }
+    for value in args.values("--text-compressed") {
+        let (keyword, text_value) = parse_text_option(&value);
+        text.push(TextChunk::Compressed {
+            keyword,
+            text: text_value,
+        });
+    }
+    for value in args.values("--text-utf8") {
+        let (keyword, text_value) = parse_text_option(&value);
+        text.push(TextChunk::Utf8 {
+            keyword,
+            text: text_value,
+        });
+    }
+    let color_type = match args
+        .value("--color-type")
+        .map(|value| parse_color_type(&value))
+    {
+        Some(Ok(color_type)) => Some(color_type),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    if alpha_mode == AlphaMode::Premultiplied && (indexed || color_type == Some(3)) {
+        eprintln!("--premultiplied cannot be combined with indexed (palette) output");
+        std::process::exit(1);
+    }
+    let significant_bits = match args.value("--significant-bits") {
+        Some(value) => match parse_significant_bits(&value) {
+            Some(bits) => Some(bits),
+            None => {
+                eprintln!(
+                    "Invalid significant bits (expected R,G,B or R,G,B,A): {}",
+                    value
+                );
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let dpi = args.value("--dpi").map(|value| match value.parse::<f32>() {
+        Ok(dpi) if dpi > 0.0 => dpi,
+        _ => {
+            eprintln!("Invalid DPI (expected a positive number): {}", value);
+            std::process::exit(1);
+        }
+    });
+    let gamma = args
+        .value("--gamma")
+        .map(|value| match value.parse::<f32>() {
+            Ok(gamma) if gamma > 0.0 => gamma,
+            _ => {
+                eprintln!("Invalid gamma (expected a positive number): {}", value);
+                std::process::exit(1);
+            }
+        });
+    let float_mapping = match args.value("--hdr").as_deref() {
+        None | Some("strict") => FloatMapping::Strict,
+        Some("clamp") => FloatMapping::Clamp,
+        Some(value) => match value.parse::<f32>() {
+            Ok(gamma) if gamma.is_finite() && gamma > 0.0 => FloatMapping::Gamma(gamma),
+            _ => {
+                eprintln!(
+                    "Invalid --hdr mode (expected strict, clamp or a gamma such as 2.2): {}",
+                    value
+                );
+                std::process::exit(1);
+            }
+        },
+    };
+    let compression_level = match parse_compression_level(&args) {
+        Ok(level) => level,
+        Err(e) => {
+            eprintln!("{}", e);
+            print_usage(&program_name);
+            std::process::exit(1);
+        }
+    };
+    let srgb = args.flag("--srgb");
+    let timestamp = args.flag("--timestamp");
+    let force = args.flag("--force");
+    if args.flag("--quiet") && args.flag("--json") {
+        eprintln!("--quiet and --json cannot be used together");
+        std::process::exit(1);
+    }
+    if args.flag("--pretty") && !cfg!(feature = "indicatif") {
+        eprintln!("--pretty requires rpng to be built with the `indicatif` feature");
+        std::process::exit(1);
+    }
+    let batch = BatchOptions {
+        recursive: args.flag("--recursive"),
+        extensions: args.value("--ext").map(|value| parse_extensions(&value)),
+        force,
+        ignore_orientation: !auto_orient,
+        color_type,
+        output: batch_output(
+            args.flag("--quiet"),
+            args.flag("--json"),
+            args.flag("--pretty"),
+            std::io::stdout().is_terminal(),
+        ),
+    };
+    let output_dir = args.value("--output-dir").map(PathBuf::from);
+    let flatten = args.flag("--flatten");
+    let preserve_structure = args.flag("--preserve-structure");
+    if flatten && preserve_structure {
+        eprintln!("--flatten and --preserve-structure cannot be used together");
+        std::process::exit(1);
+    }
+    let layout = if flatten {
+        OutputLayout::Flatten
+    } else {
+        OutputLayout::PreserveStructure
+    };
 
-    let image = match ImageReader::open(image_path) {
-        Ok(image) => match image.decode() {
-            Ok(i) => i,
-            Err(e) => {
-                eprintln!("Error decoding image: {}", e);
+    let page = match args.value("--page") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(page) => Some(page),
+            Err(_) => {
+                eprintln!("Invalid page number: {}", value);
                 std::process::exit(1);
             }
         },
+        None => None,
+    };
+
+    let compression_method = match parse_compression_method(&args, compression_level) {
+        Ok(method) => method,
         Err(e) => {
-            eprintln!("Error opening image: {}", e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     };
 
-    let output_path = if let Some(path_str) = output_path_arg {
+    if args.flag("--diff") {
+        run_diff(&program_name, &args.positional, compression_method);
+        return;
+    }
+
+    // Everything that doesn't depend on the input file, shared by single and batch
+    // conversions
+    let mut options = EncodeOptions {
+        significant_bits,
+        text,
+        auto_precision,
+        indexed,
+        auto_strip_opaque_alpha,
+        alpha_mode,
+        float_mapping,
+        transparent_gray,
+        interlace,
+        streaming,
+        max_memory,
+        compression_level,
+        #[cfg(feature = "sha256")]
+        compute_sha256: checksum.as_deref() == Some("sha256"),
+        #[cfg(feature = "md5")]
+        compute_md5: checksum.as_deref() == Some("md5"),
+        ..Default::default()
+    };
+    if let Some(dpi) = dpi {
+        options.set_dpi(dpi);
+    }
+    if let Some(gamma) = gamma {
+        options.set_gamma(gamma);
+    }
+    if srgb {
+        options.set_srgb(RenderingIntent::Perceptual);
+    }
+    if timestamp {
+        options.set_modification_time_now();
+    }
+
+    if args.flag("--batch") {
+        if let Some(option) = unsupported_in_batch(&args) {
+            eprintln!("{} is not supported with --batch", option);
+            std::process::exit(1);
+        }
+        run_batch(
+            &program_name,
+            &args.positional,
+            compression_method,
+            &options,
+            &batch,
+        );
+        return;
+    }
+
+    if args.flag("--capabilities") {
+        print_capabilities();
+        return;
+    }
+
+    let (image_path, output_path_arg) = match (args.positional.as_slice(), args.value("--output")) {
+        ([input], output) => (input, output),
+        ([input, output], None) => (input, Some(output.clone())),
+        ([_, _], Some(_)) => {
+            eprintln!("--output cannot be combined with a positional output path");
+            std::process::exit(1);
+        }
+        _ => {
+            print_usage(&program_name);
+            std::process::exit(1);
+        }
+    };
+
+    let output_path = if let Some(dir) = &output_dir {
+        if output_path_arg.is_some() {
+            eprintln!("--output-dir cannot be combined with an explicit output path");
+            std::process::exit(1);
+        }
+
+        let path = get_output_path_in_dir(Path::new(image_path), dir, layout);
+        if let Some(parent) = path.parent()
+            && let Err(e) = std::fs::create_dir_all(parent)
+        {
+            eprintln!("Error creating output directory: {}", e);
+            std::process::exit(1);
+        }
+        path
+    } else if let Some(path_str) = output_path_arg {
         let mut path = PathBuf::from(path_str);
-        if path.extension().map_or(true, |ext| ext != "png") {
+        if path.extension().is_none_or(|ext| ext != "png") {
             path.set_extension("png");
         }
         path
@@ -58,17 +386,182 @@ fn main() {
         get_output_path(input_path)
     };
 
-    match save_to_png_with_compression(&image, &output_path.to_string_lossy(), compression_method) {
-        Ok(_) => {
+    // Checked up front so an existing output fails before the input is decoded;
+    // save_output re-checks atomically when it creates the file
+    if !force && output_path.exists() {
+        eprintln!(
+            "Output file already exists: {} (use --force to overwrite)",
+            output_path.display()
+        );
+        std::process::exit(1);
+    }
+
+    if passthrough && is_png_file(Path::new(image_path)) {
+        if let Some(option) = encoding_option(&args) {
+            eprintln!(
+                "--passthrough copies the input verbatim and cannot apply {}",
+                option
+            );
+            std::process::exit(1);
+        }
+
+        if let Err(e) = copy_passthrough(Path::new(image_path), &output_path) {
+            eprintln!("Error copying image: {}", e);
+            std::process::exit(1);
+        }
+        #[cfg(any(feature = "sha256", feature = "md5"))]
+        if let Some(algorithm) = &checksum {
+            let written = hashing::digest_file(&output_path, algorithm).and_then(|digest| {
+                digest.map_or(Ok(()), |digest| {
+                    hashing::write_sidecar(&output_path, algorithm, &digest).map(|_| ())
+                })
+            });
+            if let Err(e) = written {
+                eprintln!("Error writing checksum file: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(mode) = file_mode
+            && let Err(e) = set_file_mode(&output_path, mode)
+        {
+            eprintln!("Error setting file mode: {}", e);
+            std::process::exit(1);
+        }
+
+        println!(
+            "Input is already a PNG, copied unchanged: {}",
+            output_path.display()
+        );
+        return;
+    }
+
+    let mut image = match load_image(Path::new(image_path), page, auto_orient) {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if keep_metadata && let Some(exif) = exif::read_source_exif(Path::new(image_path)) {
+        options.text.extend(exif::text_chunks(&exif));
+    }
+
+    if trim {
+        let (trimmed, offset) = match trim::trim_transparent(&image, empty_trim) {
+            Ok(trimmed) => trimmed,
+            Err(e) => {
+                eprintln!("Error trimming image: {}", e);
+                std::process::exit(1);
+            }
+        };
+        image = trimmed;
+        if record_offset {
+            options.text.push(offset.to_text_chunk());
+        }
+    }
+
+    if let Some(value) = &flatten_onto {
+        let background = match resolve_background(value, Path::new(image_path)) {
+            Ok(color) => color,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        image = composite::flatten_onto(&image, background);
+    }
+
+    if keep_chunks && is_png_file(Path::new(image_path)) {
+        let copied =
+            std::fs::read(image_path).and_then(|bytes| decoder::read_ancillary_chunks(&bytes));
+        match copied {
+            Ok(mut chunks) => {
+                // The pixels were rotated upright, so the copied EXIF must not rotate them
+                // again
+                if auto_orient {
+                    for chunk in chunks
+                        .iter_mut()
+                        .filter(|chunk| &chunk.chunk_type == b"eXIf")
+                    {
+                        exif::reset_orientation(&mut chunk.data);
+                    }
+                }
+                options.copied_chunks = chunks;
+            }
+            Err(e) => {
+                eprintln!("Error reading PNG chunks: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if progress {
+        let mut last_percent = None;
+        options.on_progress(move |done, total| {
+            let percent = done as u64 * 100 / total as u64;
+            if last_percent != Some(percent) {
+                last_percent = Some(percent);
+                eprint!("\rEncoding: {:3}%", percent);
+                if done == total {
+                    eprintln!();
+                }
+            }
+        });
+    }
+
+    let result = save_output(
+        &image,
+        &output_path,
+        compression_method,
+        options,
+        color_type,
+        force,
+        mmap,
+    );
+    match result {
+        Ok(report) => {
+            if let Some(mode) = file_mode
+                && let Err(e) = set_file_mode(&output_path, mode)
+            {
+                eprintln!("Error setting file mode: {}", e);
+                std::process::exit(1);
+            }
+
+            #[cfg(any(feature = "sha256", feature = "md5"))]
+            if let Some(algorithm) = &checksum
+                && let Some(digest) = report_digest(&report, algorithm)
+                && let Err(e) = hashing::write_sidecar(&output_path, algorithm, &digest)
+            {
+                eprintln!("Error writing checksum file: {}", e);
+                std::process::exit(1);
+            }
+
             let method_name = match compression_method {
                 CompressionMethod::Custom => "custom DEFLATE",
                 CompressionMethod::Flate2 => "flate2 DEFLATE",
+                CompressionMethod::Stored => "stored (uncompressed) DEFLATE",
             };
             println!(
                 "Successfully converted to PNG using {}: {}",
                 method_name,
                 output_path.display()
             );
+            if verbose {
+                println!(
+                    "  {} raw bytes -> {} IDAT bytes (ratio {:.2})",
+                    report.raw_bytes, report.idat_bytes, report.compression_ratio
+                );
+            }
+
+            if verify {
+                match verify_output(&image, &output_path) {
+                    Ok(()) => println!("Verified: decoded output matches the source pixels"),
+                    Err(e) => {
+                        eprintln!("Verification failed: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
         Err(e) => {
             eprintln!("Error saving image: {}", e);
@@ -77,16 +570,153 @@ fn main() {
     }
 }
 
+/// Writes `image` to `output_path`, replacing an existing file only when `force` is set.
+fn save_output(
+    image: &DynamicImage,
+    output_path: &Path,
+    compression_method: CompressionMethod,
+    options: EncodeOptions,
+    color_type: Option<u8>,
+    force: bool,
+    mmap: bool,
+) -> Result<EncodeReport, EncoderError> {
+    let builder = png_builder(compression_method, options, color_type);
+    #[cfg(feature = "mmap")]
+    if mmap {
+        return if force {
+            builder.save_mmap(image, output_path)
+        } else {
+            builder.save_mmap_new(image, output_path)
+        };
+    }
+    #[cfg(not(feature = "mmap"))]
+    let _ = mmap;
+
+    if force {
+        builder.save(image, output_path)
+    } else {
+        builder.save_new(image, output_path)
+    }
+}
+
+/// The encoder setup shared by single and batch conversions; `color_type` comes from
+/// `--color-type`.
+fn png_builder(
+    compression_method: CompressionMethod,
+    options: EncodeOptions,
+    color_type: Option<u8>,
+) -> PngEncoderBuilder {
+    let builder = PngEncoderBuilder::new()
+        .compression(compression_method)
+        .options(options);
+    match color_type {
+        Some(color_type) => builder.color_type(color_type),
+        None => builder,
+    }
+}
+
 fn print_usage(program_name: &str) {
     eprintln!("Usage:");
     eprintln!(
-        "  {} [--custom|--flate2] <image_path> [output_path]",
+        "  {} [--custom|--flate2 [--level N|--fast|--best]|--stored] [--page N] [--verbose]",
+        program_name
+    );
+    eprintln!(
+        "      [--output-dir DIR [--flatten|--preserve-structure]] <image_path> [output_path]"
+    );
+    eprintln!("  {} [options] <image_path> -o <output_path>", program_name);
+    eprintln!(
+        "  {} [--custom|--flate2|--stored] --diff <a> <b> <output>",
         program_name
     );
+    eprintln!(
+        "  {} [--custom|--flate2|--stored] [--level N] [--recursive] [--ext LIST] [--force]",
+        program_name
+    );
+    eprintln!("      --batch <input_dir> [output_dir]");
     eprintln!();
     eprintln!("Compression Methods:");
     eprintln!("  --custom  Use our custom simplified DEFLATE algorithm (default)");
     eprintln!("  --flate2  Use the standard flate2 DEFLATE implementation");
+    eprintln!("  --stored  Store the image data uncompressed (for debugging)");
+    eprintln!("  --level N Flate2 compression level 0-9 (default: 6); implies --flate2");
+    eprintln!("  --fast    Same as --level 1");
+    eprintln!("  --best    Same as --level 9");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  -h, --help Print this help");
+    eprintln!("  -o, --output PATH");
+    eprintln!("             Write the PNG to PATH (same as the second positional path)");
+    eprintln!("  --capabilities  Print the color types and features this build supports");
+    eprintln!("  --page N   Page of a multi-page TIFF to convert (default: 0)");
+    eprintln!("  --dpi N    Record the print resolution in a pHYs chunk");
+    eprintln!("  --gamma G  Record the image gamma (e.g. 0.45455) in a gAMA chunk");
+    eprintln!("  --srgb     Mark the image as sRGB (sRGB and gAMA chunks)");
+    eprintln!("  --timestamp  Record the current time in a tIME chunk");
+    eprintln!("  --force    Overwrite the output PNG if it already exists");
+    eprintln!("  --verbose  Print compression statistics after converting");
+    eprintln!("  --progress Show the percentage of scanlines encoded on stderr");
+    eprintln!("  --verify   Decode the written PNG and fail if its pixels differ from the source");
+    eprintln!("  --auto-precision  Keep 16 bits per channel for 16-bit sources");
+    eprintln!("  --indexed  Write images with at most 256 colors with a palette");
+    eprintln!("  --color-type rgb|rgba|gray|gray-alpha|palette");
+    eprintln!("             Write this color type whatever the source has (palette: at most");
+    eprintln!("             256 colors)");
+    eprintln!("  --strip-opaque-alpha  Write gray or RGB when every pixel is fully opaque");
+    eprintln!("  --premultiplied  Treat the source's colors as premultiplied by alpha");
+    eprintln!("  --hdr strict|clamp|G");
+    eprintln!("             Float sources: fail on values outside 0-1 (default), clamp them,");
+    eprintln!("             or clamp and gamma-encode with gamma G (e.g. 2.2)");
+    eprintln!("  --transparent-gray N");
+    eprintln!("             Show gray level N (0-255) as transparent in gray output (tRNS)");
+    eprintln!("  --interlace  Write an Adam7-interlaced PNG for progressive display");
+    eprintln!("  --streaming  Encode a few rows at a time to bound memory on huge images");
+    eprintln!("  --max-memory BYTES");
+    eprintln!("             Stream with buffers shrunk to fit BYTES; fails if a row is too wide");
+    eprintln!("  --mmap     Write output through a memory-mapped file (`mmap` feature)");
+    eprintln!("  --checksum sha256|md5");
+    eprintln!("             Write <output>.<algorithm> with the PNG's hash (cargo features)");
+    eprintln!("  --significant-bits R,G,B[,A]");
+    eprintln!("             Zero bits below each channel's precision and record it in sBIT");
+    eprintln!("  --mode MODE  Set the output file's permissions (octal, Unix only)");
+    eprintln!("  --passthrough  Copy PNG inputs verbatim instead of re-encoding them");
+    eprintln!("                 (encoding options are rejected; --checksum still applies)");
+    eprintln!("  --reencode     Decode and re-encode PNG inputs (default)");
+    eprintln!("  --keep-chunks  Carry gAMA, pHYs, text and other ancillary chunks of PNG");
+    eprintln!("                 inputs over to the re-encoded output (EXIF orientation is");
+    eprintln!("                 reset to upright unless --no-auto-orient is given)");
+    eprintln!("  --text Keyword=Value");
+    eprintln!("             Add Latin-1 text metadata (tEXt); may be repeated");
+    eprintln!("  --text-utf8 Keyword=Value");
+    eprintln!("             Add UTF-8 text metadata (iTXt); may be repeated");
+    eprintln!("  --text-compressed Keyword=Value");
+    eprintln!("             Add compressed Latin-1 text metadata (zTXt); may be repeated");
+    eprintln!("  --auto-orient  Rotate/flip JPEG and WebP inputs upright per EXIF (default)");
+    eprintln!("  --no-auto-orient  Keep pixels as stored, ignoring the EXIF orientation");
+    eprintln!("  --keep-metadata");
+    eprintln!("             Copy EXIF fields such as Make, Model and DateTime into text chunks");
+    eprintln!("  --trim     Crop away fully transparent borders");
+    eprintln!("  --record-offset");
+    eprintln!("             With --trim, store the crop offset and original size in tEXt");
+    eprintln!("  --empty-pixel");
+    eprintln!("             With --trim, write a fully transparent image as one transparent");
+    eprintln!("             pixel instead of failing");
+    eprintln!("  --flatten-onto <color|bg-chunk>");
+    eprintln!("             Composite transparency onto #RRGGBB or the source bKGD color");
+    eprintln!("  --output-dir DIR      Write the derived-name PNG into DIR (created if missing)");
+    eprintln!("  --preserve-structure  Mirror the input's directories under DIR (default)");
+    eprintln!("  --flatten             Put every output directly in DIR");
+    eprintln!();
+    eprintln!("Batch Options:");
+    eprintln!("  --batch <input_dir> [output_dir]");
+    eprintln!("             Convert every image in input_dir (next to the inputs by default)");
+    eprintln!("             with the encoding options above; per-file options are rejected");
+    eprintln!("  --recursive  Include subdirectories, mirroring them under output_dir");
+    eprintln!("  --ext LIST   Only convert these extensions (e.g. jpg,jpeg,bmp,gif)");
+    eprintln!("  --force      Convert even when the output PNG already exists");
+    eprintln!("  --quiet      Only report failures");
+    eprintln!("  --json       Report each file and the summary as JSON lines");
+    eprintln!("  --pretty     Show a progress bar on a terminal (needs the `indicatif` feature)");
     eprintln!();
     eprintln!("Examples:");
     eprintln!(
@@ -95,6 +725,905 @@ fn print_usage(program_name: &str) {
     );
     eprintln!("  {} --custom photo.jpg output.png", program_name);
     eprintln!("  {} --flate2 photo.jpg output.png", program_name);
+    eprintln!("  {} photo.jpg --flate2 -o output.png", program_name);
+    eprintln!("  {} --flate2 --diff a.png b.png diff.png", program_name);
+    eprintln!("  {} --flate2 --batch photos/ converted/", program_name);
+}
+
+/// Returns the streamed digest for `algorithm` from an encode report.
+#[cfg(any(feature = "sha256", feature = "md5"))]
+fn report_digest(report: &encoder::EncodeReport, algorithm: &str) -> Option<Vec<u8>> {
+    match algorithm {
+        #[cfg(feature = "sha256")]
+        "sha256" => report.sha256.map(|digest| digest.to_vec()),
+        #[cfg(feature = "md5")]
+        "md5" => report.md5.map(|digest| digest.to_vec()),
+        _ => None,
+    }
+}
+
+/// Prints the color types and features this build can encode.
+fn print_capabilities() {
+    println!("Color types (color type, bit depth):");
+    for (color_type, bit_depth) in encoder::supported_color_types() {
+        println!("  {}, {}", color_type, bit_depth);
+    }
+    println!(
+        "Interlace: {}",
+        if encoder::supports_interlace() {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+}
+
+/// Writes a PNG visualizing the per-pixel difference between two images.
+fn run_diff(program_name: &str, paths: &[String], compression_method: CompressionMethod) {
+    let [first, second, output] = paths else {
+        print_usage(program_name);
+        std::process::exit(1);
+    };
+
+    let load = |path: &str| match load_image(Path::new(path), None, true) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (first_image, second_image) = (load(first), load(second));
+
+    let diff = match diff::difference_image(&first_image, &second_image, DIFF_AMPLIFICATION) {
+        Ok(diff) => diff,
+        Err(e) => {
+            eprintln!("Error comparing images: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match save_to_png_with_options(&diff, output, compression_method, EncodeOptions::default()) {
+        Ok(_) => println!("Wrote difference image: {}", output),
+        Err(e) => {
+            eprintln!("Error saving image: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Decodes the PNG at `output_path` with rpng's own decoder and checks that its pixels
+/// match `expected`, the image that was encoded.
+///
+/// Sources with 16-bit channels are compared at 8 bits unless the output kept 16 bits.
+fn verify_output(expected: &DynamicImage, output_path: &Path) -> Result<(), String> {
+    let bytes = std::fs::read(output_path).map_err(|e| format!("Error reading output: {}", e))?;
+    let decoded = decode_png(&bytes).map_err(|e| format!("Error decoding output: {}", e))?;
+
+    if decoded.dimensions() != expected.dimensions() {
+        return Err(format!(
+            "decoded size {:?} differs from source size {:?}",
+            decoded.dimensions(),
+            expected.dimensions()
+        ));
+    }
+
+    let color = decoded.color();
+    let mismatch = if color.bytes_per_pixel() > color.channel_count() {
+        first_mismatch(&expected.to_rgba16(), &decoded.to_rgba16())
+    } else {
+        first_mismatch(&expected.to_rgba8(), &decoded.to_rgba8())
+    };
+
+    match mismatch {
+        None => Ok(()),
+        Some(description) => Err(description),
+    }
+}
+
+/// Describes the first pixel that differs between `expected` and `decoded`, if any.
+fn first_mismatch<P>(
+    expected: &ImageBuffer<P, Vec<P::Subpixel>>,
+    decoded: &ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Option<String>
+where
+    P: Pixel + PartialEq,
+    P::Subpixel: std::fmt::Debug,
+{
+    expected
+        .enumerate_pixels()
+        .zip(decoded.pixels())
+        .find(|((_, _, a), b)| a != b)
+        .map(|((x, y, a), b)| {
+            format!(
+                "pixel ({}, {}) is {:?} in the source but {:?} in the output",
+                x,
+                y,
+                a.channels(),
+                b.channels()
+            )
+        })
+}
+
+/// Converts a directory of images for `--batch`, exiting with an error status if any
+/// file failed.
+fn run_batch(
+    program_name: &str,
+    paths: &[String],
+    compression_method: CompressionMethod,
+    options: &EncodeOptions,
+    batch: &BatchOptions,
+) {
+    let (input_dir, output_dir) = match paths {
+        [input_dir] => (input_dir, None),
+        [input_dir, output_dir] => (input_dir, Some(Path::new(output_dir))),
+        _ => {
+            print_usage(program_name);
+            std::process::exit(1);
+        }
+    };
+
+    match convert_directory(
+        Path::new(input_dir),
+        output_dir,
+        compression_method,
+        options,
+        batch,
+        &mut std::io::stdout(),
+    ) {
+        Ok(summary) => {
+            if summary.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error reading directory {}: {}", input_dir, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Options that only make sense for a single input file.
+const SINGLE_FILE_FLAGS: &[&str] = &[
+    "--trim",
+    "--record-offset",
+    "--empty-pixel",
+    "--keep-metadata",
+    "--keep-chunks",
+    "--verify",
+    "--passthrough",
+    "--mmap",
+    "--progress",
+    "--verbose",
+    "--flatten",
+];
+const SINGLE_FILE_VALUES: &[&str] = &[
+    "--output",
+    "--output-dir",
+    "--page",
+    "--flatten-onto",
+    "--checksum",
+    "--mode",
+];
+
+/// Returns the first option given in `args` that `--batch` cannot apply, so it is
+/// rejected instead of silently ignored.
+fn unsupported_in_batch(args: &ParsedArgs) -> Option<&'static str> {
+    first_given(args, SINGLE_FILE_FLAGS, SINGLE_FILE_VALUES)
+}
+
+/// Options that change how the output is encoded, none of which a `--passthrough` copy
+/// can honor.
+const ENCODING_FLAGS: &[&str] = &[
+    "--custom",
+    "--flate2",
+    "--stored",
+    "--fast",
+    "--best",
+    "--auto-precision",
+    "--indexed",
+    "--strip-opaque-alpha",
+    "--premultiplied",
+    "--interlace",
+    "--streaming",
+    "--mmap",
+    "--srgb",
+    "--timestamp",
+    "--trim",
+    "--record-offset",
+    "--empty-pixel",
+    "--verify",
+    "--keep-metadata",
+    "--keep-chunks",
+];
+const ENCODING_VALUES: &[&str] = &[
+    "--level",
+    "--max-memory",
+    "--dpi",
+    "--gamma",
+    "--hdr",
+    "--transparent-gray",
+    "--significant-bits",
+    "--color-type",
+    "--text",
+    "--text-utf8",
+    "--text-compressed",
+    "--flatten-onto",
+];
+
+/// Returns the first encoding option given in `args`; see [`ENCODING_FLAGS`].
+fn encoding_option(args: &ParsedArgs) -> Option<&'static str> {
+    first_given(args, ENCODING_FLAGS, ENCODING_VALUES)
+}
+
+/// The first of `flags` set or `values` given in `args`.
+fn first_given(
+    args: &ParsedArgs,
+    flags: &[&'static str],
+    values: &[&'static str],
+) -> Option<&'static str> {
+    flags
+        .iter()
+        .find(|name| args.flag(name))
+        .or_else(|| values.iter().find(|name| args.value(name).is_some()))
+        .copied()
+}
+
+/// Which files a batch conversion picks up and whether it overwrites existing output.
+#[derive(Clone, Debug, Default)]
+struct BatchOptions {
+    /// Descend into subdirectories, mirroring them under the output directory.
+    recursive: bool,
+    /// Lowercase input extensions to convert; `None` accepts every readable image format.
+    extensions: Option<Vec<String>>,
+    /// Convert even when the PNG it would write already exists.
+    force: bool,
+    /// Keep pixels as stored instead of applying the EXIF orientation.
+    ignore_orientation: bool,
+    /// PNG color type from `--color-type`, overriding the one picked from each image.
+    color_type: Option<u8>,
+    /// How progress and the final summary are reported.
+    output: BatchOutput,
+}
+
+/// How a batch conversion reports each file and the final summary on stdout. Failures
+/// always go to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum BatchOutput {
+    /// A line per file, then a summary line.
+    #[default]
+    Lines,
+    /// Nothing but failures.
+    Quiet,
+    /// A JSON object per file, then one with the summary counts.
+    Json,
+    /// A progress bar with the files done, the current file and the throughput.
+    #[cfg(feature = "indicatif")]
+    ProgressBar,
+}
+
+/// Picks the batch output for `--quiet`, `--json` and `--pretty`. The progress bar is
+/// only drawn on a terminal and gives way to quiet or JSON output.
+fn batch_output(quiet: bool, json: bool, pretty: bool, stdout_is_terminal: bool) -> BatchOutput {
+    if quiet {
+        BatchOutput::Quiet
+    } else if json {
+        BatchOutput::Json
+    } else if pretty && stdout_is_terminal {
+        #[cfg(feature = "indicatif")]
+        return BatchOutput::ProgressBar;
+        #[cfg(not(feature = "indicatif"))]
+        BatchOutput::Lines
+    } else {
+        BatchOutput::Lines
+    }
+}
+
+/// What happened to one input of a batch conversion.
+enum BatchOutcome {
+    Converted,
+    /// The output PNG already existed.
+    Skipped,
+    Failed(String),
+}
+
+/// Writes a batch conversion's progress to `out` in the style of a [`BatchOutput`].
+struct BatchReporter<'a, W: Write> {
+    output: BatchOutput,
+    out: &'a mut W,
+    #[cfg(feature = "indicatif")]
+    bar: Option<indicatif::ProgressBar>,
+}
+
+impl<'a, W: Write> BatchReporter<'a, W> {
+    #[cfg_attr(not(feature = "indicatif"), allow(unused_variables))]
+    fn new(output: BatchOutput, total: usize, out: &'a mut W) -> Self {
+        BatchReporter {
+            output,
+            out,
+            #[cfg(feature = "indicatif")]
+            bar: (output == BatchOutput::ProgressBar).then(|| {
+                let bar = indicatif::ProgressBar::with_draw_target(
+                    Some(total as u64),
+                    indicatif::ProgressDrawTarget::stdout(),
+                );
+                bar.set_style(
+                    indicatif::ProgressStyle::with_template(
+                        "{bar:30} {pos}/{len} files ({per_sec}) {wide_msg}",
+                    )
+                    .expect("progress template is valid"),
+                );
+                bar
+            }),
+        }
+    }
+
+    /// Shows `input` as the file being converted.
+    #[cfg_attr(not(feature = "indicatif"), allow(unused_variables))]
+    fn start(&mut self, input: &Path) {
+        #[cfg(feature = "indicatif")]
+        if let Some(bar) = &self.bar {
+            bar.set_message(input.display().to_string());
+        }
+    }
+
+    fn finish_file(
+        &mut self,
+        input: &Path,
+        output: &Path,
+        outcome: &BatchOutcome,
+    ) -> std::io::Result<()> {
+        if let BatchOutcome::Failed(error) = outcome {
+            #[cfg(feature = "indicatif")]
+            if let Some(bar) = &self.bar {
+                bar.suspend(|| eprintln!("{}: {}", input.display(), error));
+                bar.inc(1);
+                return Ok(());
+            }
+            eprintln!("{}: {}", input.display(), error);
+        }
+
+        match self.output {
+            BatchOutput::Lines => match outcome {
+                BatchOutcome::Converted => {
+                    writeln!(self.out, "{} -> {}", input.display(), output.display())
+                }
+                BatchOutcome::Skipped => writeln!(
+                    self.out,
+                    "{}: {} already exists, skipping",
+                    input.display(),
+                    output.display()
+                ),
+                BatchOutcome::Failed(_) => Ok(()),
+            },
+            BatchOutput::Quiet => Ok(()),
+            BatchOutput::Json => {
+                let (status, error) = match outcome {
+                    BatchOutcome::Converted => ("converted", None),
+                    BatchOutcome::Skipped => ("skipped", None),
+                    BatchOutcome::Failed(error) => ("failed", Some(error.as_str())),
+                };
+                write!(
+                    self.out,
+                    "{{\"input\":{},\"output\":{},\"status\":\"{}\"",
+                    json_string(&input.display().to_string()),
+                    json_string(&output.display().to_string()),
+                    status
+                )?;
+                if let Some(error) = error {
+                    write!(self.out, ",\"error\":{}", json_string(error))?;
+                }
+                writeln!(self.out, "}}")
+            }
+            #[cfg(feature = "indicatif")]
+            BatchOutput::ProgressBar => {
+                if let Some(bar) = &self.bar {
+                    bar.inc(1);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self, summary: &BatchSummary) -> std::io::Result<()> {
+        #[cfg(feature = "indicatif")]
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+
+        let total = summary.converted + summary.failed + summary.skipped;
+        match self.output {
+            BatchOutput::Quiet => Ok(()),
+            BatchOutput::Json => writeln!(
+                self.out,
+                "{{\"converted\":{},\"failed\":{},\"skipped\":{}}}",
+                summary.converted, summary.failed, summary.skipped
+            ),
+            _ => writeln!(
+                self.out,
+                "Converted {} of {} images ({} failed, {} skipped)",
+                summary.converted, total, summary.failed, summary.skipped
+            ),
+        }
+    }
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl BatchOptions {
+    fn accepts(&self, path: &Path) -> bool {
+        let Some(extension) = path.extension().and_then(|ext| ext.to_str()) else {
+            return false;
+        };
+
+        match &self.extensions {
+            Some(extensions) => extensions.contains(&extension.to_ascii_lowercase()),
+            None => ImageFormat::from_extension(extension)
+                .is_some_and(|format| format.reading_enabled()),
+        }
+    }
+}
+
+/// Parses an `--ext` value such as `jpg,JPEG, .bmp` into lowercase extensions.
+fn parse_extensions(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+/// Counts of the files a batch conversion handled.
+#[derive(Debug, Default, PartialEq)]
+struct BatchSummary {
+    converted: usize,
+    failed: usize,
+    /// Inputs left alone because their PNG already existed.
+    skipped: usize,
+}
+
+/// Converts the image files in `input_dir` to PNGs with the same stem, reporting the
+/// outcome for each file and a summary to `out` as `batch.output` asks, and carrying on
+/// past failures.
+///
+/// Outputs go to the same relative path under `output_dir`, or next to their inputs when
+/// it is `None`. An input whose PNG already exists is skipped unless `batch.force` is set.
+fn convert_directory<W: Write>(
+    input_dir: &Path,
+    output_dir: Option<&Path>,
+    compression_method: CompressionMethod,
+    options: &EncodeOptions,
+    batch: &BatchOptions,
+    out: &mut W,
+) -> std::io::Result<BatchSummary> {
+    let output_root = output_dir.unwrap_or(input_dir);
+    // Never pick up earlier output when the output directory is inside the input tree
+    let skip_dir = output_dir.and_then(|dir| dir.canonicalize().ok());
+    let mut inputs = Vec::new();
+    collect_batch_inputs(input_dir, batch, skip_dir.as_deref(), &mut inputs)?;
+    inputs.sort();
+
+    let mut summary = BatchSummary::default();
+    let mut reporter = BatchReporter::new(batch.output, inputs.len(), out);
+    for input in inputs {
+        let relative = input.strip_prefix(input_dir).unwrap_or(&input);
+        let output = output_root.join(relative).with_extension("png");
+        if !batch.force && output.exists() {
+            reporter.finish_file(&input, &output, &BatchOutcome::Skipped)?;
+            summary.skipped += 1;
+            continue;
+        }
+
+        reporter.start(&input);
+        let result = load_image(&input, None, !batch.ignore_orientation).and_then(|image| {
+            if let Some(parent) = output.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("Error creating output directory: {}", e))?;
+            }
+            png_builder(compression_method, options.clone(), batch.color_type)
+                .save(&image, &output)
+                .map_err(|e| format!("Error saving image: {}", e))
+        });
+
+        let outcome = match result {
+            Ok(_) => {
+                summary.converted += 1;
+                BatchOutcome::Converted
+            }
+            Err(e) => {
+                summary.failed += 1;
+                BatchOutcome::Failed(e)
+            }
+        };
+        reporter.finish_file(&input, &output, &outcome)?;
+    }
+
+    reporter.finish(&summary)?;
+    Ok(summary)
+}
+
+/// Appends the files in `dir` that `batch` accepts to `inputs`, and those in its
+/// subdirectories other than `skip_dir` if `batch.recursive` is set.
+fn collect_batch_inputs(
+    dir: &Path,
+    batch: &BatchOptions,
+    skip_dir: Option<&Path>,
+    inputs: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            if batch.recursive && path.canonicalize().ok().as_deref() != skip_dir {
+                collect_batch_inputs(&path, batch, skip_dir, inputs)?;
+            }
+        } else if path.is_file() && batch.accepts(&path) {
+            inputs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the file at `path` starts with the PNG signature.
+fn is_png_file(path: &Path) -> bool {
+    let mut signature = [0u8; 8];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|_| signature == encoder::PNG_SIGNATURE)
+}
+
+/// Copies a PNG input to `output_path` byte for byte. Copying a file onto itself is a
+/// no-op rather than truncating it.
+fn copy_passthrough(input_path: &Path, output_path: &Path) -> std::io::Result<()> {
+    if let (Ok(input), Ok(output)) = (input_path.canonicalize(), output_path.canonicalize())
+        && input == output
+    {
+        return Ok(());
+    }
+
+    std::fs::copy(input_path, output_path).map(|_| ())
+}
+
+/// Parses an octal permission mode such as `644` or `0755`.
+fn parse_file_mode(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+}
+
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    eprintln!("Warning: --mode is only supported on Unix, ignoring");
+    Ok(())
+}
+
+/// Parses a `--color-type` name into a PNG color type.
+fn parse_color_type(value: &str) -> Result<u8, String> {
+    match value {
+        "gray" => Ok(0),
+        "rgb" => Ok(2),
+        "palette" => Ok(3),
+        "gray-alpha" => Ok(4),
+        "rgba" => Ok(6),
+        _ => Err(format!(
+            "Invalid color type (expected rgb, rgba, gray, gray-alpha or palette): {}",
+            value
+        )),
+    }
+}
+
+/// Parses `R,G,B` or `R,G,B,A` significant bit counts; alpha defaults to 8.
+fn parse_significant_bits(value: &str) -> Option<[u8; 4]> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    match parts.as_slice() {
+        [r, g, b] => Some([*r, *g, *b, 8]),
+        [r, g, b, a] => Some([*r, *g, *b, *a]),
+        _ => None,
+    }
+}
+
+/// Options that take no value.
+const FLAGS: &[&str] = &[
+    "--help",
+    "--custom",
+    "--flate2",
+    "--stored",
+    "--diff",
+    "--batch",
+    "--recursive",
+    "--force",
+    "--capabilities",
+    "--verbose",
+    "--progress",
+    "--auto-precision",
+    "--indexed",
+    "--strip-opaque-alpha",
+    "--premultiplied",
+    "--interlace",
+    "--streaming",
+    "--mmap",
+    "--trim",
+    "--record-offset",
+    "--empty-pixel",
+    "--passthrough",
+    "--reencode",
+    "--srgb",
+    "--timestamp",
+    "--flatten",
+    "--preserve-structure",
+    "--fast",
+    "--best",
+    "--verify",
+    "--keep-metadata",
+    "--keep-chunks",
+    "--auto-orient",
+    "--no-auto-orient",
+    "--quiet",
+    "--json",
+    "--pretty",
+];
+
+/// Options followed by a value, either as the next argument or after `=`.
+const VALUE_OPTIONS: &[&str] = &[
+    "--output",
+    "--output-dir",
+    "--level",
+    "--ext",
+    "--page",
+    "--dpi",
+    "--gamma",
+    "--hdr",
+    "--transparent-gray",
+    "--checksum",
+    "--significant-bits",
+    "--color-type",
+    "--mode",
+    "--text",
+    "--text-utf8",
+    "--text-compressed",
+    "--flatten-onto",
+    "--max-memory",
+];
+
+/// Short spellings and the long options they stand for.
+const SHORT_OPTIONS: &[(&str, &str)] = &[("-h", "--help"), ("-o", "--output")];
+
+/// A command line sorted into flags, option values and positional arguments.
+#[derive(Debug, Default, PartialEq)]
+struct ParsedArgs {
+    flags: Vec<&'static str>,
+    values: Vec<(&'static str, String)>,
+    positional: Vec<String>,
+}
+
+impl ParsedArgs {
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+
+    /// The value of the last occurrence of option `name`.
+    fn value(&self, name: &str) -> Option<String> {
+        self.values(name).pop()
+    }
+
+    /// Every value given for option `name`, in order.
+    fn values(&self, name: &str) -> Vec<String> {
+        self.values
+            .iter()
+            .filter(|(option, _)| *option == name)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+}
+
+/// Resolves `arg` to the name of a known flag or value option, if it is one.
+fn known_option(arg: &str) -> Option<&'static str> {
+    SHORT_OPTIONS
+        .iter()
+        .find(|(short, _)| *short == arg)
+        .map(|(_, long)| *long)
+        .or_else(|| {
+            FLAGS
+                .iter()
+                .chain(VALUE_OPTIONS)
+                .find(|name| **name == arg)
+                .copied()
+        })
+}
+
+/// Sorts the arguments after the program name into flags, option values and
+/// positional arguments. Options may appear anywhere, before or after the paths.
+/// Everything after `--` is positional.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<ParsedArgs, String> {
+    let mut parsed = ParsedArgs::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            parsed.positional.extend(args);
+            break;
+        }
+
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        match known_option(name) {
+            Some(flag) if FLAGS.contains(&flag) => {
+                if inline_value.is_some() {
+                    return Err(format!("{} does not take a value", flag));
+                }
+                parsed.flags.push(flag);
+            }
+            Some(option) => {
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => args
+                        .next()
+                        .filter(|value| known_option(value).is_none())
+                        .ok_or_else(|| format!("Missing value for {}", option))?,
+                };
+                parsed.values.push((option, value));
+            }
+            None if arg.len() > 1 && arg.starts_with('-') => {
+                return Err(format!("Unknown option: {}", arg));
+            }
+            None => parsed.positional.push(arg),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Picks the compression method from `--custom`, `--flate2` or `--stored`. A
+/// compression level only applies to flate2, so it selects flate2 when no method is
+/// given and is an error with the other two.
+fn parse_compression_method(
+    args: &ParsedArgs,
+    compression_level: Option<u8>,
+) -> Result<CompressionMethod, String> {
+    let methods = [
+        ("--custom", CompressionMethod::Custom),
+        ("--flate2", CompressionMethod::Flate2),
+        ("--stored", CompressionMethod::Stored),
+    ];
+    let chosen: Vec<_> = methods.iter().filter(|(flag, _)| args.flag(flag)).collect();
+    match (chosen.as_slice(), compression_level) {
+        ([], None) => Ok(CompressionMethod::Custom),
+        ([], Some(_)) => Ok(CompressionMethod::Flate2),
+        ([(_, CompressionMethod::Flate2)], _) | ([_], None) => Ok(chosen[0].1),
+        ([(flag, _)], Some(_)) => Err(format!(
+            "--level, --fast and --best only apply to --flate2, not {}",
+            flag
+        )),
+        _ => Err("--custom, --flate2 and --stored cannot be used together".to_string()),
+    }
+}
+
+/// Reads the flate2 compression level from `--level N`, `--fast` (1) or `--best` (9).
+fn parse_compression_level(args: &ParsedArgs) -> Result<Option<u8>, String> {
+    let given = ["--level", "--fast", "--best"]
+        .iter()
+        .filter(|name| args.flag(name) || args.value(name).is_some())
+        .count();
+    if given > 1 {
+        return Err("--level, --fast and --best cannot be used together".to_string());
+    }
+
+    if args.flag("--fast") {
+        return Ok(Some(1));
+    }
+    if args.flag("--best") {
+        return Ok(Some(9));
+    }
+    args.value("--level")
+        .map(|value| match value.parse::<u8>() {
+            Ok(level) if level <= 9 => Ok(level),
+            _ => Err(format!(
+                "Invalid compression level (expected 0-9): {}",
+                value
+            )),
+        })
+        .transpose()
+}
+
+/// Splits a `--text` value of the form `Keyword=Value`.
+fn parse_text_option(value: &str) -> (String, String) {
+    match value.split_once('=') {
+        Some((keyword, text)) => (keyword.to_string(), text.to_string()),
+        None => {
+            eprintln!("Invalid text metadata (expected Keyword=Value): {}", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Resolves a `--flatten-onto` value: either an explicit color, or `bg-chunk` to use the
+/// source PNG's bKGD color (white when the source has none).
+fn resolve_background(value: &str, image_path: &Path) -> Result<[u8; 3], String> {
+    if value != "bg-chunk" {
+        return composite::parse_color(value)
+            .ok_or_else(|| format!("Invalid background color: {}", value));
+    }
+
+    let bytes = std::fs::read(image_path).map_err(|e| format!("Error opening image: {}", e))?;
+    let background = if bytes.starts_with(&encoder::PNG_SIGNATURE) {
+        decoder::read_background(&bytes).map_err(|e| format!("Error reading bKGD: {}", e))?
+    } else {
+        None
+    };
+
+    Ok(background.unwrap_or_else(|| {
+        eprintln!("Warning: no bKGD chunk in source, flattening onto white");
+        [255, 255, 255]
+    }))
+}
+
+/// Decodes the image at `path`. With `auto_orient`, an EXIF orientation tag is applied so
+/// the result is upright; TIFF inputs are always used as stored.
+fn load_image(path: &Path, page: Option<usize>, auto_orient: bool) -> Result<DynamicImage, String> {
+    #[cfg(feature = "tiff-pages")]
+    if tiff_pages::is_tiff_path(path) {
+        let page_count =
+            tiff_pages::page_count(path).map_err(|e| format!("Error opening image: {}", e))?;
+        if page.is_none() && page_count > 1 {
+            eprintln!(
+                "Warning: TIFF has {} pages, converting page 0 (use --page N to choose)",
+                page_count
+            );
+        }
+
+        return tiff_pages::decode_page(path, page.unwrap_or(0))
+            .map_err(|e| format!("Error decoding image: {}", e));
+    }
+
+    if page.is_some_and(|page| page > 0) {
+        return Err("--page is only supported for multi-page TIFF inputs".to_string());
+    }
+
+    let mut decoder = ImageReader::open(path)
+        .map_err(|e| format!("Error opening image: {}", e))?
+        .into_decoder()
+        .map_err(|e| format!("Error decoding image: {}", e))?;
+    // Formats without EXIF, and unreadable EXIF, leave the image as stored
+    let orientation = if auto_orient {
+        decoder.orientation().unwrap_or(Orientation::NoTransforms)
+    } else {
+        Orientation::NoTransforms
+    };
+
+    let mut image =
+        DynamicImage::from_decoder(decoder).map_err(|e| format!("Error decoding image: {}", e))?;
+    image.apply_orientation(orientation);
+    Ok(image)
 }
 
 fn get_output_path(input_path: &Path) -> PathBuf {
@@ -107,3 +1636,621 @@ fn get_output_path(input_path: &Path) -> PathBuf {
 
     output_path
 }
+
+/// How derived output names are placed inside an `--output-dir`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputLayout {
+    /// Every output goes directly into the output directory.
+    Flatten,
+    /// The input's relative directory path is recreated under the output directory, so
+    /// same-named inputs from different directories don't clobber each other.
+    PreserveStructure,
+}
+
+fn get_output_path_in_dir(input_path: &Path, output_dir: &Path, layout: OutputLayout) -> PathBuf {
+    let mut output_path = output_dir.to_path_buf();
+
+    if layout == OutputLayout::PreserveStructure
+        && let Some(parent) = input_path.parent()
+    {
+        // Only plain directory names are kept so `..` or an absolute input path can't
+        // escape the output directory
+        for component in parent.components() {
+            if let Component::Normal(name) = component {
+                output_path.push(name);
+            }
+        }
+    }
+
+    output_path.push(input_path.file_stem().unwrap_or_default());
+    output_path.set_extension("png");
+
+    output_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<ParsedArgs, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args_accepts_options_after_paths() {
+        let legacy = parse(&["--custom", "in.jpg", "out.png"]).unwrap();
+        assert!(legacy.flag("--custom"));
+        assert_eq!(legacy.positional, ["in.jpg", "out.png"]);
+
+        let args = parse(&[
+            "in.jpg",
+            "--flate2",
+            "-o",
+            "out.png",
+            "--level=9",
+            "--verbose",
+        ])
+        .unwrap();
+        assert!(args.flag("--flate2"));
+        assert!(args.flag("--verbose"));
+        assert!(!args.flag("--custom"));
+        assert_eq!(args.positional, ["in.jpg"]);
+        assert_eq!(args.value("--output").as_deref(), Some("out.png"));
+        assert_eq!(args.value("--level").as_deref(), Some("9"));
+
+        let repeated = parse(&["--text", "A=1", "in.jpg", "--text", "B=2"]).unwrap();
+        assert_eq!(repeated.values("--text"), ["A=1", "B=2"]);
+
+        let escaped = parse(&["--", "-odd.jpg"]).unwrap();
+        assert_eq!(escaped.positional, ["-odd.jpg"]);
+    }
+
+    #[test]
+    fn test_compression_level_flags_reach_the_encoder() {
+        assert_eq!(
+            parse_compression_level(&parse(&["in.jpg"]).unwrap()),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_compression_level(&parse(&["--fast"]).unwrap()),
+            Ok(Some(1))
+        );
+        assert_eq!(
+            parse_compression_level(&parse(&["--best"]).unwrap()),
+            Ok(Some(9))
+        );
+        assert!(parse_compression_level(&parse(&["--level", "10"]).unwrap()).is_err());
+        assert!(parse_compression_level(&parse(&["--level", "-1"]).unwrap()).is_err());
+        assert!(parse_compression_level(&parse(&["--level", "3", "--best"]).unwrap()).is_err());
+
+        let method = |argv: &[&str]| {
+            let args = parse(argv).unwrap();
+            parse_compression_method(&args, parse_compression_level(&args).unwrap())
+        };
+        assert!(matches!(method(&["in.jpg"]), Ok(CompressionMethod::Custom)));
+        assert!(matches!(
+            method(&["--best", "in.jpg"]),
+            Ok(CompressionMethod::Flate2)
+        ));
+        assert!(matches!(
+            method(&["--flate2", "--fast", "in.jpg"]),
+            Ok(CompressionMethod::Flate2)
+        ));
+        assert!(method(&["--custom", "--level", "9", "in.jpg"]).is_err());
+        assert!(method(&["--stored", "--best", "in.jpg"]).is_err());
+        assert!(method(&["--custom", "--flate2", "in.jpg"]).is_err());
+
+        let dir = std::env::temp_dir().join("rpng_test_levels");
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * y) as u8, (x ^ y) as u8 * 3, 90])
+        }));
+
+        let size_at = |level: &str| {
+            let args = parse(&["--flate2", "--level", level, "in.png"]).unwrap();
+            let options = EncodeOptions {
+                compression_level: parse_compression_level(&args).unwrap(),
+                ..EncodeOptions::default()
+            };
+            let output = dir.join(format!("level{}.png", level));
+            save_to_png_with_options(
+                &image,
+                &output.to_string_lossy(),
+                CompressionMethod::Flate2,
+                options,
+            )
+            .unwrap();
+            assert_eq!(image::open(&output).unwrap().to_rgb8(), image.to_rgb8());
+            std::fs::metadata(&output).unwrap().len()
+        };
+        assert!(size_at("9") < size_at("0"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_verify_gradient_round_trip() {
+        let dir = std::env::temp_dir().join("rpng_test_verify");
+        std::fs::create_dir_all(&dir).unwrap();
+        let gradient = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(48, 32, |x, y| {
+            image::Rgba([x as u8 * 5, y as u8 * 8, (x + y) as u8, 255 - x as u8])
+        }));
+
+        for method in [CompressionMethod::Custom, CompressionMethod::Flate2] {
+            let output = dir.join("gradient.png");
+            save_to_png_with_options(
+                &gradient,
+                &output.to_string_lossy(),
+                method,
+                EncodeOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(verify_output(&gradient, &output), Ok(()));
+
+            let mut altered = gradient.to_rgba8();
+            altered.put_pixel(7, 3, image::Rgba([1, 2, 3, 4]));
+            let error = verify_output(&DynamicImage::ImageRgba8(altered), &output).unwrap_err();
+            assert!(error.contains("pixel (7, 3)"), "{}", error);
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_exif_orientation_rotates_input() {
+        // Left half red, right half blue; large blocks survive JPEG compression
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 8, |x, _| {
+            if x < 8 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        }));
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 95)
+            .encode_image(&image)
+            .unwrap();
+
+        // Little-endian EXIF with a single IFD0 entry: Orientation (SHORT) = 6, rotate 90° CW
+        let mut app1 = b"Exif\0\0II\x2a\0\x08\0\0\0\x01\0".to_vec();
+        app1.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        segment.extend(app1);
+        jpeg.splice(2..2, segment);
+
+        let path = std::env::temp_dir().join("rpng_test_orientation.jpg");
+        std::fs::write(&path, &jpeg).unwrap();
+
+        let stored = load_image(&path, None, false).unwrap();
+        assert_eq!(stored.dimensions(), (16, 8));
+
+        // Rotating clockwise turns the left (red) half into the top half
+        let upright = load_image(&path, None, true).unwrap().to_rgb8();
+        assert_eq!(upright.dimensions(), (8, 16));
+        let [r, _, b] = upright.get_pixel(4, 3).0;
+        assert!(r > 200 && b < 50, "top should be red");
+        let [r, _, b] = upright.get_pixel(4, 12).0;
+        assert!(r < 50 && b > 200, "bottom should be blue");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_args_reports_missing_values_and_unknown_options() {
+        assert_eq!(
+            parse(&["in.jpg", "--level"]),
+            Err("Missing value for --level".to_string())
+        );
+        assert_eq!(
+            parse(&["-o", "--flate2", "in.jpg"]),
+            Err("Missing value for --output".to_string())
+        );
+        assert_eq!(
+            parse(&["--bogus", "in.jpg"]),
+            Err("Unknown option: --bogus".to_string())
+        );
+        assert_eq!(
+            parse(&["--verbose=yes", "in.jpg"]),
+            Err("--verbose does not take a value".to_string())
+        );
+    }
+
+    #[test]
+    fn test_output_dir_preserve_structure_avoids_collisions() {
+        let out = Path::new("out");
+        let first = get_output_path_in_dir(
+            Path::new("a/photo.jpg"),
+            out,
+            OutputLayout::PreserveStructure,
+        );
+        let second = get_output_path_in_dir(
+            Path::new("b/photo.jpg"),
+            out,
+            OutputLayout::PreserveStructure,
+        );
+
+        assert_eq!(first, Path::new("out/a/photo.png"));
+        assert_eq!(second, Path::new("out/b/photo.png"));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_output_dir_flatten_and_escape() {
+        let out = Path::new("out");
+
+        assert_eq!(
+            get_output_path_in_dir(Path::new("a/b/photo.jpg"), out, OutputLayout::Flatten),
+            Path::new("out/photo.png")
+        );
+        assert_eq!(
+            get_output_path_in_dir(
+                Path::new("../up/photo.jpg"),
+                out,
+                OutputLayout::PreserveStructure
+            ),
+            Path::new("out/up/photo.png")
+        );
+    }
+
+    #[test]
+    fn test_parse_significant_bits() {
+        assert_eq!(parse_significant_bits("5,6,5"), Some([5, 6, 5, 8]));
+        assert_eq!(parse_significant_bits("5, 6, 5, 1"), Some([5, 6, 5, 1]));
+        assert_eq!(parse_significant_bits("5,6"), None);
+        assert_eq!(parse_significant_bits("5,x,5"), None);
+    }
+
+    #[test]
+    fn test_parse_file_mode() {
+        assert_eq!(parse_file_mode("644"), Some(0o644));
+        assert_eq!(parse_file_mode("0755"), Some(0o755));
+        assert_eq!(parse_file_mode("888"), None);
+        assert_eq!(parse_file_mode("17777"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_set_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join("rpng_test_file_mode.png");
+        std::fs::write(&path, b"png").unwrap();
+
+        set_file_mode(&path, 0o640).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_batch_converts_directory_and_continues_past_errors() {
+        let dir = std::env::temp_dir().join("rpng_test_batch");
+        let input = dir.join("in");
+        let output = dir.join("out");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&input).unwrap();
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(6, 4, |x, y| {
+            image::Rgb([x as u8 * 40, y as u8 * 60, 7])
+        }));
+        image.save(input.join("first.bmp")).unwrap();
+        image.save(input.join("second.png")).unwrap();
+        std::fs::write(input.join("broken.jpg"), b"not a jpeg").unwrap();
+        std::fs::write(input.join("notes.txt"), b"not an image").unwrap();
+
+        let summary = convert_directory(
+            &input,
+            Some(&output),
+            CompressionMethod::Flate2,
+            &EncodeOptions::default(),
+            &BatchOptions::default(),
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            BatchSummary {
+                converted: 2,
+                failed: 1,
+                skipped: 0
+            }
+        );
+
+        for stem in ["first", "second"] {
+            let converted = image::open(output.join(stem).with_extension("png")).unwrap();
+            assert_eq!(converted.to_rgb8(), image.to_rgb8());
+        }
+        assert!(!output.join("broken.png").exists());
+        assert!(!output.join("notes.png").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_batch_applies_encoding_options_and_rejects_per_file_ones() {
+        let dir = std::env::temp_dir().join("rpng_test_batch_options");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(9, 7, |x, y| {
+            image::Rgb([x as u8 * 25, y as u8 * 30, 99])
+        }));
+        image.save(dir.join("frame.bmp")).unwrap();
+
+        let options = EncodeOptions {
+            interlace: true,
+            ..EncodeOptions::default()
+        };
+        let summary = convert_directory(
+            &dir,
+            None,
+            CompressionMethod::Custom,
+            &options,
+            &BatchOptions::default(),
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(summary.converted, 1);
+        let bytes = std::fs::read(dir.join("frame.png")).unwrap();
+        // IHDR's interlace method byte
+        assert_eq!(bytes[28], 1);
+
+        let unsupported = |argv: &[&str]| unsupported_in_batch(&parse(argv).unwrap());
+        assert_eq!(
+            unsupported(&["--batch", "--interlace", "--dpi", "300", "in"]),
+            None
+        );
+        assert_eq!(unsupported(&["--batch", "--trim", "in"]), Some("--trim"));
+        assert_eq!(
+            unsupported(&["--batch", "--checksum", "sha256", "in"]),
+            Some("--checksum")
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_batch_json_output_suppresses_progress_bar() {
+        assert_eq!(batch_output(false, true, true, true), BatchOutput::Json);
+        assert_eq!(batch_output(true, false, true, true), BatchOutput::Quiet);
+        assert_eq!(batch_output(false, false, true, false), BatchOutput::Lines);
+
+        let dir = std::env::temp_dir().join("rpng_test_batch_json");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(3, 2));
+        image.save(dir.join("one \"quoted\".bmp")).unwrap();
+        std::fs::write(dir.join("broken.bmp"), b"not a bitmap").unwrap();
+
+        let batch = BatchOptions {
+            output: batch_output(false, true, true, true),
+            ..BatchOptions::default()
+        };
+        let mut out = Vec::new();
+        let summary = convert_directory(
+            &dir,
+            None,
+            CompressionMethod::Custom,
+            &EncodeOptions::default(),
+            &batch,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!((summary.converted, summary.failed), (1, 1));
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines
+                .iter()
+                .all(|line| line.starts_with('{') && line.ends_with('}'))
+        );
+        assert!(lines[0].contains("\"status\":\"failed\",\"error\":"));
+        assert!(lines[1].contains("one \\\"quoted\\\".bmp"));
+        assert!(lines[1].contains("\"status\":\"converted\""));
+        assert_eq!(lines[2], "{\"converted\":1,\"failed\":1,\"skipped\":0}");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_recursive_batch_mirrors_tree() {
+        let dir = std::env::temp_dir().join("rpng_test_batch_recursive");
+        std::fs::remove_dir_all(&dir).ok();
+        for sub in ["a/b", "c"] {
+            std::fs::create_dir_all(dir.join("in").join(sub)).unwrap();
+        }
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(5, 3, |x, y| {
+            image::Rgb([x as u8 * 50, y as u8 * 80, 200])
+        }));
+        let input = dir.join("in");
+        for name in ["top.bmp", "a/mid.jpg", "a/b/deep.gif", "c/skip.tiff"] {
+            image.save(input.join(name)).unwrap();
+        }
+
+        let output = dir.join("out");
+        let batch = BatchOptions {
+            recursive: true,
+            extensions: Some(parse_extensions("jpg,jpeg,BMP,.gif")),
+            ..BatchOptions::default()
+        };
+        let convert = |batch: &BatchOptions| {
+            convert_directory(
+                &input,
+                Some(&output),
+                CompressionMethod::Custom,
+                &EncodeOptions::default(),
+                batch,
+                &mut std::io::sink(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            convert(&batch),
+            BatchSummary {
+                converted: 3,
+                failed: 0,
+                skipped: 0
+            }
+        );
+        for name in ["top.png", "a/mid.png", "a/b/deep.png"] {
+            let converted = image::open(output.join(name)).unwrap();
+            assert_eq!((converted.width(), converted.height()), (5, 3));
+        }
+        assert!(!output.join("c").exists());
+
+        // A second run finds every PNG in place
+        assert_eq!(convert(&batch).skipped, 3);
+        let forced = BatchOptions {
+            force: true,
+            ..batch.clone()
+        };
+        assert_eq!(convert(&forced).converted, 3);
+
+        // Without an output directory, PNGs land next to their inputs
+        let in_place = BatchOptions {
+            recursive: true,
+            ..BatchOptions::default()
+        };
+        let summary = convert_directory(
+            &input,
+            None,
+            CompressionMethod::Custom,
+            &EncodeOptions::default(),
+            &in_place,
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(summary.converted, 4);
+        assert!(input.join("a/b/deep.png").exists());
+        assert!(input.join("c/skip.png").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_png_passthrough_and_reencode() {
+        let dir = std::env::temp_dir();
+        let input = dir.join("rpng_test_passthrough_in.png");
+        let copied = dir.join("rpng_test_passthrough_out.png");
+        let reencoded = dir.join("rpng_test_reencode_out.png");
+
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(8, 8, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 30, 90, 255])
+        }));
+        image.save(&input).unwrap();
+        let original = std::fs::read(&input).unwrap();
+
+        assert!(is_png_file(&input));
+        copy_passthrough(&input, &copied).unwrap();
+        assert_eq!(std::fs::read(&copied).unwrap(), original);
+
+        // Copying onto itself must leave the input intact
+        copy_passthrough(&input, &input).unwrap();
+        assert_eq!(std::fs::read(&input).unwrap(), original);
+
+        let encoding = |argv: &[&str]| encoding_option(&parse(argv).unwrap());
+        assert_eq!(encoding(&["--passthrough", "--force", "in.png"]), None);
+        assert_eq!(
+            encoding(&["--passthrough", "--checksum", "sha256", "in.png"]),
+            None
+        );
+        for option in [
+            &["--interlace"][..],
+            &["--dpi", "300"],
+            &["--fast"],
+            &["--stored"],
+        ] {
+            let argv = [&["--passthrough", "in.png"][..], option].concat();
+            assert_eq!(encoding(&argv), Some(option[0]));
+        }
+
+        let decoded = load_image(&input, None, true).unwrap();
+        save_to_png_with_options(
+            &decoded,
+            &reencoded.to_string_lossy(),
+            CompressionMethod::Flate2,
+            EncodeOptions::default(),
+        )
+        .unwrap();
+        assert_ne!(std::fs::read(&reencoded).unwrap(), original);
+
+        for path in [&input, &copied, &reencoded] {
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn test_color_type_flag_forces_gray() {
+        let args = parse(&["--color-type", "gray", "in.png"]).unwrap();
+        let color_type = parse_color_type(&args.value("--color-type").unwrap()).unwrap();
+        assert_eq!(color_type, 0);
+        assert!(parse_color_type("cmyk").is_err());
+
+        let output = std::env::temp_dir().join("rpng_test_color_type.png");
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(24, 24, |x, y| {
+            image::Rgb([(x * 10) as u8, (y * 10) as u8, ((x + y) * 5) as u8])
+        }));
+        let save = |color_type| {
+            save_output(
+                &image,
+                &output,
+                CompressionMethod::Flate2,
+                EncodeOptions::default(),
+                Some(color_type),
+                true,
+                false,
+            )
+        };
+
+        save(color_type).unwrap();
+        let decoded = image::open(&output).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        assert_eq!(decoded.to_luma8(), image.to_luma8());
+
+        // 576 distinct colors cannot be written as a palette
+        std::fs::remove_file(&output).ok();
+        assert!(matches!(
+            save(parse_color_type("palette").unwrap()),
+            Err(EncoderError::TooManyColors)
+        ));
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_existing_output_needs_force() {
+        let output = std::env::temp_dir().join("rpng_test_no_clobber.png");
+        std::fs::remove_file(&output).ok();
+        let first = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(4, 4, image::Luma([10])));
+        let second =
+            DynamicImage::ImageLuma8(image::GrayImage::from_pixel(4, 4, image::Luma([200])));
+        let save = |image: &DynamicImage, force: bool| {
+            save_output(
+                image,
+                &output,
+                CompressionMethod::Flate2,
+                EncodeOptions::default(),
+                None,
+                force,
+                false,
+            )
+        };
+
+        save(&first, false).unwrap();
+        let written = std::fs::read(&output).unwrap();
+        assert!(matches!(
+            save(&second, false),
+            Err(EncoderError::OutputExists(path)) if path == output
+        ));
+        assert_eq!(std::fs::read(&output).unwrap(), written);
+
+        save(&second, true).unwrap();
+        assert_eq!(image::open(&output).unwrap().to_luma8(), second.to_luma8());
+
+        std::fs::remove_file(&output).ok();
+    }
+}
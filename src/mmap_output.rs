@@ -0,0 +1,82 @@
+use memmap2::MmapMut;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+/// A writer backed by a memory-mapped file.
+///
+/// The file is pre-sized to an estimated capacity and grown by remapping if the estimate
+/// turns out too small. [`MmapWriter::finish`] truncates it to the bytes actually written.
+pub struct MmapWriter {
+    file: File,
+    map: MmapMut,
+    len: usize,
+}
+
+impl MmapWriter {
+    pub fn create(path: &Path, capacity: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::from_file(file, capacity)
+    }
+
+    /// Like [`MmapWriter::create`], but fails with `AlreadyExists` instead of truncating
+    /// a file already at `path`.
+    pub fn create_new(path: &Path, capacity: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Self::from_file(file, capacity)
+    }
+
+    fn from_file(file: File, capacity: usize) -> std::io::Result<Self> {
+        let map = map_file(&file, capacity.max(1))?;
+        Ok(MmapWriter { file, map, len: 0 })
+    }
+
+    /// Flushes the mapping and truncates the file to the written length.
+    pub fn finish(self) -> std::io::Result<()> {
+        self.map.flush()?;
+        drop(self.map);
+        self.file.set_len(self.len as u64)
+    }
+
+    fn grow(&mut self, needed: usize) -> std::io::Result<()> {
+        self.map.flush()?;
+        let capacity = needed.max(self.map.len() * 2);
+        self.map = map_file(&self.file, capacity)?;
+        Ok(())
+    }
+}
+
+impl Write for MmapWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let end = self.len + buf.len();
+        if end > self.map.len() {
+            self.grow(end)?;
+        }
+
+        self.map[self.len..end].copy_from_slice(buf);
+        self.len = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.map.flush()
+    }
+}
+
+fn map_file(file: &File, capacity: usize) -> std::io::Result<MmapMut> {
+    file.set_len(capacity as u64)?;
+    // SAFETY: the file was just created or truncated by this writer. As with any memory
+    // map, this assumes no other process resizes or writes the file while it is mapped.
+    unsafe { MmapMut::map_mut(file) }
+}
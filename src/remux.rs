@@ -0,0 +1,285 @@
+use crate::decoder::chunks;
+use crate::encoder::{PNG_SIGNATURE, encode_keyword};
+use flate2::{Compression, write::ZlibEncoder};
+use std::{io::Write, path::Path};
+
+/// Color-management chunks to add or replace with [`remux_color_chunks`].
+#[derive(Clone, Debug, Default)]
+pub struct ColorChunks {
+    /// Rendering intent (0-3) for an sRGB chunk. Replaces any iCCP chunk, since the two
+    /// must not appear together.
+    pub srgb: Option<u8>,
+    /// Image gamma times 100000, as stored in gAMA.
+    pub gamma: Option<u32>,
+    /// Profile name and uncompressed ICC profile for an iCCP chunk. Replaces any sRGB
+    /// chunk.
+    pub icc_profile: Option<(String, Vec<u8>)>,
+}
+
+impl ColorChunks {
+    /// Serializes the requested chunks in the order they are inserted after IHDR.
+    fn to_chunks(&self) -> std::io::Result<Vec<([u8; 4], Vec<u8>)>> {
+        if self.srgb.is_some() && self.icc_profile.is_some() {
+            return Err(invalid_input(
+                "sRGB and iCCP chunks cannot be used together",
+            ));
+        }
+
+        let mut chunks = Vec::new();
+        if let Some(gamma) = self.gamma {
+            chunks.push((*b"gAMA", gamma.to_be_bytes().to_vec()));
+        }
+        if let Some(intent) = self.srgb {
+            if intent > 3 {
+                return Err(invalid_input(
+                    "sRGB rendering intent must be between 0 and 3",
+                ));
+            }
+            chunks.push((*b"sRGB", vec![intent]));
+        }
+        if let Some((name, profile)) = &self.icc_profile {
+            let mut data = encode_keyword(name)?;
+            // Compression method 0 (zlib), the only one defined
+            data.push(0);
+            let mut zlib = ZlibEncoder::new(data, Compression::default());
+            zlib.write_all(profile)?;
+            chunks.push((*b"iCCP", zlib.finish()?));
+        }
+        Ok(chunks)
+    }
+
+    /// Chunk types to drop from the input because they are being replaced.
+    fn replaced_types(&self) -> Vec<[u8; 4]> {
+        let mut types = Vec::new();
+        if self.gamma.is_some() {
+            types.push(*b"gAMA");
+        }
+        if self.srgb.is_some() || self.icc_profile.is_some() {
+            types.extend([*b"sRGB", *b"iCCP"]);
+        }
+        types
+    }
+}
+
+/// Copies the PNG at `input` to `output`, dropping every chunk whose type is listed in
+/// `types`. Pixel data is never decoded; the remaining chunks are copied byte for byte.
+pub fn strip_chunks(input: &Path, output: &Path, types: &[[u8; 4]]) -> std::io::Result<()> {
+    let bytes = std::fs::read(input)?;
+    let stripped = strip_chunks_from_bytes(&bytes, types)?;
+    std::fs::write(output, stripped)
+}
+
+/// In-memory form of [`strip_chunks`].
+///
+/// Only ancillary chunks can be stripped; naming a critical chunk (IHDR, PLTE, IDAT, IEND)
+/// is an error because the result would no longer be a usable PNG.
+pub fn strip_chunks_from_bytes(bytes: &[u8], types: &[[u8; 4]]) -> std::io::Result<Vec<u8>> {
+    if let Some(critical) = types.iter().find(|chunk_type| is_critical(chunk_type)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Cannot strip critical chunk {}",
+                String::from_utf8_lossy(critical)
+            ),
+        ));
+    }
+
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        if !types.contains(&chunk.chunk_type) {
+            output.extend_from_slice(chunk.raw);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Copies the PNG at `input` to `output` with its sRGB/gAMA/iCCP chunks inserted or
+/// replaced. IHDR, IDAT and every other chunk are copied byte for byte, so pixels are
+/// untouched.
+pub fn remux_color_chunks(input: &Path, output: &Path, color: &ColorChunks) -> std::io::Result<()> {
+    let bytes = std::fs::read(input)?;
+    let remuxed = remux_color_chunks_from_bytes(&bytes, color)?;
+    std::fs::write(output, remuxed)
+}
+
+/// In-memory form of [`remux_color_chunks`]. The new chunks are placed directly after
+/// IHDR, ahead of PLTE and IDAT as the spec requires.
+pub fn remux_color_chunks_from_bytes(
+    bytes: &[u8],
+    color: &ColorChunks,
+) -> std::io::Result<Vec<u8>> {
+    let new_chunks = color.to_chunks()?;
+    let replaced = color.replaced_types();
+
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        if replaced.contains(&chunk.chunk_type) {
+            continue;
+        }
+
+        output.extend_from_slice(chunk.raw);
+        if &chunk.chunk_type == b"IHDR" {
+            for (chunk_type, data) in &new_chunks {
+                push_chunk(&mut output, chunk_type, data);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+fn push_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    output.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string())
+}
+
+/// Critical chunks have an uppercase first letter in their type.
+fn is_critical(chunk_type: &[u8; 4]) -> bool {
+    chunk_type[0].is_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{CompressionMethod, EncodeOptions, TextChunk, save_to_png_with_options};
+
+    fn chunk_list(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        chunks(bytes)
+            .unwrap()
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                (chunk.chunk_type, chunk.data.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strip_text_chunks() {
+        let dir = std::env::temp_dir();
+        let input = dir.join("rpng_test_strip_in.png");
+        let output = dir.join("rpng_test_strip_out.png");
+
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(6, 6, |x, y| {
+            image::Rgba([x as u8 * 40, y as u8 * 40, 7, 255])
+        }));
+        let options = EncodeOptions {
+            text: vec![TextChunk::Latin1 {
+                keyword: "Author".to_string(),
+                text: "Someone".to_string(),
+            }],
+            ..Default::default()
+        };
+        save_to_png_with_options(
+            &image,
+            &input.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+
+        strip_chunks(&input, &output, &[*b"tEXt", *b"tIME"]).unwrap();
+
+        let before = chunk_list(&std::fs::read(&input).unwrap());
+        let after = chunk_list(&std::fs::read(&output).unwrap());
+        assert!(before.iter().any(|(chunk_type, _)| chunk_type == b"tEXt"));
+        assert!(after.iter().all(|(chunk_type, _)| chunk_type != b"tEXt"));
+
+        let idat = |chunks: &[([u8; 4], Vec<u8>)]| -> Vec<Vec<u8>> {
+            chunks
+                .iter()
+                .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+                .map(|(_, data)| data.clone())
+                .collect()
+        };
+        assert_eq!(idat(&before), idat(&after));
+        assert_eq!(image::open(&output).unwrap().to_rgba8(), image.to_rgba8());
+
+        assert!(strip_chunks(&input, &output, &[*b"IDAT"]).is_err());
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn test_remux_replaces_srgb_keeping_pixels() {
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(5, 5, |x, y| {
+            image::Rgba([x as u8 * 50, 90, y as u8 * 50, 200])
+        }));
+        let mut original = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut original, image::ImageFormat::Png)
+            .unwrap();
+        let original = original.into_inner();
+
+        let tagged = remux_color_chunks_from_bytes(
+            &original,
+            &ColorChunks {
+                srgb: Some(0),
+                gamma: Some(45455),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let retagged = remux_color_chunks_from_bytes(
+            &tagged,
+            &ColorChunks {
+                srgb: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let chunks = chunk_list(&retagged);
+        let srgb: Vec<_> = chunks
+            .iter()
+            .filter(|(chunk_type, _)| chunk_type == b"sRGB")
+            .collect();
+        assert_eq!(srgb.len(), 1);
+        assert_eq!(srgb[0].1, vec![3]);
+        assert!(chunks.contains(&(*b"gAMA", 45455u32.to_be_bytes().to_vec())));
+        assert_eq!(&chunks[0].0, b"IHDR");
+
+        let idat = |chunks: &[([u8; 4], Vec<u8>)]| -> Vec<Vec<u8>> {
+            chunks
+                .iter()
+                .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+                .map(|(_, data)| data.clone())
+                .collect()
+        };
+        assert_eq!(idat(&chunks), idat(&chunk_list(&original)));
+        assert_eq!(
+            image::load_from_memory(&retagged).unwrap().to_rgba8(),
+            image.to_rgba8()
+        );
+
+        // Switching to an ICC profile drops the sRGB chunk
+        let with_icc = remux_color_chunks_from_bytes(
+            &retagged,
+            &ColorChunks {
+                icc_profile: Some(("Custom".to_string(), vec![1, 2, 3, 4])),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let chunks = chunk_list(&with_icc);
+        assert!(chunks.iter().any(|(chunk_type, _)| chunk_type == b"iCCP"));
+        assert!(chunks.iter().all(|(chunk_type, _)| chunk_type != b"sRGB"));
+    }
+}
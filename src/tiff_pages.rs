@@ -0,0 +1,124 @@
+use image::{DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, RgbImage, RgbaImage};
+use std::{fs::File, io::BufReader, path::Path};
+use tiff::{
+    ColorType,
+    decoder::{Decoder, DecodingResult},
+};
+
+pub fn is_tiff_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+}
+
+/// Counts the pages (IFDs) in a TIFF file without decoding any pixel data.
+pub fn page_count(path: &Path) -> std::io::Result<usize> {
+    let mut decoder = open_decoder(path)?;
+    let mut count = 1;
+
+    while decoder.more_images() {
+        decoder.next_image().map_err(to_io_error)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Decodes a single page of a (possibly multi-page) TIFF file.
+pub fn decode_page(path: &Path, page: usize) -> std::io::Result<DynamicImage> {
+    let mut decoder = open_decoder(path)?;
+
+    if page > 0 {
+        decoder.seek_to_image(page).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("TIFF page {} does not exist", page),
+            )
+        })?;
+    }
+
+    let (width, height) = decoder.dimensions().map_err(to_io_error)?;
+    let color_type = decoder.colortype().map_err(to_io_error)?;
+    let pixels = decoder.read_image().map_err(to_io_error)?;
+
+    let image = match (color_type, pixels) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            GrayAlphaImage::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (color_type, _) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported TIFF color type: {:?}", color_type),
+            ));
+        }
+    };
+
+    image.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "TIFF page data does not match its dimensions",
+        )
+    })
+}
+
+fn open_decoder(path: &Path) -> std::io::Result<Decoder<BufReader<File>>> {
+    let file = File::open(path)?;
+    Decoder::new(BufReader::new(file)).map_err(to_io_error)
+}
+
+fn to_io_error(e: tiff::TiffError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    #[test]
+    fn test_select_second_page() {
+        let path = std::env::temp_dir().join("rpng_test_two_pages.tiff");
+
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = TiffEncoder::new(file).unwrap();
+            encoder
+                .write_image::<colortype::RGBA8>(2, 2, &[10u8; 16])
+                .unwrap();
+            encoder
+                .write_image::<colortype::RGBA8>(3, 1, &[200u8; 12])
+                .unwrap();
+        }
+
+        assert_eq!(page_count(&path).unwrap(), 2);
+
+        let first = decode_page(&path, 0).unwrap();
+        assert_eq!((first.width(), first.height()), (2, 2));
+
+        let second = decode_page(&path, 1).unwrap();
+        assert_eq!((second.width(), second.height()), (3, 1));
+        assert!(second.to_rgba8().as_raw().iter().all(|&b| b == 200));
+
+        assert!(decode_page(&path, 2).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
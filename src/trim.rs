@@ -0,0 +1,231 @@
+use crate::decoder::chunks;
+use crate::encoder::{EncoderError, TextChunk};
+use image::{DynamicImage, GenericImageView};
+
+/// tEXt keyword under which the trim placement is recorded.
+pub const TRIM_KEYWORD: &str = "rpng:trim";
+
+/// Where a trimmed image sat inside the original canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrimOffset {
+    pub x: u32,
+    pub y: u32,
+    pub original_width: u32,
+    pub original_height: u32,
+}
+
+impl TrimOffset {
+    /// Serializes the offset as the JSON object stored in the trim tEXt chunk.
+    pub fn to_json(self) -> String {
+        format!(
+            "{{\"x\":{},\"y\":{},\"original_width\":{},\"original_height\":{}}}",
+            self.x, self.y, self.original_width, self.original_height
+        )
+    }
+
+    /// Parses the flat JSON object written by [`TrimOffset::to_json`]. Field order and
+    /// whitespace are not significant, but nested values are not supported.
+    pub fn from_json(json: &str) -> Option<TrimOffset> {
+        let body = json.trim().strip_prefix('{')?.strip_suffix('}')?;
+        let (mut x, mut y, mut width, mut height) = (None, None, None, None);
+
+        for field in body.split(',') {
+            let (key, value) = field.split_once(':')?;
+            let value = value.trim().parse::<u32>().ok()?;
+            match key.trim().trim_matches('"') {
+                "x" => x = Some(value),
+                "y" => y = Some(value),
+                "original_width" => width = Some(value),
+                "original_height" => height = Some(value),
+                _ => {}
+            }
+        }
+
+        Some(TrimOffset {
+            x: x?,
+            y: y?,
+            original_width: width?,
+            original_height: height?,
+        })
+    }
+
+    pub fn to_text_chunk(self) -> TextChunk {
+        TextChunk::Latin1 {
+            keyword: TRIM_KEYWORD.to_string(),
+            text: self.to_json(),
+        }
+    }
+}
+
+/// What [`trim_transparent`] does with an image that has no visible pixels at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyTrim {
+    /// Fail with [`EncoderError::EmptyImage`], since PNG cannot hold a 0x0 image.
+    #[default]
+    Error,
+    /// Keep the top-left pixel, which is fully transparent, as a 1x1 image.
+    TransparentPixel,
+}
+
+/// Crops away fully transparent rows and columns around the image content.
+///
+/// A fully transparent image trims to nothing, which `empty` decides how to handle.
+pub fn trim_transparent(
+    image: &DynamicImage,
+    empty: EmptyTrim,
+) -> Result<(DynamicImage, TrimOffset), EncoderError> {
+    let (width, height) = image.dimensions();
+    let rgba = image.to_rgba8();
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if pixel[3] == 0 {
+            continue;
+        }
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((min_x, min_y, max_x, max_y)) => {
+                (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+            }
+        });
+    }
+
+    let Some((min_x, min_y, max_x, max_y)) = bounds else {
+        return match empty {
+            EmptyTrim::Error => Err(EncoderError::EmptyImage),
+            EmptyTrim::TransparentPixel => Ok((
+                image.crop_imm(0, 0, 1, 1),
+                TrimOffset {
+                    x: 0,
+                    y: 0,
+                    original_width: width,
+                    original_height: height,
+                },
+            )),
+        };
+    };
+
+    let trimmed = image.crop_imm(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1);
+    let offset = TrimOffset {
+        x: min_x,
+        y: min_y,
+        original_width: width,
+        original_height: height,
+    };
+    Ok((trimmed, offset))
+}
+
+/// Reads back the trim offset recorded in a PNG, if it has one.
+pub fn read_trim_offset(bytes: &[u8]) -> std::io::Result<Option<TrimOffset>> {
+    for chunk in chunks(bytes)? {
+        let chunk = chunk?;
+        if &chunk.chunk_type != b"tEXt" {
+            continue;
+        }
+
+        let Some(separator) = chunk.data.iter().position(|&b| b == 0) else {
+            continue;
+        };
+        if &chunk.data[..separator] != TRIM_KEYWORD.as_bytes() {
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&chunk.data[separator + 1..]);
+        return TrimOffset::from_json(&text).map(Some).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Malformed trim offset metadata",
+            )
+        });
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{CompressionMethod, EncodeOptions, save_to_png_with_options};
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_recorded_offset_round_trips() {
+        // Opaque content spans x in 3..=6 and y in 2..=4 on a 12x9 canvas
+        let canvas = RgbaImage::from_fn(12, 9, |x, y| {
+            if (3..=6).contains(&x) && (2..=4).contains(&y) {
+                Rgba([200, 100, 50, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        let image = DynamicImage::ImageRgba8(canvas);
+
+        let (trimmed, offset) = trim_transparent(&image, EmptyTrim::Error).unwrap();
+        assert_eq!((trimmed.width(), trimmed.height()), (4, 3));
+        assert_eq!(
+            offset,
+            TrimOffset {
+                x: 3,
+                y: 2,
+                original_width: 12,
+                original_height: 9,
+            }
+        );
+
+        let path = std::env::temp_dir().join("rpng_test_trim_offset.png");
+        let options = EncodeOptions {
+            text: vec![offset.to_text_chunk()],
+            ..Default::default()
+        };
+        save_to_png_with_options(
+            &trimmed,
+            &path.to_string_lossy(),
+            CompressionMethod::Flate2,
+            options,
+        )
+        .unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let restored = read_trim_offset(&bytes).unwrap().unwrap();
+        assert_eq!(restored, offset);
+
+        // Placing the trimmed pixels back at the offset reproduces the original canvas
+        let mut rebuilt = RgbaImage::new(restored.original_width, restored.original_height);
+        image::imageops::overlay(
+            &mut rebuilt,
+            &image::load_from_memory(&bytes).unwrap().to_rgba8(),
+            restored.x as i64,
+            restored.y as i64,
+        );
+        assert_eq!(rebuilt, image.to_rgba8());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_fully_transparent_trim() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(5, 4));
+
+        assert!(matches!(
+            trim_transparent(&image, EmptyTrim::Error),
+            Err(EncoderError::EmptyImage)
+        ));
+
+        let (trimmed, offset) = trim_transparent(&image, EmptyTrim::TransparentPixel).unwrap();
+        assert_eq!(trimmed.to_rgba8().into_raw(), [0, 0, 0, 0]);
+        assert_eq!(
+            offset,
+            TrimOffset {
+                x: 0,
+                y: 0,
+                original_width: 5,
+                original_height: 4,
+            }
+        );
+
+        // The 1x1 result encodes to a valid PNG
+        let bytes = crate::encoder::encode_to_vec(&trimmed, CompressionMethod::Custom).unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1, 1));
+    }
+}